@@ -1,11 +1,14 @@
 use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{api::client_server, services, utils, Error, Result, Ruma};
+use crate::{
+    service::{appservice::Namespace, users::is_guest_localpart},
+    services, utils, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability, register,
-            request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-            whoami, ThirdPartyIdRemovalStatus,
+            change_password, check_registration_token_validity, deactivate, get_3pids,
+            get_username_availability, register, request_3pid_management_token_via_email,
+            request_3pid_management_token_via_msisdn, whoami, ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
         uiaa::{AuthFlow, AuthType, UiaaInfo},
@@ -13,11 +16,13 @@ use ruma::{
     events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
     push, UserId,
 };
+use serde_json::{json, value::to_raw_value};
 use tracing::{info, warn};
 
 use register::RegistrationKind;
 
 const RANDOM_USER_ID_LENGTH: usize = 10;
+const UNUSABLE_PASSWORD_LENGTH: usize = 64;
 
 /// # `GET /_matrix/client/r0/register/available`
 ///
@@ -25,6 +30,7 @@ const RANDOM_USER_ID_LENGTH: usize = 10;
 ///
 /// Conditions for returning true:
 /// - The user id is not historical
+/// - The user id is not numeric, as those are reserved for guests
 /// - The server name of the user id matches this server
 /// - No user or appservice on this server already claimed this username
 ///
@@ -46,6 +52,13 @@ pub async fn get_register_available_route(
         "Username is invalid.",
     ))?;
 
+    if is_guest_localpart(user_id.localpart()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Numeric user IDs are reserved for guest users.",
+        ));
+    }
+
     // Check if username is creative enough
     if services().users.exists(&user_id)? {
         return Err(Error::BadRequest(
@@ -54,7 +67,16 @@ pub async fn get_register_available_route(
         ));
     }
 
-    // TODO add check for appservice namespaces
+    if services().appservice.is_exclusive_to_other(
+        Namespace::Users,
+        user_id.as_str(),
+        body.appservice_id.as_deref(),
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Username is reserved by an appservice.",
+        ));
+    }
 
     // If no if check is true we have an username that's available to be used.
     Ok(get_username_availability::v3::Response { available: true })
@@ -68,8 +90,12 @@ pub async fn get_register_available_route(
 /// to check if the user id is valid and available.
 ///
 /// - Only works if registration is enabled
-/// - If type is guest: ignores all parameters except initial_device_display_name
-/// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
+/// - If type is guest: Only works if guests are allowed, ignores all parameters except
+///   initial_device_display_name
+/// - If sender is not appservice: Requires UIAA (a registration token stage if registration
+///   requires a token and a captcha stage if captchas are configured, otherwise only a dummy
+///   stage)
+/// - If type is guest and registration requires a token or captcha: Always fails
 /// - If type is not guest and no username is given: Always fails after UIAA check
 /// - Creates a new account and populates it with default account data
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
@@ -83,8 +109,36 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     let is_guest = body.kind == RegistrationKind::Guest;
 
+    if is_guest && !services().globals.allow_guests() && !body.from_appservice {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guest access is disabled on this server.",
+        ));
+    }
+
+    // Guests skip UIAA, so they can't complete the required stages
+    if is_guest
+        && (services().globals.registration_requires_token()
+            || services().globals.captcha().is_some())
+        && !body.from_appservice
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Guest registration is disabled on this server.",
+        ));
+    }
+
     let user_id = match (&body.username, is_guest) {
+        // Guests don't complete UIAA, so the account can be created right away
+        (_, true) => services().users.create_guest()?,
         (Some(username), false) => {
+            if is_guest_localpart(username) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidUsername,
+                    "Numeric user IDs are reserved for guest users.",
+                ));
+            }
+
             let proposed_user_id = UserId::parse_with_server_name(
                 username.to_lowercase(),
                 services().globals.server_name(),
@@ -104,6 +158,16 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
                     "Desired user ID is already taken.",
                 ));
             }
+            if services().appservice.is_exclusive_to_other(
+                Namespace::Users,
+                proposed_user_id.as_str(),
+                body.appservice_id.as_deref(),
+            )? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Exclusive,
+                    "Username is reserved by an appservice.",
+                ));
+            }
             proposed_user_id
         }
         _ => loop {
@@ -119,25 +183,41 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     };
 
     // UIAA
+    let mut stages = Vec::new();
+    let mut params = Default::default();
+    if services().globals.registration_requires_token() {
+        stages.push(AuthType::RegistrationToken);
+    }
+    if let Some(captcha) = services().globals.captcha() {
+        stages.push(AuthType::ReCaptcha);
+        params = to_raw_value(&json!({
+            "m.login.recaptcha": { "public_key": captcha.public_key },
+        }))
+        .expect("captcha params are valid json");
+    }
+    if stages.is_empty() {
+        stages.push(AuthType::Dummy);
+    }
     let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Dummy],
-        }],
+        flows: vec![AuthFlow { stages }],
         completed: Vec::new(),
-        params: Default::default(),
+        params,
         session: None,
         auth_error: None,
     };
 
     if !body.from_appservice && !is_guest {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = services().uiaa.try_auth(
-                &UserId::parse_with_server_name("", services().globals.server_name())
-                    .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-            )?;
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(
+                    &UserId::parse_with_server_name("", services().globals.server_name())
+                        .expect("we know this is valid"),
+                    "".into(),
+                    auth,
+                    &uiaainfo,
+                )
+                .await?;
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
@@ -157,14 +237,15 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         }
     }
 
-    let password = if is_guest {
-        None
-    } else {
-        body.password.as_deref()
-    };
-
-    // Create user
-    services().users.create(&user_id, password)?;
+    // Create user. Appservices usually don't set a password, but an empty one would mark the
+    // account as deactivated.
+    if !is_guest {
+        let password = body
+            .password
+            .clone()
+            .unwrap_or_else(|| utils::random_string(UNUSABLE_PASSWORD_LENGTH));
+        services().users.create(&user_id, Some(&password))?;
+    }
 
     // Default to pretty displayname
     let mut displayname = user_id.localpart().to_owned();
@@ -261,6 +342,7 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 /// - Invalidates access token
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets to-device events
+/// - Deletes pushers registered by the device
 /// - Triggers device list updates
 pub async fn change_password_route(
     body: Ruma<change_password::v3::Request>,
@@ -279,10 +361,10 @@ pub async fn change_password_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -297,21 +379,12 @@ pub async fn change_password_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    services()
-        .users
-        .set_password(sender_user, Some(&body.new_password))?;
-
-    if body.logout_devices {
-        // Logout all devices except the current one
-        for id in services()
-            .users
-            .all_device_ids(sender_user)
-            .filter_map(|id| id.ok())
-            .filter(|id| id != sender_device)
-        {
-            services().users.remove_device(sender_user, &id)?;
-        }
-    }
+    services().users.change_password(
+        sender_user,
+        sender_device,
+        &body.new_password,
+        body.logout_devices,
+    )?;
 
     info!("User {} changed their password.", sender_user);
     services()
@@ -335,7 +408,7 @@ pub async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoami::v3:
     Ok(whoami::v3::Response {
         user_id: sender_user.clone(),
         device_id,
-        is_guest: services().users.is_deactivated(sender_user)? && !body.from_appservice,
+        is_guest: services().users.is_guest(sender_user)?,
     })
 }
 
@@ -349,6 +422,8 @@ pub async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoami::v3:
 /// - Forgets all to-device events
 /// - Triggers device list updates
 /// - Removes ability to log in again
+/// - Deletes all pushers
+/// - If `erase` is true: Removes display name and avatar
 pub async fn deactivate_route(
     body: Ruma<deactivate::v3::Request>,
 ) -> Result<deactivate::v3::Response> {
@@ -366,10 +441,10 @@ pub async fn deactivate_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -384,11 +459,11 @@ pub async fn deactivate_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    // Make the user leave all rooms before deactivation
-    client_server::leave_all_rooms(sender_user).await?;
-
-    // Remove devices and mark account as deactivated
-    services().users.deactivate_account(sender_user)?;
+    // Remove devices, mark account as deactivated and leave all rooms
+    services()
+        .users
+        .deactivate_account(sender_user, body.erase)
+        .await?;
 
     info!("User {} deactivated their account.", sender_user);
     services()
@@ -398,7 +473,8 @@ pub async fn deactivate_route(
         )));
 
     Ok(deactivate::v3::Response {
-        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+        // No third party identifiers are stored, so none were bound
+        id_server_unbind_result: ThirdPartyIdRemovalStatus::Success,
     })
 }
 
@@ -442,3 +518,24 @@ pub async fn request_3pid_management_token_via_msisdn_route(
         "Third party identifier is not allowed",
     ))
 }
+
+/// # `GET /_matrix/client/v1/register/m.login.registration_token/validity`
+///
+/// Checks if a registration token can still be used to register an account.
+///
+/// Note: This will not take up a use of the token, so the token might become invalid when trying
+/// to register
+pub async fn check_registration_token_validity_route(
+    body: Ruma<check_registration_token_validity::v1::Request>,
+) -> Result<check_registration_token_validity::v1::Response> {
+    if !services().globals.allow_registration() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration has been disabled.",
+        ));
+    }
+
+    Ok(check_registration_token_validity::v1::Response {
+        valid: services().uiaa.registration_token_valid(&body.token)?,
+    })
+}
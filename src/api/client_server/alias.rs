@@ -1,5 +1,7 @@
-use crate::{services, Error, Result, Ruma};
-use regex::Regex;
+use crate::{
+    service::appservice::{alias_matches_namespace, Namespace},
+    services, Error, Result, Ruma,
+};
 use ruma::{
     api::{
         appservice,
@@ -34,6 +36,17 @@ pub async fn create_alias_route(
         return Err(Error::Conflict("Alias already exists."));
     }
 
+    if services().appservice.is_exclusive_to_other(
+        Namespace::Aliases,
+        body.room_alias.as_str(),
+        body.appservice_id.as_deref(),
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Alias is reserved by an appservice.",
+        ));
+    }
+
     services()
         .rooms
         .alias
@@ -101,20 +114,7 @@ pub(crate) async fn get_alias_helper(
         Some(r) => room_id = Some(r),
         None => {
             for (_id, registration) in services().appservice.all()? {
-                let aliases = registration
-                    .get("namespaces")
-                    .and_then(|ns| ns.get("aliases"))
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-
-                if aliases
-                    .iter()
-                    .any(|aliases| aliases.is_match(room_alias.as_str()))
+                if alias_matches_namespace(&registration, &room_alias)
                     && services()
                         .sending
                         .send_appservice_request(
@@ -89,7 +89,8 @@ pub async fn claim_keys_route(
 ///
 /// Uploads end-to-end key information for the sender user.
 ///
-/// - Requires UIAA to verify password
+/// - Requires UIAA to verify password if the user already has a master key
+/// - Keeps the signatures of keys that are uploaded again
 pub async fn upload_signing_keys_route(
     body: Ruma<upload_signing_keys::v3::Request>,
 ) -> Result<upload_signing_keys::v3::Response> {
@@ -107,33 +108,39 @@ pub async fn upload_signing_keys_route(
         auth_error: None,
     };
 
-    if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
+    // Setting up cross-signing for the first time doesn't replace anything
+    let replacing = services()
+        .users
+        .get_master_key(sender_user, &|_| true)?
+        .is_some();
+
+    if replacing {
+        if let Some(auth) = &body.auth {
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(sender_user, sender_device, auth, &uiaainfo)
+                .await?;
+            if !worked {
+                return Err(Error::Uiaa(uiaainfo));
+            }
+        // Success!
+        } else if let Some(json) = body.json_body {
+            uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
             services()
                 .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
-        if !worked {
+                .create(sender_user, sender_device, &uiaainfo, &json)?;
             return Err(Error::Uiaa(uiaainfo));
+        } else {
+            return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
         }
-    // Success!
-    } else if let Some(json) = body.json_body {
-        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
-        services()
-            .uiaa
-            .create(sender_user, sender_device, &uiaainfo, &json)?;
-        return Err(Error::Uiaa(uiaainfo));
-    } else {
-        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    if let Some(master_key) = &body.master_key {
-        services().users.add_cross_signing_keys(
-            sender_user,
-            master_key,
-            &body.self_signing_key,
-            &body.user_signing_key,
-        )?;
-    }
+    services().users.add_cross_signing_keys(
+        sender_user,
+        &body.master_key,
+        &body.self_signing_key,
+        &body.user_signing_key,
+    )?;
 
     Ok(upload_signing_keys::v3::Response {})
 }
@@ -476,3 +483,51 @@ pub(crate) async fn claim_keys_helper(
         one_time_keys,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::{api::OutgoingResponse, user_id};
+    use serde_json::value::to_raw_value;
+
+    #[test]
+    fn key_query_contains_cross_signing_keys() {
+        let user_id = user_id!("@alice:example.org");
+        let key = |usage: &str| {
+            let mut keys = BTreeMap::new();
+            keys.insert(
+                user_id.to_owned(),
+                Raw::from_json(
+                    to_raw_value(&json!({
+                        "user_id": user_id,
+                        "usage": [usage],
+                        "keys": { format!("ed25519:{usage}"): usage },
+                    }))
+                    .unwrap(),
+                ),
+            );
+            keys
+        };
+
+        let response = get_keys::v3::Response {
+            master_keys: key("master"),
+            self_signing_keys: key("self_signing"),
+            user_signing_keys: key("user_signing"),
+            device_keys: BTreeMap::new(),
+            failures: BTreeMap::new(),
+        }
+        .try_into_http_response::<Vec<u8>>()
+        .unwrap();
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["master_keys"][user_id.as_str()]["usage"][0], "master");
+        assert_eq!(
+            body["self_signing_keys"][user_id.as_str()]["usage"][0],
+            "self_signing"
+        );
+        assert_eq!(
+            body["user_signing_keys"][user_id.as_str()]["usage"][0],
+            "user_signing"
+        );
+    }
+}
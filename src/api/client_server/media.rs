@@ -5,7 +5,7 @@ use ruma::api::client::{
     error::ErrorKind,
     media::{
         create_content, get_content, get_content_as_filename, get_content_thumbnail,
-        get_media_config,
+        get_media_config, get_media_preview,
     },
 };
 
@@ -22,12 +22,32 @@ pub async fn get_media_config_route(
     })
 }
 
+/// # `GET /_matrix/media/r0/preview_url`
+///
+/// Returns the OpenGraph properties of a web page.
+///
+/// - The page is fetched by the server, its image is uploaded as local media
+/// - URLs that resolve to internal addresses are refused
+/// - Previews are cached, including failed ones
+pub async fn get_media_preview_route(
+    body: Ruma<get_media_preview::v3::Request>,
+) -> Result<get_media_preview::v3::Response> {
+    let preview = services().media.get_url_preview(&body.url).await?;
+
+    Ok(get_media_preview::v3::Response {
+        data: Some(
+            serde_json::value::to_raw_value(&preview).expect("URL preview can be serialized"),
+        ),
+    })
+}
+
 /// # `POST /_matrix/media/r0/upload`
 ///
 /// Permanently save media in the server.
 ///
 /// - Some metadata will be saved in the database
 /// - Media will be saved in the media/ directory
+/// - Images get a blurhash if `compute_blurhashes` is enabled
 pub async fn create_content_route(
     body: Ruma<create_content::v3::Request>,
 ) -> Result<create_content::v3::Response> {
@@ -41,6 +61,7 @@ pub async fn create_content_route(
         .media
         .create(
             mxc.clone(),
+            body.sender_user.as_deref(),
             body.filename
                 .as_ref()
                 .map(|filename| "inline; filename=".to_owned() + filename)
@@ -51,8 +72,8 @@ pub async fn create_content_route(
         .await?;
 
     Ok(create_content::v3::Response {
+        blurhash: services().media.blurhash(&mxc)?,
         content_uri: mxc.try_into().expect("Invalid mxc:// URI"),
-        blurhash: None,
     })
 }
 
@@ -79,6 +100,7 @@ pub async fn get_remote_content(
         .media
         .create(
             mxc.to_owned(),
+            None,
             content_response.content_disposition.as_deref(),
             content_response.content_type.as_deref(),
             &content_response.file,
@@ -178,6 +200,7 @@ pub async fn get_content_thumbnail_route(
             body.height
                 .try_into()
                 .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
+            body.method.as_ref(),
         )
         .await?
     {
@@ -212,6 +235,7 @@ pub async fn get_content_thumbnail_route(
                 get_thumbnail_response.content_type.as_deref(),
                 body.width.try_into().expect("all UInts are valid u32s"),
                 body.height.try_into().expect("all UInts are valid u32s"),
+                body.method.as_ref(),
                 &get_thumbnail_response.file,
             )
             .await?;
@@ -2,21 +2,22 @@ use ruma::{
     api::{
         client::{
             error::ErrorKind,
+            knock::knock_room,
             membership::{
                 ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
                 join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
                 unban_user, ThirdPartySigned,
             },
         },
-        federation::{self, membership::create_invite},
+        federation::{
+            self,
+            knock::{create_knock_event_template, send_knock},
+            membership::create_invite,
+        },
     },
     canonical_json::to_canonical_value,
     events::{
-        room::{
-            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-            power_levels::RoomPowerLevelsEventContent,
-        },
+        room::member::{MembershipState, RoomMemberEventContent},
         StateEventType, TimelineEventType,
     },
     serde::Base64,
@@ -32,7 +33,10 @@ use std::{
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
+    service::{
+        pdu::{gen_event_id_canonical_json, PduBuilder},
+        rooms::state_accessor::RestrictedJoin,
+    },
     services, utils, Error, PduEvent, Result, Ruma,
 };
 
@@ -130,16 +134,58 @@ pub async fn join_room_by_id_or_alias_route(
     })
 }
 
+/// # `POST /_matrix/client/v3/knock/{roomIdOrAlias}`
+///
+/// Asks to be invited into a room whose join rules allow knocking.
+///
+/// - If the server knowns about this room: creates the knock event and does auth rules locally
+/// - If the server does not know about the room: asks other servers over federation
+pub async fn knock_room_route(
+    body: Ruma<knock_room::v3::Request>,
+) -> Result<knock_room::v3::Response> {
+    let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+    let body = body.body;
+
+    let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
+        Ok(room_id) => {
+            let mut servers = body.server_name.clone();
+            servers.push(room_id.server_name().to_owned());
+
+            (servers, room_id)
+        }
+        Err(room_alias) => {
+            let response = get_alias_helper(room_alias).await?;
+
+            (response.servers.into_iter().collect(), response.room_id)
+        }
+    };
+
+    knock_room_helper(sender_user, &room_id, body.reason, &servers).await?;
+
+    Ok(knock_room::v3::Response { room_id })
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
 ///
 /// - This should always work if the user is currently joined.
+/// - Users can't leave the room they receive server notices in
 pub async fn leave_room_route(
     body: Ruma<leave_room::v3::Request>,
 ) -> Result<leave_room::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if services()
+        .admin
+        .is_server_notice_room(sender_user, &body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can't leave the server notices room.",
+        ));
+    }
+
     leave_room(sender_user, &body.room_id, body.reason.clone()).await?;
 
     Ok(leave_room::v3::Response::new())
@@ -474,6 +520,20 @@ async fn join_room_by_id_helper(
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = sender_user.expect("user is authenticated");
 
+    // Guests can only join rooms we can check the guest access rules of
+    if services().users.is_guest(sender_user)?
+        && (!services()
+            .rooms
+            .state_cache
+            .server_in_room(services().globals.server_name(), room_id)?
+            || !services().rooms.state_accessor.guests_can_join(room_id)?)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to join this room.",
+        ));
+    }
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -785,95 +845,25 @@ async fn join_room_by_id_helper(
     } else {
         info!("We can join locally");
 
-        let join_rules_event = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomJoinRules,
-            "",
-        )?;
-        let power_levels_event = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomPowerLevels,
-            "",
-        )?;
-
-        let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-            .as_ref()
-            .map(|join_rules_event| {
-                serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                    warn!("Invalid join rules event: {}", e);
-                    Error::bad_database("Invalid join rules event in db.")
-                })
-            })
-            .transpose()?;
-        let power_levels_event_content: Option<RoomPowerLevelsEventContent> = power_levels_event
-            .as_ref()
-            .map(|power_levels_event| {
-                serde_json::from_str(power_levels_event.content.get()).map_err(|e| {
-                    warn!("Invalid power levels event: {}", e);
-                    Error::bad_database("Invalid power levels event in db.")
-                })
-            })
-            .transpose()?;
+        let restricted_join = services()
+            .rooms
+            .state_accessor
+            .restricted_join(sender_user, room_id)?;
 
-        let restriction_rooms = match join_rules_event_content {
-            Some(RoomJoinRulesEventContent {
-                join_rule: JoinRule::Restricted(restricted),
-            })
-            | Some(RoomJoinRulesEventContent {
-                join_rule: JoinRule::KnockRestricted(restricted),
-            }) => restricted
-                .allow
-                .into_iter()
-                .filter_map(|a| match a {
-                    AllowRule::RoomMembership(r) => Some(r.room_id),
-                    _ => None,
-                })
-                .collect(),
-            _ => Vec::new(),
+        let authorized_user = match restricted_join {
+            Some(RestrictedJoin::Allowed) => services()
+                .rooms
+                .state_accessor
+                .restricted_join_authorizer(room_id)?,
+            Some(RestrictedJoin::Forbidden) => {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "You need an invite or to be in one of the rooms the join rules allow.",
+                ))
+            }
+            _ => None,
         };
 
-        let authorized_user = restriction_rooms
-            .iter()
-            .find_map(|restriction_room_id| {
-                if !services()
-                    .rooms
-                    .state_cache
-                    .is_joined(sender_user, restriction_room_id)
-                    .ok()?
-                {
-                    return None;
-                }
-                let authorized_user = power_levels_event_content
-                    .as_ref()
-                    .and_then(|c| {
-                        c.users
-                            .iter()
-                            .filter(|(uid, i)| {
-                                uid.server_name() == services().globals.server_name()
-                                    && **i > ruma::int!(0)
-                                    && services()
-                                        .rooms
-                                        .state_cache
-                                        .is_joined(uid, restriction_room_id)
-                                        .unwrap_or(false)
-                            })
-                            .max_by_key(|(_, i)| *i)
-                            .map(|(u, _)| u.to_owned())
-                    })
-                    .or_else(|| {
-                        // TODO: Check here if user is actually allowed to invite. Currently the auth
-                        // check will just fail in this case.
-                        services()
-                            .rooms
-                            .state_cache
-                            .room_members(restriction_room_id)
-                            .filter_map(|r| r.ok())
-                            .find(|uid| uid.server_name() == services().globals.server_name())
-                    });
-                Some(authorized_user)
-            })
-            .flatten();
-
         let event = RoomMemberEventContent {
             membership: MembershipState::Join,
             displayname: services().users.displayname(sender_user)?,
@@ -902,7 +892,7 @@ async fn join_room_by_id_helper(
             Err(e) => e,
         };
 
-        if !restriction_rooms.is_empty()
+        if restricted_join == Some(RestrictedJoin::Allowed)
             && servers
                 .iter()
                 .filter(|s| *s != services().globals.server_name())
@@ -1054,6 +1044,226 @@ async fn join_room_by_id_helper(
     Ok(join_room_by_id::v3::Response::new(room_id.to_owned()))
 }
 
+async fn knock_room_helper(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> Result<()> {
+    if services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are already joined to this room.",
+        ));
+    }
+
+    if services()
+        .rooms
+        .state_cache
+        .is_invited(sender_user, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are already invited to this room.",
+        ));
+    }
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    // Ask a remote server if we are not participating in this room
+    if !services()
+        .rooms
+        .state_cache
+        .server_in_room(services().globals.server_name(), room_id)?
+    {
+        info!("Knocking on {room_id} over federation.");
+
+        return remote_knock_room(sender_user, room_id, reason, servers).await;
+    }
+
+    if !services().rooms.state_accessor.allows_knocking(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    let event = RoomMemberEventContent {
+        membership: MembershipState::Knock,
+        displayname: services().users.displayname(sender_user)?,
+        avatar_url: services().users.avatar_url(sender_user)?,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: services().users.blurhash(sender_user)?,
+        reason,
+        join_authorized_via_users_server: None,
+    };
+
+    // The auth rules reject knocks of banned users
+    services().rooms.timeline.build_and_append_pdu(
+        PduBuilder {
+            event_type: TimelineEventType::RoomMember,
+            content: to_raw_value(&event).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(sender_user.to_string()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        &state_lock,
+    )?;
+
+    Ok(())
+}
+
+async fn remote_knock_room(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> Result<()> {
+    let mut make_knock_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in knocking.",
+    ));
+
+    for remote_server in servers {
+        if remote_server == services().globals.server_name() {
+            continue;
+        }
+        info!("Asking {remote_server} for make_knock");
+        let make_knock_response = services()
+            .sending
+            .send_federation_request(
+                remote_server,
+                create_knock_event_template::v1::Request {
+                    room_id: room_id.to_owned(),
+                    user_id: sender_user.to_owned(),
+                    ver: services().globals.supported_room_versions(),
+                },
+            )
+            .await;
+
+        make_knock_response_and_server = make_knock_response.map(|r| (r, remote_server.clone()));
+
+        if make_knock_response_and_server.is_ok() {
+            break;
+        }
+    }
+
+    let (make_knock_response, remote_server) = make_knock_response_and_server?;
+
+    let room_version_id = make_knock_response.room_version;
+    if !services()
+        .globals
+        .supported_room_versions()
+        .contains(&room_version_id)
+    {
+        return Err(Error::BadServerResponse("Room version is not supported"));
+    }
+
+    let mut knock_event_stub = serde_json::from_str::<CanonicalJsonObject>(
+        make_knock_response.event.get(),
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid make_knock event json received from server."))?;
+
+    // TODO: Is origin needed?
+    knock_event_stub.insert(
+        "origin".to_owned(),
+        CanonicalJsonValue::String(services().globals.server_name().as_str().to_owned()),
+    );
+    knock_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(
+            utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("Timestamp is valid js_int value"),
+        ),
+    );
+    knock_event_stub.insert(
+        "content".to_owned(),
+        to_canonical_value(RoomMemberEventContent {
+            membership: MembershipState::Knock,
+            displayname: services().users.displayname(sender_user)?,
+            avatar_url: services().users.avatar_url(sender_user)?,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: services().users.blurhash(sender_user)?,
+            reason,
+            join_authorized_via_users_server: None,
+        })
+        .expect("event is valid, we just created it"),
+    );
+
+    // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
+    knock_event_stub.remove("event_id");
+
+    // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
+    ruma::signatures::hash_and_sign_event(
+        services().globals.server_name().as_str(),
+        services().globals.keypair(),
+        &mut knock_event_stub,
+        &room_version_id,
+    )
+    .expect("event is valid, we just created it");
+
+    // Generate event id
+    let event_id = EventId::parse(format!(
+        "${}",
+        ruma::signatures::reference_hash(&knock_event_stub, &room_version_id)
+            .expect("ruma can calculate reference hashes")
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    // Add event_id back
+    knock_event_stub.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+
+    // It has enough fields to be called a proper event now
+    let knock_event = knock_event_stub;
+
+    info!("Asking {remote_server} for send_knock");
+    let send_knock_response = services()
+        .sending
+        .send_federation_request(
+            &remote_server,
+            send_knock::v1::Request {
+                room_id: room_id.to_owned(),
+                event_id,
+                pdu: PduEvent::convert_to_outgoing_federation_event(knock_event),
+            },
+        )
+        .await?;
+
+    info!("send_knock finished");
+
+    // We are not in the room, so we only remember the knock and the state we got for it
+    services().rooms.state_cache.update_membership(
+        room_id,
+        sender_user,
+        MembershipState::Knock,
+        sender_user,
+        Some(send_knock_response.knock_room_state),
+        true,
+    )?;
+
+    Ok(())
+}
+
 async fn make_join_request(
     sender_user: &UserId,
     room_id: &RoomId,
@@ -1343,6 +1553,13 @@ pub async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
                 .rooms_invited(user_id)
                 .map(|t| t.map(|(r, _)| r)),
         )
+        .chain(
+            services()
+                .rooms
+                .state_cache
+                .rooms_knocked(user_id)
+                .map(|t| t.map(|(r, _)| r)),
+        )
         .collect::<Vec<_>>();
 
     for room_id in all_rooms {
@@ -1371,12 +1588,16 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
             .rooms
             .state_cache
             .invite_state(user_id, room_id)?
+            .map_or_else(
+                || services().rooms.state_cache.knock_state(user_id, room_id),
+                |s| Ok(Some(s)),
+            )?
             .map_or_else(
                 || services().rooms.state_cache.left_state(user_id, room_id),
                 |s| Ok(Some(s)),
             )?;
 
-        // We always drop the invite, we can't rely on other servers
+        // We always drop the invite or knock, we can't rely on other servers
         services().rooms.state_cache.update_membership(
             room_id,
             user_id,
@@ -1449,16 +1670,26 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         "No server available to assist in leaving.",
     ));
 
-    let invite_state = services()
+    let (invite_state, knocked) = match services()
         .rooms
         .state_cache
         .invite_state(user_id, room_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::BadState,
-            "User is not invited.",
-        ))?;
+    {
+        Some(invite_state) => (invite_state, false),
+        None => (
+            services()
+                .rooms
+                .state_cache
+                .knock_state(user_id, room_id)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::BadState,
+                    "User is neither invited nor knocking.",
+                ))?,
+            true,
+        ),
+    };
 
-    let servers: HashSet<_> = invite_state
+    let mut servers: HashSet<_> = invite_state
         .iter()
         .filter_map(|event| serde_json::from_str(event.json().get()).ok())
         .filter_map(|event: serde_json::Value| event.get("sender").cloned())
@@ -1467,6 +1698,12 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         .map(|user| user.server_name().to_owned())
         .collect();
 
+    // The knock state also contains our own knock, so ask the server of the room as well
+    if knocked {
+        servers.insert(room_id.server_name().to_owned());
+        servers.remove(services().globals.server_name());
+    }
+
     for remote_server in servers {
         let make_leave_response = services()
             .sending
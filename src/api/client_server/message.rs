@@ -20,6 +20,7 @@ use std::{
 ///
 /// - Is a NOOP if the txn id was already used before and returns the same event id again
 /// - The only requirement for the content is that it has to be valid json
+/// - Guests can only send `m.room.message` and `m.room.encrypted` events
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
 pub async fn send_message_event_route(
     body: Ruma<send_message_event::v3::Request>,
@@ -38,6 +39,19 @@ pub async fn send_message_event_route(
     );
     let state_lock = mutex_state.lock().await;
 
+    // Guests can only send messages
+    if services().users.is_guest(sender_user)?
+        && !matches!(
+            TimelineEventType::from(body.event_type.to_string()),
+            TimelineEventType::RoomMessage | TimelineEventType::RoomEncrypted
+        )
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests can only send messages.",
+        ));
+    }
+
     // Forbid m.room.encrypted if encryption is disabled
     if TimelineEventType::RoomEncrypted == body.event_type.to_string().into()
         && !services().globals.allow_encryption()
@@ -16,10 +16,13 @@ mod profile;
 mod push;
 mod read_marker;
 mod redact;
+mod relations;
 mod report;
 mod room;
 mod search;
 mod session;
+mod space;
+mod sso;
 mod state;
 mod sync;
 mod tag;
@@ -49,10 +52,13 @@ pub use profile::*;
 pub use push::*;
 pub use read_marker::*;
 pub use redact::*;
+pub use relations::*;
 pub use report::*;
 pub use room::*;
 pub use search::*;
 pub use session::*;
+pub use space::*;
+pub use sso::*;
 pub use state::*;
 pub use sync::*;
 pub use tag::*;
@@ -1,5 +1,8 @@
-use crate::{services, utils, Result, Ruma};
-use ruma::api::client::presence::{get_presence, set_presence};
+use crate::{services, Error, Result, Ruma};
+use ruma::api::client::{
+    error::ErrorKind,
+    presence::{get_presence, set_presence},
+};
 use std::time::Duration;
 
 /// # `PUT /_matrix/client/r0/presence/{userId}/status`
@@ -10,29 +13,11 @@ pub async fn set_presence_route(
 ) -> Result<set_presence::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    for room_id in services().rooms.state_cache.rooms_joined(sender_user) {
-        let room_id = room_id?;
-
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: body.presence.clone(),
-                    status_msg: body.status_msg.clone(),
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
-    }
+    services().rooms.edus.presence.set_presence(
+        sender_user,
+        body.presence.clone(),
+        body.status_msg.clone(),
+    )?;
 
     Ok(set_presence::v3::Response {})
 }
@@ -47,25 +32,19 @@ pub async fn get_presence_route(
 ) -> Result<get_presence::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let mut presence_event = None;
-
-    for room_id in services()
-        .rooms
-        .user
-        .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
-    {
-        let room_id = room_id?;
-
-        if let Some(presence) = services()
+    let shares_room = sender_user == &body.user_id
+        || services()
             .rooms
-            .edus
-            .presence
-            .get_last_presence_event(sender_user, &room_id)?
-        {
-            presence_event = Some(presence);
-            break;
-        }
-    }
+            .user
+            .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
+            .next()
+            .is_some();
+
+    let presence_event = if shares_room {
+        services().rooms.edus.presence.get_presence(&body.user_id)?
+    } else {
+        None
+    };
 
     if let Some(presence) = presence_event {
         Ok(get_presence::v3::Response {
@@ -79,6 +58,9 @@ pub async fn get_presence_route(
             presence: presence.content.presence,
         })
     } else {
-        todo!();
+        Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Presence state for this user was not found.",
+        ))
     }
 }
@@ -309,7 +309,9 @@ pub async fn get_profile_route(
         });
     }
 
-    if !services().users.exists(&body.user_id)? {
+    if !services().users.exists(&body.user_id)?
+        && !services().appservice.query_user(&body.user_id).await?
+    {
         // Return 404 if this user doesn't exist
         return Err(Error::BadRequest(
             ErrorKind::NotFound,
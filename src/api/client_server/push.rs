@@ -1,16 +1,20 @@
-use crate::{services, Error, Result, Ruma};
+use crate::{
+    service::pusher::{PusherSettings, LATEST_FORMAT_VERSION, PUSHER_SETTINGS_KEY},
+    services, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
         push::{
             delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled,
             get_pushrules_all, set_pusher, set_pushrule, set_pushrule_actions,
-            set_pushrule_enabled, RuleScope,
+            set_pushrule_enabled, PusherKind, RuleScope,
         },
     },
     events::{push_rules::PushRulesEvent, GlobalAccountDataEventType},
     push::{InsertPushRuleError, RemovePushRuleError},
 };
+use tracing::warn;
 
 /// # `GET /_matrix/client/r0/pushrules`
 ///
@@ -423,10 +427,82 @@ pub async fn set_pushers_route(
     body: Ruma<set_pusher::v3::Request>,
 ) -> Result<set_pusher::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    // Appservices register pushers without a device
+    let sender_device = body.sender_device.as_deref();
+
+    // Conduit specific settings are passed next to the standard fields of the pusher data
+    let settings = body
+        .json_body
+        .clone()
+        .map(serde_json::Value::from)
+        .and_then(|mut json| {
+            json.get_mut("data")?
+                .get_mut(PUSHER_SETTINGS_KEY)
+                .map(serde_json::Value::take)
+        })
+        .map(|settings| {
+            serde_json::from_value::<PusherSettings>(settings).map_err(|_| {
+                Error::BadRequest(ErrorKind::BadJson, "Invalid Conduit pusher settings.")
+            })
+        })
+        .transpose()?;
+
+    let mut new_settings = None;
+    if let set_pusher::v3::PusherAction::Post(data) = &body.action {
+        // Checked before the settings are stored, so rejected pushers leave no settings behind
+        services().pusher.check_app_id(&data.pusher.ids.app_id)?;
+
+        if settings
+            .as_ref()
+            .map_or(false, |settings| settings.full_event)
+        {
+            let trusted = match &data.pusher.kind {
+                PusherKind::Http(http) => services()
+                    .globals
+                    .push_full_event_gateways()
+                    .contains(&http.url),
+                _ => false,
+            };
+
+            if !trusted {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "This push gateway is not trusted with full events.",
+                ));
+            }
+
+            warn!(
+                "{} enabled full events for push gateway of pusher {}, it will receive complete message contents",
+                sender_user, data.pusher.ids.pushkey
+            );
+        }
+
+        // New pushers get the latest notification layout, unless their app asked for an older one
+        let mut settings = settings.unwrap_or_default();
+        settings.format_version.get_or_insert(LATEST_FORMAT_VERSION);
+        // Registering the pusher again resumes it
+        settings.paused_until = None;
+
+        new_settings = Some((&data.pusher.ids.pushkey, settings));
+    }
+
+    // The settings must not outlive a pusher that couldn't be stored
+    services().pusher.transaction(|| {
+        if let Some((pushkey, settings)) = &new_settings {
+            services()
+                .pusher
+                .set_pusher_settings(sender_user, pushkey, settings)?;
+            if let Some(device_id) = sender_device {
+                services()
+                    .pusher
+                    .set_pusher_device(sender_user, pushkey, device_id)?;
+            }
+        }
 
-    services()
-        .pusher
-        .set_pusher(sender_user, body.action.clone())?;
+        services()
+            .pusher
+            .set_pusher(sender_user, body.action.clone())
+    })?;
 
     Ok(set_pusher::v3::Response::default())
 }
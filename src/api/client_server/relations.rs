@@ -1,10 +1,158 @@
-use crate::{services, Result, Ruma};
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
 
-/// # `GET /_matrix/client/r0/todo`
+use ruma::{
+    api::{
+        client::relations::{
+            get_relating_events, get_relating_events_with_rel_type,
+            get_relating_events_with_rel_type_and_event_type,
+        },
+        Direction,
+    },
+    events::AnyMessageLikeEvent,
+    serde::Raw,
+    EventId, RoomId, UInt, UserId,
+};
+
+use crate::{service::rooms::timeline::PduCount, services, PduEvent, Result, Ruma};
+
+/// # `GET /_matrix/client/r0/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`
+///
+/// Paginates the events relating to an event with the given rel_type and event type, newest
+/// first.
+pub async fn get_relating_events_with_rel_type_and_event_type_route(
+    body: Ruma<get_relating_events_with_rel_type_and_event_type::v1::Request>,
+) -> Result<get_relating_events_with_rel_type_and_event_type::v1::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let from = match &body.from {
+        Some(from) => PduCount::try_from_string(from)?,
+        None => PduCount::max(),
+    };
+    let to = body
+        .to
+        .as_deref()
+        .map(PduCount::try_from_string)
+        .transpose()?;
+
+    let (relations, next) = services().rooms.pdu_metadata.relations_for_type(
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        &body.rel_type.to_string(),
+        &body.event_type.to_string(),
+        from,
+        to,
+        page_limit(body.limit),
+        Direction::Backward,
+    )?;
+
+    Ok(
+        get_relating_events_with_rel_type_and_event_type::v1::Response {
+            chunk: to_chunk(sender_user, relations)?,
+            next_batch: next.map(|count| count.stringify()),
+            prev_batch: body.from.clone(),
+        },
+    )
+}
+
+/// # `GET /_matrix/client/r0/rooms/{roomId}/relations/{eventId}/{relType}`
+///
+/// Paginates the events relating to an event with the given rel_type, newest first.
+pub async fn get_relating_events_with_rel_type_route(
+    body: Ruma<get_relating_events_with_rel_type::v1::Request>,
+) -> Result<get_relating_events_with_rel_type::v1::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (chunk, next_batch) = relating_events(
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        Some(&body.rel_type.to_string()),
+        body.from.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_relating_events_with_rel_type::v1::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    })
+}
+
+/// # `GET /_matrix/client/r0/rooms/{roomId}/relations/{eventId}`
+///
+/// Paginates the events relating to an event, newest first.
 pub async fn get_relating_events_route(
-    body: Ruma<get_turn_server_info::v3::Request>,
-) -> Result<get_turn_server_info::v3::Response> {
+    body: Ruma<get_relating_events::v1::Request>,
+) -> Result<get_relating_events::v1::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    todo!();
+
+    let (chunk, next_batch) = relating_events(
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        None,
+        body.from.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_relating_events::v1::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    })
+}
+
+/// Returns one page of relating events and the token for the next page, if there is one.
+fn relating_events(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+    rel_type: Option<&str>,
+    from: Option<&str>,
+    limit: Option<UInt>,
+) -> Result<(Vec<Raw<AnyMessageLikeEvent>>, Option<String>)> {
+    let from = match from {
+        Some(from) => PduCount::try_from_string(from)?,
+        None => PduCount::max(),
+    };
+
+    let limit = page_limit(limit);
+
+    let relations = services().rooms.pdu_metadata.relations_until(
+        sender_user,
+        room_id,
+        event_id,
+        rel_type,
+        from,
+        limit,
+    )?;
+
+    let next_batch = (relations.len() == limit)
+        .then(|| relations.last().map(|(count, _)| count.stringify()))
+        .flatten();
+
+    Ok((to_chunk(sender_user, relations)?, next_batch))
+}
+
+/// Returns the requested number of events per page, 10 by default and at most 100.
+fn page_limit(limit: Option<UInt>) -> usize {
+    limit.and_then(|l| l.try_into().ok()).unwrap_or(10).min(100)
+}
+
+/// Converts the relating events for the response, hiding the transaction ids of other users.
+fn to_chunk(
+    sender_user: &UserId,
+    relations: Vec<(PduCount, Arc<PduEvent>)>,
+) -> Result<Vec<Raw<AnyMessageLikeEvent>>> {
+    let mut chunk = Vec::new();
+    for (_, pdu) in relations {
+        let mut pdu = (*pdu).clone();
+        if pdu.sender != sender_user {
+            pdu.remove_transaction_id()?;
+        }
+        chunk.push(pdu.to_message_like_event());
+    }
+
+    Ok(chunk)
 }
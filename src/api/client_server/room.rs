@@ -1,5 +1,7 @@
 use crate::{
-    api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, Result, Ruma,
+    api::client_server::invite_helper,
+    service::{appservice::Namespace, pdu::PduBuilder},
+    services, Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -16,17 +18,16 @@ use ruma::{
             member::{MembershipState, RoomMemberEventContent},
             name::RoomNameEventContent,
             power_levels::RoomPowerLevelsEventContent,
-            tombstone::RoomTombstoneEventContent,
             topic::RoomTopicEventContent,
         },
-        StateEventType, TimelineEventType,
+        TimelineEventType,
     },
     int,
     serde::JsonObject,
     CanonicalJsonObject, OwnedRoomAliasId, RoomAliasId, RoomId,
 };
 use serde_json::{json, value::to_raw_value};
-use std::{cmp::max, collections::BTreeMap, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc};
 use tracing::{info, warn};
 
 /// # `POST /_matrix/client/r0/createRoom`
@@ -99,6 +100,15 @@ pub async fn create_room_route(
                         ErrorKind::RoomInUse,
                         "Room alias already exists.",
                     ))
+                } else if services().appservice.is_exclusive_to_other(
+                    Namespace::Aliases,
+                    alias.as_str(),
+                    body.appservice_id.as_deref(),
+                )? {
+                    Err(Error::BadRequest(
+                        ErrorKind::Exclusive,
+                        "Room alias is reserved by an appservice.",
+                    ))
                 } else {
                     Ok(Some(alias))
                 }
@@ -425,11 +435,13 @@ pub async fn get_room_event_route(
 ) -> Result<get_room_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let event = services()
+    let mut event = services()
         .rooms
         .timeline
         .get_pdu(&body.event_id)?
-        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
+        .as_ref()
+        .clone();
 
     if !services().rooms.state_accessor.user_can_see_event(
         sender_user,
@@ -442,6 +454,11 @@ pub async fn get_room_event_route(
         ));
     }
 
+    services()
+        .rooms
+        .pdu_metadata
+        .bundle_aggregations(sender_user, &body.room_id, &mut event)?;
+
     Ok(get_room_event::v3::Response {
         event: event.to_room_event(),
     })
@@ -485,251 +502,19 @@ pub async fn get_room_aliases_route(
 /// - Creates a replacement room
 /// - Sends a tombstone event into the current room
 /// - Sender user joins the room
-/// - Transfers some state events
-/// - Moves local aliases
+/// - Transfers some state events and bans
+/// - Moves local aliases and invites local members
 /// - Modifies old room power levels to prevent users from speaking
 pub async fn upgrade_room_route(
     body: Ruma<upgrade_room::v3::Request>,
 ) -> Result<upgrade_room::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if !services()
-        .globals
-        .supported_room_versions()
-        .contains(&body.new_version)
-    {
-        return Err(Error::BadRequest(
-            ErrorKind::UnsupportedRoomVersion,
-            "This server does not support that room version.",
-        ));
-    }
-
-    // Create a replacement room
-    let replacement_room = RoomId::new(services().globals.server_name());
-    services()
-        .rooms
-        .short
-        .get_or_create_shortroomid(&replacement_room)?;
-
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
-    let state_lock = mutex_state.lock().await;
-
-    // Send a m.room.tombstone event to the old room to indicate that it is not intended to be used any further
-    // Fail if the sender does not have the required permissions
-    let tombstone_event_id = services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomTombstone,
-            content: to_raw_value(&RoomTombstoneEventContent {
-                body: "This room has been replaced".to_owned(),
-                replacement_room: replacement_room.clone(),
-            })
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &body.room_id,
-        &state_lock,
-    )?;
-
-    // Change lock to replacement room
-    drop(state_lock);
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(replacement_room.clone())
-            .or_default(),
-    );
-    let state_lock = mutex_state.lock().await;
-
-    // Get the old room creation event
-    let mut create_event_content = serde_json::from_str::<CanonicalJsonObject>(
-        services()
-            .rooms
-            .state_accessor
-            .room_state_get(&body.room_id, &StateEventType::RoomCreate, "")?
-            .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
-            .content
-            .get(),
-    )
-    .map_err(|_| Error::bad_database("Invalid room event in database."))?;
-
-    // Use the m.room.tombstone event as the predecessor
-    let predecessor = Some(ruma::events::room::create::PreviousRoom::new(
-        body.room_id.clone(),
-        (*tombstone_event_id).to_owned(),
-    ));
-
-    // Send a m.room.create event containing a predecessor field and the applicable room_version
-    create_event_content.insert(
-        "creator".into(),
-        json!(&sender_user)
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Error forming creation event"))?,
-    );
-    create_event_content.insert(
-        "room_version".into(),
-        json!(&body.new_version)
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Error forming creation event"))?,
-    );
-    create_event_content.insert(
-        "predecessor".into(),
-        json!(predecessor)
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Error forming creation event"))?,
-    );
-
-    // Validate creation event content
-    let de_result = serde_json::from_str::<CanonicalJsonObject>(
-        to_raw_value(&create_event_content)
-            .expect("Error forming creation event")
-            .get(),
-    );
-
-    if de_result.is_err() {
-        return Err(Error::BadRequest(
-            ErrorKind::BadJson,
-            "Error forming creation event",
-        ));
-    }
-
-    services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomCreate,
-            content: to_raw_value(&create_event_content)
-                .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &replacement_room,
-        &state_lock,
-    )?;
-
-    // Join the new room
-    services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomMember,
-            content: to_raw_value(&RoomMemberEventContent {
-                membership: MembershipState::Join,
-                displayname: services().users.displayname(sender_user)?,
-                avatar_url: services().users.avatar_url(sender_user)?,
-                is_direct: None,
-                third_party_invite: None,
-                blurhash: services().users.blurhash(sender_user)?,
-                reason: None,
-                join_authorized_via_users_server: None,
-            })
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some(sender_user.to_string()),
-            redacts: None,
-        },
-        sender_user,
-        &replacement_room,
-        &state_lock,
-    )?;
-
-    // Recommended transferable state events list from the specs
-    let transferable_state_events = vec![
-        StateEventType::RoomServerAcl,
-        StateEventType::RoomEncryption,
-        StateEventType::RoomName,
-        StateEventType::RoomAvatar,
-        StateEventType::RoomTopic,
-        StateEventType::RoomGuestAccess,
-        StateEventType::RoomHistoryVisibility,
-        StateEventType::RoomJoinRules,
-        StateEventType::RoomPowerLevels,
-    ];
-
-    // Replicate transferable state events to the new room
-    for event_type in transferable_state_events {
-        let event_content =
-            match services()
-                .rooms
-                .state_accessor
-                .room_state_get(&body.room_id, &event_type, "")?
-            {
-                Some(v) => v.content.clone(),
-                None => continue, // Skipping missing events.
-            };
-
-        services().rooms.timeline.build_and_append_pdu(
-            PduBuilder {
-                event_type: event_type.to_string().into(),
-                content: event_content,
-                unsigned: None,
-                state_key: Some("".to_owned()),
-                redacts: None,
-            },
-            sender_user,
-            &replacement_room,
-            &state_lock,
-        )?;
-    }
-
-    // Moves any local aliases to the new room
-    for alias in services()
+    let replacement_room = services()
         .rooms
-        .alias
-        .local_aliases_for_room(&body.room_id)
-        .filter_map(|r| r.ok())
-    {
-        services()
-            .rooms
-            .alias
-            .set_alias(&alias, &replacement_room)?;
-    }
-
-    // Get the old room power levels
-    let mut power_levels_event_content: RoomPowerLevelsEventContent = serde_json::from_str(
-        services()
-            .rooms
-            .state_accessor
-            .room_state_get(&body.room_id, &StateEventType::RoomPowerLevels, "")?
-            .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
-            .content
-            .get(),
-    )
-    .map_err(|_| Error::bad_database("Invalid room event in database."))?;
-
-    // Setting events_default and invite to the greater of 50 and users_default + 1
-    let new_level = max(int!(50), power_levels_event_content.users_default + int!(1));
-    power_levels_event_content.events_default = new_level;
-    power_levels_event_content.invite = new_level;
-
-    // Modify the power levels in the old room to prevent sending of events and inviting new users
-    let _ = services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomPowerLevels,
-            content: to_raw_value(&power_levels_event_content)
-                .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &body.room_id,
-        &state_lock,
-    )?;
-
-    drop(state_lock);
+        .upgrade
+        .upgrade_room(sender_user, &body.room_id, &body.new_version)
+        .await?;
 
-    // Return the replacement room id
     Ok(upgrade_room::v3::Response { replacement_room })
 }
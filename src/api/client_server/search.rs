@@ -1,9 +1,12 @@
-use crate::{services, Error, Result, Ruma};
+use crate::{
+    service::rooms::search::{self, tokenize},
+    services, Error, PduEvent, Result, Ruma,
+};
 use ruma::api::client::{
     error::ErrorKind,
     search::search_events::{
         self,
-        v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+        v3::{EventContextResult, OrderBy, ResultCategories, ResultRoomEvents, SearchResult},
     },
 };
 
@@ -13,7 +16,9 @@ use std::collections::BTreeMap;
 ///
 /// Searches rooms for messages.
 ///
-/// - Only works if the user is currently joined to the room (TODO: Respect history visibility)
+/// - Only works if the user is currently joined to the room
+/// - Only returns events the user is allowed to see
+/// - Orders by rank unless `order_by` is `recent`
 pub async fn search_events_route(
     body: Ruma<search_events::v3::Request>,
 ) -> Result<search_events::v3::Response> {
@@ -33,27 +38,17 @@ pub async fn search_events_route(
 
     let limit = filter.limit.map_or(10, |l| u64::from(l) as usize);
 
-    let mut searches = Vec::new();
-
-    for room_id in room_ids {
+    for room_id in &room_ids {
         if !services()
             .rooms
             .state_cache
-            .is_joined(sender_user, &room_id)?
+            .is_joined(sender_user, room_id)?
         {
             return Err(Error::BadRequest(
                 ErrorKind::Forbidden,
                 "You don't have permission to view this room.",
             ));
         }
-
-        if let Some(search) = services()
-            .rooms
-            .search
-            .search_pdus(&room_id, &search_criteria.search_term)?
-        {
-            searches.push(search.0.peekable());
-        }
     }
 
     let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
@@ -67,51 +62,59 @@ pub async fn search_events_route(
         None => 0, // Default to the start
     };
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
-        }
-    }
+    let terms: Vec<_> = tokenize(&search_criteria.search_term).collect();
 
-    let results: Vec<_> = results
-        .iter()
-        .filter_map(|result| {
+    let visible_pdus = services()
+        .rooms
+        .search
+        .search_rooms(&room_ids, &search_criteria.search_term)?
+        .filter_map(|pdu_id| services().rooms.timeline.get_pdu_from_id(&pdu_id).ok()?)
+        .filter(|pdu| {
             services()
                 .rooms
-                .timeline
-                .get_pdu_from_id(result)
-                .ok()?
-                .filter(|pdu| {
-                    services()
-                        .rooms
-                        .state_accessor
-                        .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
-                        .unwrap_or(false)
-                })
-                .map(|pdu| pdu.to_room_event())
-        })
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
-                context: EventContextResult {
-                    end: None,
-                    events_after: Vec::new(),
-                    events_before: Vec::new(),
-                    profile_info: BTreeMap::new(),
-                    start: None,
-                },
-                rank: None,
-                result: Some(result),
-            })
+                .state_accessor
+                .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+                .unwrap_or(false)
+        });
+
+    let ranked: Vec<_> = match search_criteria.order_by {
+        Some(OrderBy::Recent) => visible_pdus
+            .skip(skip)
+            .take(limit)
+            .map(|pdu| (None, pdu))
+            .collect(),
+        // Ranking every match would be too expensive for common words, so only the newest
+        // matches are ranked
+        _ => {
+            let mut ranked: Vec<_> = visible_pdus
+                .take(MAX_RANKED_RESULTS)
+                .map(|pdu| (rank(&pdu, &terms), pdu))
+                .collect();
+            // The sort is stable, so equally ranked results stay newest first
+            ranked.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+            ranked
+                .into_iter()
+                .skip(skip)
+                .take(limit)
+                .map(|(rank, pdu)| (Some(rank as f64), pdu))
+                .collect()
+        }
+    };
+
+    let results: Vec<_> = ranked
+        .into_iter()
+        .map(|(rank, pdu)| SearchResult {
+            context: EventContextResult {
+                end: None,
+                events_after: Vec::new(),
+                events_before: Vec::new(),
+                profile_info: BTreeMap::new(),
+                start: None,
+            },
+            rank,
+            result: Some(pdu.to_room_event()),
         })
-        .filter_map(|r| r.ok())
-        .skip(skip)
-        .take(limit)
         .collect();
 
     let next_batch = if results.len() < limit {
@@ -127,11 +130,23 @@ pub async fn search_events_route(
             next_batch,
             results,
             state: BTreeMap::new(), // TODO
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            highlights: terms,
         },
     }))
 }
+
+/// How many of the newest matches are ranked when ordering search results by rank.
+const MAX_RANKED_RESULTS: usize = 1000;
+
+/// Returns how often the search terms appear in the body of the message.
+fn rank(pdu: &PduEvent, terms: &[String]) -> usize {
+    serde_json::from_str::<serde_json::Value>(pdu.content.get())
+        .ok()
+        .and_then(|content| {
+            content
+                .get("body")
+                .and_then(|body| body.as_str())
+                .map(|body| search::rank(body, terms))
+        })
+        .unwrap_or(0)
+}
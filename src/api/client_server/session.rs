@@ -3,7 +3,10 @@ use crate::{services, utils, Error, Result, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
-        session::{get_login_types, login, logout, logout_all},
+        session::{
+            get_login_types::{self, v3::IdentityProvider},
+            login, logout, logout_all,
+        },
         uiaa::UserIdentifier,
     },
     UserId,
@@ -24,17 +27,39 @@ struct Claims {
 pub async fn get_login_types_route(
     _body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-    Ok(get_login_types::v3::Response::new(vec![
+    let mut login_types = vec![
         get_login_types::v3::LoginType::Password(Default::default()),
         get_login_types::v3::LoginType::ApplicationService(Default::default()),
-    ]))
+    ];
+
+    let providers = services().globals.sso_providers();
+    if !providers.is_empty() {
+        login_types.push(get_login_types::v3::LoginType::Sso(
+            get_login_types::v3::SsoLoginType {
+                identity_providers: providers
+                    .iter()
+                    .map(|provider| IdentityProvider {
+                        id: provider.id.clone(),
+                        name: provider.name.clone(),
+                        icon: provider.icon.clone(),
+                        brand: provider.brand.as_deref().map(Into::into),
+                    })
+                    .collect(),
+            },
+        ));
+        // The login token the client gets after SSO is exchanged with m.login.token
+        login_types.push(get_login_types::v3::LoginType::Token(Default::default()));
+    }
+
+    Ok(get_login_types::v3::Response::new(login_types))
 }
 
 /// # `POST /_matrix/client/r0/login`
 ///
 /// Authenticates the user and returns an access token it can use in subsequent requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a json web token)
+/// - The user needs to authenticate using their password (or if enabled using a json web token,
+///   or a login token from SSO)
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -67,26 +92,20 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                     "Wrong username or password.",
                 ))?;
 
-            if hash.is_empty() {
-                return Err(Error::BadRequest(
-                    ErrorKind::UserDeactivated,
-                    "The user has been deactivated",
-                ));
-            }
-
-            let hash_matches = argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
-
-            if !hash_matches {
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Wrong username or password.",
-                ));
-            }
+            check_password(&hash, password)?;
 
             user_id
         }
         login::v3::LoginInfo::Token(login::v3::Token { token }) => {
-            if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
+            if let Some(user_id) = services().sso.consume_login_token(token) {
+                if services().users.is_deactivated(&user_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
+                user_id
+            } else if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
                 let token = jsonwebtoken::decode::<Claims>(
                     token,
                     jwt_decoding_key,
@@ -94,9 +113,23 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 )
                 .map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid."))?;
                 let username = token.claims.sub.to_lowercase();
-                UserId::parse_with_server_name(username, services().globals.server_name()).map_err(
-                    |_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."),
-                )?
+                let user_id =
+                    UserId::parse_with_server_name(username, services().globals.server_name())
+                        .map_err(|_| {
+                            Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+                        })?;
+                if services().users.is_deactivated(&user_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
+                user_id
+            } else if !services().globals.sso_providers().is_empty() {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Invalid or expired login token.",
+                ));
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::Unknown,
@@ -121,6 +154,12 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                     .map_err(|_| {
                         Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
                     })?;
+            if services().users.is_deactivated(&user_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::UserDeactivated,
+                    "The user has been deactivated",
+                ));
+            }
             user_id
         }
         _ => {
@@ -211,3 +250,49 @@ pub async fn logout_all_route(
 
     Ok(logout_all::v3::Response::new())
 }
+
+/// Fails if the password doesn't match the hash, or the account was deactivated.
+fn check_password(hash: &str, password: &str) -> Result<()> {
+    if hash.is_empty() {
+        return Err(Error::BadRequest(
+            ErrorKind::UserDeactivated,
+            "The user has been deactivated",
+        ));
+    }
+
+    let hash_matches = argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false);
+
+    if !hash_matches {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Wrong username or password.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deactivated_user_cannot_log_in() {
+        let hash = utils::calculate_password_hash("hunter2").unwrap();
+        assert!(check_password(&hash, "hunter2").is_ok());
+        assert!(matches!(
+            check_password(&hash, "hunter3"),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+
+        // Deactivating an account replaces the hash with an empty string
+        assert!(matches!(
+            check_password("", "hunter2"),
+            Err(Error::BadRequest(ErrorKind::UserDeactivated, _))
+        ));
+        assert!(matches!(
+            check_password("", ""),
+            Err(Error::BadRequest(ErrorKind::UserDeactivated, _))
+        ));
+    }
+}
@@ -0,0 +1,25 @@
+use ruma::api::client::space::get_hierarchy;
+
+use crate::{services, Result, Ruma};
+
+/// # `GET /_matrix/client/v1/rooms/{room_id}/hierarchy`
+///
+/// Paginates over the space tree breadth-first to locate child rooms of a given space.
+pub async fn get_hierarchy_route(
+    body: Ruma<get_hierarchy::v1::Request>,
+) -> Result<get_hierarchy::v1::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    services()
+        .rooms
+        .spaces
+        .get_hierarchy(
+            sender_user,
+            &body.room_id,
+            body.suggested_only,
+            body.max_depth,
+            body.from.as_deref(),
+            body.limit,
+        )
+        .await
+}
@@ -0,0 +1,109 @@
+use crate::{services, utils::HtmlEscape, Error, Result, Ruma};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use http::{header::COOKIE, HeaderMap, Uri};
+use ruma::api::client::{
+    error::ErrorKind,
+    session::{sso_login, sso_login_with_provider},
+};
+use serde::Deserialize;
+use tracing::info;
+
+#[derive(Deserialize)]
+struct OidcCallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect`
+///
+/// Redirects the user to the first configured identity provider to log in.
+pub async fn sso_login_route(
+    body: Ruma<sso_login::v3::Request>,
+) -> Result<sso_login::v3::Response> {
+    let (location, cookie) = services().sso.authorization_url(None, &body.redirect_url)?;
+
+    Ok(sso_login::v3::Response {
+        location,
+        cookie: Some(cookie),
+    })
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect/{idpId}`
+///
+/// Redirects the user to the identity provider to log in.
+pub async fn sso_login_with_provider_route(
+    body: Ruma<sso_login_with_provider::v3::Request>,
+) -> Result<sso_login_with_provider::v3::Response> {
+    let (location, cookie) = services()
+        .sso
+        .authorization_url(Some(&body.idp_id), &body.redirect_url)?;
+
+    Ok(sso_login_with_provider::v3::Response {
+        location,
+        cookie: Some(cookie),
+    })
+}
+
+/// # `GET /_conduit/client/oidc/callback`
+///
+/// Where the identity provider sends the user back to after they logged in.
+///
+/// - Checks that the state was signed by this server for this browser and exchanges the code for
+///   an ID token
+/// - Creates an account on the first login
+/// - Redirects the user back to the client, with a login token for `m.login.token`. Users confirm
+///   this first, unless the client is in `sso_client_allowlist`
+pub async fn oidc_callback_route(uri: Uri, headers: HeaderMap) -> Result<Response> {
+    let params: OidcCallbackParams = serde_html_form::from_str(uri.query().unwrap_or_default())
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid callback parameters."))?;
+
+    if let Some(error) = params.error {
+        info!("Identity provider refused SSO login: {}", error);
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The identity provider refused the login.",
+        ));
+    }
+
+    let (code, state) = params.code.zip(params.state).ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Missing code or state.",
+    ))?;
+
+    let cookies = headers
+        .get(COOKIE)
+        .and_then(|cookies| cookies.to_str().ok());
+    let login = services()
+        .sso
+        .complete_login(&code, &state, cookies)
+        .await?;
+
+    if login.trusted {
+        return Ok(Redirect::to(&login.url).into_response());
+    }
+
+    // The login token gives full access to the account, so don't hand it to unknown clients
+    // without asking
+    let destination = uri_host(&login.url);
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+         <html><head><title>Continue to {destination}?</title></head><body>\n\
+         <p>You are about to log in to {server} with <b>{destination}</b>. Only continue if you \
+         trust this application and started the login there.</p>\n\
+         <p><a href=\"{url}\">Continue to {destination}</a></p>\n\
+         </body></html>",
+        destination = HtmlEscape(&destination),
+        server = HtmlEscape(services().globals.server_name().as_str()),
+        url = HtmlEscape(&login.url),
+    ))
+    .into_response())
+}
+
+/// Returns the host of the url to show to users, or the whole url if it has none.
+fn uri_host(url: &str) -> String {
+    url.parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.host().map(ToOwned::to_owned))
+        .unwrap_or_else(|| url.to_owned())
+}
@@ -186,6 +186,17 @@ async fn send_state_event_for_key_helper(
 ) -> Result<Arc<EventId>> {
     let sender_user = sender;
 
+    if event_type == &StateEventType::RoomMember
+        && services()
+            .admin
+            .is_server_notice_room(sender_user, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can't change your membership in the server notices room.",
+        ));
+    }
+
     // TODO: Review this check, error if event is unparsable, use event type, allow alias if it
     // previously existed
     if let Ok(canonical_alias) =
@@ -1,4 +1,7 @@
-use crate::{service::rooms::timeline::PduCount, services, Error, Result, Ruma, RumaResponse};
+use crate::{
+    service::{rooms::timeline::PduCount, sliding_sync},
+    services, Error, PduEvent, Result, Ruma, RumaResponse,
+};
 use ruma::{
     api::client::{
         filter::{FilterDefinition, LazyLoadOptions},
@@ -6,21 +9,24 @@ use ruma::{
             self,
             v3::{
                 Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom,
-                LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
+                KnockState, KnockedRoom, LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms,
+                State, Timeline, ToDevice,
             },
+            v4::SlidingOp,
             DeviceLists, UnreadNotificationsCount,
         },
         uiaa::UiaaResponse,
     },
     events::{
+        receipt::SyncReceiptEvent,
         room::member::{MembershipState, RoomMemberEventContent},
         StateEventType, TimelineEventType,
     },
     serde::Raw,
-    DeviceId, OwnedDeviceId, OwnedUserId, RoomId, UserId,
+    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
 use std::{
-    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     sync::Arc,
     time::Duration,
 };
@@ -56,6 +62,9 @@ use tracing::error;
 /// For invited rooms:
 /// - If the user was invited after `since`: A subset of the state of the room at the point of the invite
 ///
+/// For knocked rooms:
+/// - If the user knocked after `since`: A subset of the state of the room at the point of the knock
+///
 /// For left rooms:
 /// - If the user left after `since`: prev_batch token, empty state (TODO: subset of the state at the point of the leave)
 ///
@@ -167,8 +176,11 @@ async fn sync_helper(
     body: sync_events::v3::Request,
     // bool = caching allowed
 ) -> Result<(sync_events::v3::Response, bool), Error> {
-    // TODO: match body.set_presence {
-    services().rooms.edus.presence.ping_presence(&sender_user)?;
+    services()
+        .rooms
+        .edus
+        .presence
+        .ping_presence(&sender_user, &body.set_presence)?;
 
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
@@ -195,6 +207,7 @@ async fn sync_helper(
     };
 
     let full_state = body.full_state;
+    let unread_thread_notifications = filter.room.timeline.unread_thread_notifications;
 
     let mut joined_rooms = BTreeMap::new();
     let since = body
@@ -235,6 +248,7 @@ async fn sync_helper(
             lazy_load_enabled,
             lazy_load_send_redundant,
             full_state,
+            unread_thread_notifications,
             &mut device_list_updates,
             &mut left_encrypted_users,
         )
@@ -245,12 +259,16 @@ async fn sync_helper(
             }
 
             // Take presence updates from this room
-            for (user_id, presence) in services()
-                .rooms
-                .edus
-                .presence
-                .presence_since(&room_id, since)?
-            {
+            let room_presence = if services().globals.allow_presence() {
+                services()
+                    .rooms
+                    .edus
+                    .presence
+                    .presence_since(&room_id, since)?
+            } else {
+                HashMap::new()
+            };
+            for (user_id, presence) in room_presence {
                 match presence_updates.entry(user_id) {
                     Entry::Vacant(v) => {
                         v.insert(presence);
@@ -459,6 +477,35 @@ async fn sync_helper(
         );
     }
 
+    let mut knocked_rooms = BTreeMap::new();
+    let all_knocked_rooms: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_knocked(&sender_user)
+        .collect();
+    for result in all_knocked_rooms {
+        let (room_id, knock_state_events) = result?;
+
+        let knock_count = services()
+            .rooms
+            .state_cache
+            .get_knock_count(&room_id, &sender_user)?;
+
+        // Knocked before last sync
+        if Some(since) >= knock_count {
+            continue;
+        }
+
+        knocked_rooms.insert(
+            room_id,
+            KnockedRoom {
+                knock_state: KnockState {
+                    events: knock_state_events,
+                },
+            },
+        );
+    }
+
     for user_id in left_encrypted_users {
         let still_share_encrypted_room = services()
             .rooms
@@ -494,7 +541,7 @@ async fn sync_helper(
             leave: left_rooms,
             join: joined_rooms,
             invite: invited_rooms,
-            knock: BTreeMap::new(), // TODO
+            knock: knocked_rooms,
         },
         presence: Presence {
             events: presence_updates
@@ -562,6 +609,7 @@ async fn load_joined_room(
     lazy_load_enabled: bool,
     lazy_load_send_redundant: bool,
     full_state: bool,
+    unread_thread_notifications: bool,
     device_list_updates: &mut HashSet<OwnedUserId>,
     left_encrypted_users: &mut HashSet<OwnedUserId>,
 ) -> Result<JoinedRoom> {
@@ -581,43 +629,7 @@ async fn load_joined_room(
         drop(insert_lock);
     }
 
-    let timeline_pdus;
-    let limited;
-    if services()
-        .rooms
-        .timeline
-        .last_timeline_count(&sender_user, &room_id)?
-        > sincecount
-    {
-        let mut non_timeline_pdus = services()
-            .rooms
-            .timeline
-            .pdus_until(&sender_user, &room_id, PduCount::max())?
-            .filter_map(|r| {
-                // Filter out buggy events
-                if r.is_err() {
-                    error!("Bad pdu in pdus_since: {:?}", r);
-                }
-                r.ok()
-            })
-            .take_while(|(pducount, _)| pducount > &sincecount);
-
-        // Take the last 10 events for the timeline
-        timeline_pdus = non_timeline_pdus
-            .by_ref()
-            .take(10)
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect::<Vec<_>>();
-
-        // They /sync response doesn't always return all messages, so we say the output is
-        // limited unless there are events in non_timeline_pdus
-        limited = non_timeline_pdus.next().is_some();
-    } else {
-        timeline_pdus = Vec::new();
-        limited = false;
-    }
+    let (timeline_pdus, limited) = load_timeline(sender_user, room_id, sincecount, 10)?;
 
     let send_notification_counts = !timeline_pdus.is_empty()
         || services()
@@ -1030,6 +1042,39 @@ async fn load_joined_room(
         None
     };
 
+    // Report counts of the threads that have new messages in this sync
+    let mut thread_notifications = BTreeMap::new();
+    if send_notification_counts && unread_thread_notifications {
+        for (_, pdu) in &timeline_pdus {
+            if let Some((rel_type, thread_root)) = pdu.relates_to() {
+                if rel_type != "m.thread" || thread_notifications.contains_key(&thread_root) {
+                    continue;
+                }
+
+                let (notification_count, highlight_count) = services()
+                    .rooms
+                    .pdu_metadata
+                    .thread_notification_counts(&sender_user, &room_id, &thread_root)?;
+
+                thread_notifications.insert(
+                    thread_root,
+                    UnreadNotificationsCount {
+                        highlight_count: Some(
+                            highlight_count
+                                .try_into()
+                                .expect("highlight count can't go that high"),
+                        ),
+                        notification_count: Some(
+                            notification_count
+                                .try_into()
+                                .expect("notification count can't go that high"),
+                        ),
+                    },
+                );
+            }
+        }
+    }
+
     let highlight_count = if send_notification_counts {
         Some(
             services()
@@ -1120,10 +1165,406 @@ async fn load_joined_room(
                 .collect(),
         },
         ephemeral: Ephemeral { events: edus },
-        unread_thread_notifications: BTreeMap::new(),
+        unread_thread_notifications: thread_notifications,
+    })
+}
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc3575/sync`
+///
+/// Sliding sync: Sends the rooms of the requested windows of the room lists, and the subscribed
+/// rooms.
+///
+/// - Lists are sorted by recency, the most recently active room first
+/// - Rooms the connection already knows only get the events since they were last sent
+/// - Sticky parameters (sort, required state, timeline limit, filters, subscriptions and
+/// extensions) are remembered per connection
+/// - Supports the to-device and receipts extensions
+pub async fn sync_events_v4_route(
+    body: Ruma<sync_events::v4::Request>,
+) -> Result<sync_events::v4::Response, RumaResponse<UiaaResponse>> {
+    let sender_user = body.sender_user.expect("user is authenticated");
+    let sender_device = body.sender_device.expect("user is authenticated");
+    let mut body = body.body;
+
+    // Setup watchers, so if there's no response, we can wait for them
+    let watcher = services().globals.watch(&sender_user, &sender_device);
+
+    let next_batch = services().globals.next_count()?;
+
+    let globalsince = body
+        .pos
+        .as_ref()
+        .and_then(|string| string.parse().ok())
+        .unwrap_or(0);
+
+    if globalsince == 0 {
+        if let Some(conn_id) = &body.conn_id {
+            services().sliding_sync.forget_connection(
+                &sender_user,
+                &sender_device,
+                conn_id.clone(),
+            );
+        }
+    }
+
+    // Get sticky parameters from cache
+    let known_rooms =
+        services()
+            .sliding_sync
+            .update_request_with_cache(&sender_user, &sender_device, &mut body);
+
+    let mut all_joined_rooms = services()
+        .rooms
+        .state_cache
+        .rooms_joined(&sender_user)
+        .filter_map(|r| r.ok())
+        .map(|room_id| {
+            let count = services()
+                .rooms
+                .timeline
+                .last_timeline_count(&sender_user, &room_id)?;
+            Ok((room_id, count))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    sliding_sync::sort_by_recency(&mut all_joined_rooms);
+    let all_joined_rooms: Vec<OwnedRoomId> = all_joined_rooms
+        .into_iter()
+        .map(|(room_id, _)| room_id)
+        .collect();
+
+    let to_device_enabled = body.extensions.to_device.enabled.unwrap_or(false);
+    if to_device_enabled {
+        // The client received everything up to its position
+        services()
+            .users
+            .remove_to_device_events(&sender_user, &sender_device, globalsince)?;
+    }
+
+    let mut lists = BTreeMap::new();
+    // Required state, timeline limit and the position the client knows the room at
+    let mut todo_rooms: BTreeMap<OwnedRoomId, (BTreeSet<(StateEventType, String)>, u64, u64)> =
+        BTreeMap::new();
+
+    for (list_id, list) in body.lists {
+        let timeline_limit = list
+            .room_details
+            .timeline_limit
+            .map_or(10, u64::from)
+            .min(100);
+
+        let mut new_known_rooms = BTreeSet::new();
+        let mut ops = Vec::new();
+
+        for range in list.ranges {
+            let (range, room_ids) = sliding_sync::window(&all_joined_rooms, range);
+
+            for room_id in room_ids {
+                new_known_rooms.insert(room_id.clone());
+
+                let todo_room =
+                    todo_rooms
+                        .entry(room_id.clone())
+                        .or_insert((BTreeSet::new(), 0, u64::MAX));
+                todo_room
+                    .0
+                    .extend(list.room_details.required_state.iter().cloned());
+                todo_room.1 = todo_room.1.max(timeline_limit);
+                // 0 means the client doesn't know the room (anymore)
+                todo_room.2 = todo_room.2.min(
+                    known_rooms
+                        .get(&list_id)
+                        .and_then(|known| known.get(room_id))
+                        .copied()
+                        .unwrap_or(0),
+                );
+            }
+
+            ops.push(sync_events::v4::SyncOp {
+                op: SlidingOp::Sync,
+                range: Some(range),
+                index: None,
+                room_ids: room_ids.to_vec(),
+                room_id: None,
+            });
+        }
+
+        if let Some(conn_id) = &body.conn_id {
+            services().sliding_sync.update_known_rooms(
+                &sender_user,
+                &sender_device,
+                conn_id.clone(),
+                list_id.clone(),
+                new_known_rooms,
+                next_batch,
+            );
+        }
+
+        lists.insert(
+            list_id,
+            sync_events::v4::SyncList {
+                ops,
+                count: UInt::new_saturating(all_joined_rooms.len() as u64),
+            },
+        );
+    }
+
+    for (room_id, subscription) in body.room_subscriptions {
+        if !services()
+            .rooms
+            .state_cache
+            .is_joined(&sender_user, &room_id)?
+        {
+            continue;
+        }
+
+        let todo_room = todo_rooms
+            .entry(room_id)
+            .or_insert((BTreeSet::new(), 0, globalsince));
+        todo_room.0.extend(subscription.required_state);
+        todo_room.1 = todo_room
+            .1
+            .max(subscription.timeline_limit.map_or(10, u64::from).min(100));
+    }
+
+    let receipts_enabled = body.extensions.receipts.enabled.unwrap_or(false);
+    let mut receipts = BTreeMap::new();
+    let mut rooms = BTreeMap::new();
+
+    for (room_id, (required_state_request, timeline_limit, roomsince)) in &todo_rooms {
+        let (timeline_pdus, limited) = load_timeline(
+            &sender_user,
+            room_id,
+            PduCount::Normal(*roomsince),
+            *timeline_limit,
+        )?;
+
+        if receipts_enabled {
+            if let Some(receipt) = room_receipts(room_id, *roomsince) {
+                receipts.insert(room_id.clone(), receipt);
+            }
+        }
+
+        if *roomsince != 0 && timeline_pdus.is_empty() {
+            continue;
+        }
+
+        let prev_batch = timeline_pdus
+            .first()
+            .map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
+                Ok(Some(match pdu_count {
+                    PduCount::Backfilled(_) => {
+                        error!("timeline in backfill state?!");
+                        "0".to_owned()
+                    }
+                    PduCount::Normal(c) => c.to_string(),
+                }))
+            })?
+            .or_else(|| (*roomsince != 0).then(|| roomsince.to_string()));
+
+        let timeline = timeline_pdus
+            .iter()
+            .map(|(_, pdu)| pdu.to_sync_room_event())
+            .collect();
+
+        // Required state is only sent with the first response for a room
+        let required_state = if *roomsince == 0 {
+            required_state_request
+                .iter()
+                .filter_map(|(event_type, state_key)| {
+                    services()
+                        .rooms
+                        .state_accessor
+                        .room_state_get(room_id, event_type, state_key)
+                        .ok()
+                        .flatten()
+                })
+                .map(|pdu| pdu.to_sync_state_event())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        rooms.insert(
+            room_id.clone(),
+            sync_events::v4::SlidingSyncRoom {
+                initial: Some(*roomsince == 0),
+                unread_notifications: UnreadNotificationsCount {
+                    highlight_count: Some(UInt::new_saturating(
+                        services()
+                            .rooms
+                            .user
+                            .highlight_count(&sender_user, room_id)?,
+                    )),
+                    notification_count: Some(UInt::new_saturating(
+                        services()
+                            .rooms
+                            .user
+                            .notification_count(&sender_user, room_id)?,
+                    )),
+                },
+                timeline,
+                required_state,
+                prev_batch,
+                limited,
+                joined_count: Some(UInt::new_saturating(
+                    services()
+                        .rooms
+                        .state_cache
+                        .room_joined_count(room_id)?
+                        .unwrap_or(0),
+                )),
+                invited_count: Some(UInt::new_saturating(
+                    services()
+                        .rooms
+                        .state_cache
+                        .room_invited_count(room_id)?
+                        .unwrap_or(0),
+                )),
+                ..Default::default()
+            },
+        );
+    }
+
+    let to_device = if to_device_enabled {
+        Some(sync_events::v4::ToDevice {
+            events: services()
+                .users
+                .get_to_device_events(&sender_user, &sender_device)?,
+            next_batch: next_batch.to_string(),
+        })
+    } else {
+        None
+    };
+
+    if rooms
+        .values()
+        .all(|room| room.timeline.is_empty() && room.required_state.is_empty())
+        && to_device
+            .as_ref()
+            .map_or(true, |to_device| to_device.events.is_empty())
+        && receipts.is_empty()
+    {
+        // Hang a few seconds so requests are not spammed
+        // Stop hanging if new info arrives
+        let duration = body
+            .timeout
+            .unwrap_or(Duration::from_secs(30))
+            .min(Duration::from_secs(30));
+        let _ = tokio::time::timeout(duration, watcher).await;
+    }
+
+    Ok(sync_events::v4::Response {
+        initial: globalsince == 0,
+        txn_id: body.txn_id,
+        pos: next_batch.to_string(),
+        lists,
+        rooms,
+        extensions: sync_events::v4::Extensions {
+            to_device,
+            receipts: sync_events::v4::Receipts { rooms: receipts },
+            ..Default::default()
+        },
+        delta_token: None,
     })
 }
 
+/// Merges the read receipts of the room since `since` into one receipt event.
+fn room_receipts(room_id: &RoomId, since: u64) -> Option<Raw<SyncReceiptEvent>> {
+    let mut content = serde_json::Map::new();
+
+    for (_, _, event) in services()
+        .rooms
+        .edus
+        .read_receipt
+        .readreceipts_since(room_id, since)
+        .filter_map(|r| r.ok())
+    {
+        let event_content = match event.get_field::<serde_json::Value>("content") {
+            Ok(Some(serde_json::Value::Object(event_content))) => event_content,
+            _ => continue,
+        };
+        merge_json(&mut content, event_content);
+    }
+
+    if content.is_empty() {
+        return None;
+    }
+
+    Some(Raw::from_json(
+        serde_json::value::to_raw_value(&serde_json::json!({
+            "type": "m.receipt",
+            "content": content,
+        }))
+        .expect("receipt event can be serialized"),
+    ))
+}
+
+/// Merges nested JSON objects, e.g. the contents of receipt events per event, type and user.
+fn merge_json(
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    source: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in source {
+        match (target.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(target)), serde_json::Value::Object(source)) => {
+                merge_json(target, source);
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Returns the last `limit` events of the room after `roomsincecount` and whether there are more
+/// events in between.
+fn load_timeline(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    roomsincecount: PduCount,
+    limit: u64,
+) -> Result<(Vec<(PduCount, PduEvent)>, bool), Error> {
+    let timeline_pdus;
+    let limited;
+    if services()
+        .rooms
+        .timeline
+        .last_timeline_count(sender_user, room_id)?
+        > roomsincecount
+    {
+        let mut non_timeline_pdus = services()
+            .rooms
+            .timeline
+            .pdus_until(sender_user, room_id, PduCount::max())?
+            .filter_map(|r| {
+                // Filter out buggy events
+                if r.is_err() {
+                    error!("Bad pdu in pdus_since: {:?}", r);
+                }
+                r.ok()
+            })
+            .take_while(|(pducount, _)| pducount > &roomsincecount);
+
+        // Take the last events for the timeline
+        timeline_pdus = non_timeline_pdus
+            .by_ref()
+            .take(limit as usize)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect::<Vec<_>>();
+
+        // They /sync response doesn't always return all messages, so we say the output is
+        // limited unless there are events in non_timeline_pdus
+        limited = non_timeline_pdus.next().is_some();
+    } else {
+        timeline_pdus = Vec::new();
+        limited = false;
+    }
+
+    Ok((timeline_pdus, limited))
+}
+
 fn share_encrypted_room(
     sender_user: &UserId,
     user_id: &UserId,
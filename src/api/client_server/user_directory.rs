@@ -13,6 +13,7 @@ use ruma::{
 ///
 /// - Hides any local users that aren't in any public rooms (i.e. those that have the join rule set to public)
 /// and don't share a room with the sender
+/// - Hides deactivated local users
 pub async fn search_users_route(
     body: Ruma<search_users::v3::Request>,
 ) -> Result<search_users::v3::Response> {
@@ -23,6 +24,13 @@ pub async fn search_users_route(
         // Filter out buggy users (they should not exist, but you never know...)
         let user_id = user_id.ok()?;
 
+        // Remote users are stored like deactivated accounts, so only check our own users
+        if user_id.server_name() == services().globals.server_name()
+            && services().users.is_deactivated(&user_id).ok()?
+        {
+            return None;
+        }
+
         let user = search_users::v3::User {
             user_id: user_id.clone(),
             display_name: services().users.displayname(&user_id).ok()?,
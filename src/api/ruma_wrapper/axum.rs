@@ -1,15 +1,17 @@
-use std::{collections::BTreeMap, iter::FromIterator, str};
+use std::{collections::BTreeMap, iter::FromIterator, net::SocketAddr, str};
 
 use axum::{
     async_trait,
     body::{Full, HttpBody},
     extract::{
-        rejection::TypedHeaderRejectionReason, FromRequest, Path, RequestParts, TypedHeader,
+        rejection::TypedHeaderRejectionReason, ConnectInfo, FromRequest, MatchedPath, Path,
+        RequestParts, TypedHeader,
     },
     headers::{
         authorization::{Bearer, Credentials},
         Authorization,
     },
+    middleware::Next,
     response::{IntoResponse, Response},
     BoxError,
 };
@@ -23,7 +25,7 @@ use serde::Deserialize;
 use tracing::{debug, error, warn};
 
 use super::{Ruma, RumaResponse};
-use crate::{services, Error, Result};
+use crate::{service::rate_limiter::RateLimitClass, services, utils, Error, Result};
 
 #[async_trait]
 impl<T, B> FromRequest<B> for Ruma<T>
@@ -45,6 +47,20 @@ where
         let metadata = T::METADATA;
         let auth_header = Option::<TypedHeader<Authorization<Bearer>>>::from_request(req).await?;
         let path_params = Path::<Vec<String>>::from_request(req).await?;
+        let matched_path = MatchedPath::from_request(req).await.ok();
+        let method = req.method().clone();
+        let peer_addr = ConnectInfo::<SocketAddr>::from_request(req)
+            .await
+            .ok()
+            .map(|ConnectInfo(addr)| addr.ip());
+        // The proxy adds its header after any the client sent
+        let forwarded_for = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .iter()
+            .last()
+            .and_then(|value| value.to_str().ok());
+        let ip = utils::client_ip(peer_addr, forwarded_for);
 
         let query = req.uri().query().unwrap_or_default();
         let query_params: QueryParams = match serde_html_form::from_str(query) {
@@ -77,6 +93,8 @@ where
                 .map_or(false, |as_token| token == Some(as_token))
         });
 
+        let appservice_id = appservice_registration.map(|(id, _)| id.clone());
+
         let (sender_user, sender_device, sender_servername, from_appservice) =
             if let Some((_id, registration)) = appservice_registration {
                 match metadata.authentication {
@@ -129,12 +147,25 @@ where
                                     "Unknown access token.",
                                 ))
                             }
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(OwnedDeviceId::from(device_id)),
-                                None,
-                                false,
-                            ),
+                            Some((user_id, _))
+                                if services().users.is_guest(&user_id)?
+                                    && !matched_path.as_ref().map_or(false, |path| {
+                                        guest_allowed(method.as_str(), path.as_str())
+                                    }) =>
+                            {
+                                return Err(Error::BadRequest(
+                                    ErrorKind::GuestAccessForbidden,
+                                    "Guests can't use this endpoint.",
+                                ))
+                            }
+                            Some((user_id, device_id)) => {
+                                let device_id = OwnedDeviceId::from(device_id);
+                                services()
+                                    .users
+                                    .update_device_last_seen(&user_id, &device_id, ip)?;
+
+                                (Some(user_id), Some(device_id), None, false)
+                            }
                         }
                     }
                     AuthScheme::ServerSignatures => {
@@ -294,6 +325,7 @@ where
             sender_device,
             sender_servername,
             from_appservice,
+            appservice_id,
             json_body,
         })
     }
@@ -364,3 +396,154 @@ impl<T: OutgoingResponse> IntoResponse for RumaResponse<T> {
         }
     }
 }
+
+/// Middleware that rejects client requests exceeding the rate limit of their endpoint.
+pub async fn rate_limit<B>(req: http::Request<B>, next: Next<B>) -> Result<Response> {
+    #[derive(Deserialize)]
+    struct QueryParams {
+        access_token: Option<String>,
+    }
+
+    let class = match req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| RateLimitClass::from_route(req.method(), path.as_str()))
+    {
+        Some(class) => class,
+        None => return Ok(next.run(req).await),
+    };
+
+    let query_token = req
+        .uri()
+        .query()
+        .and_then(|query| serde_html_form::from_str::<QueryParams>(query).ok())
+        .and_then(|params| params.access_token);
+    let access_token = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query_token.as_deref());
+
+    let peer_addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let forwarded_for = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .iter()
+        .last()
+        .and_then(|value| value.to_str().ok());
+
+    services().rate_limiter.check(
+        class,
+        access_token,
+        utils::client_ip(peer_addr, forwarded_for),
+    )?;
+
+    Ok(next.run(req).await)
+}
+
+/// Client-server endpoints guest accounts may use, relative to `/_matrix/client/<version>/`.
+/// `:` matches any single path segment.
+const GUEST_ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "rooms/:/state"),
+    ("GET", "rooms/:/state/:"),
+    ("GET", "rooms/:/state/:/:"),
+    ("GET", "rooms/:/context/:"),
+    ("GET", "rooms/:/event/:"),
+    ("GET", "rooms/:/members"),
+    ("GET", "rooms/:/joined_members"),
+    ("GET", "rooms/:/messages"),
+    ("GET", "sync"),
+    ("GET", "events"),
+    ("PUT", "rooms/:/send/:/:"),
+    ("PUT", "sendToDevice/:/:"),
+    ("GET", "presence/:/status"),
+    ("PUT", "presence/:/status"),
+    ("GET", "profile/:"),
+    ("GET", "profile/:/displayname"),
+    ("GET", "profile/:/avatar_url"),
+    ("PUT", "profile/:/displayname"),
+    ("PUT", "profile/:/avatar_url"),
+    ("POST", "rooms/:/join"),
+    ("POST", "join/:"),
+    ("POST", "rooms/:/leave"),
+    ("GET", "voip/turnServer"),
+    ("PUT", "rooms/:/typing/:"),
+    ("POST", "rooms/:/receipt/:/:"),
+    ("POST", "rooms/:/read_markers"),
+    ("POST", "keys/upload"),
+    ("POST", "keys/query"),
+    ("POST", "keys/claim"),
+    ("GET", "keys/changes"),
+    ("GET", "devices"),
+    ("GET", "devices/:"),
+    ("PUT", "devices/:"),
+    ("GET", "account/whoami"),
+    ("POST", "logout"),
+    ("GET", "capabilities"),
+    ("GET", "pushrules/"),
+    ("POST", "user/:/filter"),
+    ("GET", "user/:/filter/:"),
+];
+
+/// Whether a guest may call the route matched by `path`, given with `:name` placeholders.
+fn guest_allowed(method: &str, path: &str) -> bool {
+    let path = match path
+        .strip_prefix("/_matrix/client/")
+        .and_then(|rest| rest.split_once('/'))
+    {
+        Some((_version, path)) => path,
+        None => return false,
+    };
+
+    GUEST_ENDPOINTS
+        .iter()
+        .filter(|(allowed_method, _)| *allowed_method == method)
+        .any(|(_, allowed_path)| {
+            let mut allowed = allowed_path.split('/');
+            let mut actual = path.split('/');
+
+            loop {
+                match (allowed.next(), actual.next()) {
+                    (None, None) => return true,
+                    (Some(":"), Some(segment)) if segment.starts_with(':') => {}
+                    (Some(a), Some(b)) if a == b => {}
+                    _ => return false,
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::guest_allowed;
+
+    #[test]
+    fn guests_are_limited_to_allowed_endpoints() {
+        assert!(guest_allowed("GET", "/_matrix/client/v3/sync"));
+        assert!(guest_allowed(
+            "GET",
+            "/_matrix/client/r0/rooms/:room_id/messages"
+        ));
+        assert!(guest_allowed(
+            "PUT",
+            "/_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id"
+        ));
+        assert!(guest_allowed("GET", "/_matrix/client/v3/pushrules/"));
+
+        assert!(!guest_allowed("POST", "/_matrix/client/v3/createRoom"));
+        assert!(!guest_allowed("POST", "/_matrix/client/v3/sync"));
+        assert!(!guest_allowed(
+            "PUT",
+            "/_matrix/client/v3/rooms/:room_id/state/:event_type"
+        ));
+        assert!(!guest_allowed(
+            "GET",
+            "/_matrix/client/v3/rooms/!room:example.org/members"
+        ));
+        assert!(!guest_allowed("GET", "/_matrix/media/v3/config"));
+    }
+}
@@ -7,6 +7,8 @@ use std::ops::Deref;
 
 #[cfg(feature = "conduit_bin")]
 mod axum;
+#[cfg(feature = "conduit_bin")]
+pub use self::axum::rate_limit;
 
 /// Extractor for Ruma request structs
 pub struct Ruma<T> {
@@ -17,6 +19,8 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    // The ID of the appservice that sent the request
+    pub appservice_id: Option<String>,
 }
 
 impl<T> Deref for Ruma<T> {
@@ -2,7 +2,10 @@
 
 use crate::{
     api::client_server::{self, claim_keys_helper, get_keys_helper},
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
+    service::{
+        pdu::{gen_event_id_canonical_json, PduBuilder},
+        rooms::{edus::presence::PresenceData, state_accessor::RestrictedJoin},
+    },
     services, utils, Error, PduEvent, Result, Ruma,
 };
 use axum::{response::IntoResponse, Json};
@@ -20,6 +23,7 @@ use ruma::{
             discovery::{get_server_keys, get_server_version, ServerSigningKeys, VerifyKey},
             event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             keys::{claim_keys, get_keys},
+            knock::{create_knock_event_template, send_knock},
             membership::{create_invite, create_join_event, prepare_join_event},
             query::{get_profile_information, get_room_information},
             transactions::{
@@ -33,17 +37,14 @@ use ruma::{
     directory::{Filter, RoomNetwork},
     events::{
         receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
-        room::{
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-        },
-        StateEventType, TimelineEventType,
+        room::member::{MembershipState, RoomMemberEventContent},
+        TimelineEventType,
     },
     serde::{Base64, JsonObject, Raw},
     to_device::DeviceIdOrAllDevices,
     uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
     OwnedEventId, OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId, RoomId,
-    ServerName,
+    ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
@@ -699,6 +700,20 @@ pub async fn send_transaction_message_route(
     // events that it references.
     // let mut auth_cache = EventMap::new();
 
+    // Servers that recently sent many soft-failed events have to wait, so they can't hog the
+    // event handling of rooms
+    if let Some(backoff) = services()
+        .rooms
+        .pdu_metadata
+        .soft_failure_backoff(sender_servername)?
+    {
+        info!(
+            "Delaying transaction of {} by {:?} because of soft-failed events",
+            sender_servername, backoff
+        );
+        tokio::time::sleep(backoff).await;
+    }
+
     for pdu in &body.pdus {
         let r = parse_incoming_pdu(&pdu);
         let (event_id, value, room_id) = match r {
@@ -767,10 +782,55 @@ pub async fn send_transaction_message_route(
         .filter_map(|edu| serde_json::from_str::<Edu>(edu.json().get()).ok())
     {
         match edu {
-            Edu::Presence(_) => {}
+            Edu::Presence(presence) => {
+                if !services().globals.allow_presence() {
+                    continue;
+                }
+
+                for update in presence.push {
+                    // Servers can only send the presence of their own users, and it's only kept
+                    // for users we share a room with
+                    if update.user_id.server_name() != sender_servername
+                        || services()
+                            .rooms
+                            .state_cache
+                            .rooms_joined(&update.user_id)
+                            .next()
+                            .is_none()
+                    {
+                        debug!(
+                            "Dropping presence of {} from {}",
+                            update.user_id, sender_servername
+                        );
+                        continue;
+                    }
+
+                    services().rooms.edus.presence.set_remote_presence(
+                        &update.user_id,
+                        &PresenceData {
+                            presence: update.presence,
+                            last_active_ts: utils::millis_since_unix_epoch()
+                                .saturating_sub(update.last_active_ago.into()),
+                            currently_active: update.currently_active,
+                            status_msg: update.status_msg,
+                        },
+                    )?;
+                }
+            }
             Edu::Receipt(receipt) => {
                 for (room_id, room_updates) in receipt.receipts {
                     for (user_id, user_updates) in room_updates.read {
+                        // Servers can only send receipts of their own users, who are in the room
+                        if user_id.server_name() != sender_servername
+                            || !services().rooms.state_cache.is_joined(&user_id, &room_id)?
+                        {
+                            debug!(
+                                "Dropping read receipt of {} in {} from {}",
+                                user_id, room_id, sender_servername
+                            );
+                            continue;
+                        }
+
                         if let Some((event_id, _)) = user_updates
                             .event_ids
                             .iter()
@@ -831,8 +891,14 @@ pub async fn send_transaction_message_route(
                     }
                 }
             }
-            Edu::DeviceListUpdate(DeviceListUpdateContent { user_id, .. }) => {
+            Edu::DeviceListUpdate(DeviceListUpdateContent {
+                user_id, deleted, ..
+            }) => {
                 services().users.mark_device_key_update(&user_id)?;
+
+                if deleted != Some(true) {
+                    services().pusher.notify_device_list_change(&user_id)?;
+                }
             }
             Edu::DirectToDevice(DirectDeviceContent {
                 sender,
@@ -903,14 +969,12 @@ pub async fn send_transaction_message_route(
                 if user_id.server_name() != sender_servername {
                     continue;
                 }
-                if let Some(master_key) = master_key {
-                    services().users.add_cross_signing_keys(
-                        &user_id,
-                        &master_key,
-                        &self_signing_key,
-                        &None,
-                    )?;
-                }
+                services().users.add_cross_signing_keys(
+                    &user_id,
+                    &master_key,
+                    &self_signing_key,
+                    &None,
+                )?;
             }
             Edu::_Custom(_) => {}
         }
@@ -1388,35 +1452,6 @@ pub async fn create_join_event_template_route(
     );
     let state_lock = mutex_state.lock().await;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        &body.room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
-    }
-
     let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
     if !body.ver.contains(&room_version_id) {
         return Err(Error::BadRequest(
@@ -1427,6 +1462,30 @@ pub async fn create_join_event_template_route(
         ));
     }
 
+    let join_authorized_via_users_server = match services()
+        .rooms
+        .state_accessor
+        .restricted_join(&body.user_id, &body.room_id)?
+    {
+        Some(RestrictedJoin::Allowed) => Some(
+            services()
+                .rooms
+                .state_accessor
+                .restricted_join_authorizer(&body.room_id)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::UnableToAuthorizeJoin,
+                    "No user on this server can authorize the join.",
+                ))?,
+        ),
+        Some(RestrictedJoin::Forbidden) => {
+            return Err(Error::BadRequest(
+                ErrorKind::UnableToAuthorizeJoin,
+                "User is not known to be in any of the rooms the join rules allow.",
+            ))
+        }
+        _ => None,
+    };
+
     let content = to_raw_value(&RoomMemberEventContent {
         avatar_url: None,
         blurhash: None,
@@ -1435,7 +1494,7 @@ pub async fn create_join_event_template_route(
         membership: MembershipState::Join,
         third_party_invite: None,
         reason: None,
-        join_authorized_via_users_server: None,
+        join_authorized_via_users_server,
     })
     .expect("member event is valid value");
 
@@ -1483,35 +1542,6 @@ async fn create_join_event(
         .event_handler
         .acl_check(sender_servername, room_id)?;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
-    }
-
     // We need to return the state prior to joining, let's keep a reference to that here
     let shortstatehash = services()
         .rooms
@@ -1527,7 +1557,7 @@ async fn create_join_event(
 
     // We do not add the event_id field to the pdu here because of signature and hashes checks
     let room_version_id = services().rooms.state.get_room_version(room_id)?;
-    let (event_id, value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
+    let (event_id, mut value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
         Ok(t) => t,
         Err(_) => {
             // Event could not be converted to canonical json
@@ -1538,6 +1568,63 @@ async fn create_join_event(
         }
     };
 
+    // Restricted joins authorized by one of our users need our signature
+    let authorizer = value
+        .get("content")
+        .and_then(|content| {
+            content
+                .as_object()?
+                .get("join_authorised_via_users_server")?
+                .as_str()
+        })
+        .and_then(|user_id| UserId::parse(user_id).ok())
+        .filter(|user_id| user_id.server_name() == services().globals.server_name());
+    let authorized_by_us = authorizer.is_some();
+
+    if let Some(authorizer) = authorizer {
+        let joining_user = value
+            .get("state_key")
+            .and_then(|state_key| state_key.as_str())
+            .and_then(|state_key| UserId::parse(state_key).ok())
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Join event has an invalid state key.",
+            ))?;
+
+        if services()
+            .rooms
+            .state_accessor
+            .restricted_join(&joining_user, room_id)?
+            != Some(RestrictedJoin::Allowed)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::UnableToAuthorizeJoin,
+                "User is not known to be in any of the rooms the join rules allow.",
+            ));
+        }
+
+        if !services()
+            .rooms
+            .state_accessor
+            .can_authorize_restricted_join(room_id, &authorizer)?
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::UnableToAuthorizeJoin,
+                "The authorizing user cannot invite users to the room.",
+            ));
+        }
+
+        ruma::signatures::sign_json(
+            services().globals.server_name().as_str(),
+            services().globals.keypair(),
+            &mut value,
+        )
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Failed to sign event."))?;
+    }
+
+    let signed_event =
+        authorized_by_us.then(|| PduEvent::convert_to_outgoing_federation_event(value.clone()));
+
     let origin: OwnedServerName = serde_json::from_value(
         serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -1598,7 +1685,7 @@ async fn create_join_event(
             .filter_map(|(_, id)| services().rooms.timeline.get_pdu_json(id).ok().flatten())
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
-        event: None, // TODO: handle restricted joins
+        event: signed_event,
     })
 }
 
@@ -1645,6 +1732,222 @@ pub async fn create_join_event_v2_route(
     Ok(create_join_event::v2::Response { room_state })
 }
 
+/// # `GET /_matrix/federation/v1/make_knock/{roomId}/{userId}`
+///
+/// Creates a knock template.
+pub async fn create_knock_event_template_route(
+    body: Ruma<create_knock_event_template::v1::Request>,
+) -> Result<create_knock_event_template::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    if body.user_id.server_name() != &**sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to the server.",
+        ));
+    }
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
+    if !body.ver.contains(&room_version_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::IncompatibleRoomVersion {
+                room_version: room_version_id,
+            },
+            "Room version not supported.",
+        ));
+    }
+
+    if !services()
+        .rooms
+        .state_accessor
+        .allows_knocking(&body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let content = to_raw_value(&RoomMemberEventContent {
+        avatar_url: None,
+        blurhash: None,
+        displayname: None,
+        is_direct: None,
+        membership: MembershipState::Knock,
+        third_party_invite: None,
+        reason: None,
+        join_authorized_via_users_server: None,
+    })
+    .expect("member event is valid value");
+
+    let (_pdu, mut pdu_json) = services().rooms.timeline.create_hash_and_sign_event(
+        PduBuilder {
+            event_type: TimelineEventType::RoomMember,
+            content,
+            unsigned: None,
+            state_key: Some(body.user_id.to_string()),
+            redacts: None,
+        },
+        &body.user_id,
+        &body.room_id,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    pdu_json.remove("event_id");
+
+    Ok(create_knock_event_template::v1::Response {
+        room_version: room_version_id,
+        event: to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
+    })
+}
+
+/// # `PUT /_matrix/federation/v1/send_knock/{roomId}/{eventId}`
+///
+/// Submits a signed knock event.
+pub async fn create_knock_event_route(
+    body: Ruma<send_knock::v1::Request>,
+) -> Result<send_knock::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    if !services()
+        .rooms
+        .state_accessor
+        .allows_knocking(&body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    // We do not add the event_id field to the pdu here because of signature and hashes checks
+    let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
+    let (event_id, value) = match gen_event_id_canonical_json(&body.pdu, &room_version_id) {
+        Ok(t) => t,
+        Err(_) => {
+            // Event could not be converted to canonical json
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Could not convert event to canonical json.",
+            ));
+        }
+    };
+
+    let is_knock = value.get("type").and_then(|t| t.as_str()) == Some("m.room.member")
+        && value
+            .get("content")
+            .and_then(|content| content.as_object()?.get("membership")?.as_str())
+            == Some("knock");
+    if !is_knock {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event is not a knock.",
+        ));
+    }
+
+    let origin: OwnedServerName = serde_json::from_value(
+        serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event needs an origin field.",
+        ))?)
+        .expect("CanonicalJson is valid json value"),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid."))?;
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    let mutex = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_federation
+            .write()
+            .unwrap()
+            .entry(body.room_id.to_owned())
+            .or_default(),
+    );
+    let mutex_lock = mutex.lock().await;
+    let pdu_id: Vec<u8> = services()
+        .rooms
+        .event_handler
+        .handle_incoming_pdu(&origin, &event_id, &body.room_id, value, true, &pub_key_map)
+        .await?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Could not accept incoming PDU as timeline event.",
+        ))?;
+    drop(mutex_lock);
+
+    let servers = services()
+        .rooms
+        .state_cache
+        .room_servers(&body.room_id)
+        .filter_map(|r| r.ok())
+        .filter(|server| &**server != services().globals.server_name());
+
+    services().sending.send_pdu(servers, &pdu_id)?;
+
+    let pdu = services()
+        .rooms
+        .timeline
+        .get_pdu_from_id(&pdu_id)?
+        .ok_or_else(|| Error::bad_database("Knock event was just added, but is missing."))?;
+
+    Ok(send_knock::v1::Response {
+        knock_room_state: services().rooms.state.calculate_invite_state(&pdu)?,
+    })
+}
+
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
 ///
 /// Invites a remote user to a room.
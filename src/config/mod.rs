@@ -4,7 +4,7 @@ use std::{
     net::{IpAddr, Ipv4Addr},
 };
 
-use ruma::{OwnedServerName, RoomVersionId};
+use ruma::{OwnedMxcUri, OwnedServerName, RoomVersionId};
 use serde::{de::IgnoredAny, Deserialize};
 use tracing::warn;
 
@@ -44,6 +44,18 @@ pub struct Config {
     pub max_fetch_prev_events: u16,
     #[serde(default = "false_fn")]
     pub allow_registration: bool,
+    #[serde(default = "false_fn")]
+    pub registration_requires_token: bool,
+    #[serde(default = "false_fn")]
+    pub allow_guests: bool,
+    pub captcha: Option<CaptchaConfig>,
+    #[serde(default = "Vec::new")]
+    pub sso_providers: Vec<SsoProviderConfig>,
+    pub sso_callback_url: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub sso_client_allowlist: Vec<String>,
+    #[serde(default = "default_rate_limits")]
+    pub rate_limits: RateLimitsConfig,
     #[serde(default = "true_fn")]
     pub allow_encryption: bool,
     #[serde(default = "false_fn")]
@@ -51,6 +63,27 @@ pub struct Config {
     #[serde(default = "true_fn")]
     pub allow_room_creation: bool,
     #[serde(default = "true_fn")]
+    pub allow_presence: bool,
+    #[serde(default = "default_presence_idle_timeout")]
+    pub presence_idle_timeout: u64,
+    #[serde(default = "false_fn")]
+    pub allow_url_preview: bool,
+    #[serde(default = "Vec::new")]
+    pub url_preview_domain_allowlist: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub url_preview_domain_denylist: Vec<String>,
+    #[serde(default = "default_url_preview_max_size")]
+    pub url_preview_max_size: u32,
+    #[serde(default = "default_url_preview_cache_ttl")]
+    pub url_preview_cache_ttl: u64,
+    #[serde(default = "false_fn")]
+    pub compute_blurhashes: bool,
+    pub remote_media_retention: Option<u64>,
+    pub media_user_quota: Option<u64>,
+    pub remote_media_server_quota: Option<u64>,
+    #[serde(default = "default_media_cleanup_interval")]
+    pub media_cleanup_interval: u64,
+    #[serde(default = "true_fn")]
     pub allow_unstable_room_versions: bool,
     #[serde(default = "default_default_room_version")]
     pub default_room_version: RoomVersionId,
@@ -78,6 +111,72 @@ pub struct Config {
 
     pub emergency_password: Option<String>,
 
+    #[serde(default = "default_soft_fail_backoff_threshold")]
+    pub soft_fail_backoff_threshold: u64,
+
+    #[serde(default = "default_max_push_body_length")]
+    pub max_push_body_length: usize,
+    #[serde(default = "default_push_digest_interval")]
+    pub push_digest_interval: u64,
+    #[serde(default)]
+    pub push_gateway_envelope: PushGatewayEnvelope,
+    #[serde(default = "Vec::new")]
+    pub push_notify_state_event_types: Vec<String>,
+    #[serde(default = "default_push_max_attempts")]
+    pub push_max_attempts: u32,
+    #[serde(default = "default_push_pause_duration")]
+    pub push_pause_duration: u64,
+    #[serde(default = "default_push_timeout")]
+    pub push_timeout: u64,
+    #[serde(default = "false_fn")]
+    pub push_self_edits: bool,
+    #[serde(default = "false_fn")]
+    pub push_after_leave: bool,
+    #[serde(default = "false_fn")]
+    pub push_device_list_changes: bool,
+    #[serde(default = "false_fn")]
+    pub push_redaction_updates: bool,
+    #[serde(default = "false_fn")]
+    pub push_participated_threads_high_priority: bool,
+    #[serde(default = "Vec::new")]
+    pub push_full_event_gateways: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub push_allowed_app_ids: Vec<String>,
+    #[serde(default)]
+    pub push_fallback_gateways: BTreeMap<String, String>,
+    #[serde(default = "default_push_max_fanout")]
+    pub push_max_fanout: usize,
+    #[serde(default = "default_push_retry_status_codes")]
+    pub push_retry_status_codes: Vec<u16>,
+    #[serde(default = "Vec::new")]
+    pub push_suppressed_senders: Vec<String>,
+    #[serde(default = "false_fn")]
+    pub push_suppress_appservice_users: bool,
+    pub push_max_notification_age: Option<u64>,
+    pub push_first_message_window: Option<u64>,
+    pub push_escalation_threshold: Option<u64>,
+    #[serde(default)]
+    pub push_retry_jitter: RetryJitter,
+    #[serde(default)]
+    pub push_content_fallback: ContentFallback,
+    #[serde(default = "Vec::new")]
+    pub push_scheduling_event_types: Vec<String>,
+    #[serde(default = "default_push_quarantine_threshold")]
+    pub push_quarantine_threshold: u32,
+    #[serde(default = "default_push_gateway_probe_ttl")]
+    pub push_gateway_probe_ttl: u64,
+    #[serde(default = "false_fn")]
+    pub push_room_topic: bool,
+    #[serde(default = "default_notification_history_flush_interval")]
+    pub notification_history_flush_interval: u64,
+    #[serde(default = "false_fn")]
+    pub push_email: bool,
+    pub smtp_url: Option<String>,
+    pub smtp_from: Option<String>,
+    #[serde(default = "false_fn")]
+    pub push_validate_payloads: bool,
+    pub push_mass_mention_cap: Option<usize>,
+
     #[serde(flatten)]
     pub catchall: BTreeMap<String, IgnoredAny>,
 }
@@ -88,6 +187,131 @@ pub struct TlsConfig {
     pub key: String,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct CaptchaConfig {
+    #[serde(default)]
+    pub provider: CaptchaProvider,
+    /// The site key, which clients need to show the captcha
+    pub public_key: String,
+    pub secret_key: String,
+    /// Lowest score a reCAPTCHA v3 response may have to pass
+    pub min_score: Option<f64>,
+    /// Overrides the siteverify endpoint of the provider, e.g. to test against a mock
+    pub verify_url: Option<String>,
+}
+
+/// An OpenID Connect provider users can log in with.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SsoProviderConfig {
+    /// Unique id of the provider, which clients pass when redirecting to it
+    pub id: String,
+    /// Name of the provider shown to users
+    pub name: String,
+    pub icon: Option<OwnedMxcUri>,
+    /// Brand clients can use to style the login button, e.g. "github"
+    pub brand: Option<String>,
+    /// Has to match the `iss` claim of ID tokens
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_sso_scopes")]
+    pub scopes: Vec<String>,
+    /// Localpart of new users, with claims of the ID token in braces, e.g. "{preferred_username}"
+    #[serde(default = "default_sso_localpart_template")]
+    pub localpart_template: String,
+}
+
+/// Rate limits of client endpoints. Requests are counted per user, or per IP address if they
+/// aren't authenticated.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitsConfig {
+    #[serde(default = "true_fn")]
+    pub enabled: bool,
+    /// Don't limit appservices
+    #[serde(default = "true_fn")]
+    pub exempt_appservices: bool,
+    /// Don't limit members of the admin room
+    #[serde(default = "true_fn")]
+    pub exempt_admins: bool,
+    #[serde(default = "default_login_rate_limit")]
+    pub login: RateLimitConfig,
+    #[serde(default = "default_registration_rate_limit")]
+    pub registration: RateLimitConfig,
+    #[serde(default = "default_message_rate_limit")]
+    pub message: RateLimitConfig,
+    /// All other client endpoints
+    #[serde(default = "default_other_rate_limit")]
+    pub other: RateLimitConfig,
+}
+
+/// A token bucket, which allows `burst` requests at once and then `per_second` requests per
+/// second.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// The service that verifies captchas completed during registration.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+    /// Google reCAPTCHA
+    #[default]
+    Recaptcha,
+    /// hCaptcha, which implements the same API
+    Hcaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn verify_url(self) -> &'static str {
+        match self {
+            CaptchaProvider::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+            CaptchaProvider::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+        }
+    }
+}
+
+/// The shape of the request body sent to push gateways.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PushGatewayEnvelope {
+    /// The body defined by the Matrix push gateway API
+    #[default]
+    Matrix,
+    /// Firebase Cloud Messaging HTTP v1 API
+    FcmV1,
+    /// Legacy Firebase Cloud Messaging HTTP API
+    FcmLegacy,
+}
+
+/// How the delay before retrying a failed push is randomized, so pushers that failed at the same
+/// time don't all retry at once.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryJitter {
+    /// Always wait the full exponential backoff
+    None,
+    /// Wait a random duration between zero and the backoff
+    #[default]
+    Full,
+    /// Wait at least half the backoff, plus a random duration up to the other half
+    Equal,
+}
+
+/// What is sent instead of the content of an event that can't be put into a notification.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentFallback {
+    /// Send only the ids, as if the pusher used the `event_id_only` format
+    #[default]
+    EventIdOnly,
+    /// Send a generic message body instead
+    Placeholder,
+}
+
 const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
 
 impl Config {
@@ -139,6 +363,40 @@ impl fmt::Display for Config {
                 &self.max_concurrent_requests.to_string(),
             ),
             ("Allow registration", &self.allow_registration.to_string()),
+            (
+                "Registration requires token",
+                &self.registration_requires_token.to_string(),
+            ),
+            ("SSO identity providers", {
+                let mut lst = vec![];
+                for provider in &self.sso_providers {
+                    lst.push(provider.id.as_str());
+                }
+                &lst.join(", ")
+            }),
+            (
+                "SSO clients redirected without confirmation",
+                &self.sso_client_allowlist.join(", "),
+            ),
+            ("Allow guests", &self.allow_guests.to_string()),
+            (
+                "Registration captcha",
+                match &self.captcha {
+                    Some(captcha) => match captcha.provider {
+                        CaptchaProvider::Recaptcha => "reCAPTCHA",
+                        CaptchaProvider::Hcaptcha => "hCaptcha",
+                    },
+                    None => "not set",
+                },
+            ),
+            (
+                "Rate limits",
+                if self.rate_limits.enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+            ),
             (
                 "Enabled lightning bolt",
                 &self.enable_lightning_bolt.to_string(),
@@ -146,6 +404,57 @@ impl fmt::Display for Config {
             ("Allow encryption", &self.allow_encryption.to_string()),
             ("Allow federation", &self.allow_federation.to_string()),
             ("Allow room creation", &self.allow_room_creation.to_string()),
+            ("Allow presence", &self.allow_presence.to_string()),
+            (
+                "Presence idle timeout",
+                &self.presence_idle_timeout.to_string(),
+            ),
+            ("Allow URL previews", &self.allow_url_preview.to_string()),
+            (
+                "Domains allowed for URL previews",
+                &self.url_preview_domain_allowlist.join(", "),
+            ),
+            (
+                "Domains denied for URL previews",
+                &self.url_preview_domain_denylist.join(", "),
+            ),
+            (
+                "Maximum URL preview download size",
+                &self.url_preview_max_size.to_string(),
+            ),
+            (
+                "URL preview cache in seconds",
+                &self.url_preview_cache_ttl.to_string(),
+            ),
+            (
+                "Compute blurhashes of uploads",
+                &self.compute_blurhashes.to_string(),
+            ),
+            (
+                "Remote media retention in seconds",
+                &match self.remote_media_retention {
+                    Some(retention) => retention.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
+            (
+                "Media storage quota per user in bytes",
+                &match self.media_user_quota {
+                    Some(quota) => quota.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
+            (
+                "Remote media cache quota per server in bytes",
+                &match self.remote_media_server_quota {
+                    Some(quota) => quota.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
+            (
+                "Media cleanup interval in seconds",
+                &self.media_cleanup_interval.to_string(),
+            ),
             (
                 "JWT secret",
                 match self.jwt_secret {
@@ -191,6 +500,151 @@ impl fmt::Display for Config {
                 }
                 &lst.join(", ")
             }),
+            (
+                "Soft-failed events per hour before backing off an origin",
+                &self.soft_fail_backoff_threshold.to_string(),
+            ),
+            (
+                "Maximum push body length",
+                &self.max_push_body_length.to_string(),
+            ),
+            (
+                "Push digest interval in seconds",
+                &self.push_digest_interval.to_string(),
+            ),
+            (
+                "Push gateway envelope",
+                &format!("{:?}", self.push_gateway_envelope),
+            ),
+            (
+                "State event types that notify",
+                &self.push_notify_state_event_types.join(", "),
+            ),
+            ("Maximum push attempts", &self.push_max_attempts.to_string()),
+            (
+                "Push pause duration in seconds",
+                &self.push_pause_duration.to_string(),
+            ),
+            ("Push timeout in seconds", &self.push_timeout.to_string()),
+            ("Push own edits", &self.push_self_edits.to_string()),
+            (
+                "Push for rooms that were left",
+                &self.push_after_leave.to_string(),
+            ),
+            (
+                "Push device list changes",
+                &self.push_device_list_changes.to_string(),
+            ),
+            (
+                "Push redactions of notified events",
+                &self.push_redaction_updates.to_string(),
+            ),
+            (
+                "High priority for replies in participated threads",
+                &self.push_participated_threads_high_priority.to_string(),
+            ),
+            (
+                "Push gateways trusted with full events",
+                &self.push_full_event_gateways.join(", "),
+            ),
+            (
+                "Apps allowed to register pushers",
+                &self.push_allowed_app_ids.join(", "),
+            ),
+            ("Fallback push gateways", {
+                let mut lst = vec![];
+                for (app_id, gateway) in &self.push_fallback_gateways {
+                    lst.push(format!("{app_id}: {gateway}"));
+                }
+                &lst.join(", ")
+            }),
+            (
+                "Maximum push fan-out per event",
+                &self.push_max_fanout.to_string(),
+            ),
+            ("Retried push gateway status codes", {
+                let mut lst = vec![];
+                for code in &self.push_retry_status_codes {
+                    lst.push(code.to_string());
+                }
+                &lst.join(", ")
+            }),
+            (
+                "Senders that are not pushed",
+                &self.push_suppressed_senders.join(", "),
+            ),
+            (
+                "Suppress pushes for appservice users",
+                &self.push_suppress_appservice_users.to_string(),
+            ),
+            (
+                "Maximum notification age in seconds",
+                &match self.push_max_notification_age {
+                    Some(age) => age.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
+            (
+                "Window in seconds without repeated notifications for a room",
+                &match self.push_first_message_window {
+                    Some(window) => window.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
+            (
+                "Unread notifications before escalating the push priority",
+                &match self.push_escalation_threshold {
+                    Some(threshold) => threshold.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
+            (
+                "Push retry jitter",
+                &format!("{:?}", self.push_retry_jitter),
+            ),
+            (
+                "Push content fallback",
+                &format!("{:?}", self.push_content_fallback),
+            ),
+            (
+                "Failures before a pusher is quarantined",
+                &self.push_quarantine_threshold.to_string(),
+            ),
+            (
+                "Push gateway probe cache in seconds",
+                &self.push_gateway_probe_ttl.to_string(),
+            ),
+            (
+                "Include room topic in notifications",
+                &self.push_room_topic.to_string(),
+            ),
+            (
+                "Notification history flush interval in seconds",
+                &self.notification_history_flush_interval.to_string(),
+            ),
+            ("Email notifications", &self.push_email.to_string()),
+            (
+                "SMTP server",
+                match self.smtp_url {
+                    Some(_) => "set",
+                    None => "not set",
+                },
+            ),
+            (
+                "Sender of notification emails",
+                self.smtp_from.as_deref().unwrap_or("not set"),
+            ),
+            (
+                "Validate notification payloads",
+                &self.push_validate_payloads.to_string(),
+            ),
+            (
+                "Maximum recipients of room mentions",
+                &match self.push_mass_mention_cap {
+                    Some(cap) => cap.to_string(),
+                    None => "not set".to_owned(),
+                },
+            ),
         ];
 
         let mut msg: String = "Active config values:\n\n".to_owned();
@@ -211,6 +665,54 @@ fn true_fn() -> bool {
     true
 }
 
+fn default_rate_limits() -> RateLimitsConfig {
+    RateLimitsConfig {
+        enabled: true,
+        exempt_appservices: true,
+        exempt_admins: true,
+        login: default_login_rate_limit(),
+        registration: default_registration_rate_limit(),
+        message: default_message_rate_limit(),
+        other: default_other_rate_limit(),
+    }
+}
+
+fn default_login_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 3,
+        per_second: 0.2,
+    }
+}
+
+fn default_registration_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 3,
+        per_second: 0.2,
+    }
+}
+
+fn default_message_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 10,
+        per_second: 1.0,
+    }
+}
+
+fn default_other_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        burst: 100,
+        per_second: 20.0,
+    }
+}
+
+fn default_sso_scopes() -> Vec<String> {
+    vec!["openid".to_owned(), "profile".to_owned()]
+}
+
+fn default_sso_localpart_template() -> String {
+    "{sub}".to_owned()
+}
+
 fn default_address() -> IpAddr {
     Ipv4Addr::LOCALHOST.into()
 }
@@ -239,6 +741,22 @@ fn default_pdu_cache_capacity() -> u32 {
     150_000
 }
 
+fn default_presence_idle_timeout() -> u64 {
+    5 * 60 // 5 minutes
+}
+
+fn default_url_preview_max_size() -> u32 {
+    10 * 1024 * 1024 // Default to 10 MB
+}
+
+fn default_url_preview_cache_ttl() -> u64 {
+    60 * 60 * 24
+}
+
+fn default_media_cleanup_interval() -> u64 {
+    60 * 60 // 1 hour
+}
+
 fn default_cleanup_second_interval() -> u32 {
     60 // every minute
 }
@@ -263,6 +781,51 @@ fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
 
+fn default_soft_fail_backoff_threshold() -> u64 {
+    100
+}
+
+fn default_max_push_body_length() -> usize {
+    1024
+}
+
+fn default_push_digest_interval() -> u64 {
+    60 * 60
+}
+
+fn default_push_max_attempts() -> u32 {
+    10
+}
+
+fn default_push_pause_duration() -> u64 {
+    60 * 60
+}
+
+fn default_push_timeout() -> u64 {
+    60 * 3
+}
+
+fn default_push_max_fanout() -> usize {
+    1000
+}
+
+fn default_push_quarantine_threshold() -> u32 {
+    5
+}
+
+fn default_push_gateway_probe_ttl() -> u64 {
+    60
+}
+
+fn default_notification_history_flush_interval() -> u64 {
+    10
+}
+
+pub(crate) fn default_push_retry_status_codes() -> Vec<u16> {
+    // Too Many Requests and all server errors
+    std::iter::once(429).chain(500..600).collect()
+}
+
 // I know, it's a great name
 pub fn default_default_room_version() -> RoomVersionId {
     RoomVersionId::V9
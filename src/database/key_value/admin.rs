@@ -0,0 +1,24 @@
+use ruma::{OwnedRoomId, RoomId, UserId};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::admin::Data for KeyValueDatabase {
+    fn server_notice_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>> {
+        self.userid_servernoticeroomid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .ok()
+                    .and_then(|room_id| RoomId::parse(room_id).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database("Invalid room id in userid_servernoticeroomid.")
+                    })
+            })
+            .transpose()
+    }
+
+    fn set_server_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        self.userid_servernoticeroomid
+            .insert(user_id.as_bytes(), room_id.as_bytes())
+    }
+}
@@ -43,6 +43,7 @@ impl service::globals::Data for KeyValueDatabase {
 
         futures.push(self.userroomid_joined.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_invitestate.watch_prefix(&userid_prefix));
+        futures.push(self.userroomid_knockstate.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_leftstate.watch_prefix(&userid_prefix));
         futures.push(
             self.userroomid_notificationcount
@@ -79,6 +80,8 @@ impl service::globals::Data for KeyValueDatabase {
 
             futures.push(self.readreceiptid_readreceipt.watch_prefix(&roomid_prefix));
 
+            futures.push(self.presenceid_presence.watch_prefix(&roomid_prefix));
+
             // Key changes
             futures.push(self.keychangeid_userid.watch_prefix(&roomid_prefix));
 
@@ -1,6 +1,10 @@
-use ruma::api::client::error::ErrorKind;
+use ruma::{api::client::error::ErrorKind, UserId};
 
-use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, media::UrlPreview},
+    utils, Error, Result,
+};
 
 impl service::media::Data for KeyValueDatabase {
     fn create_file_metadata(
@@ -8,6 +12,7 @@ impl service::media::Data for KeyValueDatabase {
         mxc: String,
         width: u32,
         height: u32,
+        crop: Option<bool>,
         content_disposition: Option<&str>,
         content_type: Option<&str>,
     ) -> Result<Vec<u8>> {
@@ -15,6 +20,9 @@ impl service::media::Data for KeyValueDatabase {
         key.push(0xff);
         key.extend_from_slice(&width.to_be_bytes());
         key.extend_from_slice(&height.to_be_bytes());
+        if let Some(crop) = crop {
+            key.push(u8::from(crop));
+        }
         key.push(0xff);
         key.extend_from_slice(
             content_disposition
@@ -40,11 +48,15 @@ impl service::media::Data for KeyValueDatabase {
         mxc: String,
         width: u32,
         height: u32,
+        crop: Option<bool>,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)> {
         let mut prefix = mxc.as_bytes().to_vec();
         prefix.push(0xff);
         prefix.extend_from_slice(&width.to_be_bytes());
         prefix.extend_from_slice(&height.to_be_bytes());
+        if let Some(crop) = crop {
+            prefix.push(u8::from(crop));
+        }
         prefix.push(0xff);
 
         let (key, _) = self
@@ -79,4 +91,203 @@ impl service::media::Data for KeyValueDatabase {
         };
         Ok((content_disposition, content_type, key))
     }
+
+    fn set_url_preview(&self, url: &str, preview: &UrlPreview) -> Result<()> {
+        self.url_previews.insert(
+            url.as_bytes(),
+            &serde_json::to_vec(preview).expect("UrlPreview can be serialized"),
+        )
+    }
+
+    fn get_url_preview(&self, url: &str) -> Result<Option<UrlPreview>> {
+        self.url_previews
+            .get(url.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid UrlPreview in url_previews."))
+            })
+            .transpose()
+    }
+
+    fn set_blurhash(&self, mxc: &str, blurhash: &str) -> Result<()> {
+        self.mediaid_blurhash
+            .insert(mxc.as_bytes(), blurhash.as_bytes())
+    }
+
+    fn blurhash(&self, mxc: &str) -> Result<Option<String>> {
+        self.mediaid_blurhash
+            .get(mxc.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid blurhash in mediaid_blurhash."))
+            })
+            .transpose()
+    }
+
+    fn add_media_usage(&self, mxc: &str, owner: &str, size: u64) -> Result<()> {
+        // Replaced media must not be counted twice
+        remove_media_usage(self, mxc)?;
+
+        let mut usage = owner.as_bytes().to_vec();
+        usage.push(0xff);
+        usage.extend_from_slice(&size.to_be_bytes());
+        self.mediaid_usage.insert(mxc.as_bytes(), &usage)?;
+
+        let total = self.media_usage(owner)?.saturating_add(size);
+        self.owner_mediausage
+            .insert(owner.as_bytes(), &total.to_be_bytes())
+    }
+
+    fn media_usage(&self, owner: &str) -> Result<u64> {
+        self.owner_mediausage
+            .get(owner.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid size in owner_mediausage."))
+            })
+            .unwrap_or(Ok(0))
+    }
+
+    fn media_sizes<'a>(
+        &'a self,
+        mxc_prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64, u64)>> + 'a> {
+        Box::new(
+            self.mediaid_usage
+                .scan_prefix(mxc_prefix.as_bytes().to_vec())
+                .map(|(mxc, usage)| {
+                    let (_, size) = parse_media_usage(&usage)?;
+
+                    let last_access = self
+                        .mediaid_lastaccess
+                        .get(&mxc)?
+                        .map(|bytes| {
+                            utils::u64_from_bytes(&bytes).map_err(|_| {
+                                Error::bad_database("Invalid timestamp in mediaid_lastaccess.")
+                            })
+                        })
+                        .transpose()?
+                        .unwrap_or_default();
+
+                    let mxc = utils::string_from_bytes(&mxc)
+                        .map_err(|_| Error::bad_database("Invalid MXC in mediaid_usage."))?;
+
+                    Ok((mxc, size, last_access))
+                }),
+        )
+    }
+
+    fn delete_file_metadata(&self, mxc: &str) -> Result<Vec<Vec<u8>>> {
+        let mut prefix = mxc.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let keys: Vec<_> = self
+            .mediaid_file
+            .scan_prefix(prefix)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in &keys {
+            self.mediaid_file.remove(key)?;
+        }
+
+        remove_media_usage(self, mxc)?;
+        self.mediaid_blurhash.remove(mxc.as_bytes())?;
+        self.mediaid_lastaccess.remove(mxc.as_bytes())?;
+
+        if let Some(user_id) = self.mediaid_userid.get(mxc.as_bytes())? {
+            let mut key = user_id;
+            key.push(0xff);
+            key.extend_from_slice(mxc.as_bytes());
+            self.userid_mediaid.remove(&key)?;
+            self.mediaid_userid.remove(mxc.as_bytes())?;
+        }
+
+        Ok(keys)
+    }
+
+    fn set_media_last_access(&self, mxc: &str, timestamp: u64) -> Result<()> {
+        self.mediaid_lastaccess
+            .insert(mxc.as_bytes(), &timestamp.to_be_bytes())
+    }
+
+    fn media_accessed_before<'a>(
+        &'a self,
+        timestamp: u64,
+    ) -> Box<dyn Iterator<Item = Result<String>> + 'a> {
+        Box::new(
+            self.mediaid_lastaccess
+                .iter()
+                .filter_map(move |(mxc, last_access)| {
+                    let last_access = match utils::u64_from_bytes(&last_access) {
+                        Ok(last_access) => last_access,
+                        Err(_) => {
+                            return Some(Err(Error::bad_database(
+                                "Invalid timestamp in mediaid_lastaccess.",
+                            )))
+                        }
+                    };
+
+                    (last_access < timestamp).then(|| {
+                        utils::string_from_bytes(&mxc)
+                            .map_err(|_| Error::bad_database("Invalid MXC in mediaid_lastaccess."))
+                    })
+                }),
+        )
+    }
+
+    fn set_media_uploader(&self, mxc: &str, user_id: &UserId) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(mxc.as_bytes());
+
+        self.userid_mediaid.insert(&key, &[])?;
+        self.mediaid_userid
+            .insert(mxc.as_bytes(), user_id.as_bytes())
+    }
+
+    fn media_from_user<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<String>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(self.userid_mediaid.scan_prefix(prefix).map(|(key, _)| {
+            let mxc = key
+                .splitn(2, |&b| b == 0xff)
+                .nth(1)
+                .ok_or_else(|| Error::bad_database("Invalid key in userid_mediaid."))?;
+
+            utils::string_from_bytes(mxc)
+                .map_err(|_| Error::bad_database("Invalid MXC in userid_mediaid."))
+        }))
+    }
+}
+
+/// Subtracts the size of the media from the storage used by its owner.
+fn remove_media_usage(db: &KeyValueDatabase, mxc: &str) -> Result<()> {
+    if let Some(usage) = db.mediaid_usage.get(mxc.as_bytes())? {
+        let (owner, size) = parse_media_usage(&usage)?;
+        let total = service::media::Data::media_usage(db, &owner)?.saturating_sub(size);
+        db.owner_mediausage
+            .insert(owner.as_bytes(), &total.to_be_bytes())?;
+        db.mediaid_usage.remove(mxc.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Parses the owner and size of a value in mediaid_usage.
+fn parse_media_usage(usage: &[u8]) -> Result<(String, u64)> {
+    let mut parts = usage.splitn(2, |&b| b == 0xff);
+
+    let owner = utils::string_from_bytes(parts.next().unwrap_or_default())
+        .map_err(|_| Error::bad_database("Invalid owner in mediaid_usage."))?;
+    let size = parts
+        .next()
+        .and_then(|bytes| utils::u64_from_bytes(bytes).ok())
+        .ok_or_else(|| Error::bad_database("Invalid size in mediaid_usage."))?;
+
+    Ok((owner, size))
 }
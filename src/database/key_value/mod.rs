@@ -1,5 +1,5 @@
 mod account_data;
-//mod admin;
+mod admin;
 mod appservice;
 mod globals;
 mod key_backups;
@@ -8,6 +8,7 @@ mod media;
 mod pusher;
 mod rooms;
 mod sending;
+mod sso;
 mod transaction_ids;
 mod uiaa;
 mod users;
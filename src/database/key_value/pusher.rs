@@ -1,33 +1,153 @@
+use std::{cell::RefCell, sync::Arc};
+
 use ruma::{
     api::client::push::{set_pusher, Pusher},
-    UserId,
+    DeviceId, EventId, OwnedDeviceId, OwnedEventId, OwnedUserId, RoomId, UserId,
+};
+
+use crate::{
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service::{
+        self,
+        pusher::{
+            pushkey_metadata, senderkey_users, FailedNotification, NotificationRecord,
+            PusherSettings, TweakPreferences, UndoLog,
+        },
+    },
+    services, utils, Error, Result,
 };
 
-use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+type PusherUndoLog = UndoLog<(Arc<dyn KvTree>, Vec<u8>)>;
+
+thread_local! {
+    /// Undo log of the pusher transaction running on this thread, if any
+    static PUSHER_UNDO_LOG: RefCell<Option<PusherUndoLog>> = RefCell::new(None);
+}
+
+/// Owns the undo log of this thread while a transaction runs. If the transaction panics, the
+/// log is cleared and its writes are rolled back when the guard is dropped, so later
+/// transactions on this thread aren't mistaken for nested ones.
+struct UndoLogGuard;
+
+impl UndoLogGuard {
+    fn start() -> Self {
+        PUSHER_UNDO_LOG.with(|log| *log.borrow_mut() = Some(UndoLog::default()));
+        Self
+    }
+
+    fn finish(self) -> PusherUndoLog {
+        PUSHER_UNDO_LOG
+            .with(|log| log.borrow_mut().take())
+            .expect("undo log is set while the guard lives")
+    }
+}
+
+impl Drop for UndoLogGuard {
+    fn drop(&mut self) {
+        if let Some(log) = PUSHER_UNDO_LOG.with(|log| log.borrow_mut().take()) {
+            let _ = roll_back(log);
+        }
+    }
+}
+
+fn roll_back(log: PusherUndoLog) -> Result<()> {
+    log.undo(|(tree, key), previous| match previous {
+        Some(value) => tree.insert(&key, &value),
+        None => tree.remove(&key),
+    })
+}
+
+impl KeyValueDatabase {
+    /// Remembers the current value of the key if a pusher transaction is running, so it can be
+    /// restored on rollback.
+    fn journal(&self, tree: &Arc<dyn KvTree>, key: &[u8]) -> Result<()> {
+        PUSHER_UNDO_LOG.with(|log| {
+            if let Some(log) = log.borrow_mut().as_mut() {
+                log.record((Arc::clone(tree), key.to_vec()), tree.get(key)?);
+            }
+            Ok(())
+        })
+    }
+
+    fn journaled_insert(&self, tree: &Arc<dyn KvTree>, key: &[u8], value: &[u8]) -> Result<()> {
+        self.journal(tree, key)?;
+        tree.insert(key, value)
+    }
+
+    fn journaled_remove(&self, tree: &Arc<dyn KvTree>, key: &[u8]) -> Result<()> {
+        self.journal(tree, key)?;
+        tree.remove(key)
+    }
+}
 
 impl service::pusher::Data for KeyValueDatabase {
-    fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        // Nested transactions are part of the outer one
+        if PUSHER_UNDO_LOG.with(|log| log.borrow().is_some()) {
+            return f();
+        }
+
+        let guard = UndoLogGuard::start();
+        let result = f();
+        let log = guard.finish();
+
+        if result.is_err() {
+            roll_back(log)?;
+        }
+
+        result
+    }
+
+    fn upsert_pusher(
+        &self,
+        sender: &UserId,
+        pusher: set_pusher::v3::PusherAction,
+        version: u64,
+    ) -> Result<bool> {
+        let pushkey = match &pusher {
+            set_pusher::v3::PusherAction::Post(data) => &data.pusher.ids.pushkey,
+            set_pusher::v3::PusherAction::Delete(ids) => &ids.pushkey,
+        };
+
+        let mut key = sender.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        // Reading and writing the version has to happen atomically
+        let _lock = self.pusher_upsert_lock.lock().unwrap();
+
+        let stored_version = self
+            .senderkey_pusherversion
+            .get(&key)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid version in senderkey_pusherversion."))
+            })
+            .transpose()?;
+
+        if !service::pusher::is_newer_version(stored_version, version) {
+            return Ok(false);
+        }
+
+        // The version is kept after deletions, so older registrations can't bring a pusher back
+        self.journaled_insert(&self.senderkey_pusherversion, &key, &version.to_be_bytes())?;
+
         match &pusher {
-            set_pusher::v3::PusherAction::Post(data) => {
-                let mut key = sender.as_bytes().to_vec();
-                key.push(0xff);
-                key.extend_from_slice(data.pusher.ids.pushkey.as_bytes());
-                self.senderkey_pusher.insert(
+            set_pusher::v3::PusherAction::Post(_) => {
+                self.journaled_insert(
+                    &self.senderkey_pusher,
                     &key,
                     &serde_json::to_vec(&pusher).expect("Pusher is valid JSON value"),
                 )?;
-                Ok(())
             }
-            set_pusher::v3::PusherAction::Delete(ids) => {
-                let mut key = sender.as_bytes().to_vec();
-                key.push(0xff);
-                key.extend_from_slice(ids.pushkey.as_bytes());
-                self.senderkey_pusher
-                    .remove(&key)
-                    .map(|_| ())
-                    .map_err(Into::into)
+            set_pusher::v3::PusherAction::Delete(_) => {
+                self.journaled_remove(&self.senderkey_pushersettings, &key)?;
+                self.journaled_remove(&self.senderkey_deviceid, &key)?;
+                self.journaled_remove(&self.senderkey_pusher, &key)?;
             }
         }
+
+        Ok(true)
     }
 
     fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>> {
@@ -39,7 +159,7 @@ impl service::pusher::Data for KeyValueDatabase {
             .get(&senderkey)?
             .map(|push| {
                 serde_json::from_slice(&push)
-                    .map_err(|_| Error::bad_database("Invalid Pusher in db."))
+                    .map_err(|_| Error::bad_pusher("Invalid Pusher in db."))
             })
             .transpose()
     }
@@ -52,11 +172,36 @@ impl service::pusher::Data for KeyValueDatabase {
             .scan_prefix(prefix)
             .map(|(_, push)| {
                 serde_json::from_slice(&push)
-                    .map_err(|_| Error::bad_database("Invalid Pusher in db."))
+                    .map_err(|_| Error::bad_pusher("Invalid Pusher in db."))
             })
             .collect()
     }
 
+    fn all_pushers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, Pusher)>> + 'a> {
+        Box::new(self.senderkey_pusher.iter().map(|(key, push)| {
+            let user_id = utils::string_from_bytes(
+                key.split(|&b| b == 0xff)
+                    .next()
+                    .expect("split always returns one element"),
+            )
+            .map_err(|_| Error::bad_database("Invalid user id bytes in senderkey_pusher"))
+            .and_then(|s| {
+                UserId::parse(s)
+                    .map_err(|_| Error::bad_database("Invalid user id in senderkey_pusher"))
+            })?;
+            let pusher = serde_json::from_slice(&push)
+                .map_err(|_| Error::bad_pusher("Invalid Pusher in db."))?;
+
+            Ok((user_id, pusher))
+        }))
+    }
+
+    fn users_with_pushers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a> {
+        Box::new(senderkey_users(
+            self.senderkey_pusher.iter().map(|(key, _)| key),
+        ))
+    }
+
     fn get_pushkeys<'a>(
         &'a self,
         sender: &UserId,
@@ -76,4 +221,457 @@ impl service::pusher::Data for KeyValueDatabase {
             Ok(push_key_string)
         }))
     }
+
+    fn get_pushkey_metadata<'a>(
+        &'a self,
+        sender: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, String, String)>> + 'a> {
+        let mut prefix = sender.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(self.senderkey_pusher.scan_prefix(prefix).map(|(_, push)| {
+            serde_json::from_slice(&push)
+                .map(|pusher| pushkey_metadata(&pusher))
+                .map_err(|_| Error::bad_pusher("Invalid Pusher in db."))
+        }))
+    }
+
+    fn set_pusher_device(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        device_id: &DeviceId,
+    ) -> Result<()> {
+        let mut key = sender.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.journaled_insert(&self.senderkey_deviceid, &key, device_id.as_bytes())
+    }
+
+    fn get_pusher_device(&self, sender: &UserId, pushkey: &str) -> Result<Option<OwnedDeviceId>> {
+        let mut key = sender.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.senderkey_deviceid
+            .get(&key)?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map(Into::into)
+                    .map_err(|_| Error::bad_database("Invalid device id in senderkey_deviceid."))
+            })
+            .transpose()
+    }
+
+    fn set_pusher_settings(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        settings: &PusherSettings,
+    ) -> Result<()> {
+        let mut key = sender.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.journaled_insert(
+            &self.senderkey_pushersettings,
+            &key,
+            &serde_json::to_vec(settings).expect("PusherSettings is valid JSON value"),
+        )
+    }
+
+    fn get_pusher_settings(&self, sender: &UserId, pushkey: &str) -> Result<PusherSettings> {
+        let mut key = sender.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.senderkey_pushersettings
+            .get(&key)?
+            .map(|settings| {
+                serde_json::from_slice(&settings)
+                    .map_err(|_| Error::bad_pusher("Invalid PusherSettings in db."))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn set_tweak_preferences(
+        &self,
+        user_id: &UserId,
+        preferences: &TweakPreferences,
+    ) -> Result<()> {
+        self.journaled_insert(
+            &self.userid_tweakpreferences,
+            user_id.as_bytes(),
+            &serde_json::to_vec(preferences).expect("TweakPreferences is valid JSON value"),
+        )
+    }
+
+    fn get_tweak_preferences(&self, user_id: &UserId) -> Result<TweakPreferences> {
+        self.userid_tweakpreferences
+            .get(user_id.as_bytes())?
+            .map(|preferences| {
+                serde_json::from_slice(&preferences)
+                    .map_err(|_| Error::bad_database("Invalid TweakPreferences in db."))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn clear_tweak_preferences(&self, user_id: &UserId) -> Result<()> {
+        self.journaled_remove(&self.userid_tweakpreferences, user_id.as_bytes())
+    }
+
+    fn set_last_notified(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        pushkey: &str,
+        timestamp: u64,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.journaled_insert(
+            &self.userroomidpushkey_notified,
+            &key,
+            &timestamp.to_be_bytes(),
+        )
+    }
+
+    fn last_notified(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        pushkey: &str,
+    ) -> Result<Option<u64>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.userroomidpushkey_notified
+            .get(&key)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid timestamp in userroomidpushkey_notified.")
+                })
+            })
+            .transpose()
+    }
+
+    fn clear_last_notified(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.as_bytes());
+        prefix.push(0xff);
+
+        for (key, _) in self.userroomidpushkey_notified.scan_prefix(prefix) {
+            self.journaled_remove(&self.userroomidpushkey_notified, &key)?;
+        }
+
+        Ok(())
+    }
+
+    fn queue_digest_event(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        event_id: &EventId,
+        queued_at: u64,
+    ) -> Result<()> {
+        let mut key = sender.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+
+        let mut value = queued_at.to_be_bytes().to_vec();
+        value.extend_from_slice(event_id.as_bytes());
+
+        self.journaled_insert(&self.senderkeycount_digesteventid, &key, &value)
+    }
+
+    fn digest_events(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+    ) -> Result<Vec<(u64, u64, OwnedEventId)>> {
+        let mut prefix = sender.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(pushkey.as_bytes());
+        prefix.push(0xff);
+
+        self.senderkeycount_digesteventid
+            .scan_prefix(prefix.clone())
+            .map(|(key, value)| {
+                let count = utils::u64_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                    Error::bad_database("Invalid count in senderkeycount_digesteventid")
+                })?;
+                let queued_at = value
+                    .get(..8)
+                    .and_then(|bytes| utils::u64_from_bytes(bytes).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database("Invalid queue time in senderkeycount_digesteventid")
+                    })?;
+                let event_id =
+                    EventId::parse(utils::string_from_bytes(&value[8..]).map_err(|_| {
+                        Error::bad_database(
+                            "Invalid event id bytes in senderkeycount_digesteventid",
+                        )
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid event id in senderkeycount_digesteventid")
+                    })?;
+
+                Ok((count, queued_at, event_id))
+            })
+            .collect()
+    }
+
+    fn remove_digest_events(&self, sender: &UserId, pushkey: &str, until: u64) -> Result<()> {
+        let mut prefix = sender.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(pushkey.as_bytes());
+        prefix.push(0xff);
+
+        for (key, _) in self
+            .senderkeycount_digesteventid
+            .scan_prefix(prefix.clone())
+        {
+            // Events queued while the digest was sent are part of the next one
+            match utils::u64_from_bytes(&key[prefix.len()..]) {
+                Ok(count) if count > until => break,
+                _ => self.journaled_remove(&self.senderkeycount_digesteventid, &key)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn digest_pushers<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String)>> + 'a> {
+        let mut last = None;
+
+        Box::new(
+            self.senderkeycount_digesteventid
+                .iter()
+                .filter_map(move |(key, _)| {
+                    // Strip the count, so all digest events of a pusher share the same key
+                    let senderkey = key.get(..key.len().saturating_sub(9))?.to_vec();
+                    if last.as_ref() == Some(&senderkey) {
+                        return None;
+                    }
+                    last = Some(senderkey.clone());
+                    Some(senderkey)
+                })
+                .map(|senderkey| {
+                    let mut parts = senderkey.splitn(2, |&b| b == 0xff);
+                    let user_id = utils::string_from_bytes(
+                        parts.next().expect("splitn always returns one element"),
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Invalid user id bytes in senderkeycount_digesteventid")
+                    })
+                    .and_then(|s| {
+                        UserId::parse(s).map_err(|_| {
+                            Error::bad_database("Invalid user id in senderkeycount_digesteventid")
+                        })
+                    })?;
+                    let pushkey = utils::string_from_bytes(parts.next().ok_or_else(|| {
+                        Error::bad_database("Invalid senderkeycount_digesteventid in db")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid pushkey bytes in senderkeycount_digesteventid")
+                    })?;
+
+                    Ok((user_id, pushkey))
+                }),
+        )
+    }
+
+    fn record_failed_notification(
+        &self,
+        notification_id: &str,
+        notification: &FailedNotification,
+    ) -> Result<()> {
+        let mut key = notification.to_bytes();
+        key.push(0xff);
+        key.extend_from_slice(notification_id.as_bytes());
+
+        self.journaled_insert(
+            &self.notificationid_failed,
+            notification_id.as_bytes(),
+            &notification.to_bytes(),
+        )?;
+        self.journaled_insert(&self.senderkeypduid_failednotificationid, &key, &[])
+    }
+
+    fn get_failed_notification(&self, notification_id: &str) -> Result<Option<FailedNotification>> {
+        self.notificationid_failed
+            .get(notification_id.as_bytes())?
+            .map(|bytes| FailedNotification::from_bytes(&bytes))
+            .transpose()
+    }
+
+    fn clear_failed_notifications(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        pdu_id: &[u8],
+    ) -> Result<()> {
+        let mut prefix = FailedNotification {
+            user_id: sender.to_owned(),
+            pushkey: pushkey.to_owned(),
+            pdu_id: pdu_id.to_vec(),
+        }
+        .to_bytes();
+        prefix.push(0xff);
+
+        for (key, _) in self
+            .senderkeypduid_failednotificationid
+            .scan_prefix(prefix.clone())
+        {
+            self.journaled_remove(&self.notificationid_failed, &key[prefix.len()..])?;
+            self.journaled_remove(&self.senderkeypduid_failednotificationid, &key)?;
+        }
+
+        Ok(())
+    }
+
+    fn append_notification_history(&self, records: &[NotificationRecord]) -> Result<()> {
+        let batch: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.notification_id.as_bytes().to_vec(),
+                    serde_json::to_vec(record).expect("NotificationRecord::to_vec always works"),
+                )
+            })
+            .collect();
+
+        for (key, _) in &batch {
+            self.journal(&self.notificationid_history, key)?;
+        }
+
+        self.notificationid_history
+            .insert_batch(&mut batch.into_iter())
+    }
+
+    fn add_call_invite(&self, room_id: &RoomId, call_id: &str, user_id: &UserId) -> Result<()> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(call_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        self.journaled_insert(&self.roomcallidusers_callinvite, &key, &[])
+    }
+
+    fn remove_call_invites(&self, room_id: &RoomId, call_id: &str) -> Result<()> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(call_id.as_bytes());
+        prefix.push(0xff);
+
+        for (key, _) in self.roomcallidusers_callinvite.scan_prefix(prefix) {
+            self.journaled_remove(&self.roomcallidusers_callinvite, &key)?;
+        }
+
+        Ok(())
+    }
+
+    fn missed_calls(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Ok(self
+            .roomcallidusers_callinvite
+            .scan_prefix(prefix)
+            .filter(|(key, _)| key.rsplit(|&b| b == 0xff).next() == Some(user_id.as_bytes()))
+            .count() as u64)
+    }
+
+    fn clear_missed_calls(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for (key, _) in self
+            .roomcallidusers_callinvite
+            .scan_prefix(prefix)
+            .filter(|(key, _)| key.rsplit(|&b| b == 0xff).next() == Some(user_id.as_bytes()))
+        {
+            self.journaled_remove(&self.roomcallidusers_callinvite, &key)?;
+        }
+
+        Ok(())
+    }
+
+    fn mark_notified_event(
+        &self,
+        event_id: &EventId,
+        sender: &UserId,
+        pushkey: &str,
+    ) -> Result<()> {
+        let mut key = event_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(sender.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        self.journaled_insert(&self.eventidsenderkey_notified, &key, &[])
+    }
+
+    fn notified_pushers<'a>(
+        &'a self,
+        event_id: &EventId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String)>> + 'a> {
+        let mut prefix = event_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        let prefix_len = prefix.len();
+
+        Box::new(
+            self.eventidsenderkey_notified
+                .scan_prefix(prefix)
+                .map(move |(key, _)| {
+                    let mut parts = key[prefix_len..].splitn(2, |&b| b == 0xff);
+                    let user_id = utils::string_from_bytes(
+                        parts.next().expect("splitn always returns one element"),
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Invalid user id bytes in eventidsenderkey_notified")
+                    })
+                    .and_then(|s| {
+                        UserId::parse(s).map_err(|_| {
+                            Error::bad_database("Invalid user id in eventidsenderkey_notified")
+                        })
+                    })?;
+                    let pushkey = utils::string_from_bytes(parts.next().ok_or_else(|| {
+                        Error::bad_database("Invalid eventidsenderkey_notified in db")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid pushkey bytes in eventidsenderkey_notified")
+                    })?;
+
+                    Ok((user_id, pushkey))
+                }),
+        )
+    }
+
+    fn forget_notified_event(&self, event_id: &EventId) -> Result<()> {
+        let mut prefix = event_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for (key, _) in self.eventidsenderkey_notified.scan_prefix(prefix) {
+            self.journaled_remove(&self.eventidsenderkey_notified, &key)?;
+        }
+
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 use std::{collections::HashSet, mem};
 
-use ruma::{OwnedUserId, RoomId, UserId};
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
@@ -124,4 +124,24 @@ impl service::rooms::edus::typing::Data for KeyValueDatabase {
 
         Ok(user_ids)
     }
+
+    fn typing_rooms(&self) -> Result<HashSet<OwnedRoomId>> {
+        let mut room_ids = HashSet::new();
+
+        for (key, _) in self.typingid_userid.iter() {
+            let room_id = key
+                .split(|&b| b == 0xff)
+                .next()
+                .expect("split always returns one element");
+
+            let room_id = RoomId::parse(utils::string_from_bytes(room_id).map_err(|_| {
+                Error::bad_database("Room ID in typingid_userid is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Room ID in typingid_userid is invalid."))?;
+
+            room_ids.insert(room_id);
+        }
+
+        Ok(room_ids)
+    }
 }
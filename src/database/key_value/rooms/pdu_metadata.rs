@@ -1,8 +1,8 @@
 use std::sync::Arc;
 
-use ruma::{EventId, RoomId};
+use ruma::{EventId, OwnedServerName, RoomId, ServerName};
 
-use crate::{database::KeyValueDatabase, service, Result};
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
 impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
     fn add_relation(&self, from: u64, to: u64) -> Result<()> {
@@ -12,6 +12,90 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn add_typed_relation(
+        &self,
+        from: u64,
+        to: u64,
+        rel_type: &str,
+        event_type: &str,
+    ) -> Result<()> {
+        let mut key = to.to_be_bytes().to_vec();
+        key.extend_from_slice(rel_type.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(event_type.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(&from.to_be_bytes());
+        self.totypefrom_relation.insert(&key, &[])?;
+        Ok(())
+    }
+
+    fn remove_relations_to(&self, to: u64) -> Result<()> {
+        for (key, _) in self
+            .totypefrom_relation
+            .scan_prefix(to.to_be_bytes().to_vec())
+        {
+            let mut fromto = key[key.len() - 8..].to_vec();
+            fromto.extend_from_slice(&to.to_be_bytes());
+            self.fromto_relation.remove(&fromto)?;
+            self.totypefrom_relation.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_relation(&self, from: u64, to: u64, rel_type: &str, event_type: &str) -> Result<()> {
+        let mut key = from.to_be_bytes().to_vec();
+        key.extend_from_slice(&to.to_be_bytes());
+        self.fromto_relation.remove(&key)?;
+
+        let mut key = to.to_be_bytes().to_vec();
+        key.extend_from_slice(rel_type.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(event_type.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(&from.to_be_bytes());
+        self.totypefrom_relation.remove(&key)
+    }
+
+    fn relations_to<'a>(
+        &'a self,
+        to: u64,
+        rel_type: Option<&str>,
+        event_type: Option<&str>,
+    ) -> Box<dyn Iterator<Item = Result<u64>> + 'a> {
+        // Both filters are part of the key, so they can be applied with a prefix scan
+        let mut prefix = to.to_be_bytes().to_vec();
+        if let Some(rel_type) = rel_type {
+            prefix.extend_from_slice(rel_type.as_bytes());
+            prefix.push(0xff);
+            if let Some(event_type) = event_type {
+                prefix.extend_from_slice(event_type.as_bytes());
+                prefix.push(0xff);
+            }
+        }
+
+        let event_type = event_type
+            .filter(|_| rel_type.is_none())
+            .map(|event_type| event_type.as_bytes().to_vec());
+
+        Box::new(
+            self.totypefrom_relation
+                .scan_prefix(prefix)
+                .filter(move |(key, _)| match &event_type {
+                    Some(event_type) => key[8..]
+                        .split(|&b| b == 0xff)
+                        .nth(1)
+                        .map_or(false, |t| t == event_type.as_slice()),
+                    None => true,
+                })
+                .map(|(key, _)| {
+                    utils::u64_from_bytes(&key[key.len() - 8..]).map_err(|_| {
+                        Error::bad_database("Invalid shorteventid in totypefrom_relation.")
+                    })
+                }),
+        )
+    }
+
     fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()> {
         for prev in event_ids {
             let mut key = room_id.as_bytes().to_vec();
@@ -37,4 +121,49 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
             .get(event_id.as_bytes())
             .map(|o| o.is_some())
     }
+
+    fn get_soft_failures(&self, origin: &ServerName) -> Result<Option<(u64, u64)>> {
+        self.servername_softfailures
+            .get(origin.as_bytes())?
+            .map(|bytes| parse_soft_failures(&bytes))
+            .transpose()
+    }
+
+    fn set_soft_failures(&self, origin: &ServerName, window_start: u64, count: u64) -> Result<()> {
+        let mut value = window_start.to_be_bytes().to_vec();
+        value.extend_from_slice(&count.to_be_bytes());
+        self.servername_softfailures
+            .insert(origin.as_bytes(), &value)
+    }
+
+    fn all_soft_failures<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedServerName, u64, u64)>> + 'a> {
+        Box::new(self.servername_softfailures.iter().map(|(key, value)| {
+            let origin = utils::string_from_bytes(&key)
+                .ok()
+                .and_then(|origin| OwnedServerName::try_from(origin).ok())
+                .ok_or_else(|| {
+                    Error::bad_database("Invalid server name in servername_softfailures.")
+                })?;
+            let (window_start, count) = parse_soft_failures(&value)?;
+
+            Ok((origin, window_start, count))
+        }))
+    }
+}
+
+fn parse_soft_failures(bytes: &[u8]) -> Result<(u64, u64)> {
+    if bytes.len() != 16 {
+        return Err(Error::bad_database(
+            "Invalid soft failures in servername_softfailures.",
+        ));
+    }
+
+    let window_start = utils::u64_from_bytes(&bytes[..8])
+        .map_err(|_| Error::bad_database("Invalid window start in servername_softfailures."))?;
+    let count = utils::u64_from_bytes(&bytes[8..])
+        .map_err(|_| Error::bad_database("Invalid count in servername_softfailures."))?;
+
+    Ok((window_start, count))
 }
@@ -1,4 +1,4 @@
-use ruma::{ServerName, UserId};
+use ruma::{OwnedUserId, ServerName, UserId};
 
 use crate::{
     database::KeyValueDatabase,
@@ -65,12 +65,12 @@ impl service::sending::Data for KeyValueDatabase {
         let mut batch = Vec::new();
         let mut keys = Vec::new();
         for (outgoing_kind, event) in requests {
-            let mut key = outgoing_kind.get_prefix();
-            if let SendingEventType::Pdu(value) = &event {
-                key.extend_from_slice(value)
+            let count = if outgoing_kind.needs_count(event) {
+                services().globals.next_count()?
             } else {
-                key.extend_from_slice(&services().globals.next_count()?.to_be_bytes())
-            }
+                0
+            };
+            let key = outgoing_kind.get_event_key(event, count);
             let value = if let SendingEventType::Edu(value) = &event {
                 &**value
             } else {
@@ -96,6 +96,28 @@ impl service::sending::Data for KeyValueDatabase {
         );
     }
 
+    fn push_requests<'a>(
+        &'a self,
+        active: bool,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, Vec<u8>)>> + 'a> {
+        let tree = if active {
+            &self.servercurrentevent_data
+        } else {
+            &self.servernameevent_data
+        };
+
+        Box::new(tree.scan_prefix(b"$".to_vec()).map(|(k, v)| {
+            parse_servercurrentevent(&k, v).and_then(|(kind, event)| match (kind, event) {
+                (OutgoingKind::Push(user, pushkey), SendingEventType::Pdu(pdu_id)) => {
+                    Ok((user, pushkey, pdu_id))
+                }
+                _ => Err(Error::bad_database(
+                    "Invalid push request in sending queue.",
+                )),
+            })
+        }))
+    }
+
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()> {
         for (e, key) in events {
             let value = if let SendingEventType::Edu(value) = &e {
@@ -123,6 +145,87 @@ impl service::sending::Data for KeyValueDatabase {
                     .map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
             })
     }
+
+    fn appservice_txn_id(&self, appservice_id: &str) -> Result<Option<u64>> {
+        self.appserviceid_txnid
+            .get(appservice_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid u64 in appserviceid_txnid."))
+            })
+            .transpose()
+    }
+
+    fn set_appservice_txn_id(&self, appservice_id: &str, txn_id: u64) -> Result<()> {
+        self.appserviceid_txnid
+            .insert(appservice_id.as_bytes(), &txn_id.to_be_bytes())
+    }
+
+    fn remove_appservice_txn_id(&self, appservice_id: &str) -> Result<()> {
+        self.appserviceid_txnid.remove(appservice_id.as_bytes())
+    }
+
+    fn queue_delayed_push(
+        &self,
+        due: u64,
+        user: &UserId,
+        pushkey: &str,
+        pdu_id: &[u8],
+    ) -> Result<()> {
+        let mut key = due.to_be_bytes().to_vec();
+        key.extend_from_slice(user.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(pdu_id);
+        self.duesenderkeypduid.insert(&key, &[])
+    }
+
+    fn due_delayed_pushes<'a>(
+        &'a self,
+        now: u64,
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, OwnedUserId, String, Vec<u8>)>> + 'a> {
+        // Keys start with the due time, so everything after the first pending one isn't due yet
+        Box::new(
+            self.duesenderkeypduid
+                .iter()
+                .take_while(move |(key, _)| {
+                    key.get(..8)
+                        .and_then(|due| utils::u64_from_bytes(due).ok())
+                        .map_or(true, |due| due <= now)
+                })
+                .map(|(key, _)| {
+                    let mut parts = key
+                        .get(8..)
+                        .ok_or_else(|| Error::bad_database("Invalid key in duesenderkeypduid."))?
+                        .splitn(3, |&b| b == 0xff);
+
+                    let user = parts
+                        .next()
+                        .and_then(|user| utils::string_from_bytes(user).ok())
+                        .and_then(|user| UserId::parse(user).ok())
+                        .ok_or_else(|| {
+                            Error::bad_database("Invalid user id in duesenderkeypduid.")
+                        })?;
+                    let pushkey = parts
+                        .next()
+                        .and_then(|pushkey| utils::string_from_bytes(pushkey).ok())
+                        .ok_or_else(|| {
+                            Error::bad_database("Invalid pushkey in duesenderkeypduid.")
+                        })?;
+                    let pdu_id = parts
+                        .next()
+                        .ok_or_else(|| Error::bad_database("Invalid pdu id in duesenderkeypduid."))?
+                        .to_vec();
+
+                    Ok((key, user, pushkey, pdu_id))
+                }),
+        )
+    }
+
+    fn delete_delayed_push(&self, key: Vec<u8>) -> Result<()> {
+        self.duesenderkeypduid.remove(&key)
+    }
 }
 
 #[tracing::instrument(skip(key))]
@@ -146,7 +249,13 @@ fn parse_servercurrentevent(
         (
             OutgoingKind::Appservice(server),
             if value.is_empty() {
-                SendingEventType::Pdu(event.to_vec())
+                // The pdu id follows the count of when the event was queued
+                SendingEventType::Pdu(
+                    event
+                        .get(8..)
+                        .ok_or_else(|| Error::bad_database("Invalid bytes in servercurrentpdus."))?
+                        .to_vec(),
+                )
             } else {
                 SendingEventType::Edu(value)
             },
@@ -0,0 +1,29 @@
+use ruma::{OwnedUserId, UserId};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::sso::Data for KeyValueDatabase {
+    fn sso_user(&self, idp_id: &str, subject: &str) -> Result<Option<OwnedUserId>> {
+        let mut key = idp_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(subject.as_bytes());
+
+        self.idpidsubject_userid
+            .get(&key)?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .ok()
+                    .and_then(|user_id| UserId::parse(user_id).ok())
+                    .ok_or_else(|| Error::bad_database("Invalid user id in idpidsubject_userid."))
+            })
+            .transpose()
+    }
+
+    fn set_sso_user(&self, idp_id: &str, subject: &str, user_id: &UserId) -> Result<()> {
+        let mut key = idp_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(subject.as_bytes());
+
+        self.idpidsubject_userid.insert(&key, user_id.as_bytes())
+    }
+}
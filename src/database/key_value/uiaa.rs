@@ -3,7 +3,11 @@ use ruma::{
     CanonicalJsonValue, DeviceId, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, uiaa::RegistrationToken},
+    utils, Error, Result,
+};
 
 impl service::uiaa::Data for KeyValueDatabase {
     fn set_uiaa_request(
@@ -86,4 +90,41 @@ impl service::uiaa::Data for KeyValueDatabase {
         )
         .map_err(|_| Error::bad_database("UiaaInfo in userdeviceid_uiaainfo is invalid."))
     }
+
+    fn set_registration_token(&self, token: &str, info: &RegistrationToken) -> Result<()> {
+        self.registrationtoken_info.insert(
+            token.as_bytes(),
+            &serde_json::to_vec(info).expect("RegistrationToken::to_vec always works"),
+        )
+    }
+
+    fn registration_token(&self, token: &str) -> Result<Option<RegistrationToken>> {
+        self.registrationtoken_info
+            .get(token.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid registration token in db."))
+            })
+            .transpose()
+    }
+
+    fn remove_registration_token(&self, token: &str) -> Result<bool> {
+        let existed = self.registrationtoken_info.get(token.as_bytes())?.is_some();
+        self.registrationtoken_info.remove(token.as_bytes())?;
+
+        Ok(existed)
+    }
+
+    fn registration_tokens<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, RegistrationToken)>> + 'a> {
+        Box::new(self.registrationtoken_info.iter().map(|(token, bytes)| {
+            let token = utils::string_from_bytes(&token)
+                .map_err(|_| Error::bad_database("Registration token in db is invalid unicode."))?;
+            let info = serde_json::from_slice(&bytes)
+                .map_err(|_| Error::bad_database("Invalid registration token in db."))?;
+
+            Ok((token, info))
+        }))
+    }
 }
@@ -34,6 +34,16 @@ impl service::users::Data for KeyValueDatabase {
             .is_empty())
     }
 
+    /// Check if the account was registered as a guest.
+    fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_guest.get(user_id.as_bytes())?.is_some())
+    }
+
+    /// Marks the account as a guest account.
+    fn set_guest(&self, user_id: &UserId) -> Result<()> {
+        self.userid_guest.insert(user_id.as_bytes(), &[])
+    }
+
     /// Returns the number of users registered on this server.
     fn count(&self) -> Result<usize> {
         Ok(self.userid_password.iter().count())
@@ -251,6 +261,7 @@ impl service::users::Data for KeyValueDatabase {
             .increment(user_id.as_bytes())?;
 
         self.userdeviceid_metadata.remove(&userdeviceid)?;
+        self.userdeviceid_lastseen.remove(&userdeviceid)?;
 
         Ok(())
     }
@@ -446,7 +457,7 @@ impl service::users::Data for KeyValueDatabase {
     fn add_cross_signing_keys(
         &self,
         user_id: &UserId,
-        master_key: &Raw<CrossSigningKey>,
+        master_key: &Option<Raw<CrossSigningKey>>,
         self_signing_key: &Option<Raw<CrossSigningKey>>,
         user_signing_key: &Option<Raw<CrossSigningKey>>,
     ) -> Result<()> {
@@ -456,32 +467,34 @@ impl service::users::Data for KeyValueDatabase {
         prefix.push(0xff);
 
         // Master key
-        let mut master_key_ids = master_key
-            .deserialize()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid master key"))?
-            .keys
-            .into_values();
-
-        let master_key_id = master_key_ids.next().ok_or(Error::BadRequest(
-            ErrorKind::InvalidParam,
-            "Master key contained no key.",
-        ))?;
-
-        if master_key_ids.next().is_some() {
-            return Err(Error::BadRequest(
+        if let Some(master_key) = master_key {
+            let mut master_key_ids = master_key
+                .deserialize()
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid master key"))?
+                .keys
+                .into_values();
+
+            let master_key_id = master_key_ids.next().ok_or(Error::BadRequest(
                 ErrorKind::InvalidParam,
-                "Master key contained more than one key.",
-            ));
-        }
+                "Master key contained no key.",
+            ))?;
 
-        let mut master_key_key = prefix.clone();
-        master_key_key.extend_from_slice(master_key_id.as_bytes());
+            if master_key_ids.next().is_some() {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Master key contained more than one key.",
+                ));
+            }
 
-        self.keyid_key
-            .insert(&master_key_key, master_key.json().get().as_bytes())?;
+            let mut master_key_key = prefix.clone();
+            master_key_key.extend_from_slice(master_key_id.as_bytes());
 
-        self.userid_masterkeyid
-            .insert(user_id.as_bytes(), &master_key_key)?;
+            self.keyid_key
+                .insert(&master_key_key, master_key.json().get().as_bytes())?;
+
+            self.userid_masterkeyid
+                .insert(user_id.as_bytes(), &master_key_key)?;
+        }
 
         // Self-signing key
         if let Some(self_signing_key) = self_signing_key {
@@ -592,7 +605,6 @@ impl service::users::Data for KeyValueDatabase {
             &serde_json::to_vec(&cross_signing_key).expect("CrossSigningKey::to_vec always works"),
         )?;
 
-        // TODO: Should we notify about this change?
         self.mark_device_key_update(target_id)?;
 
         Ok(())
@@ -864,12 +876,62 @@ impl service::users::Data for KeyValueDatabase {
         self.userdeviceid_metadata
             .get(&userdeviceid)?
             .map_or(Ok(None), |bytes| {
-                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                let device = serde_json::from_slice(&bytes).map_err(|_| {
                     Error::bad_database("Metadata in userdeviceid_metadata is invalid.")
-                })?))
+                })?;
+                with_last_seen(self, user_id, device).map(Some)
             })
     }
 
+    fn last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<(MilliSecondsSinceUnixEpoch, Option<String>)>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_lastseen
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                let invalid = || Error::bad_database("Invalid last seen in userdeviceid_lastseen.");
+
+                let ts = bytes
+                    .get(..size_of::<u64>())
+                    .and_then(|ts| utils::u64_from_bytes(ts).ok())
+                    .and_then(UInt::new)
+                    .map(MilliSecondsSinceUnixEpoch)
+                    .ok_or_else(invalid)?;
+                let ip = &bytes[size_of::<u64>()..];
+                let ip = if ip.is_empty() {
+                    None
+                } else {
+                    Some(utils::string_from_bytes(ip).map_err(|_| invalid())?)
+                };
+
+                Ok((ts, ip))
+            })
+            .transpose()
+    }
+
+    fn set_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ts: MilliSecondsSinceUnixEpoch,
+        ip: Option<&str>,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let mut value = u64::from(ts.get()).to_be_bytes().to_vec();
+        value.extend_from_slice(ip.unwrap_or_default().as_bytes());
+
+        self.userdeviceid_lastseen.insert(&userdeviceid, &value)
+    }
+
     fn get_devicelist_version(&self, user_id: &UserId) -> Result<Option<u64>> {
         self.userid_devicelistversion
             .get(user_id.as_bytes())?
@@ -886,14 +948,16 @@ impl service::users::Data for KeyValueDatabase {
     ) -> Box<dyn Iterator<Item = Result<Device>> + 'a> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
+        let user_id = user_id.to_owned();
 
         Box::new(
             self.userdeviceid_metadata
                 .scan_prefix(key)
-                .map(|(_, bytes)| {
-                    serde_json::from_slice::<Device>(&bytes).map_err(|_| {
+                .map(move |(_, bytes)| {
+                    let device = serde_json::from_slice::<Device>(&bytes).map_err(|_| {
                         Error::bad_database("Device in userdeviceid_metadata is invalid.")
-                    })
+                    })?;
+                    with_last_seen(self, &user_id, device)
                 }),
         )
     }
@@ -930,6 +994,17 @@ impl service::users::Data for KeyValueDatabase {
     }
 }
 
+/// Fills in when the device was last used, which is stored separately because it changes on
+/// every request.
+fn with_last_seen(db: &KeyValueDatabase, user_id: &UserId, mut device: Device) -> Result<Device> {
+    if let Some((ts, ip)) = service::users::Data::last_seen(db, user_id, &device.device_id)? {
+        device.last_seen_ts = Some(ts);
+        device.last_seen_ip = ip;
+    }
+
+    Ok(device)
+}
+
 /// Will only return with Some(username) if the password was not empty and the
 /// username could be successfully parsed.
 /// If utils::string_from_bytes(...) returns an error that username will be skipped
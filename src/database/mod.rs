@@ -2,8 +2,8 @@ pub mod abstraction;
 pub mod key_value;
 
 use crate::{
-    service::rooms::timeline::PduCount, services, utils, Config, Error, PduEvent, Result, Services,
-    SERVICES,
+    service::{self, rooms::timeline::PduCount},
+    services, utils, Config, Error, PduEvent, Result, Services, SERVICES,
 };
 use abstraction::{KeyValueDatabaseEngine, KvTree};
 use directories::ProjectDirs;
@@ -38,11 +38,13 @@ pub struct KeyValueDatabase {
 
     //pub users: users::Users,
     pub(super) userid_password: Arc<dyn KvTree>,
+    pub(super) userid_guest: Arc<dyn KvTree>, // Only contains guest accounts
     pub(super) userid_displayname: Arc<dyn KvTree>,
     pub(super) userid_avatarurl: Arc<dyn KvTree>,
     pub(super) userid_blurhash: Arc<dyn KvTree>,
     pub(super) userdeviceid_token: Arc<dyn KvTree>,
     pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
+    pub(super) userdeviceid_lastseen: Arc<dyn KvTree>, // LastSeen = Timestamp + IP address
     pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn KvTree>,
 
@@ -62,6 +64,13 @@ pub struct KeyValueDatabase {
     pub(super) userdevicesessionid_uiaainfo: Arc<dyn KvTree>, // User-interactive authentication
     pub(super) userdevicesessionid_uiaarequest:
         RwLock<BTreeMap<(OwnedUserId, OwnedDeviceId, String), CanonicalJsonValue>>,
+    pub(super) registrationtoken_info: Arc<dyn KvTree>, // Info = RegistrationToken as json
+
+    //pub sso: sso::Sso,
+    pub(super) idpidsubject_userid: Arc<dyn KvTree>, // IdpIdSubject = IdpId + Subject claim of the identity provider
+
+    //pub admin: admin::Admin,
+    pub(super) userid_servernoticeroomid: Arc<dyn KvTree>,
 
     //pub edus: RoomEdus,
     pub(super) readreceiptid_readreceipt: Arc<dyn KvTree>, // ReadReceiptId = RoomId + Count + UserId
@@ -70,7 +79,7 @@ pub struct KeyValueDatabase {
     pub(super) typingid_userid: Arc<dyn KvTree>,        // TypingId = RoomId + TimeoutTime + Count
     pub(super) roomid_lasttypingupdate: Arc<dyn KvTree>, // LastRoomTypingUpdate = Count
     pub(super) presenceid_presence: Arc<dyn KvTree>,    // PresenceId = RoomId + Count + UserId
-    pub(super) userid_lastpresenceupdate: Arc<dyn KvTree>, // LastPresenceUpdate = Count
+    pub(super) userid_presence: Arc<dyn KvTree>,        // Presence = PresenceData
 
     //pub rooms: rooms::Rooms,
     pub(super) pduid_pdu: Arc<dyn KvTree>, // PduId = ShortRoomId + Count
@@ -95,6 +104,8 @@ pub struct KeyValueDatabase {
     pub(super) roomuseroncejoinedids: Arc<dyn KvTree>,
     pub(super) userroomid_invitestate: Arc<dyn KvTree>, // InviteState = Vec<Raw<Pdu>>
     pub(super) roomuserid_invitecount: Arc<dyn KvTree>, // InviteCount = Count
+    pub(super) userroomid_knockstate: Arc<dyn KvTree>,  // KnockState = Vec<Raw<Pdu>>
+    pub(super) roomuserid_knockcount: Arc<dyn KvTree>,  // KnockCount = Count
     pub(super) userroomid_leftstate: Arc<dyn KvTree>,
     pub(super) roomuserid_leftcount: Arc<dyn KvTree>,
 
@@ -129,9 +140,11 @@ pub struct KeyValueDatabase {
     /// Any pdu that has passed the steps 1-8 in the incoming event /federation/send/txn.
     pub(super) eventid_outlierpdu: Arc<dyn KvTree>,
     pub(super) softfailedeventids: Arc<dyn KvTree>,
+    pub(super) servername_softfailures: Arc<dyn KvTree>, // Value = WindowStart + Count
 
     /// ShortEventId + ShortEventId -> ().
     pub(super) fromto_relation: Arc<dyn KvTree>,
+    pub(super) totypefrom_relation: Arc<dyn KvTree>, // ToTypeFrom = To + RelType + EventType + From
     /// RoomId + EventId -> Parent PDU EventId.
     pub(super) referencedevents: Arc<dyn KvTree>,
 
@@ -141,6 +154,13 @@ pub struct KeyValueDatabase {
 
     //pub media: media::Media,
     pub(super) mediaid_file: Arc<dyn KvTree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) mediaid_blurhash: Arc<dyn KvTree>, // MediaId = MXC, value is the blurhash
+    pub(super) mediaid_lastaccess: Arc<dyn KvTree>, // LastAccess = Timestamp in milliseconds
+    pub(super) mediaid_userid: Arc<dyn KvTree>, // MediaId = MXC, UserId = uploader
+    pub(super) userid_mediaid: Arc<dyn KvTree>, // UserMediaId = UserId + MXC
+    pub(super) mediaid_usage: Arc<dyn KvTree>, // Usage = Owner + Size, owner is a UserId or ServerName
+    pub(super) owner_mediausage: Arc<dyn KvTree>, // MediaUsage = Total size in bytes
+    pub(super) url_previews: Arc<dyn KvTree>,  // Url = UrlPreview
     //pub key_backups: key_backups::KeyBackups,
     pub(super) backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
     pub(super) backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
@@ -152,12 +172,25 @@ pub struct KeyValueDatabase {
     pub(super) servername_educount: Arc<dyn KvTree>, // EduCount: Count of last EDU sync
     pub(super) servernameevent_data: Arc<dyn KvTree>, // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) servercurrentevent_data: Arc<dyn KvTree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
+    pub(super) appserviceid_txnid: Arc<dyn KvTree>, // TxnId = Transaction currently sent to the appservice
+    pub(super) duesenderkeypduid: Arc<dyn KvTree>, // DueSenderKeyPduId = Due + UserId + PushKey + PduId, push requests of later fan-out waves
 
     //pub appservice: appservice::Appservice,
     pub(super) id_appserviceregistrations: Arc<dyn KvTree>,
 
     //pub pusher: pusher::PushData,
     pub(super) senderkey_pusher: Arc<dyn KvTree>,
+    pub(super) senderkey_pushersettings: Arc<dyn KvTree>,
+    pub(super) senderkey_pusherversion: Arc<dyn KvTree>,
+    pub(super) senderkey_deviceid: Arc<dyn KvTree>, // DeviceId = Device that registered the pusher
+    pub(super) senderkeycount_digesteventid: Arc<dyn KvTree>, // SenderKeyCount = UserId + PushKey + Count, value = QueuedAt + EventId
+    pub(super) userid_tweakpreferences: Arc<dyn KvTree>,
+    pub(super) userroomidpushkey_notified: Arc<dyn KvTree>, // Value = Timestamp in ms
+    pub(super) notificationid_failed: Arc<dyn KvTree>,      // Value = UserId + PushKey + PduId
+    pub(super) senderkeypduid_failednotificationid: Arc<dyn KvTree>,
+    pub(super) notificationid_history: Arc<dyn KvTree>,
+    pub(super) roomcallidusers_callinvite: Arc<dyn KvTree>, // RoomCallIdUser = RoomId + CallId + UserId
+    pub(super) eventidsenderkey_notified: Arc<dyn KvTree>,
 
     pub(super) cached_registrations: Arc<RwLock<HashMap<String, serde_yaml::Value>>>,
     pub(super) pdu_cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
@@ -169,6 +202,7 @@ pub struct KeyValueDatabase {
     pub(super) our_real_users_cache: RwLock<HashMap<OwnedRoomId, Arc<HashSet<OwnedUserId>>>>,
     pub(super) appservice_in_room_cache: RwLock<HashMap<OwnedRoomId, HashMap<String, bool>>>,
     pub(super) lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, PduCount>>,
+    pub(super) pusher_upsert_lock: Mutex<()>,
 }
 
 impl KeyValueDatabase {
@@ -271,11 +305,13 @@ impl KeyValueDatabase {
         let db_raw = Box::new(Self {
             _db: builder.clone(),
             userid_password: builder.open_tree("userid_password")?,
+            userid_guest: builder.open_tree("userid_guest")?,
             userid_displayname: builder.open_tree("userid_displayname")?,
             userid_avatarurl: builder.open_tree("userid_avatarurl")?,
             userid_blurhash: builder.open_tree("userid_blurhash")?,
             userdeviceid_token: builder.open_tree("userdeviceid_token")?,
             userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
+            userdeviceid_lastseen: builder.open_tree("userdeviceid_lastseen")?,
             userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
             token_userdeviceid: builder.open_tree("token_userdeviceid")?,
             onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
@@ -290,6 +326,9 @@ impl KeyValueDatabase {
 
             userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
             userdevicesessionid_uiaarequest: RwLock::new(BTreeMap::new()),
+            registrationtoken_info: builder.open_tree("registrationtoken_info")?,
+            idpidsubject_userid: builder.open_tree("idpidsubject_userid")?,
+            userid_servernoticeroomid: builder.open_tree("userid_servernoticeroomid")?,
             readreceiptid_readreceipt: builder.open_tree("readreceiptid_readreceipt")?,
             roomuserid_privateread: builder.open_tree("roomuserid_privateread")?, // "Private" read receipt
             roomuserid_lastprivatereadupdate: builder
@@ -297,7 +336,7 @@ impl KeyValueDatabase {
             typingid_userid: builder.open_tree("typingid_userid")?,
             roomid_lasttypingupdate: builder.open_tree("roomid_lasttypingupdate")?,
             presenceid_presence: builder.open_tree("presenceid_presence")?,
-            userid_lastpresenceupdate: builder.open_tree("userid_lastpresenceupdate")?,
+            userid_presence: builder.open_tree("userid_presence")?,
             pduid_pdu: builder.open_tree("pduid_pdu")?,
             eventid_pduid: builder.open_tree("eventid_pduid")?,
             roomid_pduleaves: builder.open_tree("roomid_pduleaves")?,
@@ -319,6 +358,8 @@ impl KeyValueDatabase {
             roomuseroncejoinedids: builder.open_tree("roomuseroncejoinedids")?,
             userroomid_invitestate: builder.open_tree("userroomid_invitestate")?,
             roomuserid_invitecount: builder.open_tree("roomuserid_invitecount")?,
+            userroomid_knockstate: builder.open_tree("userroomid_knockstate")?,
+            roomuserid_knockcount: builder.open_tree("roomuserid_knockcount")?,
             userroomid_leftstate: builder.open_tree("userroomid_leftstate")?,
             roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
 
@@ -347,12 +388,21 @@ impl KeyValueDatabase {
 
             eventid_outlierpdu: builder.open_tree("eventid_outlierpdu")?,
             softfailedeventids: builder.open_tree("softfailedeventids")?,
+            servername_softfailures: builder.open_tree("servername_softfailures")?,
 
             fromto_relation: builder.open_tree("fromto_relation")?,
+            totypefrom_relation: builder.open_tree("totypefrom_relation")?,
             referencedevents: builder.open_tree("referencedevents")?,
             roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
             roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
             mediaid_file: builder.open_tree("mediaid_file")?,
+            mediaid_blurhash: builder.open_tree("mediaid_blurhash")?,
+            mediaid_lastaccess: builder.open_tree("mediaid_lastaccess")?,
+            mediaid_userid: builder.open_tree("mediaid_userid")?,
+            userid_mediaid: builder.open_tree("userid_mediaid")?,
+            mediaid_usage: builder.open_tree("mediaid_usage")?,
+            owner_mediausage: builder.open_tree("owner_mediausage")?,
+            url_previews: builder.open_tree("url_previews")?,
             backupid_algorithm: builder.open_tree("backupid_algorithm")?,
             backupid_etag: builder.open_tree("backupid_etag")?,
             backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
@@ -360,8 +410,22 @@ impl KeyValueDatabase {
             servername_educount: builder.open_tree("servername_educount")?,
             servernameevent_data: builder.open_tree("servernameevent_data")?,
             servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
+            appserviceid_txnid: builder.open_tree("appserviceid_txnid")?,
+            duesenderkeypduid: builder.open_tree("duesenderkeypduid")?,
             id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
             senderkey_pusher: builder.open_tree("senderkey_pusher")?,
+            senderkey_pushersettings: builder.open_tree("senderkey_pushersettings")?,
+            senderkey_pusherversion: builder.open_tree("senderkey_pusherversion")?,
+            senderkey_deviceid: builder.open_tree("senderkey_deviceid")?,
+            senderkeycount_digesteventid: builder.open_tree("senderkeycount_digesteventid")?,
+            userid_tweakpreferences: builder.open_tree("userid_tweakpreferences")?,
+            userroomidpushkey_notified: builder.open_tree("userroomidpushkey_notified")?,
+            notificationid_failed: builder.open_tree("notificationid_failed")?,
+            senderkeypduid_failednotificationid: builder
+                .open_tree("senderkeypduid_failednotificationid")?,
+            notificationid_history: builder.open_tree("notificationid_history")?,
+            roomcallidusers_callinvite: builder.open_tree("roomcallidusers_callinvite")?,
+            eventidsenderkey_notified: builder.open_tree("eventidsenderkey_notified")?,
             global: builder.open_tree("global")?,
             server_signingkeys: builder.open_tree("server_signingkeys")?,
 
@@ -390,6 +454,7 @@ impl KeyValueDatabase {
             our_real_users_cache: RwLock::new(HashMap::new()),
             appservice_in_room_cache: RwLock::new(HashMap::new()),
             lasttimelinecount_cache: Mutex::new(HashMap::new()),
+            pusher_upsert_lock: Mutex::new(()),
         });
 
         let db = Box::leak(db_raw);
@@ -418,7 +483,7 @@ impl KeyValueDatabase {
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 13;
+        let latest_database_version = 17;
 
         if services().users.count()? > 0 {
             // MIGRATIONS
@@ -933,6 +998,145 @@ impl KeyValueDatabase {
                 warn!("Migration: 12 -> 13 finished");
             }
 
+            if services().globals.database_version()? < 14 {
+                // Media is deleted when it wasn't accessed for a while, so existing media counts
+                // as just accessed
+                let now = utils::millis_since_unix_epoch().to_be_bytes();
+                for (key, _) in db.mediaid_file.iter() {
+                    let mxc = key.split(|&b| b == 0xff).next().unwrap_or_default();
+                    db.mediaid_lastaccess.insert(mxc, &now)?;
+                }
+
+                services().globals.bump_database_version(14)?;
+
+                warn!("Migration: 13 -> 14 finished");
+            }
+
+            if services().globals.database_version()? < 15 {
+                // Appservice events are keyed by the count of when they were queued, so that
+                // events in different rooms are sent in order
+                for tree in [&db.servernameevent_data, &db.servercurrentevent_data] {
+                    // The new keys have the same prefix, so they must not be scanned again
+                    let pdu_keys = tree
+                        .scan_prefix(b"+".to_vec())
+                        .filter(|(_, value)| value.is_empty())
+                        .map(|(key, _)| key)
+                        .collect::<Vec<_>>();
+
+                    for key in pdu_keys {
+                        let mut parts = key.splitn(2, |&b| b == 0xff);
+                        let prefix = parts.next().expect("splitn always returns one element");
+                        let pdu_id = match parts.next() {
+                            Some(pdu_id) => pdu_id,
+                            None => continue,
+                        };
+
+                        let mut new_key = prefix.to_vec();
+                        new_key.push(0xff);
+                        new_key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+                        new_key.extend_from_slice(pdu_id);
+
+                        tree.insert(&new_key, &[])?;
+                        tree.remove(&key)?;
+                    }
+                }
+
+                services().globals.bump_database_version(15)?;
+
+                warn!("Migration: 14 -> 15 finished");
+            }
+
+            if services().globals.database_version()? < 16 {
+                // Relations stored before they were indexed by rel_type and event type are only
+                // in fromto_relation, so the index is rebuilt from the relating events
+                for (key, _) in db.fromto_relation.iter() {
+                    if key.len() != 16 {
+                        continue;
+                    }
+                    let from = utils::u64_from_bytes(&key[..8]).map_err(|_| {
+                        Error::bad_database("Invalid shorteventid in fromto_relation.")
+                    })?;
+                    let to = utils::u64_from_bytes(&key[8..]).map_err(|_| {
+                        Error::bad_database("Invalid shorteventid in fromto_relation.")
+                    })?;
+
+                    let pdu = match services()
+                        .rooms
+                        .short
+                        .get_eventid_from_short(from)
+                        .and_then(|event_id| services().rooms.timeline.get_pdu(&event_id))
+                    {
+                        Ok(Some(pdu)) => pdu,
+                        _ => continue,
+                    };
+
+                    // Replies are relations without a rel_type and aren't in the index. An event
+                    // can also reply to another event than the one it relates to.
+                    let content = match serde_json::from_str::<serde_json::Value>(pdu.content.get())
+                    {
+                        Ok(content) => content,
+                        Err(_) => continue,
+                    };
+                    let relates_to = content.pointer("/m.relates_to/event_id");
+                    let rel_type = content.pointer("/m.relates_to/rel_type");
+
+                    if let (Some(relates_to), Some(rel_type)) = (
+                        relates_to.and_then(|event_id| event_id.as_str()),
+                        rel_type.and_then(|rel_type| rel_type.as_str()),
+                    ) {
+                        if services().rooms.short.get_eventid_from_short(to)?.as_str() == relates_to
+                        {
+                            service::rooms::pdu_metadata::Data::add_typed_relation(
+                                db,
+                                from,
+                                to,
+                                rel_type,
+                                &pdu.kind.to_string(),
+                            )?;
+                        }
+                    }
+                }
+
+                services().globals.bump_database_version(16)?;
+
+                warn!("Migration: 15 -> 16 finished");
+            }
+
+            if services().globals.database_version()? < 17 {
+                // Guests and appservice users used to be created without a password, which
+                // marks accounts as deactivated. Deactivation removes all devices, so they are
+                // the accounts that still have some. Guests from before they were marked as
+                // such can only be told apart from appservice users by the namespaces.
+                let appservices = services().appservice.all()?;
+                for user_id in services().users.iter().filter_map(|r| r.ok()) {
+                    if user_id.server_name() != services().globals.server_name()
+                        || !services().users.is_deactivated(&user_id)?
+                    {
+                        continue;
+                    }
+
+                    if services().users.all_device_ids(&user_id).next().is_none() {
+                        continue;
+                    }
+
+                    services().users.set_password(
+                        &user_id,
+                        Some(&utils::random_string(service::users::GUEST_PASSWORD_LENGTH)),
+                    )?;
+
+                    let is_appservice_user = appservices.iter().any(|(_, registration)| {
+                        service::appservice::user_matches_namespace(registration, &user_id)
+                    });
+                    if !is_appservice_user {
+                        service::users::Data::set_guest(db, &user_id)?;
+                    }
+                }
+
+                services().globals.bump_database_version(17)?;
+
+                warn!("Migration: 16 -> 17 finished");
+            }
+
             assert_eq!(
                 services().globals.database_version().unwrap(),
                 latest_database_version
@@ -990,6 +1194,16 @@ impl KeyValueDatabase {
 
         services().sending.start_handler();
 
+        services().pusher.start_digest_handler();
+
+        services().pusher.start_history_flusher();
+
+        services().rooms.edus.typing.start_timeout_handler();
+
+        services().rooms.edus.presence.start_idle_handler();
+
+        services().media.start_retention_handler();
+
         Self::start_cleanup_task().await;
 
         Ok(())
@@ -1048,6 +1262,32 @@ impl KeyValueDatabase {
             }
         });
     }
+
+    /// Loads a fresh database for tests that need `services()`. As `services()` is global, all
+    /// tests of a run share the same database.
+    #[cfg(all(test, feature = "sqlite"))]
+    pub(crate) async fn load_for_tests() {
+        lazy_static::lazy_static! {
+            static ref LOADED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::new();
+        }
+
+        LOADED
+            .get_or_init(|| async {
+                let database_path =
+                    std::env::temp_dir().join(format!("conduit-tests-{}", std::process::id()));
+                let _ = remove_dir_all(&database_path);
+
+                let config = serde_json::from_value::<Config>(serde_json::json!({
+                    "server_name": "example.org",
+                    "database_backend": "sqlite",
+                    "database_path": database_path,
+                }))
+                .unwrap();
+
+                Self::load_or_create(config).await.unwrap();
+            })
+            .await;
+    }
 }
 
 /// Sets the emergency password and push rules for the @conduit account in case emergency password is set
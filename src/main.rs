@@ -17,7 +17,7 @@ use axum::{
     Router,
 };
 use axum_server::{bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle};
-use conduit::api::{client_server, server_server};
+use conduit::api::{client_server, ruma_wrapper, server_server};
 use figment::{
     providers::{Env, Format, Toml},
     Figment,
@@ -160,6 +160,7 @@ async fn run_server() -> io::Result<()> {
             }),
         )
         .layer(axum::middleware::from_fn(unrecognized_method))
+        .layer(axum::middleware::from_fn(ruma_wrapper::rate_limit))
         .layer(
             CorsLayer::new()
                 .allow_origin(cors::Any)
@@ -186,7 +187,9 @@ async fn run_server() -> io::Result<()> {
                 .expect("failed to convert max request size"),
         ));
 
-    let app = routes().layer(middlewares).into_make_service();
+    let app = routes()
+        .layer(middlewares)
+        .into_make_service_with_connect_info::<SocketAddr>();
     let handle = ServerHandle::new();
 
     tokio::spawn(shutdown_signal(handle.clone()));
@@ -252,8 +255,15 @@ fn routes() -> Router {
         .ruma_route(client_server::get_supported_versions_route)
         .ruma_route(client_server::get_register_available_route)
         .ruma_route(client_server::register_route)
+        .ruma_route(client_server::check_registration_token_validity_route)
         .ruma_route(client_server::get_login_types_route)
         .ruma_route(client_server::login_route)
+        .ruma_route(client_server::sso_login_route)
+        .ruma_route(client_server::sso_login_with_provider_route)
+        .route(
+            "/_conduit/client/oidc/callback",
+            get(client_server::oidc_callback_route),
+        )
         .ruma_route(client_server::whoami_route)
         .ruma_route(client_server::logout_route)
         .ruma_route(client_server::logout_all_route)
@@ -315,6 +325,7 @@ fn routes() -> Router {
         .ruma_route(client_server::join_room_by_id_route)
         .ruma_route(client_server::join_room_by_id_or_alias_route)
         .ruma_route(client_server::joined_members_route)
+        .ruma_route(client_server::knock_room_route)
         .ruma_route(client_server::leave_room_route)
         .ruma_route(client_server::forget_room_route)
         .ruma_route(client_server::joined_rooms_route)
@@ -357,12 +368,14 @@ fn routes() -> Router {
                 .put(client_server::send_state_event_for_empty_key_route),
         )
         .ruma_route(client_server::sync_events_route)
+        .ruma_route(client_server::sync_events_v4_route)
         .ruma_route(client_server::get_context_route)
         .ruma_route(client_server::get_message_events_route)
         .ruma_route(client_server::search_events_route)
         .ruma_route(client_server::turn_server_route)
         .ruma_route(client_server::send_event_to_device_route)
         .ruma_route(client_server::get_media_config_route)
+        .ruma_route(client_server::get_media_preview_route)
         .ruma_route(client_server::create_content_route)
         .ruma_route(client_server::get_content_route)
         .ruma_route(client_server::get_content_as_filename_route)
@@ -383,6 +396,10 @@ fn routes() -> Router {
         // .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::upgrade_room_route)
         .ruma_route(client_server::get_threads_route)
+        .ruma_route(client_server::get_hierarchy_route)
+        .ruma_route(client_server::get_relating_events_route)
+        .ruma_route(client_server::get_relating_events_with_rel_type_route)
+        .ruma_route(client_server::get_relating_events_with_rel_type_and_event_type_route)
         .ruma_route(server_server::get_server_version_route)
         .route(
             "/_matrix/key/v2/server",
@@ -404,6 +421,8 @@ fn routes() -> Router {
         .ruma_route(server_server::create_join_event_template_route)
         .ruma_route(server_server::create_join_event_v1_route)
         .ruma_route(server_server::create_join_event_v2_route)
+        .ruma_route(server_server::create_knock_event_template_route)
+        .ruma_route(server_server::create_knock_event_route)
         .ruma_route(server_server::create_invite_route)
         .ruma_route(server_server::get_devices_route)
         .ruma_route(server_server::get_room_information_route)
@@ -3,14 +3,14 @@ mod data;
 pub use data::Data;
 
 use ruma::{
-    events::{AnyEphemeralRoomEvent, RoomAccountDataEventType},
+    events::{AnyEphemeralRoomEvent, GlobalAccountDataEventType, RoomAccountDataEventType},
     serde::Raw,
     RoomId, UserId,
 };
 
 use std::collections::HashMap;
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -26,7 +26,16 @@ impl Service {
         event_type: RoomAccountDataEventType,
         data: &serde_json::Value,
     ) -> Result<()> {
-        self.db.update(room_id, user_id, event_type, data)
+        let is_push_rules = room_id.is_none()
+            && event_type.to_string() == GlobalAccountDataEventType::PushRules.to_string();
+
+        self.db.update(room_id, user_id, event_type, data)?;
+
+        if is_push_rules {
+            services().pusher.invalidate_push_disabled(user_id);
+        }
+
+        Ok(())
     }
 
     /// Searches the account data for a specific kind.
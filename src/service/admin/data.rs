@@ -0,0 +1,10 @@
+use crate::Result;
+use ruma::{OwnedRoomId, RoomId, UserId};
+
+pub trait Data: Send + Sync {
+    /// Returns the room server notices are sent to the user in.
+    fn server_notice_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>>;
+
+    /// Makes server notices to the user go to this room from now on.
+    fn set_server_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+}
@@ -1,13 +1,17 @@
+mod data;
+pub use data::Data;
+
 use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
 use regex::Regex;
 use ruma::{
+    api::client::error::ErrorKind,
     events::{
         room::{
             canonical_alias::RoomCanonicalAliasEventContent,
@@ -19,23 +23,32 @@ use ruma::{
             message::RoomMessageEventContent,
             name::RoomNameEventContent,
             power_levels::RoomPowerLevelsEventContent,
+            redaction::RoomRedactionEventContent,
             topic::RoomTopicEventContent,
         },
-        TimelineEventType,
+        tag::{TagEvent, TagEventContent, TagInfo, TagName},
+        RoomAccountDataEventType, StateEventType, TimelineEventType,
     },
-    EventId, OwnedRoomAliasId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    DeviceId, EventId, MilliSecondsSinceUnixEpoch, OwnedRoomAliasId, OwnedRoomId, RoomAliasId,
+    RoomId, RoomVersionId, ServerName, UserId,
 };
-use serde_json::value::to_raw_value;
+use serde_json::value::{to_raw_value, RawValue};
 use tokio::sync::{mpsc, Mutex, MutexGuard};
 
 use crate::{
-    api::client_server::{leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
+    api::client_server::AUTO_GEN_PASSWORD_LENGTH,
     services,
     utils::{self, HtmlEscape},
     Error, PduEvent, Result,
 };
 
-use super::pdu::PduBuilder;
+use super::{pdu::PduBuilder, pusher::PusherDump};
+
+/// How many redactions `redact-user` sends at once
+const REDACTION_BATCH_SIZE: usize = 50;
+
+/// How long `redact-user` waits between batches of redactions
+const REDACTION_BATCH_DELAY: Duration = Duration::from_secs(1);
 
 #[cfg_attr(test, derive(Debug))]
 #[derive(Parser)]
@@ -109,6 +122,26 @@ enum AdminCommand {
         force: bool,
     },
 
+    /// List the devices of a local user, with when and from where they were last used
+    ListDevices {
+        /// The user whose devices should be listed
+        user_id: Box<UserId>,
+    },
+
+    /// Log out a device of a local user, invalidating its access token
+    DeleteDevice {
+        /// The user who owns the device
+        user_id: Box<UserId>,
+        /// The device to log out
+        device_id: Box<DeviceId>,
+    },
+
+    /// Log out all devices of a local user, invalidating their access tokens
+    DeleteAllDevices {
+        /// The user whose devices should be logged out
+        user_id: Box<UserId>,
+    },
+
     /// Get the auth_chain of a PDU
     GetAuthChain {
         /// An event ID (the $ character followed by the base64 reference hash)
@@ -153,6 +186,88 @@ enum AdminCommand {
         password: Option<String>,
     },
 
+    /// Create a token that allows registering while registration requires one
+    CreateRegistrationToken {
+        /// The token, if unspecified one is generated
+        token: Option<String>,
+        /// How many accounts may register with the token, unlimited if unspecified
+        #[arg(short, long)]
+        uses_allowed: Option<u64>,
+        /// Number of seconds after which the token expires, never if unspecified
+        #[arg(short, long)]
+        expires_in: Option<u64>,
+    },
+
+    /// List all registration tokens and how often they were used
+    ListRegistrationTokens,
+
+    /// Revoke a registration token
+    DeleteRegistrationToken { token: String },
+
+    #[command(verbatim_doc_comment)]
+    /// Send a server notice to a local user
+    ///
+    /// The notice is sent in the user's server notices room, which is created
+    /// if the user doesn't have one yet.
+    ///
+    /// [commandbody]
+    /// # The text of the notice
+    SendServerNotice {
+        /// The user to send the notice to
+        user_id: Box<UserId>,
+    },
+
+    /// Redact all events a user sent in a room, or in all rooms this server is in
+    ///
+    /// Only rooms in which the server user is allowed to redact events are
+    /// touched.
+    RedactUser {
+        /// The user whose events should be redacted
+        user_id: Box<UserId>,
+        /// The room to redact the events in, or `all`
+        room: String,
+        /// The reason shown in the redactions
+        reason: Vec<String>,
+    },
+
+    /// List push notifications that are in flight or queued
+    ListPendingPushes {
+        /// Maximum number of notifications to list
+        #[arg(short, long, default_value_t = 100)]
+        limit: usize,
+    },
+
+    /// List servers that recently sent soft-failed events, and whether they are backed off
+    ListSoftFailureRates,
+
+    /// Dump the pushers of all users as JSON, e.g. to move them to another database backend
+    ExportPushers,
+
+    #[command(verbatim_doc_comment)]
+    /// Import pushers dumped by `export-pushers`
+    ///
+    /// Pushers with the same pushkey as an imported one are replaced.
+    ///
+    /// [commandbody]
+    /// # ```
+    /// # json content here
+    /// # ```
+    ImportPushers,
+
+    /// Queue a push notification that failed to be delivered again, by its notification id
+    RequeueNotification { notification_id: String },
+
+    /// Probe the health of a push gateway, reusing a recent response of the same host
+    ProbePushGateway { url: String },
+
+    /// Delete all media that wasn't uploaded or downloaded within the given number of seconds
+    ///
+    /// Unlike the automatic cleanup, this also deletes media uploaded to this server.
+    PurgeMediaOlderThan { seconds: u64 },
+
+    /// Delete all media a local user uploaded
+    PurgeMediaFromUser { user_id: Box<UserId> },
+
     /// Disables incoming federation handling for a room.
     DisableRoom { room_id: Box<RoomId> },
     /// Enables incoming federation handling for a room again.
@@ -166,16 +281,22 @@ pub enum AdminRoomEvent {
 }
 
 pub struct Service {
+    pub db: &'static dyn Data,
     pub sender: mpsc::UnboundedSender<AdminRoomEvent>,
     receiver: Mutex<mpsc::UnboundedReceiver<AdminRoomEvent>>,
+    /// Held while looking up or creating a server notices room, so concurrent notices to the
+    /// same user don't create more than one
+    server_notice_lock: Mutex<()>,
 }
 
 impl Service {
-    pub fn build() -> Arc<Self> {
+    pub fn build(db: &'static dyn Data) -> Arc<Self> {
         let (sender, receiver) = mpsc::unbounded_channel();
         Arc::new(Self {
+            db,
             sender,
             receiver: Mutex::new(receiver),
+            server_notice_lock: Mutex::new(()),
         })
     }
 
@@ -413,6 +534,63 @@ impl Service {
                 }
                 Err(e) => RoomMessageEventContent::text_plain(e.to_string()),
             },
+            AdminCommand::ListDevices { user_id } => {
+                if !services().users.exists(&user_id)? {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The specified user does not exist!",
+                    ));
+                }
+
+                let now = MilliSecondsSinceUnixEpoch::now();
+                let devices: Vec<_> = services()
+                    .users
+                    .all_devices_metadata(&user_id)
+                    .collect::<Result<_>>()?;
+
+                let mut msg = format!("{user_id} has {} device(s):\n", devices.len());
+                for device in devices {
+                    msg += &format!(
+                        "{}\t{}\t{}\t{}\n",
+                        device.device_id,
+                        device.display_name.as_deref().unwrap_or("-"),
+                        device.last_seen_ip.as_deref().unwrap_or("-"),
+                        device
+                            .last_seen_ts
+                            .map_or_else(|| "-".to_owned(), |ts| time_ago(ts, now)),
+                    );
+                }
+                RoomMessageEventContent::text_plain(msg)
+            }
+            AdminCommand::DeleteDevice { user_id, device_id } => {
+                if services()
+                    .users
+                    .get_device_metadata(&user_id, &device_id)?
+                    .is_none()
+                {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The specified device does not exist!",
+                    ));
+                }
+
+                services().users.remove_device(&user_id, &device_id)?;
+                RoomMessageEventContent::text_plain(format!(
+                    "Logged out device {device_id} of {user_id}."
+                ))
+            }
+            AdminCommand::DeleteAllDevices { user_id } => {
+                let device_ids: Vec<_> = services()
+                    .users
+                    .all_device_ids(&user_id)
+                    .collect::<Result<_>>()?;
+
+                for device_id in &device_ids {
+                    services().users.remove_device(&user_id, device_id)?;
+                }
+                RoomMessageEventContent::text_plain(format!(
+                    "Logged out {} device(s) of {user_id}.",
+                    device_ids.len()
+                ))
+            }
             AdminCommand::IncomingFederation => {
                 let map = services()
                     .globals
@@ -644,6 +822,253 @@ impl Service {
                     "Created user with user_id: {user_id} and password: {password}"
                 ))
             }
+            AdminCommand::ListPendingPushes { limit } => {
+                let pending = services().pusher.notification_snapshot(limit)?;
+                let mut msg: String =
+                    format!("Found {} pending push notification(s):\n", pending.len());
+
+                for notification in pending {
+                    msg += &format!(
+                        "{} {} {}: {:?}, {} failed attempt(s)\n",
+                        notification
+                            .event_id
+                            .as_ref()
+                            .map_or("<unknown event>", |event_id| event_id.as_str()),
+                        notification.user,
+                        notification.pushkey,
+                        notification.state,
+                        notification.attempts
+                    );
+                }
+                msg += &format!(
+                    "{} stale notification(s) were dropped since startup.\n",
+                    services()
+                        .pusher
+                        .stale_notifications
+                        .load(std::sync::atomic::Ordering::Relaxed)
+                );
+                for (gateway, deliveries) in
+                    services().pusher.gateway_deliveries.lock().unwrap().iter()
+                {
+                    msg += &format!("{gateway} accepted {deliveries} notification(s).\n");
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::ListSoftFailureRates => {
+                let threshold = services().globals.soft_fail_backoff_threshold();
+                let rates = services().rooms.pdu_metadata.soft_failure_rates()?;
+
+                let mut msg = format!(
+                    "{} server(s) sent soft-failed events in the last hour (backoff from {}):\n",
+                    rates.len(),
+                    threshold
+                );
+                for (origin, count) in rates {
+                    msg += &format!(
+                        "{}: {}{}\n",
+                        origin,
+                        count,
+                        if count >= threshold {
+                            " (backed off)"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::ExportPushers => {
+                let pushers = services().pusher.export_pushers()?;
+                let json = serde_json::to_string_pretty(&pushers).expect("pushers are valid json");
+
+                RoomMessageEventContent::text_html(
+                    format!(
+                        "Exported {} pusher(s):\n```json\n{}\n```",
+                        pushers.len(),
+                        json
+                    ),
+                    format!(
+                        "<p>Exported {} pusher(s):</p>\n<pre><code class=\"language-json\">{}\n</code></pre>\n",
+                        pushers.len(),
+                        HtmlEscape(&json)
+                    ),
+                )
+            }
+            AdminCommand::ImportPushers => {
+                if body.len() > 2 && body[0].trim() == "```" && body.last().unwrap().trim() == "```"
+                {
+                    let string = body[1..body.len() - 1].join("\n");
+                    match serde_json::from_str::<Vec<PusherDump>>(&string) {
+                        Ok(pushers) => {
+                            let imported = services().pusher.import_pushers(pushers)?;
+                            RoomMessageEventContent::text_plain(format!(
+                                "Imported {imported} pusher(s)."
+                            ))
+                        }
+                        Err(e) => RoomMessageEventContent::text_plain(format!(
+                            "Invalid pushers in command body: {e}"
+                        )),
+                    }
+                } else {
+                    RoomMessageEventContent::text_plain(
+                        "Expected code block in command body. Add --help for details.",
+                    )
+                }
+            }
+            AdminCommand::RequeueNotification { notification_id } => {
+                match services().pusher.requeue_notification(&notification_id) {
+                    Ok(failed) => RoomMessageEventContent::text_plain(format!(
+                        "Requeued notification {} for pusher {} of {}.",
+                        notification_id, failed.pushkey, failed.user_id
+                    )),
+                    Err(e) => RoomMessageEventContent::text_plain(format!(
+                        "Could not requeue notification {notification_id}: {e}"
+                    )),
+                }
+            }
+            AdminCommand::ProbePushGateway { url } => {
+                match services().pusher.probe_gateway(&url).await {
+                    Ok(probe) => RoomMessageEventContent::text_plain(format!(
+                        "Push gateway {} is healthy ({}): {}",
+                        url, probe.status, probe.body
+                    )),
+                    Err(e) => RoomMessageEventContent::text_plain(format!(
+                        "Push gateway {url} is unhealthy: {e}"
+                    )),
+                }
+            }
+            AdminCommand::PurgeMediaOlderThan { seconds } => {
+                let (count, freed) = services()
+                    .media
+                    .purge_media_older_than(Duration::from_secs(seconds))
+                    .await?;
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Deleted {count} media files, reclaimed {freed} bytes."
+                ))
+            }
+            AdminCommand::PurgeMediaFromUser { user_id } => {
+                let (count, freed) = services().media.purge_media_from_user(&user_id).await?;
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Deleted {count} media files of {user_id}, reclaimed {freed} bytes."
+                ))
+            }
+            AdminCommand::CreateRegistrationToken {
+                token,
+                uses_allowed,
+                expires_in,
+            } => {
+                let expiry_time = expires_in.map(|seconds| {
+                    utils::millis_since_unix_epoch().saturating_add(seconds.saturating_mul(1000))
+                });
+
+                match services()
+                    .uiaa
+                    .create_registration_token(token, uses_allowed, expiry_time)
+                {
+                    Ok(token) => RoomMessageEventContent::text_plain(format!(
+                        "Created registration token {token}."
+                    )),
+                    Err(e) => RoomMessageEventContent::text_plain(e.to_string()),
+                }
+            }
+            AdminCommand::ListRegistrationTokens => {
+                let now = utils::millis_since_unix_epoch();
+                let mut msg = String::from("Registration tokens:\n");
+                for entry in services().uiaa.registration_tokens() {
+                    let (token, info) = entry?;
+                    let uses_allowed = info
+                        .uses_allowed
+                        .map_or_else(|| "unlimited".to_owned(), |uses| uses.to_string());
+                    let state = if info.is_valid(now) {
+                        "valid"
+                    } else {
+                        "expired or used up"
+                    };
+                    msg += &format!("{token}: used {}/{uses_allowed}, {state}\n", info.completed);
+                }
+
+                RoomMessageEventContent::text_plain(msg)
+            }
+            AdminCommand::DeleteRegistrationToken { token } => {
+                if services().uiaa.delete_registration_token(&token)? {
+                    RoomMessageEventContent::text_plain("Registration token deleted.")
+                } else {
+                    RoomMessageEventContent::text_plain("Registration token doesn't exist.")
+                }
+            }
+            AdminCommand::SendServerNotice { user_id } => {
+                if body.is_empty() {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "Expected the notice in the command body. Add --help for details.",
+                    ));
+                }
+
+                let notice = RoomMessageEventContent::text_plain(body.join("\n"));
+                match self.send_server_notice(&user_id, notice).await {
+                    Ok(()) => RoomMessageEventContent::text_plain(format!(
+                        "Sent server notice to {user_id}."
+                    )),
+                    Err(e) => RoomMessageEventContent::text_plain(format!(
+                        "Failed to send server notice to {user_id}: {e}"
+                    )),
+                }
+            }
+            AdminCommand::RedactUser {
+                user_id,
+                room,
+                reason,
+            } => {
+                let room_ids = if room == "all" {
+                    services()
+                        .rooms
+                        .state_cache
+                        .server_rooms(services().globals.server_name())
+                        .filter_map(|r| r.ok())
+                        .collect()
+                } else {
+                    match RoomId::parse(&room) {
+                        Ok(room_id) => vec![room_id],
+                        Err(e) => {
+                            return Ok(RoomMessageEventContent::text_plain(format!(
+                                "Expected a room ID or \"all\": {e}"
+                            )))
+                        }
+                    }
+                };
+                let reason = (!reason.is_empty()).then(|| reason.join(" "));
+
+                let mut redacted = 0;
+                let mut skipped_rooms = Vec::new();
+                for room_id in room_ids {
+                    // The user's events stay in the room after they left
+                    if !services()
+                        .rooms
+                        .state_cache
+                        .once_joined(&user_id, &room_id)?
+                    {
+                        continue;
+                    }
+
+                    match self
+                        .redact_user_events(&user_id, &room_id, reason.as_deref())
+                        .await?
+                    {
+                        Some(count) => redacted += count,
+                        None => skipped_rooms.push(room_id.to_string()),
+                    }
+                }
+
+                let mut msg = format!("Redacted {redacted} events of {user_id}.");
+                if !skipped_rooms.is_empty() {
+                    msg += &format!(
+                        "\nSkipped these rooms, the server user can't redact events in them:\n{}",
+                        skipped_rooms.join("\n")
+                    );
+                }
+                RoomMessageEventContent::text_plain(msg)
+            }
             AdminCommand::DisableRoom { room_id } => {
                 services().rooms.metadata.disable_room(&room_id, true)?;
                 RoomMessageEventContent::text_plain("Room disabled.")
@@ -662,10 +1087,10 @@ impl Service {
                         "Making {user_id} leave all rooms before deactivation..."
                     ));
 
-                    services().users.deactivate_account(&user_id)?;
-
                     if leave_rooms {
-                        leave_all_rooms(&user_id).await?;
+                        services().users.deactivate_account(&user_id, false).await?;
+                    } else {
+                        services().users.lock_account(&user_id)?;
                     }
 
                     RoomMessageEventContent::text_plain(format!(
@@ -712,14 +1137,14 @@ impl Service {
                     }
 
                     for &user_id in &user_ids {
-                        if services().users.deactivate_account(user_id).is_ok() {
+                        if services().users.lock_account(user_id).is_ok() {
                             deactivation_count += 1
                         }
                     }
 
                     if leave_rooms {
                         for &user_id in &user_ids {
-                            let _ = leave_all_rooms(user_id).await;
+                            let _ = services().users.deactivate_account(user_id, false).await;
                         }
                     }
 
@@ -1138,6 +1563,342 @@ impl Service {
 
         Ok(())
     }
+
+    /// Sends a notice from the server to a local user, for example a reminder to accept new
+    /// terms of service or a quota warning.
+    ///
+    /// Notices are sent in a dedicated room between the server user and the user, which is
+    /// created for the first notice and reused for later ones.
+    pub async fn send_server_notice(
+        &self,
+        user_id: &UserId,
+        content: RoomMessageEventContent,
+    ) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name()
+            || !services().users.exists(user_id)?
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Server notices can only be sent to local users.",
+            ));
+        }
+
+        let room_id = {
+            let _notice_lock = self.server_notice_lock.lock().await;
+
+            let existing_room = self.db.server_notice_room(user_id)?;
+            let is_member = match &existing_room {
+                Some(room_id) => services().rooms.state_cache.is_joined(user_id, room_id)?,
+                None => false,
+            };
+
+            match reusable_notice_room(existing_room, is_member) {
+                Some(room_id) => room_id,
+                None => {
+                    let room_id = self.create_server_notice_room(user_id).await?;
+                    self.db.set_server_notice_room(user_id, &room_id)?;
+                    room_id
+                }
+            }
+        };
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMessage,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: None,
+                redacts: None,
+            },
+            &conduit_user,
+            &room_id,
+            &state_lock,
+        )?;
+
+        Ok(())
+    }
+
+    /// Redacts the events the user sent in the room as the server user, skipping events that
+    /// are already redacted. Returns the number of redacted events, or `None` if the server
+    /// user isn't allowed to redact events in the room.
+    ///
+    /// Redactions are sent in batches with a pause in between, so they don't flood federation.
+    async fn redact_user_events(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        reason: Option<&str>,
+    ) -> Result<Option<usize>> {
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        if !services()
+            .rooms
+            .state_cache
+            .is_joined(&conduit_user, room_id)?
+        {
+            return Ok(None);
+        }
+
+        let power_levels: RoomPowerLevelsEventContent = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|event| {
+                serde_json::from_str(event.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        if !can_redact(&power_levels, &conduit_user) {
+            return Ok(None);
+        }
+
+        // Collect the events first, because the timeline changes while redacting
+        let event_ids: Vec<_> = services()
+            .rooms
+            .timeline
+            .all_pdus(&conduit_user, room_id)?
+            .filter_map(|r| r.ok())
+            .map(|(_, pdu)| pdu)
+            .filter(|pdu| {
+                *pdu.sender == *user_id
+                    && pdu.kind != TimelineEventType::RoomRedaction
+                    && !pdu.is_redacted()
+            })
+            .map(|pdu| pdu.event_id)
+            .collect();
+
+        for (i, batch) in event_ids.chunks(REDACTION_BATCH_SIZE).enumerate() {
+            if i > 0 {
+                tokio::time::sleep(REDACTION_BATCH_DELAY).await;
+            }
+
+            let mutex_state = Arc::clone(
+                services()
+                    .globals
+                    .roomid_mutex_state
+                    .write()
+                    .unwrap()
+                    .entry(room_id.to_owned())
+                    .or_default(),
+            );
+            let state_lock = mutex_state.lock().await;
+
+            for event_id in batch {
+                services().rooms.timeline.build_and_append_pdu(
+                    PduBuilder {
+                        event_type: TimelineEventType::RoomRedaction,
+                        content: to_raw_value(&RoomRedactionEventContent {
+                            reason: reason.map(ToOwned::to_owned),
+                        })
+                        .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: None,
+                        redacts: Some(event_id.clone()),
+                    },
+                    &conduit_user,
+                    room_id,
+                    &state_lock,
+                )?;
+            }
+        }
+
+        Ok(Some(event_ids.len()))
+    }
+
+    /// Returns whether the user receives server notices in this room. Users can't leave it.
+    pub fn is_server_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+        Ok(self.db.server_notice_room(user_id)?.as_deref() == Some(room_id))
+    }
+
+    /// Creates a room only the server user can send to, joins the user to it and tags it with
+    /// `m.server_notice`, so clients show it as the server notices room.
+    async fn create_server_notice_room(&self, user_id: &UserId) -> Result<OwnedRoomId> {
+        let room_id = RoomId::new(services().globals.server_name());
+
+        services().rooms.short.get_or_create_shortroomid(&room_id)?;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        let send_state_event = |event_type: TimelineEventType,
+                                content: Box<RawValue>,
+                                state_key: &str,
+                                sender: &UserId| {
+            services().rooms.timeline.build_and_append_pdu(
+                PduBuilder {
+                    event_type,
+                    content,
+                    unsigned: None,
+                    state_key: Some(state_key.to_owned()),
+                    redacts: None,
+                },
+                sender,
+                &room_id,
+                &state_lock,
+            )
+        };
+
+        let mut create_content = RoomCreateEventContent::new(conduit_user.clone());
+        create_content.federate = false;
+        create_content.room_version = services().globals.default_room_version();
+        send_state_event(
+            TimelineEventType::RoomCreate,
+            to_raw_value(&create_content).expect("event is valid, we just created it"),
+            "",
+            &conduit_user,
+        )?;
+
+        send_state_event(
+            TimelineEventType::RoomMember,
+            to_raw_value(&RoomMemberEventContent::new(MembershipState::Join))
+                .expect("event is valid, we just created it"),
+            conduit_user.as_str(),
+            &conduit_user,
+        )?;
+
+        // Only the server user may send events or invite others
+        let mut users = BTreeMap::new();
+        users.insert(conduit_user.clone(), 100.into());
+        send_state_event(
+            TimelineEventType::RoomPowerLevels,
+            to_raw_value(&RoomPowerLevelsEventContent {
+                users,
+                events_default: 100.into(),
+                invite: 100.into(),
+                ..Default::default()
+            })
+            .expect("event is valid, we just created it"),
+            "",
+            &conduit_user,
+        )?;
+
+        send_state_event(
+            TimelineEventType::RoomJoinRules,
+            to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite))
+                .expect("event is valid, we just created it"),
+            "",
+            &conduit_user,
+        )?;
+
+        send_state_event(
+            TimelineEventType::RoomHistoryVisibility,
+            to_raw_value(&RoomHistoryVisibilityEventContent::new(
+                HistoryVisibility::Shared,
+            ))
+            .expect("event is valid, we just created it"),
+            "",
+            &conduit_user,
+        )?;
+
+        send_state_event(
+            TimelineEventType::RoomGuestAccess,
+            to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden))
+                .expect("event is valid, we just created it"),
+            "",
+            &conduit_user,
+        )?;
+
+        send_state_event(
+            TimelineEventType::RoomName,
+            to_raw_value(&RoomNameEventContent::new(Some(
+                "Server Notices".to_owned(),
+            )))
+            .expect("event is valid, we just created it"),
+            "",
+            &conduit_user,
+        )?;
+
+        // Invite and join the user
+        send_state_event(
+            TimelineEventType::RoomMember,
+            to_raw_value(&RoomMemberEventContent::new(MembershipState::Invite))
+                .expect("event is valid, we just created it"),
+            user_id.as_str(),
+            &conduit_user,
+        )?;
+        send_state_event(
+            TimelineEventType::RoomMember,
+            to_raw_value(&RoomMemberEventContent::new(MembershipState::Join))
+                .expect("event is valid, we just created it"),
+            user_id.as_str(),
+            user_id,
+        )?;
+
+        let mut tags = BTreeMap::new();
+        tags.insert(TagName::ServerNotice, TagInfo::new());
+        services().account_data.update(
+            Some(&room_id),
+            user_id,
+            RoomAccountDataEventType::Tag,
+            &serde_json::to_value(TagEvent {
+                content: TagEventContent { tags },
+            })
+            .expect("to json value always works"),
+        )?;
+
+        Ok(room_id)
+    }
+}
+
+/// Formats how long ago a timestamp was, like `5m ago`.
+fn time_ago(ts: MilliSecondsSinceUnixEpoch, now: MilliSecondsSinceUnixEpoch) -> String {
+    let seconds = u64::from(now.get().saturating_sub(ts.get())) / 1000;
+
+    match seconds {
+        s if s < 60 => format!("{s}s ago"),
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h ago", s / (60 * 60)),
+        s => format!("{}d ago", s / (24 * 60 * 60)),
+    }
+}
+
+/// Whether the user's power level allows redacting other users' events.
+fn can_redact(power_levels: &RoomPowerLevelsEventContent, user_id: &UserId) -> bool {
+    power_levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default)
+        >= power_levels.redact
+}
+
+/// Returns the room to send the next server notice in, if the user still is in the last one.
+fn reusable_notice_room(
+    existing_room: Option<OwnedRoomId>,
+    is_member: bool,
+) -> Option<OwnedRoomId> {
+    existing_room.filter(|_| is_member)
 }
 
 #[cfg(test)]
@@ -1169,4 +1930,48 @@ mod test {
         assert!(error.contains("Commands:"));
         assert!(error.contains("Options:"));
     }
+
+    #[test]
+    fn repeated_notices_reuse_the_room() {
+        let room_id = OwnedRoomId::try_from("!notices:example.org").unwrap();
+
+        assert_eq!(
+            reusable_notice_room(Some(room_id.clone()), true),
+            Some(room_id.clone())
+        );
+        // A new room is only created for the first notice, or if the user was removed from the
+        // last one, for example by deactivating their account
+        assert_eq!(reusable_notice_room(None, false), None);
+        assert_eq!(reusable_notice_room(Some(room_id), false), None);
+    }
+
+    #[test]
+    fn redacting_needs_the_redact_power_level() {
+        let admin = <&UserId>::try_from("@conduit:example.org").unwrap();
+        let moderator = <&UserId>::try_from("@mod:example.org").unwrap();
+        let user = <&UserId>::try_from("@user:example.org").unwrap();
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(admin.to_owned(), 100.into());
+        power_levels.users.insert(moderator.to_owned(), 50.into());
+
+        assert!(can_redact(&power_levels, admin));
+        assert!(can_redact(&power_levels, moderator));
+        assert!(!can_redact(&power_levels, user));
+
+        power_levels.redact = 75.into();
+        assert!(!can_redact(&power_levels, moderator));
+    }
+
+    #[test]
+    fn last_seen_is_shown_relative_to_now() {
+        let ts = |seconds: u32| MilliSecondsSinceUnixEpoch((seconds * 1000).into());
+        let now = ts(10 * 24 * 60 * 60);
+
+        assert_eq!(time_ago(now, now), "0s ago");
+        assert_eq!(time_ago(ts(10 * 24 * 60 * 60 - 90), now), "1m ago");
+        assert_eq!(time_ago(ts(9 * 24 * 60 * 60), now), "1d ago");
+        // Clocks can go backwards
+        assert_eq!(time_ago(ts(10 * 24 * 60 * 60 + 5), now), "0s ago");
+    }
 }
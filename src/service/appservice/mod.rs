@@ -1,17 +1,66 @@
 mod data;
 
+use std::sync::{Arc, Mutex};
+
 pub use data::Data;
 
-use crate::Result;
+use regex::Regex;
+use ruma::{
+    api::{appservice, client::error::ErrorKind},
+    RoomAliasId, RoomId, ServerName, UserId,
+};
+
+use crate::{services, Error, Result};
+
+/// The namespaces an appservice can claim in its registration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Namespace {
+    Users,
+    Aliases,
+    Rooms,
+}
+
+impl Namespace {
+    fn key(self) -> &'static str {
+        match self {
+            Self::Users => "users",
+            Self::Aliases => "aliases",
+            Self::Rooms => "rooms",
+        }
+    }
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Compiled regexes of the users namespaces of all appservices, if they were needed since
+    /// the last registration change
+    pub user_namespaces: Mutex<Option<Arc<Vec<Regex>>>>,
 }
 
 impl Service {
     /// Registers an appservice and returns the ID to the caller
+    ///
+    /// Fails if the appservice exclusively claims a namespace another appservice claims
+    /// exclusively already.
     pub fn register_appservice(&self, yaml: serde_yaml::Value) -> Result<String> {
-        self.db.register_appservice(yaml)
+        let id = yaml.get("id").and_then(|id| id.as_str());
+
+        for (other_id, other) in self.all()? {
+            // Registering an appservice again replaces the old registration
+            if Some(other_id.as_str()) != id
+                && exclusive_namespaces_conflict(&yaml, &other, services().globals.server_name())
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::Exclusive,
+                    "The appservice exclusively claims a namespace another appservice claims exclusively.",
+                ));
+            }
+        }
+
+        let id = self.db.register_appservice(yaml)?;
+        *self.user_namespaces.lock().unwrap() = None;
+
+        Ok(id)
     }
 
     /// Remove an appservice registration
@@ -20,7 +69,10 @@ impl Service {
     ///
     /// * `service_name` - the name you send to register the service previously
     pub fn unregister_appservice(&self, service_name: &str) -> Result<()> {
-        self.db.unregister_appservice(service_name)
+        self.db.unregister_appservice(service_name)?;
+        *self.user_namespaces.lock().unwrap() = None;
+
+        Ok(())
     }
 
     pub fn get_registration(&self, id: &str) -> Result<Option<serde_yaml::Value>> {
@@ -34,4 +86,288 @@ impl Service {
     pub fn all(&self) -> Result<Vec<(String, serde_yaml::Value)>> {
         self.db.all()
     }
+
+    /// Returns the regexes of the users namespaces of all appservices. They are only compiled
+    /// again after an appservice was registered or unregistered.
+    pub fn user_namespaces(&self) -> Result<Arc<Vec<Regex>>> {
+        let mut user_namespaces = self.user_namespaces.lock().unwrap();
+
+        if let Some(regexes) = &*user_namespaces {
+            return Ok(Arc::clone(regexes));
+        }
+
+        let regexes = Arc::new(user_namespace_regexes(&self.all()?));
+        *user_namespaces = Some(Arc::clone(&regexes));
+
+        Ok(regexes)
+    }
+
+    /// Returns whether an appservice other than `appservice_id` claims the user ID, alias or
+    /// room ID exclusively, so only that appservice may use it.
+    pub fn is_exclusive_to_other(
+        &self,
+        namespace: Namespace,
+        id: &str,
+        appservice_id: Option<&str>,
+    ) -> Result<bool> {
+        Ok(self.all()?.iter().any(|(other_id, registration)| {
+            Some(other_id.as_str()) != appservice_id
+                && namespace_regexes(registration, namespace)
+                    .iter()
+                    .any(|(regex, exclusive)| *exclusive && regex.is_match(id))
+        }))
+    }
+
+    /// Asks the appservices whose users namespace contains the user whether it exists, so they
+    /// can create it on demand. Returns whether the user exists afterwards.
+    pub async fn query_user(&self, user_id: &UserId) -> Result<bool> {
+        for (_id, registration) in self.all()? {
+            if user_matches_namespace(&registration, user_id)
+                && services()
+                    .sending
+                    .send_appservice_request(
+                        registration,
+                        appservice::query::query_user_id::v1::Request {
+                            user_id: user_id.to_owned(),
+                        },
+                    )
+                    .await
+                    .is_ok()
+            {
+                return services().users.exists(user_id);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Returns whether the appservice asked to receive ephemeral events like typing notifications,
+/// read receipts and presence in its transactions.
+pub fn receives_ephemeral(registration: &serde_yaml::Value) -> bool {
+    ["receive_ephemeral", "de.sorunome.msc2409.push_ephemeral"]
+        .iter()
+        .any(|key| {
+            registration
+                .get(key)
+                .and_then(|value| value.as_bool())
+                .unwrap_or(false)
+        })
+}
+
+/// Returns whether the user ID is in the users namespace of the appservice.
+pub fn user_matches_namespace(registration: &serde_yaml::Value, user_id: &UserId) -> bool {
+    matches_namespace(registration, Namespace::Users, user_id.as_str())
+}
+
+/// Returns whether the alias is in the aliases namespace of the appservice.
+pub fn alias_matches_namespace(registration: &serde_yaml::Value, alias: &RoomAliasId) -> bool {
+    matches_namespace(registration, Namespace::Aliases, alias.as_str())
+}
+
+/// Returns whether the room ID is in the rooms namespace of the appservice.
+pub fn room_matches_namespace(registration: &serde_yaml::Value, room_id: &RoomId) -> bool {
+    matches_namespace(registration, Namespace::Rooms, room_id.as_str())
+}
+
+/// Returns the regexes of the users namespaces of all the appservices. Invalid regexes are
+/// skipped.
+pub fn user_namespace_regexes(appservices: &[(String, serde_yaml::Value)]) -> Vec<Regex> {
+    appservices
+        .iter()
+        .flat_map(|(_, registration)| namespace_regexes(registration, Namespace::Users))
+        .map(|(regex, _)| regex)
+        .collect()
+}
+
+fn matches_namespace(registration: &serde_yaml::Value, namespace: Namespace, id: &str) -> bool {
+    namespace_regexes(registration, namespace)
+        .iter()
+        .any(|(regex, _)| regex.is_match(id))
+}
+
+/// Returns the regexes of the namespace and whether each is claimed exclusively. Invalid
+/// regexes are skipped.
+fn namespace_regexes(registration: &serde_yaml::Value, namespace: Namespace) -> Vec<(Regex, bool)> {
+    registration
+        .get("namespaces")
+        .and_then(|namespaces| namespaces.get(namespace.key()))
+        .and_then(|entries| entries.as_sequence())
+        .map_or_else(Vec::new, |entries| {
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    let regex = Regex::new(entry.get("regex")?.as_str()?).ok()?;
+                    let exclusive = entry
+                        .get("exclusive")
+                        .and_then(|exclusive| exclusive.as_bool())
+                        .unwrap_or(false);
+                    Some((regex, exclusive))
+                })
+                .collect()
+        })
+}
+
+/// Returns whether two appservices claim the same part of a namespace exclusively. Whether two
+/// regexes can match the same ID can't be decided in general, so this catches identical
+/// regexes and exclusive user regexes that match the sender of the other appservice.
+fn exclusive_namespaces_conflict(
+    a: &serde_yaml::Value,
+    b: &serde_yaml::Value,
+    server_name: &ServerName,
+) -> bool {
+    let exclusive = |registration, namespace| {
+        namespace_regexes(registration, namespace)
+            .into_iter()
+            .filter_map(|(regex, exclusive)| exclusive.then_some(regex))
+            .collect::<Vec<_>>()
+    };
+    let claims_sender = |regexes: &[Regex], other: &serde_yaml::Value| {
+        other
+            .get("sender_localpart")
+            .and_then(|localpart| localpart.as_str())
+            .map_or(false, |localpart| {
+                let sender = format!("@{localpart}:{server_name}");
+                regexes.iter().any(|regex| regex.is_match(&sender))
+            })
+    };
+
+    [Namespace::Users, Namespace::Aliases, Namespace::Rooms]
+        .into_iter()
+        .any(|namespace| {
+            let (a_regexes, b_regexes) = (exclusive(a, namespace), exclusive(b, namespace));
+
+            a_regexes
+                .iter()
+                .any(|a| b_regexes.iter().any(|b| a.as_str() == b.as_str()))
+                || namespace == Namespace::Users
+                    && (claims_sender(&a_regexes, b) || claims_sender(&b_regexes, a))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(yaml: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn telegram() -> serde_yaml::Value {
+        registration(
+            r#"
+id: telegram
+sender_localpart: telegrambot
+namespaces:
+  users:
+    - exclusive: true
+      regex: '@telegram_[0-9]+:example\.org'
+  aliases:
+    - exclusive: true
+      regex: '#telegram_.+:example\.org'
+"#,
+        )
+    }
+
+    fn irc() -> serde_yaml::Value {
+        registration(
+            r#"
+id: irc
+sender_localpart: ircbot
+namespaces:
+  users:
+    - exclusive: true
+      regex: '@irc_.*:example\.org'
+  aliases:
+    - exclusive: false
+      regex: '#irc_.*:example\.org'
+  rooms:
+    - exclusive: false
+      regex: '!irc.*:example\.org'
+"#,
+        )
+    }
+
+    fn server_name() -> &'static ServerName {
+        <&ServerName>::try_from("example.org").unwrap()
+    }
+
+    #[test]
+    fn namespaces_match_bridged_ids() {
+        let telegram = telegram();
+        let irc = irc();
+
+        let user = <&UserId>::try_from("@telegram_123456:example.org").unwrap();
+        assert!(user_matches_namespace(&telegram, user));
+        assert!(!user_matches_namespace(&irc, user));
+        assert!(!user_matches_namespace(
+            &telegram,
+            <&UserId>::try_from("@telegram_abc:example.org").unwrap()
+        ));
+
+        let alias = <&RoomAliasId>::try_from("#irc_#rust:example.org").unwrap();
+        assert!(alias_matches_namespace(&irc, alias));
+        assert!(!alias_matches_namespace(&telegram, alias));
+
+        let room_id = <&RoomId>::try_from("!ircabc:example.org").unwrap();
+        assert!(room_matches_namespace(&irc, room_id));
+        assert!(!room_matches_namespace(&telegram, room_id));
+    }
+
+    #[test]
+    fn only_non_exclusive_namespaces_may_overlap() {
+        let monitoring = registration(
+            r#"
+id: monitoring
+sender_localpart: monitor
+namespaces:
+  users:
+    - exclusive: false
+      regex: '@.*:example\.org'
+"#,
+        );
+        assert!(!exclusive_namespaces_conflict(
+            &telegram(),
+            &irc(),
+            server_name()
+        ));
+        assert!(!exclusive_namespaces_conflict(
+            &monitoring,
+            &telegram(),
+            server_name()
+        ));
+
+        let second_telegram = registration(
+            r#"
+id: telegram2
+sender_localpart: telegrambot2
+namespaces:
+  users:
+    - exclusive: true
+      regex: '@telegram_[0-9]+:example\.org'
+"#,
+        );
+        assert!(exclusive_namespaces_conflict(
+            &telegram(),
+            &second_telegram,
+            server_name()
+        ));
+
+        // Claims the IRC bridge's own user
+        let greedy = registration(
+            r#"
+id: greedy
+sender_localpart: greedy
+namespaces:
+  users:
+    - exclusive: true
+      regex: '@irc.*:example\.org'
+"#,
+        );
+        assert!(exclusive_namespaces_conflict(
+            &greedy,
+            &irc(),
+            server_name()
+        ));
+    }
 }
@@ -6,7 +6,15 @@ use ruma::{
 
 use crate::api::server_server::FedDest;
 
-use crate::{services, Config, Error, Result};
+use crate::{
+    config::{
+        CaptchaConfig, ContentFallback, PushGatewayEnvelope, RateLimitsConfig, RetryJitter,
+        SsoProviderConfig,
+    },
+    services, Config, Error, Result,
+};
+use lettre::{message::Mailbox, AsyncSmtpTransport, Tokio1Executor};
+use regex::RegexSet;
 use ruma::{
     api::{
         client::sync::sync_events,
@@ -45,6 +53,8 @@ pub struct Service {
     keypair: Arc<ruma::signatures::Ed25519KeyPair>,
     dns_resolver: TokioAsyncResolver,
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
+    push_suppressed_senders: RegexSet,
+    smtp: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
     federation_client: reqwest::Client,
     default_client: reqwest::Client,
     pub stable_room_versions: Vec<RoomVersionId>,
@@ -113,6 +123,34 @@ impl Service {
             .as_ref()
             .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()));
 
+        let push_suppressed_senders =
+            RegexSet::new(&config.push_suppressed_senders).map_err(|e| {
+                error!("Invalid push_suppressed_senders pattern: {}", e);
+                Error::bad_config("Invalid regex in push_suppressed_senders.")
+            })?;
+
+        let smtp = if config.push_email {
+            let url = config.smtp_url.as_deref().ok_or(Error::bad_config(
+                "push_email is enabled, but smtp_url is not set.",
+            ))?;
+            let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+                .map_err(|e| {
+                    error!("Invalid smtp_url: {}", e);
+                    Error::bad_config("Invalid smtp_url.")
+                })?
+                .build();
+            let from = config
+                .smtp_from
+                .clone()
+                .unwrap_or_else(|| format!("Conduit <noreply@{}>", config.server_name))
+                .parse::<Mailbox>()
+                .map_err(|_| Error::bad_config("Invalid smtp_from address."))?;
+
+            Some((transport, from))
+        } else {
+            None
+        };
+
         let default_client = reqwest_client_builder(&config)?.build()?;
         let name_override = Arc::clone(&tls_name_override);
         let federation_client = reqwest_client_builder(&config)?
@@ -151,6 +189,8 @@ impl Service {
             federation_client,
             default_client,
             jwt_decoding_key,
+            push_suppressed_senders,
+            smtp,
             stable_room_versions,
             unstable_room_versions,
             bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
@@ -196,6 +236,16 @@ impl Service {
         self.federation_client.clone()
     }
 
+    /// Returns a client for fetching URL previews. It connects to `host` only through `addr`, which
+    /// was already checked, so the host can't resolve to a different address on connect, and
+    /// doesn't follow redirects on its own.
+    pub fn url_preview_client(&self, host: &str, addr: SocketAddr) -> Result<reqwest::Client> {
+        Ok(reqwest_client_builder(&self.config)?
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(host, addr)
+            .build()?)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn next_count(&self) -> Result<u64> {
         self.db.next_count()
@@ -234,6 +284,40 @@ impl Service {
         self.config.allow_registration
     }
 
+    pub fn registration_requires_token(&self) -> bool {
+        self.config.registration_requires_token
+    }
+
+    pub fn allow_guests(&self) -> bool {
+        self.config.allow_guests
+    }
+
+    pub fn rate_limits(&self) -> &RateLimitsConfig {
+        &self.config.rate_limits
+    }
+
+    pub fn captcha(&self) -> Option<&CaptchaConfig> {
+        self.config.captcha.as_ref()
+    }
+
+    pub fn sso_providers(&self) -> &[SsoProviderConfig] {
+        &self.config.sso_providers
+    }
+
+    pub fn sso_client_allowlist(&self) -> &[String] {
+        &self.config.sso_client_allowlist
+    }
+
+    /// Where identity providers send users back to after they logged in.
+    pub fn sso_callback_url(&self) -> String {
+        self.config.sso_callback_url.clone().unwrap_or_else(|| {
+            format!(
+                "https://{}/_conduit/client/oidc/callback",
+                self.server_name()
+            )
+        })
+    }
+
     pub fn allow_encryption(&self) -> bool {
         self.config.allow_encryption
     }
@@ -246,6 +330,54 @@ impl Service {
         self.config.allow_room_creation
     }
 
+    pub fn allow_presence(&self) -> bool {
+        self.config.allow_presence
+    }
+
+    pub fn presence_idle_timeout(&self) -> u64 {
+        self.config.presence_idle_timeout
+    }
+
+    pub fn allow_url_preview(&self) -> bool {
+        self.config.allow_url_preview
+    }
+
+    pub fn url_preview_domain_allowlist(&self) -> &[String] {
+        &self.config.url_preview_domain_allowlist
+    }
+
+    pub fn url_preview_domain_denylist(&self) -> &[String] {
+        &self.config.url_preview_domain_denylist
+    }
+
+    pub fn url_preview_max_size(&self) -> u32 {
+        self.config.url_preview_max_size
+    }
+
+    pub fn url_preview_cache_ttl(&self) -> u64 {
+        self.config.url_preview_cache_ttl
+    }
+
+    pub fn compute_blurhashes(&self) -> bool {
+        self.config.compute_blurhashes
+    }
+
+    pub fn remote_media_retention(&self) -> Option<u64> {
+        self.config.remote_media_retention
+    }
+
+    pub fn media_user_quota(&self) -> Option<u64> {
+        self.config.media_user_quota
+    }
+
+    pub fn remote_media_server_quota(&self) -> Option<u64> {
+        self.config.remote_media_server_quota
+    }
+
+    pub fn media_cleanup_interval(&self) -> u64 {
+        self.config.media_cleanup_interval
+    }
+
     pub fn allow_unstable_room_versions(&self) -> bool {
         self.config.allow_unstable_room_versions
     }
@@ -294,6 +426,141 @@ impl Service {
         &self.config.emergency_password
     }
 
+    pub fn soft_fail_backoff_threshold(&self) -> u64 {
+        self.config.soft_fail_backoff_threshold
+    }
+
+    pub fn max_push_body_length(&self) -> usize {
+        self.config.max_push_body_length
+    }
+
+    pub fn push_digest_interval(&self) -> u64 {
+        self.config.push_digest_interval
+    }
+
+    pub fn push_gateway_envelope(&self) -> PushGatewayEnvelope {
+        self.config.push_gateway_envelope
+    }
+
+    pub fn push_notify_state_event_types(&self) -> &[String] {
+        &self.config.push_notify_state_event_types
+    }
+
+    pub fn push_max_attempts(&self) -> u32 {
+        self.config.push_max_attempts
+    }
+
+    pub fn push_pause_duration(&self) -> u64 {
+        self.config.push_pause_duration
+    }
+
+    pub fn push_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.push_timeout)
+    }
+
+    pub fn push_self_edits(&self) -> bool {
+        self.config.push_self_edits
+    }
+
+    pub fn push_after_leave(&self) -> bool {
+        self.config.push_after_leave
+    }
+
+    pub fn push_device_list_changes(&self) -> bool {
+        self.config.push_device_list_changes
+    }
+
+    pub fn push_redaction_updates(&self) -> bool {
+        self.config.push_redaction_updates
+    }
+
+    pub fn push_participated_threads_high_priority(&self) -> bool {
+        self.config.push_participated_threads_high_priority
+    }
+
+    pub fn push_full_event_gateways(&self) -> &[String] {
+        &self.config.push_full_event_gateways
+    }
+
+    pub fn push_allowed_app_ids(&self) -> &[String] {
+        &self.config.push_allowed_app_ids
+    }
+
+    pub fn push_fallback_gateways(&self) -> &BTreeMap<String, String> {
+        &self.config.push_fallback_gateways
+    }
+
+    pub fn push_max_fanout(&self) -> usize {
+        self.config.push_max_fanout
+    }
+
+    pub fn push_retry_status_codes(&self) -> &[u16] {
+        &self.config.push_retry_status_codes
+    }
+
+    pub fn push_suppressed_senders(&self) -> &RegexSet {
+        &self.push_suppressed_senders
+    }
+
+    /// Returns the SMTP server and sender address for notification emails, if they are enabled.
+    pub fn smtp(&self) -> Option<(&AsyncSmtpTransport<Tokio1Executor>, &Mailbox)> {
+        self.smtp
+            .as_ref()
+            .map(|(transport, from)| (transport, from))
+    }
+
+    pub fn push_mass_mention_cap(&self) -> Option<usize> {
+        self.config.push_mass_mention_cap
+    }
+
+    pub fn push_validate_payloads(&self) -> bool {
+        self.config.push_validate_payloads
+    }
+
+    pub fn push_suppress_appservice_users(&self) -> bool {
+        self.config.push_suppress_appservice_users
+    }
+
+    pub fn push_max_notification_age(&self) -> Option<u64> {
+        self.config.push_max_notification_age
+    }
+
+    pub fn push_first_message_window(&self) -> Option<u64> {
+        self.config.push_first_message_window
+    }
+
+    pub fn push_escalation_threshold(&self) -> Option<u64> {
+        self.config.push_escalation_threshold
+    }
+
+    pub fn push_retry_jitter(&self) -> RetryJitter {
+        self.config.push_retry_jitter
+    }
+
+    pub fn push_content_fallback(&self) -> ContentFallback {
+        self.config.push_content_fallback
+    }
+
+    pub fn push_scheduling_event_types(&self) -> &[String] {
+        &self.config.push_scheduling_event_types
+    }
+
+    pub fn push_quarantine_threshold(&self) -> u32 {
+        self.config.push_quarantine_threshold
+    }
+
+    pub fn push_gateway_probe_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.push_gateway_probe_ttl)
+    }
+
+    pub fn push_room_topic(&self) -> bool {
+        self.config.push_room_topic
+    }
+
+    pub fn notification_history_flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.notification_history_flush_interval.max(1))
+    }
+
     pub fn supported_room_versions(&self) -> Vec<RoomVersionId> {
         let mut room_versions: Vec<RoomVersionId> = vec![];
         room_versions.extend(self.stable_room_versions.clone());
@@ -351,6 +618,10 @@ impl Service {
         // On shutdown
         info!(target: "shutdown-sync", "Received shutdown notification, notifying sync helpers...");
         services().globals.rotate.fire();
+
+        if let Err(e) = services().pusher.flush_notification_history() {
+            error!("Failed to flush notification history on shutdown: {}", e);
+        }
     }
 }
 
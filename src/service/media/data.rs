@@ -1,11 +1,16 @@
+use super::UrlPreview;
 use crate::Result;
+use ruma::UserId;
 
 pub trait Data: Send + Sync {
+    /// Stores the metadata of a file. `crop` is only set for thumbnails and says whether the
+    /// thumbnail was cropped or scaled.
     fn create_file_metadata(
         &self,
         mxc: String,
         width: u32,
         height: u32,
+        crop: Option<bool>,
         content_disposition: Option<&str>,
         content_type: Option<&str>,
     ) -> Result<Vec<u8>>;
@@ -16,5 +21,56 @@ pub trait Data: Send + Sync {
         mxc: String,
         width: u32,
         height: u32,
+        crop: Option<bool>,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)>;
+
+    /// Caches the preview of a URL, replacing older previews.
+    fn set_url_preview(&self, url: &str, preview: &UrlPreview) -> Result<()>;
+
+    /// Returns the cached preview of the URL, even if it's outdated.
+    fn get_url_preview(&self, url: &str) -> Result<Option<UrlPreview>>;
+
+    /// Stores the blurhash of an uploaded image.
+    fn set_blurhash(&self, mxc: &str, blurhash: &str) -> Result<()>;
+
+    /// Returns the blurhash of the image, if one was computed.
+    fn blurhash(&self, mxc: &str) -> Result<Option<String>>;
+
+    /// Counts the size of the media towards the storage used by its owner, a local user or a
+    /// remote server.
+    fn add_media_usage(&self, mxc: &str, owner: &str, size: u64) -> Result<()>;
+
+    /// Returns how many bytes the media of the owner take up.
+    fn media_usage(&self, owner: &str) -> Result<u64>;
+
+    /// Returns the mxc, size and last access of all counted media whose mxc starts with the
+    /// prefix.
+    fn media_sizes<'a>(
+        &'a self,
+        mxc_prefix: &str,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64, u64)>> + 'a>;
+
+    /// Removes the metadata of the file and all its thumbnails, its blurhash, its last access, its
+    /// uploader and its size from the storage used by its owner.
+    /// Returns the metadata keys of the removed files.
+    fn delete_file_metadata(&self, mxc: &str) -> Result<Vec<Vec<u8>>>;
+
+    /// Records when the media was last uploaded or downloaded, in milliseconds since the unix
+    /// epoch.
+    fn set_media_last_access(&self, mxc: &str, timestamp: u64) -> Result<()>;
+
+    /// Returns all media that was last accessed before the timestamp.
+    fn media_accessed_before<'a>(
+        &'a self,
+        timestamp: u64,
+    ) -> Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+    /// Records which local user uploaded the media.
+    fn set_media_uploader(&self, mxc: &str, user_id: &UserId) -> Result<()>;
+
+    /// Returns all media the user uploaded.
+    fn media_from_user<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<String>> + 'a>;
 }
@@ -1,16 +1,73 @@
 mod data;
-use std::io::Cursor;
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 pub use data::Data;
 
-use crate::{services, Result};
-use image::imageops::FilterType;
+use crate::{services, utils, Error, Result};
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+};
+use regex::Regex;
+use reqwest::{
+    header::{CONTENT_TYPE, LOCATION},
+    Url,
+};
+use ruma::{
+    api::client::{error::ErrorKind, media::get_content_thumbnail::v3::Method},
+    UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tracing::{debug, info, warn};
 
 use tokio::{
-    fs::File,
+    fs::{self, File},
     io::{AsyncReadExt, AsyncWriteExt, BufReader},
 };
 
+/// Sizes of the generated thumbnails. Clients get the smallest one that is at least as large as
+/// the size they asked for.
+const THUMBNAIL_SIZES: &[(u32, u32)] = &[(32, 32), (96, 96), (320, 240), (640, 480), (800, 600)];
+
+/// Largest width or height clients can ask a thumbnail for.
+const MAX_REQUESTED_SIZE: u32 = 4096;
+
+/// Largest width or height of images that are thumbnailed. Larger images could take up a lot of
+/// memory when decoded, e.g. small files that decompress into huge images.
+const MAX_IMAGE_SIZE: u32 = 8192;
+
+/// Content types of the images that can be thumbnailed.
+const THUMBNAIL_CONTENT_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif"];
+
+/// Most redirects that are followed when fetching a URL preview.
+const MAX_URL_PREVIEW_REDIRECTS: usize = 5;
+
+const MXC_LENGTH: usize = 32;
+
+/// A cached URL preview. Failed previews are cached too, so the URL isn't fetched again until the
+/// cache expires.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UrlPreview {
+    /// When the URL was fetched, in milliseconds since the unix epoch
+    pub fetched_at: u64,
+    /// The OpenGraph properties of the page, `None` if it couldn't be previewed
+    pub data: Option<BTreeMap<String, JsonValue>>,
+}
+
+/// What a page says about itself in its OpenGraph, Twitter card and other meta tags.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct PageMeta {
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+}
+
 pub struct FileMeta {
     pub content_disposition: Option<String>,
     pub content_type: Option<String>,
@@ -22,18 +79,63 @@ pub struct Service {
 }
 
 impl Service {
-    /// Uploads a file.
+    /// Uploads a file. `sender_user` is the local user who uploaded it, if any.
     pub async fn create(
         &self,
         mxc: String,
+        sender_user: Option<&UserId>,
         content_disposition: Option<&str>,
         content_type: Option<&str>,
         file: &[u8],
     ) -> Result<()> {
+        let size = file.len() as u64;
+
+        // Uploads count towards the quota of the user, cached remote media towards the quota of
+        // its server. Remote servers don't get an error, their oldest media is evicted instead.
+        let owner = match sender_user {
+            Some(sender_user) => {
+                if size > u64::from(services().globals.max_request_size()) {
+                    return Err(Error::BadRequest(ErrorKind::TooLarge, "File is too large."));
+                }
+
+                check_quota(
+                    self.db.media_usage(sender_user.as_str())?,
+                    size,
+                    services().globals.media_user_quota(),
+                )?;
+                Some(sender_user.to_string())
+            }
+            None => match media_server(&mxc) {
+                Some(server) if server != services().globals.server_name().as_str() => {
+                    if let Some(quota) = services().globals.remote_media_server_quota() {
+                        self.evict_remote_media(server, quota.saturating_sub(size))
+                            .await?;
+                    }
+                    Some(server.to_owned())
+                }
+                _ => None,
+            },
+        };
+
+        self.db
+            .set_media_last_access(&mxc, utils::millis_since_unix_epoch())?;
+        if let Some(sender_user) = sender_user {
+            self.db.set_media_uploader(&mxc, sender_user)?;
+        }
+        if let Some(owner) = owner {
+            self.db.add_media_usage(&mxc, &owner, size)?;
+        }
+
+        if services().globals.compute_blurhashes() && is_content_type(content_type, "image/") {
+            if let Some(blurhash) = compute_blurhash(file) {
+                self.db.set_blurhash(&mxc, &blurhash)?;
+            }
+        }
+
         // Width, Height = 0 if it's not a thumbnail
-        let key = self
-            .db
-            .create_file_metadata(mxc, 0, 0, content_disposition, content_type)?;
+        let key =
+            self.db
+                .create_file_metadata(mxc, 0, 0, None, content_disposition, content_type)?;
 
         let path = services().globals.get_media_file(&key);
         let mut f = File::create(path).await?;
@@ -41,6 +143,11 @@ impl Service {
         Ok(())
     }
 
+    /// Returns the blurhash of an uploaded image, if blurhashes were enabled when it was uploaded.
+    pub fn blurhash(&self, mxc: &str) -> Result<Option<String>> {
+        self.db.blurhash(mxc)
+    }
+
     /// Uploads or replaces a file thumbnail.
     #[allow(clippy::too_many_arguments)]
     pub async fn upload_thumbnail(
@@ -50,11 +157,24 @@ impl Service {
         content_type: Option<&str>,
         width: u32,
         height: u32,
+        method: Option<&Method>,
         file: &[u8],
     ) -> Result<()> {
-        let key =
-            self.db
-                .create_file_metadata(mxc, width, height, content_disposition, content_type)?;
+        let crop = self
+            .thumbnail_properties(width, height, method)
+            .map_or(method == Some(&Method::Crop), |(_, _, crop)| crop);
+
+        self.db
+            .set_media_last_access(&mxc, utils::millis_since_unix_epoch())?;
+
+        let key = self.db.create_file_metadata(
+            mxc,
+            width,
+            height,
+            Some(crop),
+            content_disposition,
+            content_type,
+        )?;
 
         let path = services().globals.get_media_file(&key);
         let mut f = File::create(path).await?;
@@ -66,8 +186,11 @@ impl Service {
     /// Downloads a file.
     pub async fn get(&self, mxc: String) -> Result<Option<FileMeta>> {
         if let Ok((content_disposition, content_type, key)) =
-            self.db.search_file_metadata(mxc, 0, 0)
+            self.db.search_file_metadata(mxc.clone(), 0, 0, None)
         {
+            self.db
+                .set_media_last_access(&mxc, utils::millis_since_unix_epoch())?;
+
             let path = services().globals.get_media_file(&key);
             let mut file = Vec::new();
             BufReader::new(File::open(path).await?)
@@ -85,16 +208,24 @@ impl Service {
     }
 
     /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
-    /// the server should send the original file.
-    pub fn thumbnail_properties(&self, width: u32, height: u32) -> Option<(u32, u32, bool)> {
-        match (width, height) {
-            (0..=32, 0..=32) => Some((32, 32, true)),
-            (0..=96, 0..=96) => Some((96, 96, true)),
-            (0..=320, 0..=240) => Some((320, 240, false)),
-            (0..=640, 0..=480) => Some((640, 480, false)),
-            (0..=800, 0..=600) => Some((800, 600, false)),
-            _ => None,
-        }
+    /// the server should send the original file. Without a method, the two smallest sizes are
+    /// cropped and the others scaled.
+    pub fn thumbnail_properties(
+        &self,
+        width: u32,
+        height: u32,
+        method: Option<&Method>,
+    ) -> Option<(u32, u32, bool)> {
+        let &(width, height) = THUMBNAIL_SIZES
+            .iter()
+            .find(|&&(w, h)| width <= w && height <= h)?;
+
+        let crop = match method {
+            Some(method) => *method == Method::Crop,
+            None => width <= 96 && height <= 96,
+        };
+
+        Some((width, height, crop))
     }
 
     /// Downloads a file's thumbnail.
@@ -103,126 +234,948 @@ impl Service {
     ///
     /// - Client requests an image with width=567, height=567
     /// - Server rounds that up to (800, 600), so it doesn't have to save too many thumbnails
-    /// - Server rounds that up again to (958, 600) to fix the aspect ratio (only for width,height>96)
+    /// - Server rounds that up again to (958, 600) to fix the aspect ratio (only when scaling)
     /// - Server creates the thumbnail and sends it to the user
     ///
-    /// For width,height <= 96 the server uses another thumbnailing algorithm which crops the image afterwards.
+    /// When cropping, the server fills the whole thumbnail and crops the image afterwards.
+    /// Thumbnails are PNG images, animated images become a still of their first frame. Files that
+    /// can't be thumbnailed are sent as they are.
     pub async fn get_thumbnail(
         &self,
         mxc: String,
         width: u32,
         height: u32,
+        method: Option<&Method>,
     ) -> Result<Option<FileMeta>> {
-        let (width, height, crop) = self
-            .thumbnail_properties(width, height)
-            .unwrap_or((0, 0, false)); // 0, 0 because that's the original file
+        check_requested_size(width, height)?;
+
+        let (width, height, crop) = match self.thumbnail_properties(width, height, method) {
+            Some(properties) => properties,
+            None => return self.get(mxc).await,
+        };
 
         if let Ok((content_disposition, content_type, key)) =
-            self.db.search_file_metadata(mxc.clone(), width, height)
+            self.db
+                .search_file_metadata(mxc.clone(), width, height, Some(crop))
         {
             // Using saved thumbnail
+            self.db
+                .set_media_last_access(&mxc, utils::millis_since_unix_epoch())?;
+
             let path = services().globals.get_media_file(&key);
             let mut file = Vec::new();
             File::open(path).await?.read_to_end(&mut file).await?;
 
-            Ok(Some(FileMeta {
+            return Ok(Some(FileMeta {
                 content_disposition,
                 content_type,
-                file: file.to_vec(),
-            }))
-        } else if let Ok((content_disposition, content_type, key)) =
-            self.db.search_file_metadata(mxc.clone(), 0, 0)
-        {
-            // Generate a thumbnail
+                file,
+            }));
+        }
+
+        let original = match self.get(mxc.clone()).await? {
+            Some(original) => original,
+            None => return Ok(None),
+        };
+
+        if !can_thumbnail(original.content_type.as_deref()) {
+            return Ok(Some(original));
+        }
+
+        // Images smaller than the thumbnail are sent as they are, just like files that are no
+        // images or are too large to decode safely
+        let thumbnail = match create_thumbnail(&original.file, width, height, crop) {
+            Some(thumbnail) => thumbnail,
+            None => return Ok(Some(original)),
+        };
+
+        // Save thumbnail in database so we don't have to generate it again next time
+        let content_type = Some("image/png".to_owned());
+        let thumbnail_key = self.db.create_file_metadata(
+            mxc,
+            width,
+            height,
+            Some(crop),
+            original.content_disposition.as_deref(),
+            content_type.as_deref(),
+        )?;
+
+        let path = services().globals.get_media_file(&thumbnail_key);
+        let mut f = File::create(path).await?;
+        f.write_all(&thumbnail).await?;
+
+        Ok(Some(FileMeta {
+            content_disposition: original.content_disposition,
+            content_type,
+            file: thumbnail,
+        }))
+    }
+
+    /// Deletes the file and all its thumbnails. Returns the number of bytes that were freed.
+    pub async fn delete(&self, mxc: &str) -> Result<u64> {
+        let mut freed = 0;
+
+        for key in self.db.delete_file_metadata(mxc)? {
             let path = services().globals.get_media_file(&key);
-            let mut file = Vec::new();
-            File::open(path).await?.read_to_end(&mut file).await?;
 
-            if let Ok(image) = image::load_from_memory(&file) {
-                let original_width = image.width();
-                let original_height = image.height();
-                if width > original_width || height > original_height {
-                    return Ok(Some(FileMeta {
-                        content_disposition,
-                        content_type,
-                        file: file.to_vec(),
-                    }));
+            if let Ok(metadata) = fs::metadata(&path).await {
+                freed += metadata.len();
+            }
+
+            if let Err(e) = fs::remove_file(&path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to delete media file of {}: {}", mxc, e);
                 }
+            }
+        }
 
-                let thumbnail = if crop {
-                    image.resize_to_fill(width, height, FilterType::CatmullRom)
-                } else {
-                    let (exact_width, exact_height) = {
-                        // Copied from image::dynimage::resize_dimensions
-                        let ratio = u64::from(original_width) * u64::from(height);
-                        let nratio = u64::from(width) * u64::from(original_height);
-
-                        let use_width = nratio <= ratio;
-                        let intermediate = if use_width {
-                            u64::from(original_height) * u64::from(width)
-                                / u64::from(original_width)
-                        } else {
-                            u64::from(original_width) * u64::from(height)
-                                / u64::from(original_height)
-                        };
-                        if use_width {
-                            if intermediate <= u64::from(::std::u32::MAX) {
-                                (width, intermediate as u32)
-                            } else {
-                                (
-                                    (u64::from(width) * u64::from(::std::u32::MAX) / intermediate)
-                                        as u32,
-                                    ::std::u32::MAX,
-                                )
-                            }
-                        } else if intermediate <= u64::from(::std::u32::MAX) {
-                            (intermediate as u32, height)
-                        } else {
-                            (
-                                ::std::u32::MAX,
-                                (u64::from(height) * u64::from(::std::u32::MAX) / intermediate)
-                                    as u32,
-                            )
-                        }
-                    };
-
-                    image.thumbnail_exact(exact_width, exact_height)
+        Ok(freed)
+    }
+
+    /// Deletes all media that wasn't uploaded or downloaded for the duration, local media
+    /// included. Returns how many files were deleted and how many bytes were freed.
+    pub async fn purge_media_older_than(&self, duration: Duration) -> Result<(usize, u64)> {
+        self.purge_media_accessed_before(duration, true).await
+    }
+
+    /// Deletes all media the local user uploaded. Returns how many files were deleted and how
+    /// many bytes were freed.
+    pub async fn purge_media_from_user(&self, user_id: &UserId) -> Result<(usize, u64)> {
+        let media: Vec<_> = self.db.media_from_user(user_id).collect::<Result<_>>()?;
+
+        let mut freed = 0;
+        for mxc in &media {
+            freed += self.delete(mxc).await?;
+        }
+
+        info!(
+            "Purged {} media files of {}, reclaimed {} bytes",
+            media.len(),
+            user_id,
+            freed
+        );
+
+        Ok((media.len(), freed))
+    }
+
+    async fn purge_media_accessed_before(
+        &self,
+        duration: Duration,
+        include_local: bool,
+    ) -> Result<(usize, u64)> {
+        let cutoff = utils::millis_since_unix_epoch()
+            .saturating_sub(duration.as_millis().try_into().unwrap_or(u64::MAX));
+
+        let media: Vec<_> = self
+            .db
+            .media_accessed_before(cutoff)
+            .filter_map(|r| r.ok())
+            .filter(|mxc| include_local || !is_local_media(mxc))
+            .collect();
+
+        let mut freed = 0;
+        for mxc in &media {
+            freed += self.delete(mxc).await?;
+        }
+
+        info!(
+            "Purged {} media files not accessed for {:?}, reclaimed {} bytes",
+            media.len(),
+            duration,
+            freed
+        );
+
+        Ok((media.len(), freed))
+    }
+
+    /// Deletes the least recently used media of the remote server until its media take up at most
+    /// `target` bytes.
+    async fn evict_remote_media(&self, server: &str, target: u64) -> Result<()> {
+        let usage = self.db.media_usage(server)?;
+        if usage <= target {
+            return Ok(());
+        }
+
+        let media: Vec<_> = self
+            .db
+            .media_sizes(&format!("mxc://{server}/"))
+            .filter_map(|r| r.ok())
+            .collect();
+
+        let evicted = least_recently_used(media, usage, target);
+
+        let mut freed = 0;
+        for mxc in &evicted {
+            freed += self.delete(mxc).await?;
+        }
+
+        info!(
+            "Evicted {} media files of {} to stay within its cache quota, reclaimed {} bytes",
+            evicted.len(),
+            server,
+            freed
+        );
+
+        Ok(())
+    }
+
+    /// Regularly deletes remote media that wasn't accessed within the configured retention time.
+    /// Media uploaded to this server is kept.
+    pub fn start_retention_handler(&self) {
+        let retention = match services().globals.remote_media_retention() {
+            Some(retention) => Duration::from_secs(retention),
+            None => return,
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(
+                services().globals.media_cleanup_interval(),
+            ));
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = services()
+                    .media
+                    .purge_media_accessed_before(retention, false)
+                    .await
+                {
+                    warn!("Failed to purge remote media: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Returns the OpenGraph properties of the page at the URL, with the image of the page
+    /// uploaded as local media. Previews are cached for the configured time, including failed
+    /// ones.
+    pub async fn get_url_preview(&self, url: &str) -> Result<BTreeMap<String, JsonValue>> {
+        if !services().globals.allow_url_preview() {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "URL previews are disabled.",
+            ));
+        }
+
+        let parsed_url = Url::parse(url)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "URL is invalid."))?;
+
+        // The domain lists could have changed since the preview was cached
+        check_url(&parsed_url)?;
+
+        let now = utils::millis_since_unix_epoch();
+        let ttl = services()
+            .globals
+            .url_preview_cache_ttl()
+            .saturating_mul(1000);
+
+        let preview = match self.db.get_url_preview(url)? {
+            Some(preview) if now.saturating_sub(preview.fetched_at) < ttl => preview,
+            _ => {
+                let data = match self.fetch_url_preview(parsed_url).await {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        debug!("Failed to preview {}: {}", url, e);
+                        None
+                    }
                 };
 
-                let mut thumbnail_bytes = Vec::new();
-                thumbnail.write_to(
-                    &mut Cursor::new(&mut thumbnail_bytes),
-                    image::ImageOutputFormat::Png,
-                )?;
+                let preview = UrlPreview {
+                    fetched_at: now,
+                    data,
+                };
+                self.db.set_url_preview(url, &preview)?;
+                preview
+            }
+        };
 
-                // Save thumbnail in database so we don't have to generate it again next time
-                let thumbnail_key = self.db.create_file_metadata(
-                    mxc,
-                    width,
-                    height,
-                    content_disposition.as_deref(),
-                    content_type.as_deref(),
-                )?;
+        preview.data.ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "URL could not be previewed.",
+        ))
+    }
 
-                let path = services().globals.get_media_file(&thumbnail_key);
-                let mut f = File::create(path).await?;
-                f.write_all(&thumbnail_bytes).await?;
+    async fn fetch_url_preview(&self, url: Url) -> Result<BTreeMap<String, JsonValue>> {
+        let (url, content_type, body) = fetch_url(url).await?;
 
-                Ok(Some(FileMeta {
-                    content_disposition,
-                    content_type,
-                    file: thumbnail_bytes.to_vec(),
-                }))
-            } else {
-                // Couldn't parse file to generate thumbnail, send original
-                Ok(Some(FileMeta {
-                    content_disposition,
-                    content_type,
-                    file: file.to_vec(),
-                }))
+        let mut data = BTreeMap::new();
+        let image = if is_content_type(content_type.as_deref(), "image/") {
+            // The URL points to the image itself
+            Some((content_type, body))
+        } else if is_content_type(content_type.as_deref(), "text/html") {
+            let meta = parse_page_meta(&String::from_utf8_lossy(&body));
+
+            if let Some(title) = meta.title {
+                data.insert("og:title".to_owned(), title.into());
+            }
+            if let Some(description) = meta.description {
+                data.insert("og:description".to_owned(), description.into());
+            }
+
+            match meta.image.and_then(|image| url.join(&image).ok()) {
+                Some(image_url) => match fetch_url(image_url).await {
+                    Ok((_, content_type, body))
+                        if is_content_type(content_type.as_deref(), "image/") =>
+                    {
+                        Some((content_type, body))
+                    }
+                    Ok(_) => None,
+                    Err(e) => {
+                        debug!("Failed to fetch preview image of {}: {}", url, e);
+                        None
+                    }
+                },
+                None => None,
             }
         } else {
-            Ok(None)
+            return Err(Error::BadServerResponse(
+                "URL is neither a web page nor an image.",
+            ));
+        };
+
+        if let Some((content_type, file)) = image {
+            // Large images are scaled down, clients won't show them in full size anyway
+            let (content_type, file) = match create_thumbnail(&file, 800, 600, false) {
+                Some(thumbnail) => (Some("image/png".to_owned()), thumbnail),
+                None => (content_type, file),
+            };
+
+            let mxc = format!(
+                "mxc://{}/{}",
+                services().globals.server_name(),
+                utils::random_string(MXC_LENGTH)
+            );
+            self.create(mxc.clone(), None, None, content_type.as_deref(), &file)
+                .await?;
+
+            data.insert("og:image".to_owned(), mxc.into());
+            data.insert("matrix:image:size".to_owned(), file.len().into());
+            if let Some(content_type) = content_type {
+                data.insert("og:image:type".to_owned(), content_type.into());
+            }
         }
+
+        Ok(data)
+    }
+}
+
+/// Downloads the URL, following redirects, and returns the final URL, the content type and the
+/// body. Every host is resolved and checked before connecting, so URLs can't be used to reach
+/// internal services.
+async fn fetch_url(mut url: Url) -> Result<(Url, Option<String>, Vec<u8>)> {
+    let max_size = services().globals.url_preview_max_size() as usize;
+
+    let mut redirects = 0;
+    loop {
+        check_url(&url)?;
+
+        let host = url
+            .host_str()
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "URL has no host.",
+            ))?
+            .to_owned();
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let ips: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => services()
+                .globals
+                .dns_resolver()
+                .lookup_ip(host.as_str())
+                .await
+                .map_err(|_| Error::BadServerResponse("Failed to resolve URL host."))?
+                .iter()
+                .collect(),
+        };
+        let ip = check_ips(&ips)?;
+
+        let mut response = services()
+            .globals
+            .url_preview_client(&host, SocketAddr::new(ip, port))?
+            .get(url.clone())
+            .send()
+            .await?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+            url = redirect_target(&url, location, redirects)?;
+            redirects += 1;
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(Error::BadServerResponse("URL returned an error."));
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        if response
+            .content_length()
+            .map_or(false, |length| length > max_size as u64)
+        {
+            return Err(Error::BadServerResponse("URL content is too large."));
+        }
+
+        // The content length can be missing or wrong
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max_size {
+                return Err(Error::BadServerResponse("URL content is too large."));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        return Ok((url, content_type, body));
+    }
+}
+
+/// Checks that the URL can be previewed, according to its scheme and the configured domain lists.
+fn check_url(url: &Url) -> Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only http and https URLs can be previewed.",
+        ));
+    }
+
+    let host = url.host_str().ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "URL has no host.",
+    ))?;
+
+    if !is_domain_allowed(
+        host,
+        services().globals.url_preview_domain_allowlist(),
+        services().globals.url_preview_domain_denylist(),
+    ) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "URL is not allowed to be previewed.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns whether the host is allowed by the domain lists. Entries also match subdomains, denied
+/// domains win over allowed ones and an empty allowlist allows all domains.
+fn is_domain_allowed(host: &str, allowlist: &[String], denylist: &[String]) -> bool {
+    let matches = |domain: &String| {
+        let domain = domain.to_ascii_lowercase();
+        host.eq_ignore_ascii_case(&domain)
+            || host.to_ascii_lowercase().ends_with(&format!(".{domain}"))
+    };
+
+    !denylist.iter().any(matches) && (allowlist.is_empty() || allowlist.iter().any(matches))
+}
+
+/// Returns the address to connect to, if all addresses the host resolved to are public. Hosts with
+/// any internal address are refused, because the one used on connect could be chosen by them.
+fn check_ips(ips: &[IpAddr]) -> Result<IpAddr> {
+    if ips.iter().any(|&ip| !is_public_ip(ip)) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "URL resolves to an internal address.",
+        ));
+    }
+
+    ips.first()
+        .copied()
+        .ok_or(Error::BadServerResponse("URL host has no address."))
+}
+
+/// Returns whether the address is reachable on the public internet, as opposed to loopback,
+/// private, link-local and other special-purpose addresses.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // "This network", shared address space, protocol assignments, benchmarking and
+                // reserved addresses
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(ip.into());
+            }
+
+            let [a, b, ..] = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, link-local, NAT64 and documentation addresses
+                || (a & 0xfe00) == 0xfc00
+                || (a & 0xffc0) == 0xfe80
+                || (a == 0x64 && b == 0xff9b)
+                || (a == 0x2001 && b == 0x0db8))
+        }
+    }
+}
+
+/// Returns the URL a redirect points to, as long as not too many redirects were followed already.
+fn redirect_target(url: &Url, location: Option<&str>, redirects: usize) -> Result<Url> {
+    if redirects >= MAX_URL_PREVIEW_REDIRECTS {
+        return Err(Error::BadServerResponse("URL redirects too often."));
+    }
+
+    let location = location.ok_or(Error::BadServerResponse(
+        "URL redirects without a location.",
+    ))?;
+
+    url.join(location)
+        .map_err(|_| Error::BadServerResponse("URL redirects to an invalid location."))
+}
+
+/// Returns whether the content type starts with the prefix, e.g. `image/` or `text/html`.
+fn is_content_type(content_type: Option<&str>, prefix: &str) -> bool {
+    content_type.map_or(false, |content_type| {
+        content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with(prefix)
+    })
+}
+
+/// Extracts title, description and image of a web page. OpenGraph tags are preferred, then
+/// Twitter cards and then the plain HTML title and description.
+fn parse_page_meta(html: &str) -> PageMeta {
+    let meta_tag = Regex::new(r"(?is)<meta\s[^>]*>").expect("regex is valid");
+    let attribute_regex =
+        Regex::new(r#"(?is)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("regex is valid");
+    let title_tag = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("regex is valid");
+
+    let mut properties = BTreeMap::new();
+    for tag in meta_tag.find_iter(html) {
+        let mut key = None;
+        let mut content = None;
+
+        for attribute in attribute_regex.captures_iter(tag.as_str()) {
+            let value = attribute
+                .get(2)
+                .or_else(|| attribute.get(3))
+                .map_or("", |value| value.as_str());
+
+            match attribute[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => key = Some(value.to_ascii_lowercase()),
+                "content" => content = Some(decode_entities(value.trim())),
+                _ => {}
+            }
+        }
+
+        if let (Some(key), Some(content)) = (key, content) {
+            if !content.is_empty() {
+                properties.entry(key).or_insert(content);
+            }
+        }
+    }
+
+    let first = |keys: &[&str]| keys.iter().find_map(|key| properties.get(*key).cloned());
+
+    PageMeta {
+        title: first(&["og:title", "twitter:title"]).or_else(|| {
+            title_tag
+                .captures(html)
+                .map(|title| decode_entities(title[1].trim()))
+                .filter(|title| !title.is_empty())
+        }),
+        description: first(&["og:description", "twitter:description", "description"]),
+        image: first(&[
+            "og:image",
+            "og:image:url",
+            "og:image:secure_url",
+            "twitter:image",
+            "twitter:image:src",
+        ]),
+    }
+}
+
+/// Decodes the most common HTML entities.
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Returns the server name of the mxc.
+fn media_server(mxc: &str) -> Option<&str> {
+    mxc.strip_prefix("mxc://")
+        .and_then(|mxc| mxc.split('/').next())
+}
+
+/// Returns whether the media was uploaded to this server.
+fn is_local_media(mxc: &str) -> bool {
+    media_server(mxc) == Some(services().globals.server_name().as_str())
+}
+
+/// Rejects an upload that would make the owner use more storage than the quota allows.
+fn check_quota(usage: u64, size: u64, quota: Option<u64>) -> Result<()> {
+    if quota.map_or(false, |quota| usage.saturating_add(size) > quota) {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Media storage quota exceeded.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the least recently used of the media, given as mxc, size and last access, that have to
+/// be deleted so the others take up at most `target` bytes.
+fn least_recently_used(
+    mut media: Vec<(String, u64, u64)>,
+    mut usage: u64,
+    target: u64,
+) -> Vec<String> {
+    media.sort_by_key(|&(_, _, last_access)| last_access);
+
+    let mut evicted = Vec::new();
+    for (mxc, size, _) in media {
+        if usage <= target {
+            break;
+        }
+
+        usage = usage.saturating_sub(size);
+        evicted.push(mxc);
+    }
+
+    evicted
+}
+
+/// Computes the blurhash of an image, with 4x3 components. Returns None if the file is no image
+/// that can be decoded within the size limits.
+fn compute_blurhash(file: &[u8]) -> Option<String> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIZE);
+    limits.max_image_height = Some(MAX_IMAGE_SIZE);
+
+    let mut reader = Reader::new(Cursor::new(file)).with_guessed_format().ok()?;
+    reader.limits(limits);
+
+    // The hash only describes a blurred image, so a small version is enough and much faster
+    let image = reader.decode().ok()?.thumbnail(100, 100).to_rgba8();
+
+    blurhash::encode(4, 3, image.width(), image.height(), image.as_raw()).ok()
+}
+
+/// Rejects thumbnail requests that are so large that they can't be meant seriously.
+fn check_requested_size(width: u32, height: u32) -> Result<()> {
+    if width > MAX_REQUESTED_SIZE || height > MAX_REQUESTED_SIZE {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Requested thumbnail is too large.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns whether files of this content type can be thumbnailed. Files without content type are
+/// tried anyway.
+fn can_thumbnail(content_type: Option<&str>) -> bool {
+    content_type.map_or(true, |content_type| {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        THUMBNAIL_CONTENT_TYPES.contains(&essence.as_str())
+    })
+}
+
+/// Creates a PNG thumbnail of the image. Returns None if the file is no image that can be decoded
+/// within the size limits, or if the image is smaller than the thumbnail.
+fn create_thumbnail(file: &[u8], width: u32, height: u32, crop: bool) -> Option<Vec<u8>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIZE);
+    limits.max_image_height = Some(MAX_IMAGE_SIZE);
+
+    let mut reader = Reader::new(Cursor::new(file)).with_guessed_format().ok()?;
+    reader.limits(limits);
+    let image = reader.decode().ok()?;
+
+    let original_width = image.width();
+    let original_height = image.height();
+    if width > original_width || height > original_height {
+        return None;
+    }
+
+    let thumbnail = if crop {
+        image.resize_to_fill(width, height, FilterType::CatmullRom)
+    } else {
+        let (exact_width, exact_height) = {
+            // Copied from image::dynimage::resize_dimensions
+            let ratio = u64::from(original_width) * u64::from(height);
+            let nratio = u64::from(width) * u64::from(original_height);
+
+            let use_width = nratio <= ratio;
+            let intermediate = if use_width {
+                u64::from(original_height) * u64::from(width) / u64::from(original_width)
+            } else {
+                u64::from(original_width) * u64::from(height) / u64::from(original_height)
+            };
+            if use_width {
+                if intermediate <= u64::from(::std::u32::MAX) {
+                    (width, intermediate as u32)
+                } else {
+                    (
+                        (u64::from(width) * u64::from(::std::u32::MAX) / intermediate) as u32,
+                        ::std::u32::MAX,
+                    )
+                }
+            } else if intermediate <= u64::from(::std::u32::MAX) {
+                (intermediate as u32, height)
+            } else {
+                (
+                    ::std::u32::MAX,
+                    (u64::from(height) * u64::from(::std::u32::MAX) / intermediate) as u32,
+                )
+            }
+        };
+
+        image.thumbnail_exact(exact_width, exact_height)
+    };
+
+    let mut thumbnail_bytes = Vec::new();
+    thumbnail
+        .write_to(
+            &mut Cursor::new(&mut thumbnail_bytes),
+            image::ImageOutputFormat::Png,
+        )
+        .ok()?;
+
+    Some(thumbnail_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    fn encode(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        });
+
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn dimensions(png: &[u8]) -> (u32, u32) {
+        let image = image::load_from_memory(png).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn crop_and_scale_thumbnails() {
+        for original in [
+            encode(200, 100, ImageOutputFormat::Png),
+            encode(200, 100, ImageOutputFormat::Jpeg(90)),
+        ] {
+            // Cropped thumbnails fill the requested size
+            let cropped = create_thumbnail(&original, 32, 32, true).unwrap();
+            assert_eq!(dimensions(&cropped), (32, 32));
+
+            // Scaled thumbnails keep the aspect ratio
+            let scaled = create_thumbnail(&original, 96, 96, false).unwrap();
+            assert_eq!(dimensions(&scaled), (96, 48));
+
+            // Images smaller than the thumbnail are sent as they are
+            assert!(create_thumbnail(&original, 320, 240, false).is_none());
+        }
+
+        assert!(create_thumbnail(b"no image", 32, 32, true).is_none());
+    }
+
+    #[test]
+    fn oversized_images_and_requests_are_rejected() {
+        // Decoding such images could allocate huge amounts of memory
+        let wide = encode(MAX_IMAGE_SIZE + 1, 1, ImageOutputFormat::Png);
+        assert!(create_thumbnail(&wide, 32, 1, false).is_none());
+
+        assert!(check_requested_size(800, 600).is_ok());
+        assert!(check_requested_size(MAX_REQUESTED_SIZE + 1, 32).is_err());
+        assert!(check_requested_size(32, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn blurhash_round_trips() {
+        let original = encode(200, 100, ImageOutputFormat::Png);
+
+        let hash = compute_blurhash(&original).unwrap();
+        // Size flag, maximum value, DC component and 11 AC components
+        assert_eq!(hash.len(), 1 + 1 + 4 + 11 * 2);
+
+        let decoded = blurhash::decode(&hash, 32, 16, 1.0).unwrap();
+        assert_eq!(decoded.len(), 32 * 16 * 4);
+
+        assert!(compute_blurhash(b"no image").is_none());
+    }
+
+    #[test]
+    fn quota_accounting() {
+        let quota = Some(100);
+        let mut usage = 0;
+
+        for (size, accepted) in [(60, true), (50, false), (40, true), (1, false)] {
+            assert_eq!(check_quota(usage, size, quota).is_ok(), accepted);
+            if accepted {
+                usage += size;
+            }
+        }
+        assert_eq!(usage, 100);
+
+        // Deleting media makes room for new uploads
+        usage -= 60;
+        assert!(check_quota(usage, 50, quota).is_ok());
+
+        assert!(check_quota(u64::MAX, 1, None).is_ok());
+    }
+
+    #[test]
+    fn least_recently_used_media_is_evicted() {
+        let media = vec![
+            ("mxc://remote/new".to_owned(), 30, 3000),
+            ("mxc://remote/old".to_owned(), 30, 1000),
+            ("mxc://remote/middle".to_owned(), 30, 2000),
+        ];
+
+        assert_eq!(
+            least_recently_used(media.clone(), 90, 50),
+            ["mxc://remote/old", "mxc://remote/middle"]
+        );
+        assert_eq!(
+            least_recently_used(media.clone(), 90, 60),
+            ["mxc://remote/old"]
+        );
+        assert!(least_recently_used(media.clone(), 90, 90).is_empty());
+
+        // Media larger than the whole quota evicts everything
+        assert_eq!(least_recently_used(media, 90, 0).len(), 3);
+    }
+
+    #[test]
+    fn redirects_are_limited() {
+        let url = Url::parse("https://example.com/a/b").unwrap();
+
+        assert_eq!(
+            redirect_target(&url, Some("/c"), 0).unwrap().as_str(),
+            "https://example.com/c"
+        );
+        assert_eq!(
+            redirect_target(
+                &url,
+                Some("https://example.org/"),
+                MAX_URL_PREVIEW_REDIRECTS - 1
+            )
+            .unwrap()
+            .as_str(),
+            "https://example.org/"
+        );
+        assert!(redirect_target(&url, Some("/c"), MAX_URL_PREVIEW_REDIRECTS).is_err());
+        assert!(redirect_target(&url, None, 0).is_err());
+    }
+
+    #[test]
+    fn internal_addresses_are_blocked() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip} is internal");
+        }
+
+        assert!(is_public_ip("93.184.216.34".parse().unwrap()));
+        assert!(is_public_ip("2606:2800:220:1::".parse().unwrap()));
+
+        // Hosts resolving to any internal address are refused
+        let public = "93.184.216.34".parse().unwrap();
+        assert_eq!(check_ips(&[public]).unwrap(), public);
+        assert!(check_ips(&[public, "10.0.0.1".parse().unwrap()]).is_err());
+        assert!(check_ips(&[]).is_err());
+    }
+
+    #[test]
+    fn domain_lists() {
+        let allowlist = vec!["example.com".to_owned()];
+        let denylist = vec!["private.example.com".to_owned()];
+
+        assert!(is_domain_allowed("example.com", &allowlist, &denylist));
+        assert!(is_domain_allowed("www.example.com", &allowlist, &denylist));
+        assert!(!is_domain_allowed("notexample.com", &allowlist, &denylist));
+        assert!(!is_domain_allowed(
+            "a.private.example.com",
+            &allowlist,
+            &denylist
+        ));
+        assert!(is_domain_allowed("example.org", &[], &denylist));
+    }
+
+    #[test]
+    fn opengraph_tags_are_parsed() {
+        let html = r#"<html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content="Tom &amp; Jerry">
+            <meta name='twitter:description' content='A cat and a mouse' />
+            <meta content="/cover.png" property="og:image">
+        </head></html>"#;
+
+        assert_eq!(
+            parse_page_meta(html),
+            PageMeta {
+                title: Some("Tom & Jerry".to_owned()),
+                description: Some("A cat and a mouse".to_owned()),
+                image: Some("/cover.png".to_owned()),
+            }
+        );
+        assert_eq!(
+            parse_page_meta("<title> Just a title </title>").title,
+            Some("Just a title".to_owned())
+        );
+    }
+
+    #[test]
+    fn thumbnailable_content_types() {
+        assert!(can_thumbnail(Some("image/png")));
+        assert!(can_thumbnail(Some("IMAGE/JPEG; charset=binary")));
+        assert!(can_thumbnail(None));
+        assert!(!can_thumbnail(Some("image/svg+xml")));
+        assert!(!can_thumbnail(Some("application/pdf")));
     }
 }
@@ -1,6 +1,6 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap},
+    sync::{atomic::AtomicU64, Arc, Mutex},
 };
 
 use lru_cache::LruCache;
@@ -15,8 +15,11 @@ pub mod key_backups;
 pub mod media;
 pub mod pdu;
 pub mod pusher;
+pub mod rate_limiter;
 pub mod rooms;
 pub mod sending;
+pub mod sliding_sync;
+pub mod sso;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
@@ -24,6 +27,7 @@ pub mod users;
 pub struct Services {
     pub appservice: appservice::Service,
     pub pusher: pusher::Service,
+    pub rate_limiter: rate_limiter::Service,
     pub rooms: rooms::Service,
     pub transaction_ids: transaction_ids::Service,
     pub uiaa: uiaa::Service,
@@ -34,11 +38,14 @@ pub struct Services {
     pub key_backups: key_backups::Service,
     pub media: media::Service,
     pub sending: Arc<sending::Service>,
+    pub sliding_sync: sliding_sync::Service,
+    pub sso: sso::Service,
 }
 
 impl Services {
     pub fn build<
         D: appservice::Data
+            + admin::Data
             + pusher::Data
             + rooms::Data
             + transaction_ids::Data
@@ -49,14 +56,29 @@ impl Services {
             + key_backups::Data
             + media::Data
             + sending::Data
+            + sso::Data
             + 'static,
     >(
         db: &'static D,
         config: Config,
     ) -> Result<Self> {
         Ok(Self {
-            appservice: appservice::Service { db },
-            pusher: pusher::Service { db },
+            appservice: appservice::Service {
+                db,
+                user_namespaces: Mutex::new(None),
+            },
+            pusher: pusher::Service {
+                db,
+                stale_notifications: AtomicU64::new(0),
+                pusher_failures: Mutex::new(HashMap::new()),
+                push_disabled: Mutex::new(HashMap::new()),
+                gateway_probes: Mutex::new(pusher::GatewayProbeCache::default()),
+                history_buffer: Mutex::new(Vec::new()),
+                gateway_deliveries: Mutex::new(HashMap::new()),
+            },
+            rate_limiter: rate_limiter::Service {
+                buckets: Mutex::new(HashMap::new()),
+            },
             rooms: rooms::Service {
                 alias: rooms::alias::Service { db },
                 auth_chain: rooms::auth_chain::Service { db },
@@ -76,6 +98,7 @@ impl Services {
                 pdu_metadata: rooms::pdu_metadata::Service { db },
                 search: rooms::search::Service { db },
                 short: rooms::short::Service { db },
+                spaces: rooms::spaces::Service,
                 state: rooms::state::Service { db },
                 state_accessor: rooms::state_accessor::Service {
                     db,
@@ -98,16 +121,24 @@ impl Services {
                     lasttimelinecount_cache: Mutex::new(HashMap::new()),
                 },
                 threads: rooms::threads::Service { db },
+                upgrade: rooms::upgrade::Service,
                 user: rooms::user::Service { db },
             },
             transaction_ids: transaction_ids::Service { db },
-            uiaa: uiaa::Service { db },
+            uiaa: uiaa::Service {
+                db,
+                registration_token_lock: Mutex::new(()),
+            },
             users: users::Service { db },
             account_data: account_data::Service { db },
-            admin: admin::Service::build(),
+            admin: admin::Service::build(db),
             key_backups: key_backups::Service { db },
             media: media::Service { db },
             sending: sending::Service::build(db, &config),
+            sliding_sync: sliding_sync::Service {
+                connections: Mutex::new(BTreeMap::new()),
+            },
+            sso: sso::Service::build(db),
 
             globals: globals::Service::load(db, config)?,
         })
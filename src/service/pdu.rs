@@ -1,9 +1,9 @@
 use crate::Error;
 use ruma::{
     events::{
-        room::member::RoomMemberEventContent, AnyEphemeralRoomEvent, AnyMessageLikeEvent,
-        AnyStateEvent, AnyStrippedStateEvent, AnySyncStateEvent, AnySyncTimelineEvent,
-        AnyTimelineEvent, StateEvent, TimelineEventType,
+        room::member::RoomMemberEventContent, space::child::HierarchySpaceChildEvent,
+        AnyEphemeralRoomEvent, AnyMessageLikeEvent, AnyStateEvent, AnyStrippedStateEvent,
+        AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent, StateEvent, TimelineEventType,
     },
     serde::Raw,
     state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
@@ -103,6 +103,87 @@ impl PduEvent {
         Ok(())
     }
 
+    /// Returns the `rel_type` and the parent event id of the `m.relates_to` field of the content.
+    pub fn relates_to(&self) -> Option<(String, OwnedEventId)> {
+        #[derive(Deserialize)]
+        struct ExtractRelatesTo {
+            #[serde(rename = "m.relates_to")]
+            relates_to: ExtractRelation,
+        }
+
+        #[derive(Deserialize)]
+        struct ExtractRelation {
+            rel_type: String,
+            event_id: OwnedEventId,
+        }
+
+        serde_json::from_str::<ExtractRelatesTo>(self.content.get())
+            .ok()
+            .map(|content| (content.relates_to.rel_type, content.relates_to.event_id))
+    }
+
+    /// Returns the event id this event replies to, from `m.relates_to.m.in_reply_to`.
+    pub fn in_reply_to(&self) -> Option<OwnedEventId> {
+        #[derive(Deserialize)]
+        struct ExtractRelatesTo {
+            #[serde(rename = "m.relates_to")]
+            relates_to: ExtractInReplyTo,
+        }
+
+        #[derive(Deserialize)]
+        struct ExtractInReplyTo {
+            #[serde(rename = "m.in_reply_to")]
+            in_reply_to: ExtractEventId,
+        }
+
+        #[derive(Deserialize)]
+        struct ExtractEventId {
+            event_id: OwnedEventId,
+        }
+
+        serde_json::from_str::<ExtractRelatesTo>(self.content.get())
+            .ok()
+            .map(|content| content.relates_to.in_reply_to.event_id)
+    }
+
+    /// Returns whether this event replaces the content of another event.
+    pub fn is_edit(&self) -> bool {
+        matches!(self.relates_to(), Some((rel_type, _)) if rel_type == "m.replace")
+    }
+
+    /// Returns whether this event was redacted, see [`PduEvent::redact`].
+    pub fn is_redacted(&self) -> bool {
+        #[derive(Deserialize)]
+        struct ExtractRedactedBecause {
+            redacted_because: Option<serde::de::IgnoredAny>,
+        }
+
+        self.unsigned
+            .as_ref()
+            .and_then(|unsigned| {
+                serde_json::from_str::<ExtractRedactedBecause>(unsigned.get()).ok()
+            })
+            .map_or(false, |unsigned| unsigned.redacted_because.is_some())
+    }
+
+    /// Adds or replaces a field of the unsigned data of this event.
+    pub fn add_unsigned(&mut self, key: &str, value: serde_json::Value) -> crate::Result<()> {
+        let mut unsigned: BTreeMap<String, Box<RawJsonValue>> = self
+            .unsigned
+            .as_ref()
+            .map(|unsigned| serde_json::from_str(unsigned.get()))
+            .transpose()
+            .map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?
+            .unwrap_or_default();
+        unsigned.insert(
+            key.to_owned(),
+            to_raw_value(&value).expect("to_raw_value always works"),
+        );
+        self.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn to_sync_room_event(&self) -> Raw<AnySyncTimelineEvent> {
         let mut json = json!({
@@ -248,6 +329,19 @@ impl PduEvent {
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn to_stripped_spacechild_state_event(&self) -> Raw<HierarchySpaceChildEvent> {
+        let json = json!({
+            "content": self.content,
+            "type": self.kind,
+            "sender": self.sender,
+            "state_key": self.state_key,
+            "origin_server_ts": self.origin_server_ts,
+        });
+
+        serde_json::from_value(json).expect("Raw::from_value always works")
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn to_member_event(&self) -> Raw<StateEvent<RoomMemberEventContent>> {
         let mut json = json!({
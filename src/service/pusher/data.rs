@@ -1,16 +1,155 @@
+use super::{FailedNotification, NotificationRecord, PusherSettings, TweakPreferences};
 use crate::Result;
 use ruma::{
     api::client::push::{set_pusher, Pusher},
-    UserId,
+    DeviceId, EventId, OwnedDeviceId, OwnedEventId, OwnedUserId, RoomId, UserId,
 };
 
 pub trait Data: Send + Sync {
-    fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()>;
+    /// Runs `f` as one unit: if it fails, all pusher data it wrote is restored. Transactions
+    /// started within `f` are part of this one.
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()>;
+
+    /// Adds, updates or deletes a pusher, unless a newer version of it was stored already.
+    /// Returns whether the pusher was changed.
+    fn upsert_pusher(
+        &self,
+        sender: &UserId,
+        pusher: set_pusher::v3::PusherAction,
+        version: u64,
+    ) -> Result<bool>;
 
     fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>>;
 
     fn get_pushers(&self, sender: &UserId) -> Result<Vec<Pusher>>;
 
+    /// Returns the pushers of all users.
+    fn all_pushers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, Pusher)>> + 'a>;
+
+    /// Returns every user with at least one pusher, once.
+    fn users_with_pushers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a>;
+
     fn get_pushkeys<'a>(&'a self, sender: &UserId)
         -> Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+    /// Returns the pushkey, app id and kind of all pushers of this user.
+    fn get_pushkey_metadata<'a>(
+        &'a self,
+        sender: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, String, String)>> + 'a>;
+
+    /// Remembers which device registered the pusher. Forgotten when the pusher is deleted.
+    fn set_pusher_device(&self, sender: &UserId, pushkey: &str, device_id: &DeviceId)
+        -> Result<()>;
+
+    /// Returns the device that registered the pusher, if known.
+    fn get_pusher_device(&self, sender: &UserId, pushkey: &str) -> Result<Option<OwnedDeviceId>>;
+
+    fn set_pusher_settings(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        settings: &PusherSettings,
+    ) -> Result<()>;
+
+    /// Returns the Conduit specific settings of this pusher, or the defaults if none were set.
+    fn get_pusher_settings(&self, sender: &UserId, pushkey: &str) -> Result<PusherSettings>;
+
+    fn set_tweak_preferences(&self, user_id: &UserId, preferences: &TweakPreferences)
+        -> Result<()>;
+
+    /// Returns the tweak preferences of this user, or the defaults if none were set.
+    fn get_tweak_preferences(&self, user_id: &UserId) -> Result<TweakPreferences>;
+
+    fn clear_tweak_preferences(&self, user_id: &UserId) -> Result<()>;
+
+    /// Remembers when this pusher last notified the user about the room.
+    fn set_last_notified(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        pushkey: &str,
+        timestamp: u64,
+    ) -> Result<()>;
+
+    /// Returns when this pusher last notified the user about the room since they last read it.
+    fn last_notified(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        pushkey: &str,
+    ) -> Result<Option<u64>>;
+
+    /// Forgets about all notifications of the user about the room, e.g. because they read it.
+    fn clear_last_notified(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+
+    /// Adds an event to the pending notification digest of this pusher. `queued_at` is in
+    /// milliseconds since the unix epoch.
+    fn queue_digest_event(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        event_id: &EventId,
+        queued_at: u64,
+    ) -> Result<()>;
+
+    /// Returns all events in the pending notification digest of this pusher together with the
+    /// count and the time they were queued at, oldest first.
+    fn digest_events(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+    ) -> Result<Vec<(u64, u64, OwnedEventId)>>;
+
+    /// Removes the events up to and including `until` from the pending notification digest of
+    /// this pusher, e.g. because they were sent.
+    fn remove_digest_events(&self, sender: &UserId, pushkey: &str, until: u64) -> Result<()>;
+
+    /// Returns all pushers that have a pending notification digest.
+    fn digest_pushers<'a>(&'a self)
+        -> Box<dyn Iterator<Item = Result<(OwnedUserId, String)>> + 'a>;
+
+    /// Remembers a notification that could not be delivered under its id.
+    fn record_failed_notification(
+        &self,
+        notification_id: &str,
+        notification: &FailedNotification,
+    ) -> Result<()>;
+
+    fn get_failed_notification(&self, notification_id: &str) -> Result<Option<FailedNotification>>;
+
+    /// Forgets about all failed notifications of this pusher for the pdu, e.g. because it was
+    /// delivered now.
+    fn clear_failed_notifications(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        pdu_id: &[u8],
+    ) -> Result<()>;
+
+    /// Adds the records to the notification history.
+    fn append_notification_history(&self, records: &[NotificationRecord]) -> Result<()>;
+
+    /// Remembers an unanswered call invite to the user in the room.
+    fn add_call_invite(&self, room_id: &RoomId, call_id: &str, user_id: &UserId) -> Result<()>;
+
+    /// Forgets the call invites of this call to all users, e.g. because it was answered.
+    fn remove_call_invites(&self, room_id: &RoomId, call_id: &str) -> Result<()>;
+
+    /// Returns the number of unanswered call invites to the user in the room.
+    fn missed_calls(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
+
+    fn clear_missed_calls(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+
+    /// Remembers that the pusher was notified about the event.
+    fn mark_notified_event(&self, event_id: &EventId, sender: &UserId, pushkey: &str)
+        -> Result<()>;
+
+    /// Returns the user and pushkey of every pusher that was notified about the event.
+    fn notified_pushers<'a>(
+        &'a self,
+        event_id: &EventId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String)>> + 'a>;
+
+    fn forget_notified_event(&self, event_id: &EventId) -> Result<()>;
 }
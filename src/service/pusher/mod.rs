@@ -2,11 +2,24 @@ mod data;
 pub use data::Data;
 use ruma::events::AnySyncTimelineEvent;
 
-use crate::{services, Error, PduEvent, Result};
+use crate::{
+    config::{ContentFallback, PushGatewayEnvelope},
+    service::sending::OutgoingKind,
+    services, utils, Error, PduEvent, Result,
+};
 use bytes::BytesMut;
+use futures_util::{stream::FuturesUnordered, Future, StreamExt};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncTransport, Message,
+};
+use regex::{Regex, RegexSet};
 use ruma::{
     api::{
-        client::push::{set_pusher, Pusher, PusherKind},
+        client::{
+            error::ErrorKind,
+            push::{set_pusher, HttpPusherData, Pusher, PusherKind},
+        },
         push_gateway::send_event_notification::{
             self,
             v1::{Device, Notification, NotificationCounts, NotificationPriority},
@@ -14,50 +27,768 @@ use ruma::{
         IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken,
     },
     events::{
-        room::{name::RoomNameEventContent, power_levels::RoomPowerLevelsEventContent},
-        StateEventType, TimelineEventType,
+        push_rules::PushRulesEvent,
+        room::{
+            name::RoomNameEventContent, power_levels::RoomPowerLevelsEventContent,
+            server_acl::RoomServerAclEventContent, topic::RoomTopicEventContent,
+        },
+        GlobalAccountDataEventType, StateEventType, TimelineEventType,
+    },
+    push::{
+        Action, FlattenedJson, PushCondition, PushConditionRoomCtx, PushFormat, PusherData,
+        Ruleset, Tweak,
     },
-    push::{Action, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
     serde::Raw,
-    uint, RoomId, UInt, UserId,
+    uint, DeviceId, EventId, OwnedEventId, OwnedUserId, RoomId, UInt, UserId,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::RawValue as RawJsonValue};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Debug,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
+use tracing::{debug, error, info, warn};
+
+/// Global account data event type users can set to receive notifications as a periodic digest.
+pub const PUSH_DIGEST_EVENT_TYPE: &str = "rs.conduit.push_digest";
+
+/// Content of the [`PUSH_DIGEST_EVENT_TYPE`] account data event.
+#[derive(Debug, Default, Deserialize)]
+pub struct PushDigestSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Highlights (e.g. mentions) are still pushed immediately instead of waiting for the digest
+    #[serde(default)]
+    pub highlights_immediately: bool,
+    /// Seconds between two emails to the user's email pushers, instead of the server's digest
+    /// interval. Emails are always sent as a digest, whether `enabled` is set or not.
+    #[serde(default)]
+    pub email_interval: Option<u64>,
+    /// Highlights are emailed immediately instead of waiting for the next email
+    #[serde(default)]
+    pub email_highlights_immediately: bool,
+}
+
+/// Global account data event type users can set to be pushed for events of bot senders again.
+pub const PUSH_BOT_SENDERS_EVENT_TYPE: &str = "rs.conduit.push_bot_senders";
+
+/// Content of the [`PUSH_BOT_SENDERS_EVENT_TYPE`] account data event.
+#[derive(Debug, Default, Deserialize)]
+pub struct PushBotSendersSettings {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Body of notifications whose content couldn't be serialized, see [`ContentFallback`].
+const CONTENT_PLACEHOLDER: &str = "New message";
+
+/// Event type of the state events announcing a live location share (MSC3672).
+const BEACON_INFO_TYPE: &str = "org.matrix.msc3672.beacon_info";
+
+/// Event type of the location updates of a live location share (MSC3672).
+const BEACON_TYPE: &str = "org.matrix.msc3672.beacon";
+
+/// Event type of the silent notifications sent when the device list of a user in a shared
+/// encrypted room changes.
+pub const DEVICE_LIST_NOTIFICATION_TYPE: &str = "rs.conduit.device_list_update";
+
+/// Key in the `data` object of a pusher under which Conduit specific [`PusherSettings`] can be
+/// passed when registering the pusher.
+pub const PUSHER_SETTINGS_KEY: &str = "rs.conduit";
+
+/// Layout of the notifications sent to newly registered pushers. Pushers of older app versions
+/// can keep getting the layout they understand by registering with an older format version:
+///
+/// 1. The plain notification of the push gateway API with the device's own default payload
+/// 2. Conduit specific additions, like the notification id and group in the default payload,
+///    quick actions, the room topic and the complete event
+pub const LATEST_FORMAT_VERSION: u32 = 2;
+
+/// Conduit specific settings of a pusher, stored next to the pusher itself.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PusherSettings {
+    /// Template for the notification body, see [`TEMPLATE_PLACEHOLDERS`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Overrides the globally configured maximum number of delivery attempts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Overrides the globally configured gateway timeout, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Overrides the `Content-Type` header of requests to the push gateway, the body stays JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Additional headers sent with every request to the push gateway, e.g. a tenant id
+    #[serde(default, skip_serializing_if = "CustomHeaders::is_empty")]
+    pub headers: CustomHeaders,
+    /// Include the complete event under [`FULL_EVENT_KEY`] in notifications. This hands message
+    /// contents and metadata to the gateway, so it is only honored for gateways the server admin
+    /// listed in `push_full_event_gateways`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub full_event: bool,
+    /// The app can show interactive notifications, so describe the available quick actions under
+    /// [`QUICK_ACTIONS_KEY`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quick_actions: bool,
+    /// Why the pusher was disabled after failing repeatedly. Registering the pusher again lifts
+    /// the quarantine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<String>,
+    /// Until when, in milliseconds since the unix epoch, nothing is sent to the pusher because its
+    /// gateway failed for all delivery attempts. Registering the pusher again resumes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused_until: Option<u64>,
+    /// Outside of these hours nothing is sent to the pusher, e.g. for a work phone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_hours: Option<ActiveHours>,
+    /// Layout of the notifications sent to this pusher, see [`LATEST_FORMAT_VERSION`]. Pushers
+    /// registered before format versions existed get the first layout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format_version: Option<u32>,
+    /// Push gateway that notifications are sent to when the pusher's own gateway fails.
+    /// Overrides the gateway configured for the app in `push_fallback_gateways`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_gateway: Option<String>,
+}
+
+/// How a single push rule was evaluated for an event.
+#[derive(Debug)]
+pub struct RuleTrace {
+    /// The kind of the rule, e.g. `override`
+    pub kind: &'static str,
+    pub rule_id: String,
+    pub enabled: bool,
+    /// Every condition of the rule as JSON, with whether the event satisfied it
+    pub conditions: Vec<(String, bool)>,
+    /// Whether this rule decided the actions
+    pub matched: bool,
+    pub actions: Vec<Action>,
+}
+
+/// A pusher together with its owner and Conduit specific settings, as dumped to move pushers to
+/// another database backend.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PusherDump {
+    pub user_id: OwnedUserId,
+    pub pusher: Pusher,
+    #[serde(default)]
+    pub settings: PusherSettings,
+}
+
+/// A push notification that could not be delivered, remembered so it can be requeued by id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedNotification {
+    pub user_id: OwnedUserId,
+    pub pushkey: String,
+    pub pdu_id: Vec<u8>,
+}
+
+impl FailedNotification {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.user_id.as_bytes().to_vec();
+        bytes.push(0xff);
+        bytes.extend_from_slice(self.pushkey.as_bytes());
+        bytes.push(0xff);
+        bytes.extend_from_slice(&self.pdu_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // The pdu id comes last because it may contain 0xff itself
+        let mut parts = bytes.splitn(3, |&b| b == 0xff);
+
+        let user_id = parts
+            .next()
+            .and_then(|part| utils::string_from_bytes(part).ok())
+            .and_then(|user_id| OwnedUserId::try_from(user_id).ok())
+            .ok_or_else(|| Error::bad_database("Invalid user id in failed notification."))?;
+        let pushkey = parts
+            .next()
+            .and_then(|part| utils::string_from_bytes(part).ok())
+            .ok_or_else(|| Error::bad_database("Invalid pushkey in failed notification."))?;
+        let pdu_id = parts
+            .next()
+            .ok_or_else(|| Error::bad_database("Missing pdu id in failed notification."))?
+            .to_vec();
+
+        Ok(Self {
+            user_id,
+            pushkey,
+            pdu_id,
+        })
+    }
+}
+
+/// Custom headers of a pusher by name. They might contain secrets, so their values are never
+/// logged.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct CustomHeaders(pub BTreeMap<String, String>);
+
+impl CustomHeaders {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Debug for CustomHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|name| (name, "<redacted>")))
+            .finish()
+    }
+}
+
+/// Headers that are set by Conduit itself or through dedicated settings.
+const RESERVED_HEADERS: &[&str] = &["content-type", "content-length", "host"];
+
+/// Key in the default payload of a device under which the unique id of a notification is sent.
+pub const NOTIFICATION_ID_KEY: &str = "rs.conduit.notification_id";
+
+/// Key in the default payload of a device under which the group of a notification is sent.
+/// Clients can stack notifications with the same group, which is the thread root for thread
+/// replies and the room id otherwise.
+pub const NOTIFICATION_GROUP_KEY: &str = "rs.conduit.group";
+
+/// Key in the default payload of a device under which the quick actions of a notification are
+/// sent, for pushers that enabled [`PusherSettings::quick_actions`].
+pub const QUICK_ACTIONS_KEY: &str = "rs.conduit.actions";
+
+/// Key in the default payload of a device under which the topic of the room is sent, if enabled.
+pub const ROOM_TOPIC_KEY: &str = "rs.conduit.room_topic";
+
+/// Maximum length of the room topic in notifications.
+const ROOM_TOPIC_LENGTH: usize = 200;
+
+/// Key in the notification content under which the context of a reply is sent.
+pub const REPLY_CONTEXT_KEY: &str = "rs.conduit.in_reply_to";
+
+/// Maximum length of the body of the replied-to event in the reply context.
+const REPLY_CONTEXT_BODY_LENGTH: usize = 100;
+
+/// Key in the notification under which the complete event is sent to trusted gateways.
+pub const FULL_EVENT_KEY: &str = "rs.conduit.event";
+
+/// Tweaks a user never wants to receive, regardless of which push rule matched.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TweakPreferences {
+    /// Names of the suppressed tweaks, e.g. `sound` or `highlight`
+    #[serde(default)]
+    pub suppressed: BTreeSet<String>,
+}
+
+impl TweakPreferences {
+    /// Removes the suppressed tweaks.
+    pub fn filter(&self, tweaks: Vec<Tweak>) -> Vec<Tweak> {
+        tweaks
+            .into_iter()
+            .filter(|tweak| !self.suppressed.contains(tweak_name(tweak)))
+            .collect()
+    }
+}
+
+fn tweak_name(tweak: &Tweak) -> &str {
+    match tweak {
+        Tweak::Sound(_) => "sound",
+        Tweak::Highlight(_) => "highlight",
+        Tweak::Custom { name, .. } => name,
+        _ => "",
+    }
+}
+
+/// How often and how long delivery to a pusher is attempted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns whether delivery should be given up after `tries` failed attempts.
+    pub fn exhausted(&self, tries: u32) -> bool {
+        tries >= self.max_attempts
+    }
+}
+
+/// Placeholders that can be used in a notification template, e.g. `{sender}: {body}`.
+pub const TEMPLATE_PLACEHOLDERS: &[&str] = &["sender", "room", "body"];
+
+impl PusherSettings {
+    /// Returns the retry policy of this pusher, using `defaults` for settings that are not
+    /// overridden.
+    pub fn retry_policy(&self, defaults: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(defaults.max_attempts),
+            timeout: self
+                .timeout
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.timeout),
+        }
+    }
+
+    /// Returns whether notifications to this gateway should include the complete event.
+    pub fn sends_full_event(&self, gateway: &str, trusted_gateways: &[String]) -> bool {
+        self.full_event && trusted_gateways.iter().any(|trusted| trusted == gateway)
+    }
+
+    /// Returns the layout of the notifications sent to this pusher.
+    pub fn format_version(&self) -> u32 {
+        self.format_version.unwrap_or(1)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(template) = &self.template {
+            let mut rest = template.as_str();
+            while let Some(start) = rest.find('{') {
+                let end = rest[start..].find('}').ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Unclosed placeholder in notification template.",
+                ))? + start;
+
+                if !TEMPLATE_PLACEHOLDERS.contains(&&rest[start + 1..end]) {
+                    return Err(Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "Unknown placeholder in notification template.",
+                    ));
+                }
+
+                rest = &rest[end + 1..];
+            }
+        }
+
+        if let Some(content_type) = &self.content_type {
+            if http::HeaderValue::from_str(content_type).is_err() {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid content type for push gateway requests.",
+                ));
+            }
+        }
+
+        for (name, value) in &self.headers.0 {
+            let name = http::HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid header name for push gateway requests.",
+                )
+            })?;
+            if RESERVED_HEADERS.contains(&name.as_str()) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "This header can't be set for push gateway requests.",
+                ));
+            }
+            if http::HeaderValue::from_str(value).is_err() {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid header value for push gateway requests.",
+                ));
+            }
+        }
+
+        if !(1..=LATEST_FORMAT_VERSION).contains(&self.format_version()) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Unknown notification format version.",
+            ));
+        }
+
+        if let Some(fallback_gateway) = &self.fallback_gateway {
+            if gateway_host(fallback_gateway).is_none() {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid fallback push gateway url.",
+                ));
+            }
+        }
+
+        if let Some(active_hours) = &self.active_hours {
+            if active_hours.start >= MINUTES_PER_DAY
+                || active_hours.end >= MINUTES_PER_DAY
+                || active_hours.utc_offset.unsigned_abs() > MAX_UTC_OFFSET
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid active hours of the pusher.",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Largest offset of a timezone from UTC, in minutes.
+const MAX_UTC_OFFSET: u16 = 14 * 60;
+
+/// Daily window in which a pusher receives notifications, in the local time of the device.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ActiveHours {
+    /// Minute of the day the window opens, e.g. 540 for 09:00
+    pub start: u16,
+    /// Minute of the day the window closes. Windows closing before they open span midnight.
+    pub end: u16,
+    /// Offset of the device's timezone from UTC in minutes, e.g. 120 for UTC+2
+    #[serde(default)]
+    pub utc_offset: i16,
+}
+
+impl ActiveHours {
+    /// Returns whether the window is open at `now`, in milliseconds since the unix epoch.
+    pub fn contains(&self, now: u64) -> bool {
+        let minutes = (now / 60_000) as i64 + i64::from(self.utc_offset);
+        let minute = minutes.rem_euclid(i64::from(MINUTES_PER_DAY)) as u16;
+
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Upper bound for the number of entries returned by [`Service::notification_snapshot`].
+pub const MAX_NOTIFICATION_SNAPSHOT: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationState {
+    /// Currently being sent to the push gateway, or waiting for a retry
+    InFlight,
+    /// Waiting for the in-flight notifications of the same pusher
+    Queued,
+}
+
+/// Entry of the notification history, an audit log of the notifications sent to pushers.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct NotificationRecord {
+    pub notification_id: String,
+    pub user_id: OwnedUserId,
+    pub pushkey: String,
+    pub event_id: OwnedEventId,
+    /// When the notification was sent, in milliseconds since the unix epoch
+    pub sent_at: u64,
+    pub delivered: bool,
+}
+
+/// Response of a push gateway to a health probe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GatewayProbe {
+    pub status: u16,
+    pub body: String,
+}
+
+/// Maximum number of devices of one user that are notified at the same time.
+const MAX_PARALLEL_DEVICES: usize = 4;
+
+/// Maximum number of seconds between two checks for digests that are due.
+const DIGEST_CHECK_INTERVAL: u64 = 60;
+
+/// Recent probe responses of push gateways by host, so they are not probed over and over again.
+#[derive(Default)]
+pub struct GatewayProbeCache {
+    entries: HashMap<String, (Instant, GatewayProbe)>,
+}
+
+impl GatewayProbeCache {
+    /// Returns the response of the host, unless it is older than the ttl.
+    pub fn get(&self, host: &str, ttl: Duration, now: Instant) -> Option<GatewayProbe> {
+        self.entries
+            .get(host)
+            .filter(|(probed_at, _)| now.saturating_duration_since(*probed_at) < ttl)
+            .map(|(_, probe)| probe.clone())
+    }
+
+    pub fn insert(&mut self, host: String, probe: GatewayProbe, now: Instant) {
+        self.entries.insert(host, (now, probe));
+    }
+
+    pub fn invalidate(&mut self, host: &str) {
+        self.entries.remove(host);
+    }
+}
 
-use std::{fmt::Debug, mem};
-use tracing::{info, warn};
+/// A push notification that has not been delivered yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingNotification {
+    /// `None` if the event can no longer be found
+    pub event_id: Option<OwnedEventId>,
+    pub user: OwnedUserId,
+    pub pushkey: String,
+    pub state: NotificationState,
+    /// Failed delivery attempts of this pusher so far
+    pub attempts: u32,
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Number of notifications that were dropped because they were too old when dispatched
+    pub stale_notifications: AtomicU64,
+    /// Consecutive failures of pushers that were caused by their stored data
+    pub pusher_failures: Mutex<HashMap<(OwnedUserId, String), u32>>,
+    /// Whether users disabled all notifications with the master push rule
+    pub push_disabled: Mutex<HashMap<OwnedUserId, bool>>,
+    /// Recent health probe responses of push gateways
+    pub gateway_probes: Mutex<GatewayProbeCache>,
+    /// Notification history records that were not written to the database yet
+    pub history_buffer: Mutex<Vec<NotificationRecord>>,
+    /// Number of notifications each push gateway accepted, including fallback gateways
+    pub gateway_deliveries: Mutex<HashMap<String, u64>>,
 }
 
 impl Service {
+    /// Adds, updates or deletes a pusher. Concurrent changes to the same pusher are resolved by
+    /// keeping the one that started last.
     pub fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
-        self.db.set_pusher(sender, pusher)
+        if let set_pusher::v3::PusherAction::Post(data) = &pusher {
+            self.check_app_id(&data.pusher.ids.app_id)?;
+        }
+
+        let version = services().globals.next_count()?;
+        self.transaction(|| {
+            if !self.db.upsert_pusher(sender, pusher.clone(), version)? {
+                debug!("Ignoring outdated change of a pusher of {}", sender);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Runs `f` as one unit, so a failure halfway doesn't leave partially written pusher data
+    /// behind.
+    pub fn transaction(&self, mut f: impl FnMut() -> Result<()>) -> Result<()> {
+        self.db.transaction(&mut f)
+    }
+
+    /// Fails if the server only accepts pushers of other apps.
+    pub fn check_app_id(&self, app_id: &str) -> Result<()> {
+        if !app_id_allowed(services().globals.push_allowed_app_ids(), app_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This app is not allowed to register pushers on this server.",
+            ));
+        }
+
+        Ok(())
     }
 
     pub fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>> {
         self.db.get_pusher(sender, pushkey)
     }
 
+    /// Returns the pushers of this user, ordered by app id and pushkey.
     pub fn get_pushers(&self, sender: &UserId) -> Result<Vec<Pusher>> {
-        self.db.get_pushers(sender)
+        let mut pushers = self.db.get_pushers(sender)?;
+        sort_pushers(&mut pushers);
+        Ok(pushers)
+    }
+
+    /// Returns every user with at least one pusher, without going through all accounts.
+    pub fn users_with_pushers(&self) -> impl Iterator<Item = Result<OwnedUserId>> + '_ {
+        self.db.users_with_pushers()
+    }
+
+    /// Returns the pushers of all users, e.g. to move them to another database backend.
+    pub fn export_pushers(&self) -> Result<Vec<PusherDump>> {
+        export_pushers(self.db)
+    }
+
+    /// Stores dumped pushers, replacing existing pushers with the same pushkey. Returns the
+    /// number of imported pushers.
+    pub fn import_pushers(&self, pushers: Vec<PusherDump>) -> Result<usize> {
+        import_pushers(self.db, pushers, services().globals.next_count()?)
+    }
+
+    /// Remembers which device registered the pusher, so it can be deleted with the device.
+    pub fn set_pusher_device(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        device_id: &DeviceId,
+    ) -> Result<()> {
+        self.db.set_pusher_device(sender, pushkey, device_id)
+    }
+
+    /// Deletes all pushers registered by this device, e.g. because it was logged out.
+    pub fn remove_device_pushers(&self, sender: &UserId, device_id: &DeviceId) -> Result<()> {
+        for pusher in self.db.get_pushers(sender)? {
+            if self
+                .db
+                .get_pusher_device(sender, &pusher.ids.pushkey)?
+                .as_deref()
+                == Some(device_id)
+            {
+                self.set_pusher(sender, set_pusher::v3::PusherAction::Delete(pusher.ids))?;
+            }
+        }
+
+        Ok(())
     }
 
     pub fn get_pushkeys(&self, sender: &UserId) -> Box<dyn Iterator<Item = Result<String>>> {
         self.db.get_pushkeys(sender)
     }
 
+    /// Returns `(pushkey, app_id, kind)` of all pushers of this user, without loading them one
+    /// by one.
+    pub fn get_pushkey_metadata(
+        &self,
+        sender: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, String, String)>>> {
+        self.db.get_pushkey_metadata(sender)
+    }
+
+    pub fn set_pusher_settings(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        settings: &PusherSettings,
+    ) -> Result<()> {
+        settings.validate()?;
+        self.db.set_pusher_settings(sender, pushkey, settings)
+    }
+
+    pub fn get_pusher_settings(&self, sender: &UserId, pushkey: &str) -> Result<PusherSettings> {
+        self.db.get_pusher_settings(sender, pushkey)
+    }
+
+    /// Returns whether this pusher was disabled because it failed repeatedly.
+    pub fn is_quarantined(&self, sender: &UserId, pushkey: &str) -> Result<bool> {
+        Ok(self
+            .get_pusher_settings(sender, pushkey)?
+            .quarantined
+            .is_some())
+    }
+
+    /// Tracks consecutive failures of a pusher that were caused by its stored data, `None` if
+    /// sending succeeded. Once there are too many, the pusher is quarantined so it can't disturb
+    /// sending to all other pushers.
+    pub fn record_pusher_outcome(
+        &self,
+        sender: &UserId,
+        pushkey: &str,
+        error: Option<&Error>,
+    ) -> Result<()> {
+        let key = (sender.to_owned(), pushkey.to_owned());
+
+        let error = match error {
+            Some(error) if is_caused_by_pusher(error) => error,
+            // Other errors, e.g. an unreachable gateway, say nothing about the pusher itself
+            Some(_) => return Ok(()),
+            None => {
+                self.pusher_failures.lock().unwrap().remove(&key);
+                return Ok(());
+            }
+        };
+
+        if !count_failure(
+            &mut self.pusher_failures.lock().unwrap(),
+            key,
+            services().globals.push_quarantine_threshold(),
+        ) {
+            return Ok(());
+        }
+
+        warn!(
+            "Quarantining pusher {} of {} after repeated failures: {}",
+            pushkey, sender, error
+        );
+        let mut settings = self.get_pusher_settings(sender, pushkey)?;
+        settings.quarantined = Some(error.to_string());
+        self.db.set_pusher_settings(sender, pushkey, &settings)
+    }
+
+    /// Suppresses the given tweaks in all notifications to this user.
+    pub fn set_tweak_preferences(
+        &self,
+        user_id: &UserId,
+        preferences: &TweakPreferences,
+    ) -> Result<()> {
+        self.db.set_tweak_preferences(user_id, preferences)
+    }
+
+    pub fn get_tweak_preferences(&self, user_id: &UserId) -> Result<TweakPreferences> {
+        self.db.get_tweak_preferences(user_id)
+    }
+
+    pub fn clear_tweak_preferences(&self, user_id: &UserId) -> Result<()> {
+        self.db.clear_tweak_preferences(user_id)
+    }
+
+    /// Returns the retry policy of this pusher, falling back to the global configuration.
+    pub fn retry_policy(&self, sender: &UserId, pushkey: &str) -> Result<RetryPolicy> {
+        Ok(self
+            .get_pusher_settings(sender, pushkey)?
+            .retry_policy(default_retry_policy()))
+    }
+
+    /// Returns whether a failed push should be retried. Only the configured gateway status codes
+    /// are retried, other errors (e.g. connection errors) always are.
+    pub fn is_retryable(&self, error: &Error) -> bool {
+        match error {
+            Error::PushGatewayError(status) => is_retryable_status(
+                status.as_u16(),
+                services().globals.push_retry_status_codes(),
+            ),
+            _ => true,
+        }
+    }
+
+    /// Returns up to `limit` (at most [`MAX_NOTIFICATION_SNAPSHOT`]) notifications that are
+    /// in flight or queued, in-flight ones first.
+    pub fn notification_snapshot(&self, limit: usize) -> Result<Vec<PendingNotification>> {
+        let requests = services()
+            .sending
+            .push_requests(true)
+            .map(|r| r.map(|request| (NotificationState::InFlight, request)))
+            .chain(
+                services()
+                    .sending
+                    .push_requests(false)
+                    .map(|r| r.map(|request| (NotificationState::Queued, request))),
+            )
+            .filter_map(|r| r.ok());
+
+        collect_snapshot(
+            requests,
+            limit,
+            |user, pushkey| {
+                services()
+                    .sending
+                    .failed_attempts(&OutgoingKind::Push(user.to_owned(), pushkey.to_owned()))
+            },
+            |pdu_id| {
+                Ok(services()
+                    .rooms
+                    .timeline
+                    .get_pdu_from_id(pdu_id)?
+                    .map(|pdu| pdu.event_id.as_ref().to_owned()))
+            },
+        )
+    }
+
     #[tracing::instrument(skip(self, destination, request))]
     pub async fn send_request<T: OutgoingRequest>(
         &self,
         destination: &str,
         request: T,
+        settings: &PusherSettings,
     ) -> Result<T::IncomingResponse>
     where
         T: Debug,
     {
         let destination = destination.replace("/_matrix/push/v1/notify", "");
 
-        let http_request = request
+        let mut http_request = request
             .try_into_http_request::<BytesMut>(
                 &destination,
                 SendAccessToken::IfRequired(""),
@@ -69,11 +800,12 @@ impl Service {
             })?
             .map(|body| body.freeze());
 
+        set_gateway_headers(http_request.headers_mut(), settings)?;
+
         let reqwest_request = reqwest::Request::try_from(http_request)
             .expect("all http requests are valid reqwest requests");
 
-        // TODO: we could keep this very short and let expo backoff do it's thing...
-        //*reqwest_request.timeout_mut() = Some(Duration::from_secs(5));
+        // Callers limit how long to wait for the gateway, see `send_notification`
 
         let url = reqwest_request.url().clone();
         let response = services()
@@ -101,7 +833,7 @@ impl Service {
                     Vec::new().into()
                 }); // TODO: handle timeout
 
-                if status != 200 {
+                if !status.is_success() {
                     info!(
                         "Push gateway returned bad response {} {}\n{}\n{:?}",
                         destination,
@@ -109,6 +841,7 @@ impl Service {
                         url,
                         crate::utils::string_from_bytes(&body)
                     );
+                    return Err(Error::PushGatewayError(status));
                 }
 
                 let response = T::IncomingResponse::try_from_http_response(
@@ -131,170 +864,4349 @@ impl Service {
         }
     }
 
-    #[tracing::instrument(skip(self, user, unread, pusher, ruleset, pdu))]
-    pub async fn send_push_notice(
+    /// Sends the notification to the push gateway, or to the fallback gateway of the pusher if
+    /// that fails. Pushers whose pushkeys the gateway rejected are removed.
+    #[tracing::instrument(skip(self, user, destination, notification, settings, full_event))]
+    async fn send_notification(
         &self,
         user: &UserId,
-        unread: UInt,
-        pusher: &Pusher,
-        ruleset: Ruleset,
-        pdu: &PduEvent,
+        app_id: &str,
+        destination: &str,
+        notification: Notification,
+        settings: &PusherSettings,
+        full_event: Option<&PduEvent>,
     ) -> Result<()> {
-        let mut notify = None;
-        let mut tweaks = Vec::new();
+        let fallback = fallback_gateway(
+            settings,
+            app_id,
+            services().globals.push_fallback_gateways(),
+        );
 
-        let power_levels: RoomPowerLevelsEventContent = services()
-            .rooms
-            .state_accessor
-            .room_state_get(&pdu.room_id, &StateEventType::RoomPowerLevels, "")?
-            .map(|ev| {
-                serde_json::from_str(ev.content.get())
-                    .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
-            })
-            .transpose()?
-            .unwrap_or_default();
+        let (result, gateway) = with_fallback(destination, fallback, |gateway| {
+            self.send_to_gateway(gateway, notification.clone(), settings, full_event)
+        })
+        .await;
 
-        for action in self.get_actions(
-            user,
-            &ruleset,
-            &power_levels,
-            &pdu.to_sync_room_event(),
-            &pdu.room_id,
-        )? {
-            let n = match action {
-                Action::Notify => true,
-                Action::SetTweak(tweak) => {
-                    tweaks.push(tweak.clone());
-                    continue;
-                }
-                _ => false,
-            };
+        if result.is_ok() {
+            *self
+                .gateway_deliveries
+                .lock()
+                .unwrap()
+                .entry(gateway.to_owned())
+                .or_default() += 1;
+        }
 
-            if notify.is_some() {
-                return Err(Error::bad_database(
-                    r#"Malformed pushrule contains more than one of these actions: ["dont_notify", "notify", "coalesce"]"#,
-                ));
-            }
+        self.remove_rejected_pushers(user, &result?)
+    }
 
-            notify = Some(n);
+    /// Sends the notification to one push gateway, giving up after the pusher's timeout. Returns
+    /// the pushkeys the gateway rejected.
+    async fn send_to_gateway(
+        &self,
+        destination: &str,
+        notification: Notification,
+        settings: &PusherSettings,
+        full_event: Option<&PduEvent>,
+    ) -> Result<Vec<String>> {
+        let timeout = settings.retry_policy(default_retry_policy()).timeout;
+
+        let result = tokio::time::timeout(
+            timeout,
+            self.send_enveloped(destination, notification, settings, full_event),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            warn!("Timeout waiting for push gateway response of {destination}");
+            Err(Error::BadServerResponse(
+                "Timeout waiting for push gateway response",
+            ))
+        });
+
+        // The gateway might be unhealthy now, so it has to be probed again
+        if result.is_err() {
+            if let Some(host) = gateway_host(destination) {
+                self.gateway_probes.lock().unwrap().invalidate(&host);
+            }
         }
 
-        if notify == Some(true) {
-            self.send_notice(unread, pusher, tweaks, pdu).await?;
+        result
+    }
+
+    /// Removes the pushers of the user with pushkeys the push gateway rejected, e.g. because the
+    /// app was uninstalled.
+    fn remove_rejected_pushers(&self, user: &UserId, rejected: &[String]) -> Result<()> {
+        self.transaction(|| {
+            for pushkey in rejected {
+                if let Some(pusher) = self.get_pusher(user, pushkey)? {
+                    warn!(
+                        "Push gateway rejected pushkey {} of {}, removing the pusher",
+                        pushkey, user
+                    );
+                    self.set_pusher(user, set_pusher::v3::PusherAction::Delete(pusher.ids))?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Returns whether the pusher is paused because its gateway kept failing.
+    pub fn is_paused(&self, sender: &UserId, pushkey: &str) -> Result<bool> {
+        Ok(still_paused(
+            self.get_pusher_settings(sender, pushkey)?.paused_until,
+            utils::millis_since_unix_epoch(),
+        ))
+    }
+
+    /// Stops sending to the pusher for the configured time, after its gateway failed for all
+    /// delivery attempts.
+    pub fn pause_pusher(&self, sender: &UserId, pushkey: &str) -> Result<()> {
+        let paused_until = utils::millis_since_unix_epoch().saturating_add(
+            services()
+                .globals
+                .push_pause_duration()
+                .saturating_mul(1000),
+        );
+
+        let mut settings = self.get_pusher_settings(sender, pushkey)?;
+        settings.paused_until = Some(paused_until);
+        self.db.set_pusher_settings(sender, pushkey, &settings)
+    }
+
+    /// Sends to the pusher again before its pause is over.
+    pub fn resume_pusher(&self, sender: &UserId, pushkey: &str) -> Result<()> {
+        let mut settings = self.get_pusher_settings(sender, pushkey)?;
+        if settings.paused_until.take().is_some() {
+            self.db.set_pusher_settings(sender, pushkey, &settings)?;
         }
-        // Else the event triggered no actions
 
         Ok(())
     }
 
-    #[tracing::instrument(skip(self, user, ruleset, pdu))]
-    pub fn get_actions<'a>(
+    /// Probes the health of the push gateway behind this url. Responses of the same host are
+    /// reused for the configured time.
+    #[tracing::instrument(skip(self))]
+    pub async fn probe_gateway(&self, url: &str) -> Result<GatewayProbe> {
+        let host = gateway_host(url).ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid push gateway url.",
+        ))?;
+
+        let ttl = services().globals.push_gateway_probe_ttl();
+        if let Some(probe) = self
+            .gateway_probes
+            .lock()
+            .unwrap()
+            .get(&host, ttl, Instant::now())
+        {
+            debug!("Using cached probe response of {}", host);
+            return Ok(probe);
+        }
+
+        let mut health_url = reqwest::Url::parse(url).expect("url was parsed before");
+        health_url.set_path("/health");
+        health_url.set_query(None);
+
+        let response = services()
+            .globals
+            .default_client()
+            .get(health_url)
+            .timeout(services().globals.push_timeout())
+            .send()
+            .await;
+
+        let response = match response {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                self.gateway_probes.lock().unwrap().invalidate(&host);
+                return Err(Error::PushGatewayError(response.status()));
+            }
+            Err(e) => {
+                warn!("Could not probe push gateway {}: {}", host, e);
+                self.gateway_probes.lock().unwrap().invalidate(&host);
+                return Err(e.into());
+            }
+        };
+
+        let probe = GatewayProbe {
+            status: response.status().as_u16(),
+            body: response.text().await.unwrap_or_default(),
+        };
+
+        self.gateway_probes
+            .lock()
+            .unwrap()
+            .insert(host, probe.clone(), Instant::now());
+
+        Ok(probe)
+    }
+
+    /// Sends the notification to the push gateway, wrapped in the configured envelope. Returns the
+    /// pushkeys the gateway rejected.
+    #[tracing::instrument(skip(self, destination, notification, settings, full_event))]
+    async fn send_enveloped(
+        &self,
+        destination: &str,
+        notification: Notification,
+        settings: &PusherSettings,
+        full_event: Option<&PduEvent>,
+    ) -> Result<Vec<String>> {
+        let envelope = services().globals.push_gateway_envelope();
+
+        // Debugging aid to catch changes that break the payload for push gateways
+        if services().globals.push_validate_payloads() {
+            if let Some(mismatch) =
+                notification_schema_mismatch(&notification_json(&notification, full_event))
+            {
+                error!(
+                    "Notification for {} doesn't match the push gateway schema: {}",
+                    destination, mismatch
+                );
+                if cfg!(debug_assertions) {
+                    return Err(Error::BadServerResponse(
+                        "Notification doesn't match the push gateway schema.",
+                    ));
+                }
+            }
+        }
+
+        if envelope == PushGatewayEnvelope::Matrix && full_event.is_none() {
+            let response = self
+                .send_request(
+                    destination,
+                    send_event_notification::v1::Request::new(notification),
+                    settings,
+                )
+                .await?;
+
+            return Ok(response.rejected);
+        }
+
+        let body = envelope_body(envelope, notification_json(&notification, full_event));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
+        set_gateway_headers(&mut headers, settings)?;
+
+        let response = services()
+            .globals
+            .default_client()
+            .post(destination)
+            .headers(headers)
+            .body(serde_json::to_vec(&body).expect("JSON value can be serialized"))
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("Could not send request to pusher {}: {}", destination, e);
+                e
+            })?;
+
+        if !response.status().is_success() {
+            info!(
+                "Push gateway returned bad response {} {}",
+                destination,
+                response.status()
+            );
+            return Err(Error::PushGatewayError(response.status()));
+        }
+
+        Ok(rejected_pushkeys(
+            &response.bytes().await.unwrap_or_default(),
+        ))
+    }
+
+    /// Notifies the given pushers of the user about the event, if their push rules say so. The
+    /// rules are evaluated once, the pushers are sent to in parallel and their outcomes are
+    /// returned in the same order.
+    #[tracing::instrument(
+        skip(self, user, unread, pushers, ruleset, pdu),
+        fields(event_id = %pdu.event_id, user_id = %user)
+    )]
+    pub async fn send_push_notice(
         &self,
         user: &UserId,
-        ruleset: &'a Ruleset,
-        power_levels: &RoomPowerLevelsEventContent,
-        pdu: &Raw<AnySyncTimelineEvent>,
-        room_id: &RoomId,
-    ) -> Result<&'a [Action]> {
-        let ctx = PushConditionRoomCtx {
-            room_id: room_id.to_owned(),
-            member_count: 10_u32.into(), // TODO: get member count efficiently
-            user_id: user.to_owned(),
-            user_display_name: services()
-                .users
-                .displayname(user)?
-                .unwrap_or_else(|| user.localpart().to_owned()),
-            users_power_levels: power_levels.users.clone(),
-            default_power_level: power_levels.users_default,
-            notification_power_levels: power_levels.notifications.clone(),
-        };
+        unread: UInt,
+        pushers: Vec<Pusher>,
+        ruleset: Ruleset,
+        pdu: &PduEvent,
+    ) -> Result<Vec<Result<()>>> {
+        let skipped = pushers.iter().map(|_| Ok(())).collect();
 
-        Ok(ruleset.get_actions(pdu, &ctx))
+        // Nothing can notify users that disabled all notifications, so don't evaluate anything
+        if self.is_push_disabled(user)? {
+            return Ok(skipped);
+        }
+
+        // Own events are only pushed to let the user's other devices know about edits
+        if pdu.sender == user {
+            if services().globals.push_self_edits() && pdu.is_edit() {
+                return Ok(
+                    for_each_device(pushers, MAX_PARALLEL_DEVICES, |pusher| async move {
+                        self.send_silent_notice(user, unread, &pusher, pdu).await
+                    })
+                    .await,
+                );
+            }
+            return Ok(skipped);
+        }
+
+        // The user might have left or been banned from the room while the push was queued
+        if !services().globals.push_after_leave()
+            && services().rooms.state_cache.is_left(user, &pdu.room_id)?
+        {
+            return Ok(skipped);
+        }
+
+        // The sender's server might have been denied by the room's ACL while the push was queued
+        if self.is_denied_by_acl(pdu)? {
+            debug!(
+                "Dropping notification for {}, the sender's server is denied by the ACL",
+                pdu.event_id
+            );
+            return Ok(skipped);
+        }
+
+        // Bots are noisy, users have to opt in to be notified about their events
+        if self.is_bot_sender(&pdu.sender)? && !self.bot_senders_settings(user)?.enabled {
+            return Ok(skipped);
+        }
+
+        let mut notify = None;
+        let mut tweaks = Vec::new();
+
+        let power_levels = self.power_levels(&pdu.room_id)?;
+
+        for action in self.get_actions(
+            user,
+            &ruleset,
+            &power_levels,
+            &pdu.to_sync_room_event(),
+            &pdu.room_id,
+        )? {
+            let n = match action {
+                Action::Notify => true,
+                Action::SetTweak(tweak) => {
+                    tweaks.push(tweak.clone());
+                    continue;
+                }
+                _ => false,
+            };
+
+            if notify.is_some() {
+                return Err(Error::bad_database(
+                    r#"Malformed pushrule contains more than one of these actions: ["dont_notify", "notify", "coalesce"]"#,
+                ));
+            }
+
+            notify = Some(n);
+        }
+
+        // Push rules don't cover most state events, but operators can opt into notifications for
+        // some of them, e.g. widgets
+        if notify.is_none() && (self.is_notifying_state_event(pdu) || self.is_scheduling_event(pdu))
+        {
+            notify = Some(true);
+        }
+
+        if notify == Some(true) {
+            let highlight = tweaks.iter().any(|t| matches!(t, Tweak::Highlight(true)));
+
+            // Only senders with the power level for notifying the whole room may do so, even if
+            // the user's rules would notify about the message anyway
+            if suppresses_room_mention(pdu, &power_levels, highlight) {
+                debug!(
+                    "Dropping notification for {}, the sender may not mention the room",
+                    pdu.event_id
+                );
+                return Ok(skipped);
+            }
+
+            let digest = self.digest_settings(user)?;
+
+            return Ok(for_each_device(pushers, MAX_PARALLEL_DEVICES, |pusher| {
+                let (tweaks, digest) = (tweaks.clone(), &digest);
+                async move {
+                    self.notify_pusher(user, unread, &pusher, tweaks, highlight, digest, pdu)
+                        .await
+                }
+            })
+            .await);
+        }
+        // Else the event triggered no actions
+
+        Ok(skipped)
     }
 
-    #[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
-    async fn send_notice(
+    /// Sends the notification about the event to one pusher, or adds it to the pusher's digest.
+    #[allow(clippy::too_many_arguments)]
+    async fn notify_pusher(
         &self,
+        user: &UserId,
         unread: UInt,
         pusher: &Pusher,
         tweaks: Vec<Tweak>,
-        event: &PduEvent,
+        highlight: bool,
+        digest: &PushDigestSettings,
+        pdu: &PduEvent,
     ) -> Result<()> {
-        // TODO: email
-        match &pusher.kind {
-            PusherKind::Http(http) => {
-                // TODO:
-                // Two problems with this
-                // 1. if "event_id_only" is the only format kind it seems we should never add more info
-                // 2. can pusher/devices have conflicting formats
-                let event_id_only = http.format == Some(PushFormat::EventIdOnly);
+        // Only the first message of a conversation notifies until the user reads the room,
+        // mentions still get through
+        let now = utils::millis_since_unix_epoch();
+        let window = services().globals.push_first_message_window();
+        if window.is_some() {
+            let last_notified = self
+                .db
+                .last_notified(user, &pdu.room_id, &pusher.ids.pushkey)?;
+            if !highlight && notified_within(last_notified, now, window) {
+                return Ok(());
+            }
+        }
 
-                let mut device = Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
-                device.data.default_payload = http.default_payload.clone();
-                device.data.format = http.format.clone();
+        let email = matches!(pusher.kind, PusherKind::Email(_));
+        if email && services().globals.smtp().is_none() {
+            return Ok(());
+        }
 
-                // Tweaks are only added if the format is NOT event_id_only
-                if !event_id_only {
-                    device.tweaks = tweaks.clone();
-                }
+        if queues_for_digest(digest, email, highlight) {
+            self.db.queue_digest_event(
+                user,
+                &pusher.ids.pushkey,
+                &pdu.event_id,
+                utils::millis_since_unix_epoch(),
+            )?;
+        } else {
+            self.send_notice(user, unread, pusher, tweaks, pdu).await?;
+        }
 
-                let d = vec![device];
-                let mut notifi = Notification::new(d);
+        if window.is_some() {
+            self.db
+                .set_last_notified(user, &pdu.room_id, &pusher.ids.pushkey, now)?;
+        }
 
-                notifi.prio = NotificationPriority::Low;
-                notifi.event_id = Some((*event.event_id).to_owned());
-                notifi.room_id = Some((*event.room_id).to_owned());
-                // TODO: missed calls
-                notifi.counts = NotificationCounts::new(unread, uint!(0));
+        Ok(())
+    }
+
+    /// Returns whether the event would trigger a notification for this user, without sending
+    /// anything.
+    pub fn would_notify(&self, user: &UserId, event_id: &EventId) -> Result<bool> {
+        let pdu = services()
+            .rooms
+            .timeline
+            .get_pdu(event_id)?
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+        let ruleset = self.ruleset(user)?;
+        let power_levels = self.power_levels(&pdu.room_id)?;
+
+        Ok(notifies(self.get_actions(
+            user,
+            &ruleset,
+            &power_levels,
+            &pdu.to_sync_room_event(),
+            &pdu.room_id,
+        )?))
+    }
+
+    /// Returns the push rules of this user, or the server default rules if they have none.
+    pub fn ruleset(&self, user: &UserId) -> Result<Ruleset> {
+        Ok(services()
+            .account_data
+            .get(
+                None,
+                user,
+                GlobalAccountDataEventType::PushRules.to_string().into(),
+            )?
+            .and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
+            .map(|ev: PushRulesEvent| ev.content.global)
+            .unwrap_or_else(|| Ruleset::server_default(user)))
+    }
+
+    /// Returns whether the user disabled all notifications by enabling the master push rule.
+    pub fn is_push_disabled(&self, user: &UserId) -> Result<bool> {
+        if let Some(disabled) = self.push_disabled.lock().unwrap().get(user) {
+            return Ok(*disabled);
+        }
+
+        let disabled = master_rule_disables_push(&self.ruleset(user)?);
+        self.push_disabled
+            .lock()
+            .unwrap()
+            .insert(user.to_owned(), disabled);
+
+        Ok(disabled)
+    }
+
+    /// Forgets the cached master push rule state of the user, e.g. because their rules changed.
+    pub fn invalidate_push_disabled(&self, user: &UserId) {
+        self.push_disabled.lock().unwrap().remove(user);
+    }
+
+    pub fn power_levels(&self, room_id: &RoomId) -> Result<RoomPowerLevelsEventContent> {
+        Ok(services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|ev| {
+                serde_json::from_str(ev.content.get())
+                    .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
+            })
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /// Returns whether the event is of one of the configured scheduling event types, e.g. a
+    /// calendar entry.
+    fn is_scheduling_event(&self, pdu: &PduEvent) -> bool {
+        pdu.state_key.is_none()
+            && services()
+                .globals
+                .push_scheduling_event_types()
+                .iter()
+                .any(|event_type| *event_type == pdu.kind.to_string())
+    }
+
+    /// Returns whether the current server ACL of the room denies the server of the sender.
+    fn is_denied_by_acl(&self, pdu: &PduEvent) -> Result<bool> {
+        let acl = services()
+            .rooms
+            .state_accessor
+            .room_state_get(&pdu.room_id, &StateEventType::RoomServerAcl, "")?
+            .and_then(|event| {
+                serde_json::from_str::<RoomServerAclEventContent>(event.content.get())
+                    .map_err(|_| warn!("Invalid ACL event in room {}", pdu.room_id))
+                    .ok()
+            });
+
+        Ok(acl.map_or(false, |acl| denies_sender(&acl, &pdu.sender)))
+    }
+
+    fn is_notifying_state_event(&self, pdu: &PduEvent) -> bool {
+        pdu.state_key.is_some()
+            && services()
+                .globals
+                .push_notify_state_event_types()
+                .iter()
+                .any(|event_type| *event_type == pdu.kind.to_string())
+    }
+
+    /// Returns the notification digest settings of this user.
+    pub fn digest_settings(&self, user: &UserId) -> Result<PushDigestSettings> {
+        Ok(services()
+            .account_data
+            .get(None, user, PUSH_DIGEST_EVENT_TYPE.into())?
+            .and_then(|event| serde_json::from_str::<serde_json::Value>(event.get()).ok())
+            .and_then(|mut event| serde_json::from_value(event.get_mut("content")?.take()).ok())
+            .unwrap_or_default())
+    }
+
+    /// Allows all pushers of the user to notify about the room again.
+    pub fn clear_last_notified(&self, user: &UserId, room_id: &RoomId) -> Result<()> {
+        self.db.clear_last_notified(user, room_id)
+    }
+
+    /// Forgets about the missed calls of the user in the room, e.g. because they read it.
+    pub fn clear_missed_calls(&self, user: &UserId, room_id: &RoomId) -> Result<()> {
+        self.db.clear_missed_calls(user, room_id)
+    }
 
-                if event.kind == TimelineEventType::RoomEncrypted
-                    || tweaks
-                        .iter()
-                        .any(|t| matches!(t, Tweak::Highlight(true) | Tweak::Sound(_)))
+    /// Keeps track of unanswered call invites, so they can be counted as missed calls. Invites
+    /// stay counted after their lifetime expired until the call is answered or hung up, or the
+    /// user reads the room.
+    pub fn update_missed_calls(&self, pdu: &PduEvent) -> Result<()> {
+        match call_update(&pdu.kind, pdu.content.get()) {
+            Some(CallUpdate::Invite(call_id)) => {
+                for user in services()
+                    .rooms
+                    .state_cache
+                    .get_our_real_users(&pdu.room_id)?
+                    .iter()
+                    .filter(|user| *user != &pdu.sender)
                 {
-                    notifi.prio = NotificationPriority::High
+                    self.db.add_call_invite(&pdu.room_id, &call_id, user)?;
                 }
+                Ok(())
+            }
+            Some(CallUpdate::End(call_id)) => self.db.remove_call_invites(&pdu.room_id, &call_id),
+            None => Ok(()),
+        }
+    }
 
-                if event_id_only {
-                    self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-                        .await?;
-                } else {
-                    notifi.sender = Some(event.sender.clone());
-                    notifi.event_type = Some(event.kind.clone());
-                    notifi.content = serde_json::value::to_raw_value(&event.content).ok();
+    /// Queues a notification that failed before again, for delivery right away.
+    pub fn requeue_notification(&self, notification_id: &str) -> Result<FailedNotification> {
+        let failed = requeue_target(self.db.get_failed_notification(notification_id)?)?;
 
-                    if event.kind == TimelineEventType::RoomMember {
-                        notifi.user_is_target =
-                            event.state_key.as_deref() == Some(event.sender.as_str());
-                    }
+        if self.get_pusher(&failed.user_id, &failed.pushkey)?.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "The pusher of this notification was removed.",
+            ));
+        }
 
-                    notifi.sender_display_name = services().users.displayname(&event.sender)?;
+        services().sending.requeue_push_pdu(
+            &failed.pdu_id,
+            &failed.user_id,
+            failed.pushkey.clone(),
+        )?;
 
-                    let room_name = if let Some(room_name_pdu) = services()
-                        .rooms
-                        .state_accessor
-                        .room_state_get(&event.room_id, &StateEventType::RoomName, "")?
-                    {
-                        serde_json::from_str::<RoomNameEventContent>(room_name_pdu.content.get())
-                            .map_err(|_| {
-                                Error::bad_database("Invalid room name event in database.")
-                            })?
-                            .name
-                    } else {
-                        None
-                    };
+        Ok(failed)
+    }
 
-                    notifi.room_name = room_name;
+    /// Returns the topic to include in notifications about the room, if enabled.
+    fn room_topic(&self, room_id: &RoomId) -> Result<Option<String>> {
+        let enabled = services().globals.push_room_topic();
+        if !enabled {
+            return Ok(None);
+        }
+
+        let topic_event = services().rooms.state_accessor.room_state_get(
+            room_id,
+            &StateEventType::RoomTopic,
+            "",
+        )?;
+
+        Ok(notification_topic(
+            enabled,
+            topic_event.as_ref().map(|pdu| pdu.content.get()),
+        ))
+    }
+
+    /// Returns whether this user wants to be pushed for events of bot senders.
+    pub fn bot_senders_settings(&self, user: &UserId) -> Result<PushBotSendersSettings> {
+        Ok(services()
+            .account_data
+            .get(None, user, PUSH_BOT_SENDERS_EVENT_TYPE.into())?
+            .and_then(|event| serde_json::from_str::<serde_json::Value>(event.get()).ok())
+            .and_then(|mut event| serde_json::from_value(event.get_mut("content")?.take()).ok())
+            .unwrap_or_default())
+    }
+
+    /// Returns whether the sender is considered a bot, either because it matches one of the
+    /// configured patterns or because it is in the user namespace of an appservice.
+    pub fn is_bot_sender(&self, sender: &UserId) -> Result<bool> {
+        let appservice_users = if services().globals.push_suppress_appservice_users() {
+            services().appservice.user_namespaces()?
+        } else {
+            Arc::new(Vec::new())
+        };
+
+        Ok(matches_bot_sender(
+            sender,
+            services().globals.push_suppressed_senders(),
+            &appservice_users,
+        ))
+    }
+
+    pub fn start_digest_handler(&self) {
+        // Digests become due at different times, depending on when their first event came in
+        let period = Duration::from_secs(
+            services()
+                .globals
+                .push_digest_interval()
+                .clamp(1, DIGEST_CHECK_INTERVAL),
+        );
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
 
-                    self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-                        .await?;
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = services().pusher.send_digests().await {
+                    warn!("Failed to send notification digests: {}", e);
                 }
+            }
+        });
+    }
 
-                Ok(())
+    /// Regularly writes the buffered notification history to the database.
+    pub fn start_history_flusher(&self) {
+        let period = services().globals.notification_history_flush_interval();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = services().pusher.flush_notification_history() {
+                    warn!("Failed to flush notification history: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Writes the buffered notification history to the database, e.g. before shutting down.
+    /// Returns how many records were written.
+    pub fn flush_notification_history(&self) -> Result<usize> {
+        flush_history(&self.history_buffer, |records| {
+            self.db.append_notification_history(records)
+        })
+    }
+
+    /// Sends a silent notification to the pushers of local users sharing an encrypted room with
+    /// this user, so their clients can prompt to verify the user's new devices.
+    pub fn notify_device_list_change(&self, user_id: &UserId) -> Result<()> {
+        if !services().globals.push_device_list_changes() {
+            return Ok(());
+        }
+
+        let mut rooms = Vec::new();
+        for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+            let room_id = room_id?;
+            let encrypted = services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &StateEventType::RoomEncryption, "")?
+                .is_some();
+            if encrypted {
+                rooms.push(services().rooms.state_cache.get_our_real_users(&room_id)?);
+            }
+        }
+
+        let mut recipients = Vec::new();
+        for member in co_members(user_id, rooms.iter().map(|members| members.iter())) {
+            let pushers = self.get_pushers(&member)?;
+            if !pushers.is_empty() {
+                recipients.push((member, pushers));
+            }
+        }
+
+        if recipients.is_empty() {
+            return Ok(());
+        }
+
+        tokio::spawn(async move {
+            for (user, pushers) in recipients {
+                let outcomes = for_each_device(pushers, MAX_PARALLEL_DEVICES, |pusher| {
+                    let user = &user;
+                    async move {
+                        services()
+                            .pusher
+                            .send_device_list_notice(user, &pusher)
+                            .await
+                    }
+                })
+                .await;
+
+                for e in outcomes.into_iter().filter_map(Result::err) {
+                    warn!("Failed to send device list notification to {}: {}", user, e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, user, pusher), fields(user_id = %user))]
+    async fn send_device_list_notice(&self, user: &UserId, pusher: &Pusher) -> Result<()> {
+        match (&pusher.kind, device_list_notification(pusher)) {
+            (PusherKind::Http(http), Some(notifi)) => {
+                let settings = self.get_pusher_settings(user, &pusher.ids.pushkey)?;
+                self.send_notification(user, &pusher.ids.app_id, &http.url, notifi, &settings, None)
+                    .await
             }
-            // TODO: Handle email
-            PusherKind::Email(_) => Ok(()),
             _ => Ok(()),
         }
     }
+
+    /// Sends a silent notification about the redaction of an event to the pushers that were
+    /// notified about the event, so the clients can remove the stale notification.
+    pub fn notify_redaction(&self, room_id: &RoomId, redacted_id: &EventId) -> Result<()> {
+        if !services().globals.push_redaction_updates() {
+            return Ok(());
+        }
+
+        let notified = self
+            .db
+            .notified_pushers(redacted_id)
+            .collect::<Result<Vec<_>>>()?;
+        self.db.forget_notified_event(redacted_id)?;
+
+        let mut recipients = Vec::new();
+        for (user, pushkey) in notified {
+            if let Some(pusher) = self.get_pusher(&user, &pushkey)? {
+                recipients.push((user, pusher));
+            }
+        }
+
+        if recipients.is_empty() {
+            return Ok(());
+        }
+
+        let room_id = room_id.to_owned();
+        let redacted_id = redacted_id.to_owned();
+        tokio::spawn(async move {
+            for (user, pusher) in recipients {
+                if let Err(e) = services()
+                    .pusher
+                    .send_redaction_notice(&user, &pusher, &room_id, &redacted_id)
+                    .await
+                {
+                    warn!("Failed to send redaction notification to {}: {}", user, e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, user, pusher), fields(user_id = %user))]
+    async fn send_redaction_notice(
+        &self,
+        user: &UserId,
+        pusher: &Pusher,
+        room_id: &RoomId,
+        redacted_id: &EventId,
+    ) -> Result<()> {
+        match (
+            &pusher.kind,
+            redaction_notification(pusher, room_id, redacted_id),
+        ) {
+            (PusherKind::Http(http), Some(notifi)) => {
+                let settings = self.get_pusher_settings(user, &pusher.ids.pushkey)?;
+                self.send_notification(user, &pusher.ids.app_id, &http.url, notifi, &settings, None)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends one summary notification to every pusher with pending digest events.
+    #[tracing::instrument(skip(self))]
+    pub async fn send_digests(&self) -> Result<()> {
+        let digest_pushers = self.db.digest_pushers().collect::<Result<Vec<_>>>()?;
+        let now = utils::millis_since_unix_epoch();
+        let interval = services().globals.push_digest_interval();
+
+        let mut digests: BTreeMap<OwnedUserId, Vec<_>> = BTreeMap::new();
+        for (user, pushkey) in digest_pushers {
+            let digest = match pending_digest(self.db.digest_events(&user, &pushkey)?) {
+                Some(digest) => digest,
+                None => continue,
+            };
+
+            let pusher = match self.get_pusher(&user, &pushkey)? {
+                Some(pusher) => pusher,
+                None => {
+                    self.db
+                        .remove_digest_events(&user, &pushkey, digest.until)?;
+                    continue;
+                }
+            };
+
+            let events = digest
+                .event_ids
+                .iter()
+                .filter_map(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten())
+                .collect::<Vec<_>>();
+
+            // The digest is sent once its oldest event waited for the whole interval. This is
+            // measured from when the event was queued, as senders choose origin_server_ts.
+            let interval = digest_interval(
+                &self.digest_settings(&user)?,
+                matches!(pusher.kind, PusherKind::Email(_)),
+                interval,
+            );
+            let oldest = (!events.is_empty()).then_some(digest.queued_at);
+            if !digest_due(oldest, now, interval) {
+                continue;
+            }
+
+            digests
+                .entry(user)
+                .or_default()
+                .push((pusher, digest.until, events));
+        }
+
+        for (user, digests) in digests {
+            let outcomes =
+                for_each_device(digests, MAX_PARALLEL_DEVICES, |(pusher, until, events)| {
+                    let user = &user;
+                    async move {
+                        self.send_digest(user, &pusher, &events).await?;
+
+                        // Only forget about the events once they were sent, else they are part of
+                        // the next attempt
+                        self.db
+                            .remove_digest_events(user, &pusher.ids.pushkey, until)
+                    }
+                })
+                .await;
+
+            for e in outcomes.into_iter().filter_map(Result::err) {
+                warn!("Failed to send notification digest to {}: {}", user, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, user, pusher, events), fields(user_id = %user))]
+    async fn send_digest(
+        &self,
+        user: &UserId,
+        pusher: &Pusher,
+        events: &[Arc<PduEvent>],
+    ) -> Result<()> {
+        let last = match events.last() {
+            Some(last) => last,
+            None => return Ok(()),
+        };
+
+        match &pusher.kind {
+            PusherKind::Http(http) => {
+                let mut device = Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
+                device.data.default_payload = http.default_payload.clone();
+                device.data.format = resolve_push_format(http, &device.data);
+                let event_id_only = device.data.format == Some(PushFormat::EventIdOnly);
+
+                let mut notifi = Notification::new(vec![device]);
+                notifi.prio = NotificationPriority::Low;
+                notifi.event_id = Some((*last.event_id).to_owned());
+
+                let rooms = events
+                    .iter()
+                    .map(|event| &*event.room_id)
+                    .collect::<HashSet<_>>();
+                if rooms.len() == 1 {
+                    notifi.room_id = Some((*last.room_id).to_owned());
+                }
+
+                let mut unread = 0;
+                for room_id in &rooms {
+                    unread += services().rooms.user.notification_count(user, room_id)?;
+                }
+                notifi.counts = NotificationCounts::new(
+                    unread
+                        .try_into()
+                        .expect("notification count can't go that high"),
+                    uint!(0),
+                );
+
+                if !event_id_only {
+                    notifi.content = serde_json::value::to_raw_value(&json!({
+                        "msgtype": "m.notice",
+                        "body": digest_summary(events.len(), rooms.len()),
+                    }))
+                    .ok();
+                }
+
+                let settings = self.get_pusher_settings(user, &pusher.ids.pushkey)?;
+                self.send_notification(
+                    user,
+                    &pusher.ids.app_id,
+                    &http.url,
+                    notifi,
+                    &settings,
+                    None,
+                )
+                .await?;
+
+                Ok(())
+            }
+            PusherKind::Email(_) => self.send_email(pusher, events).await,
+            _ => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(
+        skip(self, user, ruleset, power_levels, pdu, room_id),
+        fields(user_id = %user, room_id = %room_id)
+    )]
+    pub fn get_actions<'a>(
+        &self,
+        user: &UserId,
+        ruleset: &'a Ruleset,
+        power_levels: &RoomPowerLevelsEventContent,
+        pdu: &Raw<AnySyncTimelineEvent>,
+        room_id: &RoomId,
+    ) -> Result<&'a [Action]> {
+        let ctx = self.room_ctx(user, power_levels, room_id)?;
+
+        // Room specific rules, e.g. "mentions only" or muted rooms, are part of the ruleset and
+        // evaluated in the order of the spec: override, content, room, sender, underride
+        Ok(ruleset.get_actions(pdu, &ctx))
+    }
+
+    /// Returns how every push rule of the user was evaluated for the event, up to the rule that
+    /// decided the actions.
+    pub fn explain_actions(&self, user: &UserId, event_id: &EventId) -> Result<Vec<RuleTrace>> {
+        let pdu = services()
+            .rooms
+            .timeline
+            .get_pdu(event_id)?
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+        let ruleset = self.ruleset(user)?;
+        let power_levels = self.power_levels(&pdu.room_id)?;
+        let ctx = self.room_ctx(user, &power_levels, &pdu.room_id)?;
+
+        Ok(trace_ruleset(&ruleset, &pdu.to_sync_room_event(), &ctx))
+    }
+
+    fn room_ctx(
+        &self,
+        user: &UserId,
+        power_levels: &RoomPowerLevelsEventContent,
+        room_id: &RoomId,
+    ) -> Result<PushConditionRoomCtx> {
+        // Rules like .m.rule.room_one_to_one depend on the real member count
+        let member_count = services()
+            .rooms
+            .state_cache
+            .room_joined_count(room_id)?
+            .unwrap_or_default();
+
+        Ok(push_condition_ctx(
+            room_id,
+            UInt::new_saturating(member_count),
+            user,
+            services()
+                .users
+                .displayname(user)?
+                .unwrap_or_else(|| user.localpart().to_owned()),
+            power_levels,
+        ))
+    }
+
+    /// Sends a low priority notification without tweaks, so clients can update in the background.
+    #[tracing::instrument(
+        skip(self, user, unread, pusher, event),
+        fields(event_id = %event.event_id, user_id = %user)
+    )]
+    async fn send_silent_notice(
+        &self,
+        user: &UserId,
+        unread: UInt,
+        pusher: &Pusher,
+        event: &PduEvent,
+    ) -> Result<()> {
+        match &pusher.kind {
+            PusherKind::Http(http) => {
+                let mut device = Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
+                device.data.default_payload = http.default_payload.clone();
+                device.data.format = resolve_push_format(http, &device.data);
+
+                let mut notifi = Notification::new(vec![device]);
+                notifi.prio = NotificationPriority::Low;
+                notifi.event_id = Some((*event.event_id).to_owned());
+                notifi.room_id = Some((*event.room_id).to_owned());
+                notifi.counts = NotificationCounts::new(unread, uint!(0));
+
+                let settings = self.get_pusher_settings(user, &pusher.ids.pushkey)?;
+                self.send_notification(user, &pusher.ids.app_id, &http.url, notifi, &settings, None)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether the event is a reply in a thread the user started or replied to before.
+    fn is_participated_thread_reply(&self, user: &UserId, event: &PduEvent) -> Result<bool> {
+        let thread_root = match event.relates_to() {
+            Some((rel_type, thread_root)) if rel_type == "m.thread" => thread_root,
+            _ => return Ok(false),
+        };
+
+        let root = services().rooms.timeline.get_pdu(&thread_root)?;
+        let replies =
+            services()
+                .rooms
+                .pdu_metadata
+                .relations(&thread_root, Some("m.thread"), None)?;
+
+        Ok(participates_in_thread(
+            user,
+            root.iter().chain(&replies).map(|pdu| &*pdu.sender),
+        ))
+    }
+
+    /// Returns the event content to include in a notification. The body is rendered using the
+    /// pusher's template, if any, and truncated to the configured maximum length.
+    fn notification_content(
+        &self,
+        event: &PduEvent,
+        settings: &PusherSettings,
+        sender_display_name: Option<&str>,
+        room_name: Option<&str>,
+    ) -> Option<Box<RawJsonValue>> {
+        let mut content = serde_json::from_str::<serde_json::Value>(event.content.get()).ok()?;
+
+        if self.is_notifying_state_event(event) {
+            if let Some(content) = content.as_object_mut() {
+                let body = state_event_body(
+                    &event.kind.to_string(),
+                    event.state_key.as_deref().unwrap_or_default(),
+                    content,
+                    sender_display_name.unwrap_or(event.sender.as_str()),
+                );
+                content.insert("body".to_owned(), body.into());
+            }
+        }
+
+        if let Some(body) = content.as_object().and_then(audio_body) {
+            content["body"] = body.into();
+        }
+
+        if let Some((body, geo_uri)) = content
+            .as_object()
+            .and_then(|content| location_body(&event.kind.to_string(), content))
+        {
+            content["body"] = body.into();
+            if let Some(geo_uri) = geo_uri {
+                content["geo_uri"] = geo_uri.into();
+            }
+        }
+
+        if self.is_scheduling_event(event) {
+            if let Some(content) = content.as_object_mut() {
+                let body = scheduling_body(content);
+                content.insert("body".to_owned(), body.into());
+            }
+        }
+
+        if let Some(body) = content.get_mut("body") {
+            if let Some(text) = body.as_str() {
+                let text = match &settings.template {
+                    Some(template) => render_template(
+                        template,
+                        sender_display_name.unwrap_or(event.sender.as_str()),
+                        room_name.unwrap_or(event.room_id.as_str()),
+                        text,
+                    ),
+                    None => text.to_owned(),
+                };
+
+                *body =
+                    utils::truncate_with_ellipsis(&text, services().globals.max_push_body_length())
+                        .into_owned()
+                        .into();
+            }
+        }
+
+        if let Some(in_reply_to) = event.in_reply_to() {
+            // The replied-to event might be unknown to us, or in another room the user can't see
+            let replied = services()
+                .rooms
+                .timeline
+                .get_pdu(&in_reply_to)
+                .ok()
+                .flatten()
+                .filter(|replied| replied.room_id == event.room_id);
+            let sender_display_name = replied
+                .as_ref()
+                .and_then(|replied| services().users.displayname(&replied.sender).ok().flatten());
+
+            if let Some(content) = content.as_object_mut() {
+                content.insert(
+                    REPLY_CONTEXT_KEY.to_owned(),
+                    reply_context(
+                        &in_reply_to,
+                        replied.as_deref(),
+                        sender_display_name.as_deref(),
+                    ),
+                );
+            }
+        }
+
+        serde_json::value::to_raw_value(&content).ok()
+    }
+
+    #[tracing::instrument(
+        skip(self, user, unread, pusher, tweaks, event),
+        fields(
+            event_id = %event.event_id,
+            user_id = %user,
+            notification_id = tracing::field::Empty,
+        )
+    )]
+    async fn send_notice(
+        &self,
+        user: &UserId,
+        unread: UInt,
+        pusher: &Pusher,
+        tweaks: Vec<Tweak>,
+        event: &PduEvent,
+    ) -> Result<()> {
+        // Notifications that were stuck in the queue, e.g. during an outage of the push gateway,
+        // would only disturb the user by now
+        if is_stale(
+            event.origin_server_ts,
+            utils::millis_since_unix_epoch(),
+            services().globals.push_max_notification_age(),
+        ) {
+            self.stale_notifications.fetch_add(1, Ordering::Relaxed);
+            debug!("Dropping stale notification for {}", event.event_id);
+            return Ok(());
+        }
+
+        let settings = self.get_pusher_settings(user, &pusher.ids.pushkey)?;
+        if let Some(active_hours) = &settings.active_hours {
+            if !active_hours.contains(utils::millis_since_unix_epoch()) {
+                debug!(
+                    "Not notifying pusher {} outside of its active hours",
+                    pusher.ids.pushkey
+                );
+                return Ok(());
+            }
+        }
+
+        let tweaks = self.get_tweak_preferences(user)?.filter(tweaks);
+
+        let notification_id = notification_id();
+        tracing::Span::current().record("notification_id", notification_id.as_str());
+        debug!("Sending notification {}", notification_id);
+
+        let pdu_id = services()
+            .rooms
+            .timeline
+            .get_pdu_id(&event.event_id)?
+            .ok_or_else(|| Error::bad_database("Notified event has no pdu id."))?;
+
+        let result = self
+            .deliver_notice(
+                user,
+                unread,
+                pusher,
+                &settings,
+                tweaks,
+                event,
+                &notification_id,
+            )
+            .await;
+
+        self.history_buffer
+            .lock()
+            .unwrap()
+            .push(NotificationRecord {
+                notification_id: notification_id.clone(),
+                user_id: user.to_owned(),
+                pushkey: pusher.ids.pushkey.clone(),
+                event_id: (*event.event_id).to_owned(),
+                sent_at: utils::millis_since_unix_epoch(),
+                delivered: result.is_ok(),
+            });
+
+        if result.is_ok() {
+            self.db
+                .clear_failed_notifications(user, &pusher.ids.pushkey, &pdu_id)?;
+
+            // Clients can only remove the notification again if they're told about the redaction
+            if services().globals.push_redaction_updates() {
+                self.db
+                    .mark_notified_event(&event.event_id, user, &pusher.ids.pushkey)?;
+            }
+        } else {
+            self.db.record_failed_notification(
+                &notification_id,
+                &FailedNotification {
+                    user_id: user.to_owned(),
+                    pushkey: pusher.ids.pushkey.clone(),
+                    pdu_id,
+                },
+            )?;
+        }
+
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn deliver_notice(
+        &self,
+        user: &UserId,
+        unread: UInt,
+        pusher: &Pusher,
+        settings: &PusherSettings,
+        tweaks: Vec<Tweak>,
+        event: &PduEvent,
+        notification_id: &str,
+    ) -> Result<()> {
+        match &pusher.kind {
+            PusherKind::Http(http) => {
+                let mut device = Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
+                device.data.default_payload = versioned_payload(
+                    settings.format_version(),
+                    http.default_payload.clone(),
+                    notification_id,
+                    &notification_group(event),
+                );
+                let conduit_payload = settings.format_version() >= 2;
+                if conduit_payload && settings.quick_actions {
+                    device.data.default_payload[QUICK_ACTIONS_KEY] =
+                        quick_actions(&event.room_id, &event.event_id);
+                }
+                device.data.format = resolve_push_format(http, &device.data);
+                let event_id_only = device.data.format == Some(PushFormat::EventIdOnly);
+
+                // Tweaks are only added if the format is NOT event_id_only
+                if !event_id_only {
+                    device.tweaks = tweaks.clone();
+
+                    if let Some(topic) =
+                        self.room_topic(&event.room_id)?.filter(|_| conduit_payload)
+                    {
+                        device.data.default_payload[ROOM_TOPIC_KEY] = topic.into();
+                    }
+                }
+
+                let d = vec![device];
+                let mut notifi = Notification::new(d);
+
+                notifi.prio = NotificationPriority::Low;
+                notifi.event_id = Some((*event.event_id).to_owned());
+                notifi.room_id = Some((*event.room_id).to_owned());
+                notifi.counts = NotificationCounts::new(
+                    unread,
+                    UInt::new_saturating(self.db.missed_calls(user, &event.room_id)?),
+                );
+
+                let participated_thread_reply =
+                    services().globals.push_participated_threads_high_priority()
+                        && self.is_participated_thread_reply(user, event)?;
+
+                notifi.prio = notification_priority(
+                    event.kind == TimelineEventType::RoomEncrypted
+                        || tweaks
+                            .iter()
+                            .any(|t| matches!(t, Tweak::Highlight(true) | Tweak::Sound(_))),
+                    participated_thread_reply,
+                );
+                // The unread count is reset when the user reads the room, so it counts the
+                // notifications the user hasn't acknowledged yet
+                notifi.prio = escalated_priority(
+                    notifi.prio,
+                    unread.into(),
+                    services().globals.push_escalation_threshold(),
+                );
+
+                if event_id_only {
+                    self.send_notification(
+                        user,
+                        &pusher.ids.app_id,
+                        &http.url,
+                        notifi,
+                        settings,
+                        None,
+                    )
+                    .await?;
+                } else {
+                    notifi.sender = Some(event.sender.clone());
+                    notifi.event_type = Some(event.kind.clone());
+
+                    if event.kind == TimelineEventType::RoomMember {
+                        notifi.user_is_target =
+                            event.state_key.as_deref() == Some(event.sender.as_str());
+                    }
+
+                    notifi.sender_display_name = services().users.displayname(&event.sender)?;
+
+                    let room_name = self.room_name(&event.room_id)?;
+
+                    notifi.content = self.notification_content(
+                        event,
+                        settings,
+                        notifi.sender_display_name.as_deref(),
+                        room_name.as_deref(),
+                    );
+
+                    notifi.room_name = room_name;
+
+                    let mut full_event = (conduit_payload
+                        && settings.sends_full_event(
+                            &http.url,
+                            services().globals.push_full_event_gateways(),
+                        ))
+                    .then_some(event);
+
+                    if notifi.content.is_none() {
+                        warn!(
+                            "Failed to serialize the content of {} for a notification",
+                            event.event_id
+                        );
+                        apply_content_fallback(
+                            &mut notifi,
+                            services().globals.push_content_fallback(),
+                        );
+                        full_event = None;
+                    }
+
+                    self.send_notification(
+                        user,
+                        &pusher.ids.app_id,
+                        &http.url,
+                        notifi,
+                        settings,
+                        full_event,
+                    )
+                    .await?;
+                }
+
+                Ok(())
+            }
+            // Emails are usually sent as a digest of all pending events, see `send_digests`, this
+            // is only reached by highlights the user wants to be emailed about immediately
+            PusherKind::Email(_) => self.send_email(pusher, &[Arc::new(event.clone())]).await,
+            _ => Ok(()),
+        }
+    }
+
+    fn room_name(&self, room_id: &RoomId) -> Result<Option<String>> {
+        Ok(
+            if let Some(room_name_pdu) = services().rooms.state_accessor.room_state_get(
+                room_id,
+                &StateEventType::RoomName,
+                "",
+            )? {
+                serde_json::from_str::<RoomNameEventContent>(room_name_pdu.content.get())
+                    .map_err(|_| Error::bad_database("Invalid room name event in database."))?
+                    .name
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Sends one email about all the events to the address of an email pusher.
+    #[tracing::instrument(skip(self, pusher, events))]
+    async fn send_email(&self, pusher: &Pusher, events: &[Arc<PduEvent>]) -> Result<()> {
+        let (transport, from) = match services().globals.smtp() {
+            Some(smtp) => smtp,
+            None => return Ok(()),
+        };
+
+        // Email pushers use the address as their pushkey
+        let to = pusher.ids.pushkey.parse::<Mailbox>().map_err(|_| {
+            Error::BadRequest(ErrorKind::InvalidParam, "Invalid address of email pusher.")
+        })?;
+        let event_id_only = serde_json::to_value(&pusher.kind)
+            .ok()
+            .and_then(|kind| {
+                kind.pointer("/data/format")?
+                    .as_str()
+                    .map(ToOwned::to_owned)
+            })
+            .as_deref()
+            == Some("event_id_only");
+
+        let mut email_events = Vec::new();
+        for event in events {
+            let sender = services()
+                .users
+                .displayname(&event.sender)?
+                .unwrap_or_else(|| event.sender.to_string());
+            let room = self
+                .room_name(&event.room_id)?
+                .unwrap_or_else(|| event.room_id.to_string());
+            let snippet = (!event_id_only && event.kind != TimelineEventType::RoomEncrypted)
+                .then(|| email_snippet(event))
+                .flatten();
+
+            email_events.push(EmailEvent {
+                sender,
+                room,
+                snippet,
+            });
+        }
+
+        let (subject, body) = match notification_email(&email_events) {
+            Some(email) => email,
+            None => return Ok(()),
+        };
+
+        let email = Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| {
+                warn!("Failed to build notification email: {}", e);
+                Error::BadServerResponse("Failed to build notification email.")
+            })?;
+
+        transport.send(email).await.map_err(|e| {
+            warn!("Failed to send notification email: {}", e);
+            Error::BadServerResponse("Failed to send notification email.")
+        })?;
+
+        Ok(())
+    }
+}
+
+fn default_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: services().globals.push_max_attempts(),
+        timeout: services().globals.push_timeout(),
+    }
+}
+
+/// Checks a serialized notification against the schema of the push gateway API and describes the
+/// first mismatch, if any.
+fn notification_schema_mismatch(notification: &serde_json::Value) -> Option<String> {
+    use serde_json::Value;
+
+    let notification = match notification.as_object() {
+        Some(notification) => notification,
+        None => return Some("notification is not an object".to_owned()),
+    };
+
+    for key in [
+        "event_id",
+        "room_id",
+        "type",
+        "sender",
+        "sender_display_name",
+        "room_name",
+        "room_alias",
+    ] {
+        if notification
+            .get(key)
+            .map_or(false, |value| !value.is_string())
+        {
+            return Some(format!("{key} is not a string"));
+        }
+    }
+
+    if notification
+        .get("user_is_target")
+        .map_or(false, |value| !value.is_boolean())
+    {
+        return Some("user_is_target is not a boolean".to_owned());
+    }
+
+    if let Some(prio) = notification.get("prio") {
+        if prio != "high" && prio != "low" {
+            return Some(format!("unknown prio {prio}"));
+        }
+    }
+
+    if notification
+        .get("content")
+        .map_or(false, |value| !value.is_object())
+    {
+        return Some("content is not an object".to_owned());
+    }
+
+    if let Some(counts) = notification.get("counts") {
+        for key in ["unread", "missed_calls"] {
+            if counts.get(key).map_or(false, |value| !value.is_u64()) {
+                return Some(format!("counts.{key} is not an unsigned integer"));
+            }
+        }
+    }
+
+    let devices = match notification.get("devices").and_then(Value::as_array) {
+        Some(devices) if !devices.is_empty() => devices,
+        _ => return Some("devices is not a non-empty array".to_owned()),
+    };
+
+    for (i, device) in devices.iter().enumerate() {
+        for key in ["app_id", "pushkey"] {
+            if !device.get(key).map_or(false, Value::is_string) {
+                return Some(format!("devices[{i}].{key} is not a string"));
+            }
+        }
+        if device
+            .get("pushkey_ts")
+            .map_or(false, |value| !value.is_u64())
+        {
+            return Some(format!(
+                "devices[{i}].pushkey_ts is not an unsigned integer"
+            ));
+        }
+        for key in ["data", "tweaks"] {
+            if device.get(key).map_or(false, |value| !value.is_object()) {
+                return Some(format!("devices[{i}].{key} is not an object"));
+            }
+        }
+    }
+
+    None
+}
+
+/// Wraps a serialized Matrix push notification in the request body expected by the gateway.
+fn envelope_body(
+    envelope: PushGatewayEnvelope,
+    notification: serde_json::Value,
+) -> serde_json::Value {
+    let pushkey = notification
+        .pointer("/devices/0/pushkey")
+        .cloned()
+        .unwrap_or_default();
+    let high_priority = notification.get("prio").and_then(|p| p.as_str()) == Some("high");
+
+    // FCM only accepts string values in the data payload
+    let data = notification
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| key.as_str() != "devices")
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (key.clone(), serde_json::Value::String(value))
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    match envelope {
+        PushGatewayEnvelope::Matrix => json!({ "notification": notification }),
+        PushGatewayEnvelope::FcmV1 => json!({
+            "message": {
+                "token": pushkey,
+                "data": data,
+                "android": {
+                    "priority": if high_priority { "HIGH" } else { "NORMAL" },
+                },
+            }
+        }),
+        PushGatewayEnvelope::FcmLegacy => json!({
+            "to": pushkey,
+            "priority": if high_priority { "high" } else { "normal" },
+            "data": data,
+        }),
+    }
+}
+
+/// Replaces the missing content of a notification according to the configured fallback, so the
+/// gateway doesn't get a notification that has everything but the content.
+fn apply_content_fallback(notifi: &mut Notification, fallback: ContentFallback) {
+    match fallback {
+        ContentFallback::EventIdOnly => {
+            notifi.sender = None;
+            notifi.sender_display_name = None;
+            notifi.event_type = None;
+            notifi.room_name = None;
+            notifi.user_is_target = false;
+            notifi.content = None;
+        }
+        ContentFallback::Placeholder => {
+            notifi.content = serde_json::value::to_raw_value(&json!({
+                "msgtype": "m.text",
+                "body": CONTENT_PLACEHOLDER,
+            }))
+            .ok();
+        }
+    }
+}
+
+/// Returns a notification body for audio messages, whose body is usually just a file name. Voice
+/// messages (MSC3245) include their duration.
+fn audio_body(content: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
+    if content.get("msgtype").and_then(|t| t.as_str()) != Some("m.audio") {
+        return None;
+    }
+
+    if !content.contains_key("org.matrix.msc3245.voice") {
+        return Some("Audio message".to_owned());
+    }
+
+    let duration = content
+        .get("info")
+        .and_then(|info| info.get("duration"))
+        .or_else(|| {
+            content
+                .get("org.matrix.msc1767.audio")
+                .and_then(|audio| audio.get("duration"))
+        })
+        .and_then(|duration| duration.as_u64());
+
+    Some(match duration {
+        Some(millis) => {
+            let seconds = (millis + 500) / 1000;
+            format!("🎤 Voice message ({}:{:02})", seconds / 60, seconds % 60)
+        }
+        None => "🎤 Voice message".to_owned(),
+    })
+}
+
+/// Returns a notification body for location shares, whose body is usually just the geo URI,
+/// together with the geo URI so clients can show a map. Live location shares (MSC3672) are
+/// announced by their beacon info, followed by beacons with the current location.
+fn location_body(
+    kind: &str,
+    content: &serde_json::Map<String, serde_json::Value>,
+) -> Option<(String, Option<String>)> {
+    let location = content.get("org.matrix.msc3488.location");
+    let geo_uri = content
+        .get("geo_uri")
+        .or_else(|| location?.get("uri"))
+        .and_then(|uri| uri.as_str())
+        .map(ToOwned::to_owned);
+    let description = location
+        .and_then(|location| location.get("description"))
+        .or_else(|| content.get("description"))
+        .and_then(|description| description.as_str())
+        .filter(|description| !description.is_empty());
+
+    let (body, description, geo_uri) = match kind {
+        BEACON_INFO_TYPE => ("📍 started sharing their live location", description, None),
+        BEACON_TYPE => ("📍 shared their live location", None, geo_uri),
+        _ if content.get("msgtype").and_then(|t| t.as_str()) == Some("m.location") => {
+            // Clients put the geo URI into the body when the user didn't name the place
+            let description = description.or_else(|| {
+                content
+                    .get("body")
+                    .and_then(|body| body.as_str())
+                    .filter(|body| !body.is_empty() && !body.contains("geo:"))
+            });
+            ("📍 shared a location", description, geo_uri)
+        }
+        _ => return None,
+    };
+
+    Some((
+        match description {
+            Some(description) => format!("{body}: {description}"),
+            None => body.to_owned(),
+        },
+        geo_uri,
+    ))
+}
+
+/// Returns a calendar-style notification body for a scheduling event. Scheduling MSCs name their
+/// fields differently, so the usual names of the title and start time are tried in order.
+fn scheduling_body(content: &serde_json::Map<String, serde_json::Value>) -> String {
+    let title = ["title", "name", "summary", "body"]
+        .iter()
+        .find_map(|key| content.get(*key)?.as_str())
+        .filter(|title| !title.is_empty());
+
+    let start = ["start", "start_time", "starts_at", "timestamp"]
+        .iter()
+        .find_map(|key| content.get(*key))
+        .and_then(|start| match start {
+            serde_json::Value::Number(millis) => millis.as_u64().map(format_utc),
+            serde_json::Value::String(start) if !start.is_empty() => Some(start.clone()),
+            _ => None,
+        });
+
+    match (title, start) {
+        (Some(title), Some(start)) => format!("📅 {title} ({start})"),
+        (Some(title), None) => format!("📅 {title}"),
+        (None, Some(start)) => format!("📅 New event ({start})"),
+        (None, None) => "📅 New event".to_owned(),
+    }
+}
+
+/// Formats a unix timestamp in milliseconds as `YYYY-MM-DD HH:MM UTC`.
+fn format_utc(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // Converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        time / 3600,
+        time % 3600 / 60
+    )
+}
+
+/// Returns a human readable notification body for a state event.
+fn state_event_body(
+    event_type: &str,
+    state_key: &str,
+    content: &serde_json::Map<String, serde_json::Value>,
+    sender: &str,
+) -> String {
+    match event_type {
+        "m.widget" | "im.vector.modular.widgets" => {
+            let name = content
+                .get("name")
+                .or_else(|| content.get("url"))
+                .and_then(|name| name.as_str())
+                .unwrap_or(state_key);
+            let widget_type = content.get("type").and_then(|t| t.as_str());
+
+            if content.is_empty() {
+                format!("{sender} removed the widget {name}")
+            } else if matches!(widget_type, Some("jitsi" | "m.jitsi")) {
+                format!("{sender} started a Jitsi call")
+            } else {
+                format!("{sender} added the widget {name}")
+            }
+        }
+        _ => format!("{sender} updated {event_type}"),
+    }
+}
+
+fn push_condition_ctx(
+    room_id: &RoomId,
+    member_count: UInt,
+    user: &UserId,
+    user_display_name: String,
+    power_levels: &RoomPowerLevelsEventContent,
+) -> PushConditionRoomCtx {
+    PushConditionRoomCtx {
+        room_id: room_id.to_owned(),
+        member_count,
+        user_id: user.to_owned(),
+        user_display_name,
+        users_power_levels: power_levels.users.clone(),
+        default_power_level: power_levels.users_default,
+        notification_power_levels: power_levels.notifications.clone(),
+    }
+}
+
+/// Evaluates the rules in the same order as [`Ruleset::get_actions`], recording every checked
+/// condition until a rule matches. Content, room and sender rules are traced as the equivalent
+/// `event_match` conditions.
+fn trace_ruleset(
+    ruleset: &Ruleset,
+    event: &Raw<AnySyncTimelineEvent>,
+    ctx: &PushConditionRoomCtx,
+) -> Vec<RuleTrace> {
+    let event = FlattenedJson::from_raw(event);
+    let event_match = |key: &str, pattern: &str| PushCondition::EventMatch {
+        key: key.to_owned(),
+        pattern: pattern.to_owned(),
+    };
+
+    let mut rules = Vec::new();
+    for rule in &ruleset.override_ {
+        rules.push((
+            "override",
+            rule.rule_id.clone(),
+            rule.enabled,
+            rule.conditions.clone(),
+            &rule.actions,
+        ));
+    }
+    for rule in &ruleset.content {
+        rules.push((
+            "content",
+            rule.rule_id.clone(),
+            rule.enabled,
+            vec![event_match("content.body", &rule.pattern)],
+            &rule.actions,
+        ));
+    }
+    for rule in &ruleset.room {
+        rules.push((
+            "room",
+            rule.rule_id.to_string(),
+            rule.enabled,
+            vec![event_match("room_id", rule.rule_id.as_str())],
+            &rule.actions,
+        ));
+    }
+    for rule in &ruleset.sender {
+        rules.push((
+            "sender",
+            rule.rule_id.to_string(),
+            rule.enabled,
+            vec![event_match("sender", rule.rule_id.as_str())],
+            &rule.actions,
+        ));
+    }
+    for rule in &ruleset.underride {
+        rules.push((
+            "underride",
+            rule.rule_id.clone(),
+            rule.enabled,
+            rule.conditions.clone(),
+            &rule.actions,
+        ));
+    }
+
+    let mut trace = Vec::new();
+    for (kind, rule_id, enabled, conditions, actions) in rules {
+        let conditions = conditions
+            .iter()
+            .map(|condition| {
+                (
+                    serde_json::to_string(condition).expect("push condition is valid JSON"),
+                    condition.applies(&event, ctx),
+                )
+            })
+            .collect::<Vec<_>>();
+        let matched = enabled && conditions.iter().all(|(_, applies)| *applies);
+
+        trace.push(RuleTrace {
+            kind,
+            rule_id,
+            enabled,
+            conditions,
+            matched,
+            actions: actions.clone(),
+        });
+
+        if matched {
+            break;
+        }
+    }
+
+    trace
+}
+
+/// Returns whether the master rule, which overrides all other rules, is enabled and doesn't
+/// notify.
+fn master_rule_disables_push(ruleset: &Ruleset) -> bool {
+    ruleset
+        .override_
+        .iter()
+        .find(|rule| rule.rule_id == ".m.rule.master")
+        .map_or(false, |rule| rule.enabled && !notifies(&rule.actions))
+}
+
+/// Returns whether these push rule actions result in a notification.
+fn notifies(actions: &[Action]) -> bool {
+    actions
+        .iter()
+        .any(|action| matches!(action, Action::Notify))
+}
+
+/// Replaces the placeholders in a notification template in a single pass, so placeholders in the
+/// substituted values are left alone.
+fn render_template(template: &str, sender: &str, room: &str, body: &str) -> String {
+    let mut rendered = String::with_capacity(template.len() + body.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+
+        let end = match rest[start..].find('}') {
+            Some(end) => end + start,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+
+        rendered.push_str(match &rest[start + 1..end] {
+            "sender" => sender,
+            "room" => room,
+            "body" => body,
+            _ => &rest[start..=end],
+        });
+        rest = &rest[end + 1..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Returns a short summary of the replied-to event, only containing its id if it is unavailable.
+fn reply_context(
+    in_reply_to: &EventId,
+    replied: Option<&PduEvent>,
+    sender_display_name: Option<&str>,
+) -> serde_json::Value {
+    let mut context = json!({ "event_id": in_reply_to });
+
+    if let Some(replied) = replied {
+        context["sender"] = json!(replied.sender);
+        if let Some(name) = sender_display_name {
+            context["sender_display_name"] = json!(name);
+        }
+
+        if let Some(body) = serde_json::from_str::<serde_json::Value>(replied.content.get())
+            .ok()
+            .and_then(|content| content.get("body")?.as_str().map(ToOwned::to_owned))
+        {
+            context["body"] = json!(utils::truncate_with_ellipsis(
+                &body,
+                REPLY_CONTEXT_BODY_LENGTH
+            ));
+        }
+    }
+
+    context
+}
+
+/// Returns whether a pusher change with `version` replaces the stored version, if any. Equal
+/// versions replace each other, so retries of the same change are applied.
+pub fn is_newer_version(stored_version: Option<u64>, version: u64) -> bool {
+    stored_version.map_or(true, |stored| version >= stored)
+}
+
+/// Returns a random (version 4) UUID identifying a notification.
+fn notification_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Returns the default payload of a device in the layout of this format version.
+fn versioned_payload(
+    format_version: u32,
+    default_payload: serde_json::Value,
+    notification_id: &str,
+    group: &str,
+) -> serde_json::Value {
+    match format_version {
+        1 => default_payload,
+        _ => device_payload(default_payload, notification_id, group),
+    }
+}
+
+/// Adds the notification id and group to the default payload of a device, which gateways pass
+/// on to it.
+fn device_payload(
+    mut default_payload: serde_json::Value,
+    notification_id: &str,
+    group: &str,
+) -> serde_json::Value {
+    if !default_payload.is_object() {
+        default_payload = json!({});
+    }
+    default_payload[NOTIFICATION_ID_KEY] = notification_id.into();
+    default_payload[NOTIFICATION_GROUP_KEY] = group.into();
+
+    default_payload
+}
+
+/// Describes the client-server API requests behind the quick actions of a notification. The app
+/// sends them with its own access token, so no credentials are part of the notification.
+fn quick_actions(room_id: &RoomId, event_id: &EventId) -> serde_json::Value {
+    json!([
+        {
+            "id": "reply",
+            "method": "PUT",
+            "path": format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{{txnId}}"),
+            "reply_to": event_id,
+        },
+        {
+            "id": "mark_read",
+            "method": "POST",
+            "path": format!("/_matrix/client/v3/rooms/{room_id}/receipt/m.read/{event_id}"),
+        },
+        {
+            "id": "mute",
+            "method": "PUT",
+            "path": format!("/_matrix/client/v3/pushrules/global/room/{room_id}"),
+            "body": { "actions": [] },
+        },
+    ])
+}
+
+/// Returns the group of notifications the event belongs to: its thread, or else its room.
+fn notification_group(event: &PduEvent) -> String {
+    match event.relates_to() {
+        Some((rel_type, thread_root)) if rel_type == "m.thread" => thread_root.to_string(),
+        _ => event.room_id.to_string(),
+    }
+}
+
+/// Returns the gateway to use when the pusher's own gateway fails, if there is one.
+fn fallback_gateway<'a>(
+    settings: &'a PusherSettings,
+    app_id: &str,
+    configured: &'a BTreeMap<String, String>,
+) -> Option<&'a str> {
+    settings
+        .fallback_gateway
+        .as_deref()
+        .or_else(|| configured.get(app_id).map(String::as_str))
+}
+
+/// Sends to the primary gateway and, if that fails, to the fallback gateway. Returns the outcome
+/// together with the gateway it came from.
+async fn with_fallback<'a, F, Fut, R>(
+    primary: &'a str,
+    fallback: Option<&'a str>,
+    send: F,
+) -> (Result<R>, &'a str)
+where
+    F: Fn(&'a str) -> Fut,
+    Fut: Future<Output = Result<R>>,
+{
+    let result = send(primary).await;
+
+    match (result, fallback) {
+        (Err(e), Some(fallback)) if fallback != primary => {
+            warn!(
+                "Push gateway {} failed ({}), trying fallback gateway {}",
+                primary, e, fallback
+            );
+            (send(fallback).await, fallback)
+        }
+        (result, _) => (result, primary),
+    }
+}
+
+/// Returns whether the error comes from malformed data of the pusher itself rather than from a
+/// temporary problem.
+fn is_caused_by_pusher(error: &Error) -> bool {
+    matches!(error, Error::BadPusher(_))
+}
+
+/// Counts a failure of the pusher and returns whether it failed `threshold` times in a row now.
+fn count_failure(
+    failures: &mut HashMap<(OwnedUserId, String), u32>,
+    key: (OwnedUserId, String),
+    threshold: u32,
+) -> bool {
+    let count = failures.entry(key.clone()).or_default();
+    *count += 1;
+
+    if *count >= threshold {
+        failures.remove(&key);
+        true
+    } else {
+        false
+    }
+}
+
+fn is_retryable_status(status: u16, retryable: &[u16]) -> bool {
+    retryable.contains(&status)
+}
+
+/// Dumps all pushers of all users in `db` together with their settings.
+pub fn export_pushers(db: &dyn Data) -> Result<Vec<PusherDump>> {
+    db.all_pushers()
+        .map(|r| {
+            let (user_id, pusher) = r?;
+            let settings = db.get_pusher_settings(&user_id, &pusher.ids.pushkey)?;
+
+            Ok(PusherDump {
+                user_id,
+                pusher,
+                settings,
+            })
+        })
+        .collect()
+}
+
+/// Loads dumped pushers into `db`, which might use another backend than the one they were
+/// dumped from.
+pub fn import_pushers(db: &dyn Data, pushers: Vec<PusherDump>, version: u64) -> Result<usize> {
+    let mut imported = 0;
+
+    for dump in pushers {
+        let pushkey = dump.pusher.ids.pushkey.clone();
+        let action = set_pusher::v3::PusherAction::Post(set_pusher::v3::PusherPostData {
+            pusher: dump.pusher,
+            append: false,
+        });
+
+        if db.upsert_pusher(&dump.user_id, action, version)? {
+            db.set_pusher_settings(&dump.user_id, &pushkey, &dump.settings)?;
+            imported += 1;
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Values that writes of a transaction replaced, `None` for keys that didn't exist before.
+pub struct UndoLog<K> {
+    writes: Vec<(K, Option<Vec<u8>>)>,
+}
+
+impl<K> Default for UndoLog<K> {
+    fn default() -> Self {
+        Self { writes: Vec::new() }
+    }
+}
+
+impl<K> UndoLog<K> {
+    /// Remembers the value of the key before it is written.
+    pub fn record(&mut self, key: K, previous: Option<Vec<u8>>) {
+        self.writes.push((key, previous));
+    }
+
+    /// Restores the recorded values, latest write first, so every key ends up with the value it
+    /// had before its first write.
+    pub fn undo(self, mut restore: impl FnMut(K, Option<Vec<u8>>) -> Result<()>) -> Result<()> {
+        for (key, previous) in self.writes.into_iter().rev() {
+            restore(key, previous)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Hands the buffered records to `persist` and empties the buffer. If they can't be persisted, they
+/// stay buffered for the next attempt.
+fn flush_history(
+    buffer: &Mutex<Vec<NotificationRecord>>,
+    persist: impl FnOnce(&[NotificationRecord]) -> Result<()>,
+) -> Result<usize> {
+    let records = mem::take(&mut *buffer.lock().unwrap());
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    if let Err(e) = persist(&records) {
+        let mut buffer = buffer.lock().unwrap();
+        let newer = mem::replace(&mut *buffer, records);
+        buffer.extend(newer);
+        return Err(e);
+    }
+
+    Ok(records.len())
+}
+
+/// Runs `send` for each device of one user, at most `limit` of them at the same time. The
+/// outcomes are returned in the order of the devices, a failing device doesn't affect the others.
+async fn for_each_device<T, F, Fut, R>(devices: Vec<T>, limit: usize, send: F) -> Vec<R>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let start = |(index, device): (usize, T)| {
+        let sending = send(device);
+        async move { (index, sending.await) }
+    };
+
+    let mut devices = devices.into_iter().enumerate();
+    let mut running = devices
+        .by_ref()
+        .take(limit.max(1))
+        .map(start)
+        .collect::<FuturesUnordered<_>>();
+
+    let mut outcomes = Vec::new();
+    while let Some(outcome) = running.next().await {
+        outcomes.push(outcome);
+        if let Some(device) = devices.next() {
+            running.push(start(device));
+        }
+    }
+
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+/// Returns the host and port of a push gateway url, which its probe responses are cached by.
+fn gateway_host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+
+    Some(match url.port_or_known_default() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    })
+}
+
+/// Returns the failed notification to requeue, unless it is unknown or was delivered since.
+fn requeue_target(failed: Option<FailedNotification>) -> Result<FailedNotification> {
+    failed.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Unknown notification or it was delivered already.",
+    ))
+}
+
+/// Returns the pushkey, app id and kind (e.g. `http`) of a pusher.
+pub fn pushkey_metadata(pusher: &Pusher) -> (String, String, String) {
+    let kind = serde_json::to_value(&pusher.kind)
+        .ok()
+        .and_then(|kind| kind.get("kind")?.as_str().map(ToOwned::to_owned))
+        .unwrap_or_default();
+
+    (pusher.ids.pushkey.clone(), pusher.ids.app_id.clone(), kind)
+}
+
+/// Returns the users of sorted keys starting with a user id followed by `0xff`, e.g. those of
+/// `senderkey_pusher`. Users with several keys are only returned once.
+pub fn senderkey_users<'a>(
+    keys: impl Iterator<Item = Vec<u8>> + 'a,
+) -> impl Iterator<Item = Result<OwnedUserId>> + 'a {
+    let mut last = None;
+
+    keys.filter_map(move |key| {
+        let user = key
+            .split(|&b| b == 0xff)
+            .next()
+            .expect("split always returns one element")
+            .to_vec();
+        if last.as_ref() == Some(&user) {
+            return None;
+        }
+        last = Some(user.clone());
+
+        Some(
+            utils::string_from_bytes(&user)
+                .ok()
+                .and_then(|user| UserId::parse(user).ok())
+                .ok_or_else(|| Error::bad_database("Invalid user id in senderkey_pusher.")),
+        )
+    })
+}
+
+/// Sorts pushers by app id and pushkey, so they are listed in the same order every time.
+fn sort_pushers(pushers: &mut [Pusher]) {
+    pushers.sort_by(|a, b| (&a.ids.app_id, &a.ids.pushkey).cmp(&(&b.ids.app_id, &b.ids.pushkey)));
+}
+
+/// Returns the truncated topic of a room topic event content, unless topics are disabled or it is
+/// missing, empty or malformed.
+fn notification_topic(enabled: bool, content: Option<&str>) -> Option<String> {
+    if !enabled {
+        return None;
+    }
+
+    let topic = serde_json::from_str::<RoomTopicEventContent>(content?)
+        .map_err(|e| warn!("Invalid room topic event in database: {}", e))
+        .ok()?
+        .topic;
+
+    (!topic.trim().is_empty())
+        .then(|| utils::truncate_with_ellipsis(topic.trim(), ROOM_TOPIC_LENGTH).into_owned())
+}
+
+/// Returns the notification as JSON, with the complete event added if given.
+fn notification_json(
+    notification: &Notification,
+    full_event: Option<&PduEvent>,
+) -> serde_json::Value {
+    let mut json = serde_json::to_value(notification).expect("notification is valid JSON value");
+
+    if let Some(event) = full_event {
+        json[FULL_EVENT_KEY] = serde_json::to_value(event).expect("pdu is valid JSON value");
+    }
+
+    json
+}
+
+/// Returns the priority of a notification. Important notifications, e.g. highlights, and replies
+/// in threads the user participates in are sent with high priority.
+fn notification_priority(important: bool, participated_thread_reply: bool) -> NotificationPriority {
+    if important || participated_thread_reply {
+        NotificationPriority::High
+    } else {
+        NotificationPriority::Low
+    }
+}
+
+/// Returns the priority of a notification, raised to high once the user ignored `threshold`
+/// notifications of the room in a row.
+fn escalated_priority(
+    priority: NotificationPriority,
+    unacknowledged: u64,
+    threshold: Option<u64>,
+) -> NotificationPriority {
+    match threshold {
+        Some(threshold) if unacknowledged >= threshold => NotificationPriority::High,
+        _ => priority,
+    }
+}
+
+/// Returns whether the user sent one of the events of a thread.
+fn participates_in_thread<'a>(
+    user: &UserId,
+    mut senders: impl Iterator<Item = &'a UserId>,
+) -> bool {
+    senders.any(|sender| sender == user)
+}
+
+/// Returns the members of the given rooms, except `user_id` itself.
+fn co_members<'a>(
+    user_id: &UserId,
+    rooms: impl Iterator<Item = impl Iterator<Item = &'a OwnedUserId>>,
+) -> HashSet<OwnedUserId> {
+    rooms
+        .flatten()
+        .filter(|member| *member != user_id)
+        .cloned()
+        .collect()
+}
+
+/// Returns the format of the notifications for a device of the pusher. The format of the device
+/// takes precedence over the one of the pusher, but if either only wants event ids, nothing else
+/// may be sent.
+fn resolve_push_format(pusher: &HttpPusherData, device: &PusherData) -> Option<PushFormat> {
+    let event_id_only = Some(PushFormat::EventIdOnly);
+    if pusher.format == event_id_only || device.format == event_id_only {
+        return event_id_only;
+    }
+
+    device.format.clone().or_else(|| pusher.format.clone())
+}
+
+/// Returns a low priority notification without tweaks for the pusher. `None` for pushers that
+/// are not HTTP pushers.
+fn silent_notification(pusher: &Pusher) -> Option<Notification> {
+    match &pusher.kind {
+        PusherKind::Http(http) => {
+            let mut device = Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
+            device.data.default_payload = http.default_payload.clone();
+            device.data.format = resolve_push_format(http, &device.data);
+
+            let mut notifi = Notification::new(vec![device]);
+            notifi.prio = NotificationPriority::Low;
+
+            Some(notifi)
+        }
+        _ => None,
+    }
+}
+
+/// Returns a low priority notification without event, telling the client that a device list
+/// changed. `None` for pushers that are not HTTP pushers.
+fn device_list_notification(pusher: &Pusher) -> Option<Notification> {
+    let mut notifi = silent_notification(pusher)?;
+    notifi.event_type = Some(TimelineEventType::from(DEVICE_LIST_NOTIFICATION_TYPE));
+
+    Some(notifi)
+}
+
+/// Returns a low priority notification telling the client that a notified event was redacted.
+/// `None` for pushers that are not HTTP pushers.
+fn redaction_notification(
+    pusher: &Pusher,
+    room_id: &RoomId,
+    redacted_id: &EventId,
+) -> Option<Notification> {
+    let mut notifi = silent_notification(pusher)?;
+    notifi.event_id = Some(redacted_id.to_owned());
+    notifi.room_id = Some(room_id.to_owned());
+    notifi.event_type = Some(TimelineEventType::RoomRedaction);
+
+    Some(notifi)
+}
+
+/// Sets the `Content-Type` override and the custom headers of a pusher on a request to its push
+/// gateway.
+fn set_gateway_headers(headers: &mut http::HeaderMap, settings: &PusherSettings) -> Result<()> {
+    if let Some(content_type) = &settings.content_type {
+        let value = http::HeaderValue::from_str(content_type)
+            .map_err(|_| Error::bad_pusher("Invalid content type in pusher settings."))?;
+        headers.insert(http::header::CONTENT_TYPE, value);
+    }
+
+    for (name, value) in &settings.headers.0 {
+        let name = http::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| Error::bad_pusher("Invalid header name in pusher settings."))?;
+        let mut value = http::HeaderValue::from_str(value)
+            .map_err(|_| Error::bad_pusher("Invalid header value in pusher settings."))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+    }
+
+    Ok(())
+}
+
+fn collect_snapshot(
+    requests: impl Iterator<Item = (NotificationState, (OwnedUserId, String, Vec<u8>))>,
+    limit: usize,
+    attempts: impl Fn(&UserId, &str) -> u32,
+    event_id: impl Fn(&[u8]) -> Result<Option<OwnedEventId>>,
+) -> Result<Vec<PendingNotification>> {
+    requests
+        .take(limit.min(MAX_NOTIFICATION_SNAPSHOT))
+        .map(|(state, (user, pushkey, pdu_id))| {
+            Ok(PendingNotification {
+                event_id: event_id(&pdu_id)?,
+                attempts: attempts(&user, &pushkey),
+                user,
+                pushkey,
+                state,
+            })
+        })
+        .collect()
+}
+
+/// Events in the pending notification digest of a pusher.
+struct PendingDigest {
+    /// Count of the newest event, up to which the events are removed once the digest was sent
+    until: u64,
+    /// When the oldest event was queued, in milliseconds since the unix epoch
+    queued_at: u64,
+    /// The events to summarize, oldest first
+    event_ids: Vec<OwnedEventId>,
+}
+
+/// Returns the pending digest of the events queued for a pusher, if there are any.
+fn pending_digest(queued: Vec<(u64, u64, OwnedEventId)>) -> Option<PendingDigest> {
+    let until = queued.last()?.0;
+    let queued_at = queued.first()?.1;
+
+    Some(PendingDigest {
+        until,
+        queued_at,
+        event_ids: queued
+            .into_iter()
+            .map(|(_, _, event_id)| event_id)
+            .collect(),
+    })
+}
+
+/// Returns whether a digest whose oldest event was queued at `oldest` has to be sent at `now`,
+/// with digests sent every `interval` seconds. Digests without any event left are sent right
+/// away, to get rid of them.
+fn digest_due(oldest: Option<u64>, now: u64, interval: u64) -> bool {
+    oldest.map_or(true, |oldest| {
+        now.saturating_sub(oldest) >= interval.saturating_mul(1000)
+    })
+}
+
+/// Returns whether a notification is added to the user's digest instead of being sent now.
+/// Emails always are, unless the user wants highlights to be emailed immediately.
+fn queues_for_digest(settings: &PushDigestSettings, email: bool, highlight: bool) -> bool {
+    if email {
+        !(highlight && settings.email_highlights_immediately)
+    } else {
+        settings.enabled && !(highlight && settings.highlights_immediately)
+    }
+}
+
+/// Returns the number of seconds between two digests of a pusher, given the server's interval.
+fn digest_interval(settings: &PushDigestSettings, email: bool, default: u64) -> u64 {
+    settings.email_interval.filter(|_| email).unwrap_or(default)
+}
+
+/// Returns the body of a digest notification summarizing `events` notifications.
+fn digest_summary(events: usize, rooms: usize) -> String {
+    format!(
+        "{} new notification{} in {} room{}",
+        events,
+        if events == 1 { "" } else { "s" },
+        rooms,
+        if rooms == 1 { "" } else { "s" },
+    )
+}
+
+/// An event as it is described in a notification email.
+struct EmailEvent {
+    sender: String,
+    room: String,
+    /// The body of the event, if its content can be shown
+    snippet: Option<String>,
+}
+
+/// Returns the body of a message event, shortened for notification emails.
+fn email_snippet(event: &PduEvent) -> Option<String> {
+    let content = serde_json::from_str::<serde_json::Value>(event.content.get()).ok()?;
+    let body = content.get("body")?.as_str()?;
+
+    Some(
+        utils::truncate_with_ellipsis(body, services().globals.max_push_body_length()).into_owned(),
+    )
+}
+
+/// Returns the subject and plain text body of an email about these events, if there are any.
+fn notification_email(events: &[EmailEvent]) -> Option<(String, String)> {
+    let subject = match events {
+        [] => return None,
+        [event] => format!("New message from {} in {}", event.sender, event.room),
+        events => digest_summary(
+            events.len(),
+            events
+                .iter()
+                .map(|event| &event.room)
+                .collect::<HashSet<_>>()
+                .len(),
+        ),
+    };
+
+    let body = events
+        .iter()
+        .map(|event| {
+            format!(
+                "{} in {}:\n{}\n",
+                event.sender,
+                event.room,
+                event.snippet.as_deref().unwrap_or("You have a new message")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Some((subject, body))
+}
+
+/// How a call event changes the missed calls of the room.
+#[derive(Debug, PartialEq, Eq)]
+enum CallUpdate {
+    /// The call with this id started ringing
+    Invite(String),
+    /// The call with this id was answered or hung up, so it isn't missed
+    End(String),
+}
+
+/// Returns how the event changes the missed calls. In case of glare, both invites are counted
+/// until the losing call is hung up.
+fn call_update(kind: &TimelineEventType, content: &str) -> Option<CallUpdate> {
+    let call_id = || -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(content)
+            .ok()?
+            .get("call_id")?
+            .as_str()
+            .map(ToOwned::to_owned)
+    };
+
+    match kind {
+        TimelineEventType::CallInvite => call_id().map(CallUpdate::Invite),
+        TimelineEventType::CallAnswer | TimelineEventType::CallHangup => {
+            call_id().map(CallUpdate::End)
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether the event mentions the whole room, either with intentional mentions or the
+/// `@room` keyword.
+pub fn is_room_mention(pdu: &PduEvent) -> bool {
+    let content = match serde_json::from_str::<serde_json::Value>(pdu.content.get()) {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+
+    content.pointer("/m.mentions/room") == Some(&serde_json::Value::Bool(true))
+        || content
+            .get("body")
+            .and_then(|body| body.as_str())
+            .map_or(false, |body| contains_word(body, "@room"))
+}
+
+/// Returns whether `word` occurs in `text` on its own, not as part of a longer word, the same way
+/// the `event_match` condition of `.m.rule.roomnotif` matches the body.
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    text.match_indices(word).any(|(start, _)| {
+        let end = start + word.len();
+        !text[..start]
+            .chars()
+            .next_back()
+            .map_or(false, is_word_char)
+            && !text[end..].chars().next().map_or(false, is_word_char)
+    })
+}
+
+/// Returns whether a notification about a room mention has to be dropped, because the sender is
+/// below the `notifications.room` power level. Users that are highlighted for other reasons, e.g.
+/// because they were mentioned themselves, are still notified.
+fn suppresses_room_mention(
+    pdu: &PduEvent,
+    power_levels: &RoomPowerLevelsEventContent,
+    highlight: bool,
+) -> bool {
+    let sender_level = power_levels
+        .users
+        .get(&pdu.sender)
+        .unwrap_or(&power_levels.users_default);
+
+    !highlight && *sender_level < power_levels.notifications.room && is_room_mention(pdu)
+}
+
+/// Returns the pushkeys rejected by the gateway, according to the body of its response.
+fn rejected_pushkeys(body: &[u8]) -> Vec<String> {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body.get("rejected")?.as_array().cloned())
+        .into_iter()
+        .flatten()
+        .filter_map(|pushkey| pushkey.as_str().map(ToOwned::to_owned))
+        .collect()
+}
+
+/// Returns whether a pusher paused until `paused_until` is still paused.
+fn still_paused(paused_until: Option<u64>, now: u64) -> bool {
+    paused_until.map_or(false, |until| now < until)
+}
+
+/// Returns whether pushers of the app may be registered. An empty allowlist allows all apps.
+fn app_id_allowed(allowlist: &[String], app_id: &str) -> bool {
+    allowlist.is_empty() || allowlist.iter().any(|allowed| allowed == app_id)
+}
+
+/// Returns whether an event sent at `origin_server_ts` is older than `max_age` seconds.
+fn is_stale(origin_server_ts: UInt, now: u64, max_age: Option<u64>) -> bool {
+    max_age.map_or(false, |max_age| {
+        now.saturating_sub(origin_server_ts.into()) > max_age.saturating_mul(1000)
+    })
+}
+
+/// Returns whether the last notification, if any, was sent less than `window` seconds ago.
+fn notified_within(last_notified: Option<u64>, now: u64, window: Option<u64>) -> bool {
+    match (last_notified, window) {
+        (Some(last_notified), Some(window)) => {
+            now.saturating_sub(last_notified) < window.saturating_mul(1000)
+        }
+        _ => false,
+    }
+}
+
+fn denies_sender(acl: &RoomServerAclEventContent, sender: &UserId) -> bool {
+    !acl.is_allowed(sender.server_name())
+}
+
+/// Returns the user namespace regexes of the given appservice registrations.
+fn matches_bot_sender(sender: &UserId, patterns: &RegexSet, appservice_users: &[Regex]) -> bool {
+    patterns.is_match(sender.as_str())
+        || appservice_users
+            .iter()
+            .any(|regex| regex.is_match(sender.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pdu(content: serde_json::Value) -> PduEvent {
+        serde_json::from_value(serde_json::json!({
+            "event_id": "$event:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "type": "m.room.message",
+            "content": content,
+            "prev_events": [],
+            "depth": 1,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn self_edit_detection() {
+        let edit = pdu(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "* fixed",
+            "m.new_content": { "msgtype": "m.text", "body": "fixed" },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": "$original:example.org" },
+        }));
+        assert!(edit.is_edit());
+
+        let message = pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello" }));
+        assert!(!message.is_edit());
+    }
+
+    #[test]
+    fn digest_summary_pluralization() {
+        assert_eq!(digest_summary(1, 1), "1 new notification in 1 room");
+        assert_eq!(digest_summary(5, 2), "5 new notifications in 2 rooms");
+    }
+
+    fn actions_for(ruleset: &Ruleset, event: serde_json::Value) -> Vec<Action> {
+        let ctx = PushConditionRoomCtx {
+            room_id: ruma::room_id!("!room:example.org").to_owned(),
+            member_count: uint!(10),
+            user_id: ruma::user_id!("@alice:example.org").to_owned(),
+            user_display_name: "Alice".to_owned(),
+            users_power_levels: Default::default(),
+            default_power_level: Default::default(),
+            notification_power_levels: Default::default(),
+        };
+        let event = serde_json::from_value(event).unwrap();
+
+        ruleset.get_actions(&event, &ctx).to_vec()
+    }
+
+    #[test]
+    fn would_notify_decision() {
+        let ruleset = Ruleset::server_default(ruma::user_id!("@alice:example.org"));
+
+        let message = serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$message:example.org",
+            "sender": "@bob:example.org",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": "hello" },
+        });
+        assert!(notifies(&actions_for(&ruleset, message)));
+
+        let notice = serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$notice:example.org",
+            "sender": "@bot:example.org",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.notice", "body": "beep" },
+        });
+        assert!(!notifies(&actions_for(&ruleset, notice)));
+    }
+
+    #[test]
+    fn gateway_envelopes() {
+        let notification = serde_json::json!({
+            "event_id": "$event:example.org",
+            "prio": "high",
+            "counts": { "unread": 2 },
+            "devices": [{ "app_id": "org.example.app", "pushkey": "token" }],
+        });
+
+        let matrix = envelope_body(PushGatewayEnvelope::Matrix, notification.clone());
+        assert_eq!(matrix["notification"], notification);
+
+        let fcm_v1 = envelope_body(PushGatewayEnvelope::FcmV1, notification.clone());
+        assert_eq!(fcm_v1["message"]["token"], "token");
+        assert_eq!(fcm_v1["message"]["android"]["priority"], "HIGH");
+        assert_eq!(fcm_v1["message"]["data"]["event_id"], "$event:example.org");
+        assert_eq!(fcm_v1["message"]["data"]["counts"], r#"{"unread":2}"#);
+        assert!(fcm_v1["message"]["data"].get("devices").is_none());
+
+        let legacy = envelope_body(PushGatewayEnvelope::FcmLegacy, notification);
+        assert_eq!(legacy["to"], "token");
+        assert_eq!(legacy["priority"], "high");
+        assert_eq!(legacy["data"]["event_id"], "$event:example.org");
+    }
+
+    #[test]
+    fn widget_state_event_body() {
+        let jitsi = serde_json::json!({ "type": "jitsi", "name": "Call" });
+        assert_eq!(
+            state_event_body(
+                "im.vector.modular.widgets",
+                "w1",
+                jitsi.as_object().unwrap(),
+                "Bob"
+            ),
+            "Bob started a Jitsi call"
+        );
+
+        let etherpad = serde_json::json!({ "type": "m.etherpad", "name": "Notes" });
+        assert_eq!(
+            state_event_body("m.widget", "w2", etherpad.as_object().unwrap(), "Bob"),
+            "Bob added the widget Notes"
+        );
+
+        assert_eq!(
+            state_event_body("m.widget", "w2", &serde_json::Map::new(), "Bob"),
+            "Bob removed the widget w2"
+        );
+    }
+
+    #[test]
+    fn per_pusher_retry_policy() {
+        let defaults = RetryPolicy {
+            max_attempts: 10,
+            timeout: Duration::from_secs(180),
+        };
+
+        let message_pusher = PusherSettings::default().retry_policy(defaults);
+        let voip_pusher = PusherSettings {
+            max_attempts: Some(2),
+            timeout: Some(5),
+            ..Default::default()
+        }
+        .retry_policy(defaults);
+
+        assert_eq!(message_pusher, defaults);
+        assert_eq!(voip_pusher.timeout, Duration::from_secs(5));
+
+        let attempts = |policy: RetryPolicy| (1..).find(|&tries| policy.exhausted(tries)).unwrap();
+        assert_eq!(attempts(voip_pusher), 2);
+        assert_eq!(attempts(message_pusher), 10);
+    }
+
+    #[test]
+    fn template_rendering() {
+        let settings = PusherSettings {
+            template: Some("{sender} in {room}: {body}".to_owned()),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_ok());
+        assert_eq!(
+            render_template(
+                settings.template.as_deref().unwrap(),
+                "Alice",
+                "Lounge",
+                "hi {room}"
+            ),
+            "Alice in Lounge: hi {room}"
+        );
+    }
+
+    #[test]
+    fn gateway_content_type() {
+        let device = Device::new("org.example.app".to_owned(), "token".to_owned());
+        let mut request =
+            send_event_notification::v1::Request::new(Notification::new(vec![device]))
+                .try_into_http_request::<Vec<u8>>(
+                    "https://push.example.org",
+                    SendAccessToken::IfRequired(""),
+                    &[MatrixVersion::V1_0],
+                )
+                .unwrap();
+        assert_eq!(
+            request.headers()[http::header::CONTENT_TYPE],
+            "application/json"
+        );
+
+        let settings = PusherSettings {
+            content_type: Some("application/vnd.example+json".to_owned()),
+            ..Default::default()
+        };
+        set_gateway_headers(request.headers_mut(), &settings).unwrap();
+        assert_eq!(
+            request.headers()[http::header::CONTENT_TYPE],
+            "application/vnd.example+json"
+        );
+        assert!(serde_json::from_slice::<serde_json::Value>(request.body()).is_ok());
+
+        let settings = PusherSettings {
+            content_type: Some("application/json\n".to_owned()),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn device_list_change_notification() {
+        let alice = ruma::user_id!("@alice:example.org").to_owned();
+        let bob = ruma::user_id!("@bob:example.org").to_owned();
+        let carol = ruma::user_id!("@carol:example.org").to_owned();
+
+        // Bob added a device, Alice shares an encrypted room with him
+        let rooms = [vec![alice.clone(), bob.clone()], vec![bob.clone()]];
+        let recipients = co_members(&bob, rooms.iter().map(|members| members.iter()));
+        assert_eq!(recipients, HashSet::from([alice]));
+        assert!(!recipients.contains(&carol));
+
+        let pusher: Pusher = serde_json::from_value(serde_json::json!({
+            "pushkey": "token",
+            "kind": "http",
+            "app_id": "org.example.app",
+            "app_display_name": "Example",
+            "device_display_name": "Phone",
+            "lang": "en",
+            "data": { "url": "https://push.example.org/_matrix/push/v1/notify" },
+        }))
+        .unwrap();
+
+        let notification = device_list_notification(&pusher).unwrap();
+        assert_eq!(notification.prio, NotificationPriority::Low);
+        assert!(notification.event_id.is_none());
+        assert_eq!(
+            notification.event_type.unwrap().to_string(),
+            DEVICE_LIST_NOTIFICATION_TYPE
+        );
+        assert!(notification.devices[0].tweaks.is_empty());
+    }
+
+    #[test]
+    fn redacting_notified_event_clears_notification() {
+        let pusher: Pusher = serde_json::from_value(serde_json::json!({
+            "pushkey": "token",
+            "kind": "http",
+            "app_id": "org.example.app",
+            "app_display_name": "Example",
+            "device_display_name": "Phone",
+            "lang": "en",
+            "data": { "url": "https://push.example.org/_matrix/push/v1/notify" },
+        }))
+        .unwrap();
+
+        let room_id = ruma::room_id!("!room:example.org");
+        let redacted_id = ruma::event_id!("$spam:example.org");
+
+        // The client gets the id of the redacted event, but nothing that would show a banner
+        let notification = redaction_notification(&pusher, room_id, redacted_id).unwrap();
+        assert_eq!(notification.event_id.as_deref(), Some(redacted_id));
+        assert_eq!(notification.room_id.as_deref(), Some(room_id));
+        assert_eq!(
+            notification.event_type,
+            Some(TimelineEventType::RoomRedaction)
+        );
+        assert_eq!(notification.prio, NotificationPriority::Low);
+        assert!(notification.content.is_none());
+        assert!(notification.devices[0].tweaks.is_empty());
+    }
+
+    #[test]
+    fn participated_thread_priority() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let bob = ruma::user_id!("@bob:example.org");
+        let carol = ruma::user_id!("@carol:example.org");
+
+        // Bob replies to a thread Alice replied to and to one she never took part in
+        let participated = [carol, alice, bob];
+        let other = [carol, bob];
+
+        let priority = |thread: &[&UserId]| {
+            notification_priority(false, participates_in_thread(alice, thread.iter().copied()))
+        };
+        assert_eq!(priority(&participated), NotificationPriority::High);
+        assert_eq!(priority(&other), NotificationPriority::Low);
+
+        assert_eq!(
+            notification_priority(true, false),
+            NotificationPriority::High
+        );
+    }
+
+    #[test]
+    fn full_event_only_for_trusted_gateways() {
+        let event = pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello" }));
+        let device = Device::new("org.example.app".to_owned(), "token".to_owned());
+        let notification = Notification::new(vec![device]);
+
+        let gateway = "https://push.example.org/_matrix/push/v1/notify";
+        let trusted = vec![gateway.to_owned()];
+
+        let opted_in = PusherSettings {
+            full_event: true,
+            ..Default::default()
+        };
+        let body = |settings: &PusherSettings, trusted: &[String]| {
+            notification_json(
+                &notification,
+                settings
+                    .sends_full_event(gateway, trusted)
+                    .then_some(&event),
+            )
+        };
+
+        let full = body(&opted_in, &trusted);
+        assert_eq!(full[FULL_EVENT_KEY]["event_id"], "$event:example.org");
+        assert_eq!(full[FULL_EVENT_KEY]["content"]["body"], "hello");
+
+        assert!(body(&opted_in, &[]).get(FULL_EVENT_KEY).is_none());
+        assert!(body(&PusherSettings::default(), &trusted)
+            .get(FULL_EVENT_KEY)
+            .is_none());
+    }
+
+    #[test]
+    fn stable_pusher_order() {
+        let pusher = |app_id: &str, pushkey: &str| -> Pusher {
+            serde_json::from_value(serde_json::json!({
+                "pushkey": pushkey,
+                "kind": "http",
+                "app_id": app_id,
+                "app_display_name": "Example",
+                "device_display_name": "Device",
+                "lang": "en",
+                "data": { "url": "https://push.example.org/_matrix/push/v1/notify" },
+            }))
+            .unwrap()
+        };
+        let ids = |pushers: &[Pusher]| {
+            pushers
+                .iter()
+                .map(|p| (p.ids.app_id.clone(), p.ids.pushkey.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut first = vec![
+            pusher("org.example.web", "a"),
+            pusher("org.example.app", "z"),
+            pusher("org.example.app", "b"),
+        ];
+        let mut second = vec![
+            pusher("org.example.app", "b"),
+            pusher("org.example.web", "a"),
+            pusher("org.example.app", "z"),
+        ];
+        sort_pushers(&mut first);
+        sort_pushers(&mut second);
+
+        assert_eq!(ids(&first), ids(&second));
+        assert_eq!(
+            ids(&first),
+            [
+                ("org.example.app".to_owned(), "b".to_owned()),
+                ("org.example.app".to_owned(), "z".to_owned()),
+                ("org.example.web".to_owned(), "a".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reply_context_of_reply() {
+        let reply = pdu(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "> <@bob:example.org> lunch?\n\nsure",
+            "m.relates_to": { "m.in_reply_to": { "event_id": "$original:example.org" } },
+        }));
+        let in_reply_to = reply.in_reply_to().unwrap();
+        assert_eq!(in_reply_to, "$original:example.org");
+
+        let mut original = pdu(serde_json::json!({ "msgtype": "m.text", "body": "lunch?" }));
+        original.sender = ruma::user_id!("@bob:example.org").to_owned();
+
+        let context = reply_context(&in_reply_to, Some(&original), Some("Bob"));
+        assert_eq!(context["event_id"], "$original:example.org");
+        assert_eq!(context["sender"], "@bob:example.org");
+        assert_eq!(context["sender_display_name"], "Bob");
+        assert_eq!(context["body"], "lunch?");
+
+        let unavailable = reply_context(&in_reply_to, None, None);
+        assert_eq!(
+            unavailable,
+            serde_json::json!({ "event_id": "$original:example.org" })
+        );
+
+        let message = pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello" }));
+        assert!(message.in_reply_to().is_none());
+    }
+
+    #[test]
+    fn suppressed_sound_tweak() {
+        let tweaks = vec![Tweak::Sound("default".to_owned()), Tweak::Highlight(false)];
+
+        let preferences = TweakPreferences {
+            suppressed: BTreeSet::from(["sound".to_owned()]),
+        };
+        let filtered = preferences.filter(tweaks.clone());
+        assert_eq!(filtered.len(), 1);
+        assert!(matches!(filtered[0], Tweak::Highlight(false)));
+
+        assert_eq!(TweakPreferences::default().filter(tweaks).len(), 2);
+    }
+
+    #[test]
+    fn concurrent_pusher_registrations() {
+        use std::sync::Mutex;
+
+        // Versions are handed out in order, but the registrations reach the database in any order
+        let stored = Arc::new(Mutex::new(None::<(u64, String)>));
+        let handles = [3, 1, 5, 2, 4]
+            .into_iter()
+            .map(|version| {
+                let stored = Arc::clone(&stored);
+                std::thread::spawn(move || {
+                    let mut stored = stored.lock().unwrap();
+                    if is_newer_version(stored.as_ref().map(|(v, _)| *v), version) {
+                        *stored = Some((version, format!("https://push{version}.example.org")));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            *stored.lock().unwrap(),
+            Some((5, "https://push5.example.org".to_owned()))
+        );
+        assert!(!is_newer_version(Some(5), 4));
+        assert!(is_newer_version(Some(5), 5));
+        assert!(is_newer_version(None, 1));
+    }
+
+    #[test]
+    fn unacknowledged_pushes_escalate() {
+        let threshold = Some(3);
+
+        // The unread count includes the notification that is being sent
+        let priorities = (1..=4)
+            .map(|unread| escalated_priority(NotificationPriority::Low, unread, threshold))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            priorities,
+            [
+                NotificationPriority::Low,
+                NotificationPriority::Low,
+                NotificationPriority::High,
+                NotificationPriority::High,
+            ]
+        );
+
+        // Without a threshold nothing is escalated
+        assert_eq!(
+            escalated_priority(NotificationPriority::Low, 100, None),
+            NotificationPriority::Low
+        );
+    }
+
+    #[test]
+    fn unserializable_content_falls_back() {
+        // The content of the event couldn't be serialized, so it is missing
+        let notification = || {
+            let mut notifi = Notification::new(vec![]);
+            notifi.event_id = Some(ruma::event_id!("$event:example.org").to_owned());
+            notifi.room_id = Some(ruma::room_id!("!room:example.org").to_owned());
+            notifi.sender = Some(ruma::user_id!("@alice:example.org").to_owned());
+            notifi.sender_display_name = Some("Alice".to_owned());
+            notifi.event_type = Some(TimelineEventType::RoomMessage);
+            notifi.room_name = Some("Lunch".to_owned());
+            notifi
+        };
+
+        let mut ids_only = notification();
+        apply_content_fallback(&mut ids_only, ContentFallback::EventIdOnly);
+        assert!(ids_only.event_id.is_some() && ids_only.room_id.is_some());
+        assert!(ids_only.sender.is_none() && ids_only.sender_display_name.is_none());
+        assert!(ids_only.event_type.is_none() && ids_only.room_name.is_none());
+        assert!(ids_only.content.is_none());
+
+        let mut placeholder = notification();
+        apply_content_fallback(&mut placeholder, ContentFallback::Placeholder);
+        assert_eq!(placeholder.sender_display_name.as_deref(), Some("Alice"));
+        let content: serde_json::Value =
+            serde_json::from_str(placeholder.content.unwrap().get()).unwrap();
+        assert_eq!(content["body"], CONTENT_PLACEHOLDER);
+    }
+
+    #[test]
+    fn static_location_share_body() {
+        let pin = json!({
+            "msgtype": "m.location",
+            "body": "Conference venue",
+            "geo_uri": "geo:51.5008,0.1247;u=35",
+            "org.matrix.msc3488.location": {
+                "uri": "geo:51.5008,0.1247;u=35",
+                "description": "Conference venue",
+            },
+        });
+        assert_eq!(
+            location_body("m.room.message", pin.as_object().unwrap()),
+            Some((
+                "📍 shared a location: Conference venue".to_owned(),
+                Some("geo:51.5008,0.1247;u=35".to_owned())
+            ))
+        );
+
+        // Without a place name, the body only repeats the geo URI
+        let unnamed = json!({
+            "msgtype": "m.location",
+            "body": "Location geo:51.5008,0.1247;u=35",
+            "geo_uri": "geo:51.5008,0.1247;u=35",
+        });
+        assert_eq!(
+            location_body("m.room.message", unnamed.as_object().unwrap())
+                .unwrap()
+                .0,
+            "📍 shared a location"
+        );
+
+        let live = json!({ "description": "Walking home", "live": true, "timeout": 3600000 });
+        assert_eq!(
+            location_body(BEACON_INFO_TYPE, live.as_object().unwrap()),
+            Some((
+                "📍 started sharing their live location: Walking home".to_owned(),
+                None
+            ))
+        );
+
+        let text = json!({ "msgtype": "m.text", "body": "geo:51.5008,0.1247" });
+        assert!(location_body("m.room.message", text.as_object().unwrap()).is_none());
+    }
+
+    #[test]
+    fn voice_message_body() {
+        let voice = serde_json::json!({
+            "msgtype": "m.audio",
+            "body": "Voice message.ogg",
+            "info": { "duration": 12_300, "mimetype": "audio/ogg" },
+            "org.matrix.msc1767.audio": { "duration": 12_300, "waveform": [0, 512, 1024] },
+            "org.matrix.msc3245.voice": {},
+        });
+        assert_eq!(
+            audio_body(voice.as_object().unwrap()).unwrap(),
+            "🎤 Voice message (0:12)"
+        );
+
+        let song = serde_json::json!({
+            "msgtype": "m.audio",
+            "body": "song.mp3",
+            "info": { "duration": 180_000 },
+        });
+        assert_eq!(
+            audio_body(song.as_object().unwrap()).unwrap(),
+            "Audio message"
+        );
+
+        let text = serde_json::json!({ "msgtype": "m.text", "body": "hi" });
+        assert!(audio_body(text.as_object().unwrap()).is_none());
+    }
+
+    #[test]
+    fn retryable_status_codes() {
+        let defaults = crate::config::default_push_retry_status_codes();
+
+        assert!(!is_retryable_status(400, &defaults));
+        assert!(!is_retryable_status(403, &defaults));
+        assert!(is_retryable_status(503, &defaults));
+        assert!(is_retryable_status(429, &defaults));
+
+        assert!(!is_retryable_status(503, &[429]));
+    }
+
+    #[test]
+    fn notification_id_in_payload() {
+        let id = notification_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert_ne!(id, notification_id());
+
+        let room_id = "!room:example.org";
+        let payload = device_payload(serde_json::json!({ "aps": { "badge": 1 } }), &id, room_id);
+        assert_eq!(payload[NOTIFICATION_ID_KEY], id.as_str());
+        assert_eq!(payload["aps"]["badge"], 1);
+
+        let device = Device::new("org.example.app".to_owned(), "token".to_owned());
+        let payload = device_payload(device.data.default_payload, &id, room_id);
+        assert_eq!(
+            payload,
+            serde_json::json!({ NOTIFICATION_ID_KEY: id, NOTIFICATION_GROUP_KEY: room_id })
+        );
+    }
+
+    #[test]
+    fn template_validation() {
+        for template in ["{sender", "{unknown}: {body}"] {
+            let settings = PusherSettings {
+                template: Some(template.to_owned()),
+                ..Default::default()
+            };
+            assert!(settings.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn notification_snapshot_contents() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let request =
+            |pushkey: &str, pdu_id: u8| (alice.to_owned(), pushkey.to_owned(), vec![pdu_id]);
+        let requests = vec![
+            (NotificationState::InFlight, request("phone", 1)),
+            (NotificationState::Queued, request("phone", 2)),
+            (NotificationState::Queued, request("laptop", 3)),
+        ];
+        let attempts = |_: &UserId, pushkey: &str| if pushkey == "phone" { 3 } else { 0 };
+        let event_id = |pdu_id: &[u8]| {
+            Ok(Some(
+                EventId::parse(format!("${}:example.org", pdu_id[0])).unwrap(),
+            ))
+        };
+
+        let snapshot =
+            collect_snapshot(requests.clone().into_iter(), 10, attempts, event_id).unwrap();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot[0].state, NotificationState::InFlight);
+        assert_eq!(snapshot[0].attempts, 3);
+        assert_eq!(
+            snapshot[1].event_id.as_deref(),
+            Some(ruma::event_id!("$2:example.org"))
+        );
+        assert_eq!(snapshot[1].state, NotificationState::Queued);
+        assert_eq!(snapshot[2].pushkey, "laptop");
+        assert_eq!(snapshot[2].attempts, 0);
+
+        let bounded = collect_snapshot(requests.into_iter(), 1, attempts, event_id).unwrap();
+        assert_eq!(bounded.len(), 1);
+    }
+
+    #[test]
+    fn digest_accumulates_events() {
+        assert!(pending_digest(Vec::new()).is_none());
+
+        let queued = vec![
+            (3, 1_000, ruma::event_id!("$first:example.org").to_owned()),
+            (7, 2_000, ruma::event_id!("$second:example.org").to_owned()),
+            (8, 3_000, ruma::event_id!("$third:example.org").to_owned()),
+        ];
+        let digest = pending_digest(queued).unwrap();
+
+        // All events go into one digest, events queued while it's sent are left for the next
+        assert_eq!(digest.event_ids.len(), 3);
+        assert_eq!(digest.event_ids[0], "$first:example.org");
+        assert_eq!(digest.until, 8);
+        // The schedule follows the oldest event
+        assert_eq!(digest.queued_at, 1_000);
+    }
+
+    #[test]
+    fn digest_delivered_on_schedule() {
+        let hour = 60 * 60;
+        let oldest = 1_000_000;
+
+        assert!(!digest_due(Some(oldest), oldest, hour));
+        assert!(!digest_due(Some(oldest), oldest + hour * 1000 - 1, hour));
+        assert!(digest_due(Some(oldest), oldest + hour * 1000, hour));
+        assert!(digest_due(None, oldest, hour));
+    }
+
+    #[test]
+    fn highlights_bypass_digest() {
+        let settings = PushDigestSettings {
+            enabled: true,
+            highlights_immediately: true,
+            ..Default::default()
+        };
+        assert!(queues_for_digest(&settings, false, false));
+        assert!(!queues_for_digest(&settings, false, true));
+
+        let settings = PushDigestSettings {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(queues_for_digest(&settings, false, true));
+        assert!(!queues_for_digest(
+            &PushDigestSettings::default(),
+            false,
+            false
+        ));
+    }
+
+    #[test]
+    fn email_digest_interval() {
+        let hour = 60 * 60;
+        let settings = PushDigestSettings {
+            email_interval: Some(24 * hour),
+            ..Default::default()
+        };
+
+        // Emails accumulate for the user's interval, other pushers use the server's
+        assert_eq!(digest_interval(&settings, true, hour), 24 * hour);
+        assert_eq!(digest_interval(&settings, false, hour), hour);
+        assert_eq!(
+            digest_interval(&PushDigestSettings::default(), true, hour),
+            hour
+        );
+        assert!(!digest_due(
+            Some(0),
+            hour * 1000,
+            digest_interval(&settings, true, hour)
+        ));
+
+        // Emails are digests even if the user didn't enable digests for their other pushers
+        assert!(queues_for_digest(&settings, true, false));
+        assert!(queues_for_digest(&settings, true, true));
+    }
+
+    #[test]
+    fn highlights_emailed_immediately() {
+        let settings = PushDigestSettings {
+            email_highlights_immediately: true,
+            ..Default::default()
+        };
+        assert!(!queues_for_digest(&settings, true, true));
+        assert!(queues_for_digest(&settings, true, false));
+    }
+
+    #[test]
+    fn digest_settings_defaults() {
+        let settings: PushDigestSettings = serde_json::from_str("{}").unwrap();
+        assert!(!settings.enabled);
+        assert!(!settings.highlights_immediately);
+
+        let settings: PushDigestSettings =
+            serde_json::from_str(r#"{"enabled": true, "highlights_immediately": true}"#).unwrap();
+        assert!(settings.enabled);
+        assert!(settings.highlights_immediately);
+    }
+
+    #[test]
+    fn bot_senders_are_suppressed() {
+        let registration: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+            id: irc
+            namespaces:
+              users:
+                - exclusive: true
+                  regex: "@_irc_.*:example.org"
+            "#,
+        )
+        .unwrap();
+        let appservice_users =
+            crate::service::appservice::user_namespace_regexes(&[("irc".to_owned(), registration)]);
+        let patterns = RegexSet::new(["^@.*bot:example.org$"]).unwrap();
+
+        let bridged = ruma::user_id!("@_irc_bob:example.org");
+        let bot = ruma::user_id!("@welcomebot:example.org");
+        let human = ruma::user_id!("@alice:example.org");
+
+        assert!(matches_bot_sender(bridged, &patterns, &appservice_users));
+        assert!(matches_bot_sender(bot, &patterns, &appservice_users));
+        assert!(!matches_bot_sender(human, &patterns, &appservice_users));
+    }
+
+    #[test]
+    fn event_id_only_wins_over_other_formats() {
+        let custom = PushFormat::from("org.example.custom");
+        let pusher = |format: Option<PushFormat>| {
+            let mut pusher = HttpPusherData::new("https://push.example.org".to_owned());
+            pusher.format = format;
+            pusher
+        };
+        let device = |format: Option<PushFormat>| {
+            let mut device = PusherData::default();
+            device.format = format;
+            device
+        };
+
+        assert_eq!(resolve_push_format(&pusher(None), &device(None)), None);
+        assert_eq!(
+            resolve_push_format(&pusher(Some(custom.clone())), &device(None)),
+            Some(custom.clone())
+        );
+        assert_eq!(
+            resolve_push_format(&pusher(None), &device(Some(custom.clone()))),
+            Some(custom.clone())
+        );
+
+        // Neither layer can override the other asking for event ids only
+        assert_eq!(
+            resolve_push_format(
+                &pusher(Some(PushFormat::EventIdOnly)),
+                &device(Some(custom.clone()))
+            ),
+            Some(PushFormat::EventIdOnly)
+        );
+        assert_eq!(
+            resolve_push_format(
+                &pusher(Some(custom)),
+                &device(Some(PushFormat::EventIdOnly))
+            ),
+            Some(PushFormat::EventIdOnly)
+        );
+    }
+
+    #[test]
+    fn users_with_pushers_are_listed_once() {
+        let senderkey = |user: &str, pushkey: &str| {
+            let mut key = user.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(pushkey.as_bytes());
+            key
+        };
+        let keys = vec![
+            senderkey("@alice:example.org", "laptop"),
+            senderkey("@alice:example.org", "phone"),
+            senderkey("@bob:example.org", "phone"),
+            senderkey("@carol:example.org", "tablet"),
+        ];
+
+        let users = senderkey_users(keys.into_iter())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            users,
+            [
+                ruma::user_id!("@alice:example.org"),
+                ruma::user_id!("@bob:example.org"),
+                ruma::user_id!("@carol:example.org"),
+            ]
+        );
+
+        assert!(senderkey_users(std::iter::empty()).next().is_none());
+        assert!(senderkey_users(vec![b"not a user".to_vec()].into_iter())
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn only_allowlisted_apps_register_pushers() {
+        let allowlist = vec!["org.example.app".to_owned()];
+        assert!(app_id_allowed(&allowlist, "org.example.app"));
+        assert!(!app_id_allowed(&allowlist, "org.example.app.dev"));
+        assert!(!app_id_allowed(&allowlist, "com.unknown.app"));
+
+        // Without an allowlist every app can register pushers
+        assert!(app_id_allowed(&[], "com.unknown.app"));
+    }
+
+    #[test]
+    fn devices_outside_active_hours_are_not_notified() {
+        // 2023-01-02 18:30 UTC
+        let now = 1_672_684_200_000;
+
+        let work_phone = ActiveHours {
+            start: 9 * 60,
+            end: 17 * 60,
+            utc_offset: 0,
+        };
+        let personal_phone = ActiveHours {
+            start: 8 * 60,
+            end: 22 * 60,
+            utc_offset: 0,
+        };
+        assert!(!work_phone.contains(now));
+        assert!(personal_phone.contains(now));
+
+        // It's still work time further west
+        let remote_work_phone = ActiveHours {
+            utc_offset: -5 * 60,
+            ..work_phone
+        };
+        assert!(remote_work_phone.contains(now));
+
+        let night_shift = ActiveHours {
+            start: 18 * 60,
+            end: 6 * 60,
+            utc_offset: 0,
+        };
+        assert!(night_shift.contains(now));
+        assert!(!night_shift.contains(now - 12 * 60 * 60 * 1000));
+    }
+
+    #[test]
+    fn stale_notifications_are_dropped() {
+        let sent = UInt::from(1_000_000_u32);
+        let hour = 60 * 60;
+
+        assert!(is_stale(sent, 1_000_000 + 2 * hour * 1000, Some(hour)));
+        assert!(!is_stale(sent, 1_000_000 + 60 * 1000, Some(hour)));
+        assert!(!is_stale(sent, 1_000_000 + 2 * hour * 1000, None));
+    }
+
+    #[test]
+    fn first_message_only_until_read() {
+        let window = Some(60 * 60);
+        let mut last_notified = None;
+
+        // The first message notifies
+        assert!(!notified_within(last_notified, 1_000, window));
+        last_notified = Some(1_000);
+
+        // A second message shortly after doesn't
+        assert!(notified_within(last_notified, 6_000, window));
+
+        // Reading the room resets the state
+        last_notified = None;
+        assert!(!notified_within(last_notified, 7_000, window));
+
+        // The mode can be disabled
+        assert!(!notified_within(Some(1_000), 6_000, None));
+    }
+
+    #[test]
+    fn pushkey_metadata_matches_pusher() {
+        let pusher: Pusher = serde_json::from_value(serde_json::json!({
+            "pushkey": "abc",
+            "app_id": "org.example.app",
+            "kind": "http",
+            "app_display_name": "App",
+            "device_display_name": "Phone",
+            "lang": "en",
+            "data": { "url": "https://push.example.org/_matrix/push/v1/notify" },
+        }))
+        .unwrap();
+
+        let (pushkey, app_id, kind) = pushkey_metadata(&pusher);
+        assert_eq!(pushkey, pusher.ids.pushkey);
+        assert_eq!(app_id, pusher.ids.app_id);
+        assert_eq!(kind, "http");
+    }
+
+    #[test]
+    fn failing_pusher_is_quarantined() {
+        let mut failures = HashMap::new();
+        let key = (
+            ruma::user_id!("@alice:example.org").to_owned(),
+            "broken".to_owned(),
+        );
+
+        let error = Error::BadPusher("Invalid PusherSettings in db.");
+        assert!(is_caused_by_pusher(&error));
+        assert!(!is_caused_by_pusher(&Error::BadDatabase(
+            "Invalid room name event in database."
+        )));
+        assert!(!is_caused_by_pusher(&Error::PushGatewayError(
+            http::StatusCode::BAD_GATEWAY
+        )));
+
+        for _ in 0..4 {
+            assert!(!count_failure(&mut failures, key.clone(), 5));
+        }
+        assert!(count_failure(&mut failures, key.clone(), 5));
+        assert!(!failures.contains_key(&key));
+    }
+
+    #[test]
+    fn room_notification_levels() {
+        let user = ruma::user_id!("@bob:example.org");
+        let room_id = ruma::room_id!("!room:example.org");
+        let ctx = push_condition_ctx(
+            room_id,
+            uint!(5),
+            user,
+            "Bob".to_owned(),
+            &RoomPowerLevelsEventContent::default(),
+        );
+
+        let plain =
+            pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello" })).to_sync_room_event();
+        let mention = pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello Bob" }))
+            .to_sync_room_event();
+
+        // Clients insert their own rules before the server default ones, after .m.rule.master
+        let ruleset = |kind: &str, rule: serde_json::Value| -> Ruleset {
+            let mut ruleset = serde_json::to_value(Ruleset::server_default(user)).unwrap();
+            let rules = ruleset[kind].as_array_mut().unwrap();
+            let index = if kind == "override" { 1 } else { 0 };
+            rules.insert(index, rule);
+            serde_json::from_value(ruleset).unwrap()
+        };
+
+        // All messages
+        let all = Ruleset::server_default(user);
+        assert!(notifies(all.get_actions(&plain, &ctx)));
+        assert!(notifies(all.get_actions(&mention, &ctx)));
+
+        // Mentions only
+        let mentions_only = ruleset(
+            "room",
+            serde_json::json!({
+                "rule_id": room_id,
+                "default": false,
+                "enabled": true,
+                "actions": [],
+            }),
+        );
+        assert!(!notifies(mentions_only.get_actions(&plain, &ctx)));
+        assert!(notifies(mentions_only.get_actions(&mention, &ctx)));
+
+        // None
+        let muted = ruleset(
+            "override",
+            serde_json::json!({
+                "rule_id": room_id,
+                "default": false,
+                "enabled": true,
+                "conditions": [
+                    { "kind": "event_match", "key": "room_id", "pattern": room_id },
+                ],
+                "actions": [],
+            }),
+        );
+        assert!(!notifies(muted.get_actions(&plain, &ctx)));
+        assert!(!notifies(muted.get_actions(&mention, &ctx)));
+    }
+
+    #[test]
+    fn pusher_dump_round_trip() {
+        let pusher = |pushkey: &str| -> Pusher {
+            serde_json::from_value(serde_json::json!({
+                "pushkey": pushkey,
+                "kind": "http",
+                "app_id": "org.example.app",
+                "app_display_name": "Example",
+                "device_display_name": "Phone",
+                "profile_tag": "tag",
+                "lang": "en",
+                "data": {
+                    "url": "https://push.example.org/_matrix/push/v1/notify",
+                    "format": "event_id_only",
+                },
+            }))
+            .unwrap()
+        };
+
+        let dump = vec![
+            PusherDump {
+                user_id: ruma::user_id!("@alice:example.org").to_owned(),
+                pusher: pusher("alice-phone"),
+                settings: PusherSettings {
+                    max_attempts: Some(3),
+                    ..Default::default()
+                },
+            },
+            PusherDump {
+                user_id: ruma::user_id!("@bob:example.org").to_owned(),
+                pusher: pusher("bob-laptop"),
+                settings: PusherSettings::default(),
+            },
+        ];
+
+        let json = serde_json::to_string(&dump).unwrap();
+        let imported: Vec<PusherDump> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(imported.len(), dump.len());
+        for (imported, exported) in imported.iter().zip(&dump) {
+            assert_eq!(imported.user_id, exported.user_id);
+            assert_eq!(
+                serde_json::to_value(&imported.pusher).unwrap(),
+                serde_json::to_value(&exported.pusher).unwrap()
+            );
+            assert_eq!(
+                imported.settings.max_attempts,
+                exported.settings.max_attempts
+            );
+        }
+    }
+
+    #[test]
+    fn thread_replies_are_grouped_by_thread() {
+        let reply = pdu(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "in thread",
+            "m.relates_to": { "rel_type": "m.thread", "event_id": "$root:example.org" },
+        }));
+        assert_eq!(notification_group(&reply), "$root:example.org");
+
+        let message = pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello" }));
+        assert_eq!(notification_group(&message), "!room:example.org");
+    }
+
+    #[test]
+    fn acled_servers_are_not_pushed() {
+        let spammer = ruma::user_id!("@spammer:evil.example.org");
+        let friend = ruma::user_id!("@friend:example.org");
+
+        let before: RoomServerAclEventContent =
+            serde_json::from_value(serde_json::json!({ "allow": ["*"] })).unwrap();
+        assert!(!denies_sender(&before, spammer));
+
+        // Queued notifications of the server are dropped once it's denied
+        let after: RoomServerAclEventContent = serde_json::from_value(serde_json::json!({
+            "allow": ["*"],
+            "deny": ["evil.example.org"],
+        }))
+        .unwrap();
+        assert!(denies_sender(&after, spammer));
+        assert!(!denies_sender(&after, friend));
+    }
+
+    #[test]
+    fn rule_evaluation_trace() {
+        let user = ruma::user_id!("@bob:example.org");
+        let ctx = push_condition_ctx(
+            ruma::room_id!("!room:example.org"),
+            uint!(5),
+            user,
+            "Bob".to_owned(),
+            &RoomPowerLevelsEventContent::default(),
+        );
+        let mention = pdu(serde_json::json!({ "msgtype": "m.text", "body": "hello Bob" }))
+            .to_sync_room_event();
+
+        let trace = trace_ruleset(&Ruleset::server_default(user), &mention, &ctx);
+
+        // The master rule is disabled by default
+        assert_eq!(trace[0].rule_id, ".m.rule.master");
+        assert!(!trace[0].enabled);
+        assert!(!trace[0].matched);
+
+        let last = trace.last().unwrap();
+        assert_eq!(last.rule_id, ".m.rule.contains_display_name");
+        assert!(last.matched);
+        assert_eq!(
+            last.conditions,
+            [(r#"{"kind":"contains_display_name"}"#.to_owned(), true)]
+        );
+        assert!(notifies(&last.actions));
+        assert_eq!(trace.iter().filter(|rule| rule.matched).count(), 1);
+    }
+
+    #[test]
+    fn custom_gateway_headers() {
+        let settings: PusherSettings = serde_json::from_value(serde_json::json!({
+            "headers": { "X-Tenant-Id": "tenant", "Authorization": "Bearer secret" },
+        }))
+        .unwrap();
+        assert!(settings.validate().is_ok());
+        assert!(!format!("{settings:?}").contains("secret"));
+
+        let stored: PusherSettings =
+            serde_json::from_slice(&serde_json::to_vec(&settings).unwrap()).unwrap();
+
+        let mut headers = http::HeaderMap::new();
+        set_gateway_headers(&mut headers, &stored).unwrap();
+        assert_eq!(headers["x-tenant-id"], "tenant");
+        assert_eq!(headers[http::header::AUTHORIZATION], "Bearer secret");
+        assert!(headers[http::header::AUTHORIZATION].is_sensitive());
+
+        for headers in [
+            serde_json::json!({ "Invalid Name": "value" }),
+            serde_json::json!({ "X-Region": "eu\n" }),
+            serde_json::json!({ "Host": "evil.example.org" }),
+        ] {
+            let settings: PusherSettings =
+                serde_json::from_value(serde_json::json!({ "headers": headers })).unwrap();
+            assert!(settings.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn scheduling_event_body() {
+        let content = serde_json::json!({
+            "title": "Team sync",
+            "start": 1_700_000_000_000_u64,
+            "end": 1_700_003_600_000_u64,
+        });
+        assert_eq!(
+            scheduling_body(content.as_object().unwrap()),
+            "📅 Team sync (2023-11-14 22:13 UTC)"
+        );
+
+        let content = serde_json::json!({ "name": "Retro", "start_time": "tomorrow 10:00" });
+        assert_eq!(
+            scheduling_body(content.as_object().unwrap()),
+            "📅 Retro (tomorrow 10:00)"
+        );
+
+        let content = serde_json::json!({ "title": "" });
+        assert_eq!(
+            scheduling_body(content.as_object().unwrap()),
+            "📅 New event"
+        );
+
+        assert_eq!(format_utc(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_utc(951_782_400_000), "2000-02-29 00:00 UTC");
+    }
+
+    #[test]
+    fn master_rule_disables_all_push() {
+        let user = ruma::user_id!("@alice:example.org");
+
+        let ruleset = Ruleset::server_default(user);
+        assert!(!master_rule_disables_push(&ruleset));
+
+        let mut ruleset = serde_json::to_value(Ruleset::server_default(user)).unwrap();
+        for rule in ruleset["override"].as_array_mut().unwrap() {
+            if rule["rule_id"] == ".m.rule.master" {
+                rule["enabled"] = true.into();
+            }
+        }
+        let ruleset: Ruleset = serde_json::from_value(ruleset).unwrap();
+        assert!(master_rule_disables_push(&ruleset));
+    }
+
+    #[test]
+    fn quick_actions_metadata() {
+        let settings: PusherSettings =
+            serde_json::from_value(serde_json::json!({ "quick_actions": true })).unwrap();
+        assert!(settings.quick_actions);
+        assert!(!PusherSettings::default().quick_actions);
+
+        let actions = quick_actions(
+            ruma::room_id!("!room:example.org"),
+            ruma::event_id!("$event:example.org"),
+        );
+        let ids = actions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| action["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["reply", "mark_read", "mute"]);
+
+        assert_eq!(
+            actions[1]["path"],
+            "/_matrix/client/v3/rooms/!room:example.org/receipt/m.read/$event:example.org"
+        );
+        assert_eq!(actions[0]["reply_to"], "$event:example.org");
+        assert!(actions
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|action| action["method"].is_string() && action["path"].is_string()));
+    }
+
+    #[test]
+    fn requeue_failed_notification() {
+        let failed = FailedNotification {
+            user_id: ruma::user_id!("@alice:example.org").to_owned(),
+            pushkey: "pushkey".to_owned(),
+            // Pdu ids are binary and can contain the separator
+            pdu_id: vec![0, 0, 0, 0xff, 1, 0xff],
+        };
+
+        let stored = FailedNotification::from_bytes(&failed.to_bytes()).unwrap();
+        assert_eq!(requeue_target(Some(stored)).unwrap(), failed);
+
+        // Delivered notifications are forgotten and can't be requeued
+        assert!(requeue_target(None).is_err());
+    }
+
+    #[test]
+    fn cached_gateway_probe() {
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now();
+        let probe = GatewayProbe {
+            status: 200,
+            body: "OK".to_owned(),
+        };
+        let host = gateway_host("https://push.example.org/_matrix/push/v1/notify").unwrap();
+        assert_eq!(host, "push.example.org:443");
+
+        let mut cache = GatewayProbeCache::default();
+        assert_eq!(cache.get(&host, ttl, now), None);
+        cache.insert(host.clone(), probe.clone(), now);
+
+        // A second check within the ttl doesn't reach the gateway
+        let later = now + Duration::from_secs(30);
+        assert_eq!(cache.get(&host, ttl, later), Some(probe.clone()));
+        assert_eq!(
+            cache.get(
+                &gateway_host("https://push.example.org/health").unwrap(),
+                ttl,
+                later
+            ),
+            Some(probe)
+        );
+
+        assert_eq!(cache.get(&host, ttl, now + ttl), None);
+
+        cache.invalidate(&host);
+        assert_eq!(cache.get(&host, ttl, later), None);
+    }
+
+    #[tokio::test]
+    async fn parallel_device_dispatch() {
+        use std::cell::Cell;
+
+        let running = Cell::new(0);
+        let max_running = Cell::new(0);
+
+        let outcomes = for_each_device((0..6).collect(), 3, |device: u32| {
+            let (running, max_running) = (&running, &max_running);
+            async move {
+                running.set(running.get() + 1);
+                max_running.set(max_running.get().max(running.get()));
+                tokio::task::yield_now().await;
+                running.set(running.get() - 1);
+
+                if device % 2 == 0 {
+                    Ok(device)
+                } else {
+                    Err(device)
+                }
+            }
+        })
+        .await;
+
+        // Devices are notified concurrently, but not more than the limit at once
+        assert_eq!(max_running.get(), 3);
+        assert_eq!(outcomes, [Ok(0), Err(1), Ok(2), Err(3), Ok(4), Err(5)]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn failed_transaction_is_rolled_back() {
+        crate::KeyValueDatabase::load_for_tests().await;
+
+        let user = ruma::user_id!("@transaction:example.org");
+        let pusher = &services().pusher;
+
+        pusher
+            .set_pusher_device(user, "kept", ruma::device_id!("OLD"))
+            .unwrap();
+
+        let result = pusher.transaction(|| {
+            pusher.set_pusher_device(user, "kept", ruma::device_id!("NEW"))?;
+            pusher.set_pusher_device(user, "new", ruma::device_id!("NEW"))?;
+            Err(Error::BadDatabase("Write failed."))
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            pusher
+                .db
+                .get_pusher_device(user, "kept")
+                .unwrap()
+                .as_deref(),
+            Some(ruma::device_id!("OLD"))
+        );
+        assert_eq!(pusher.db.get_pusher_device(user, "new").unwrap(), None);
+
+        // A panicking transaction is rolled back too, and doesn't turn later ones into nested
+        // transactions that can't roll back
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pusher.transaction(|| {
+                pusher.set_pusher_device(user, "new", ruma::device_id!("NEW"))?;
+                panic!("Transaction panicked.");
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(pusher.db.get_pusher_device(user, "new").unwrap(), None);
+
+        let result = pusher.transaction(|| {
+            pusher.set_pusher_device(user, "new", ruma::device_id!("NEW"))?;
+            Err(Error::BadDatabase("Write failed."))
+        });
+        assert!(result.is_err());
+        assert_eq!(pusher.db.get_pusher_device(user, "new").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn fallback_gateway_delivers_when_primary_fails() {
+        let primary = "https://push.example.org/_matrix/push/v1/notify";
+        let mut configured = BTreeMap::new();
+        configured.insert(
+            "org.example.app".to_owned(),
+            "https://backup.example.org/_matrix/push/v1/notify".to_owned(),
+        );
+        let settings = PusherSettings::default();
+        let fallback = fallback_gateway(&settings, "org.example.app", &configured);
+
+        let (result, gateway) = with_fallback(primary, fallback, |gateway| async move {
+            if gateway == primary {
+                Err(Error::BadServerResponse(
+                    "Timeout waiting for push gateway response",
+                ))
+            } else {
+                Ok(Vec::<String>::new())
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(gateway, "https://backup.example.org/_matrix/push/v1/notify");
+
+        // The fallback of the pusher wins over the one of the app
+        let settings = PusherSettings {
+            fallback_gateway: Some("https://own.example.org".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            fallback_gateway(&settings, "org.example.app", &configured),
+            Some("https://own.example.org")
+        );
+
+        // Without a fallback, the failure of the primary gateway is returned
+        let (result, gateway) = with_fallback(primary, None, |_| async {
+            Err::<(), _>(Error::BadServerResponse(
+                "Push gateway returned bad response.",
+            ))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(gateway, primary);
+    }
+
+    #[test]
+    fn room_topic_in_notification() {
+        let content = r#"{"topic":"  Talk about Conduit  "}"#;
+        assert_eq!(
+            notification_topic(true, Some(content)).as_deref(),
+            Some("Talk about Conduit")
+        );
+        assert_eq!(notification_topic(false, Some(content)), None);
+
+        let long = json!({ "topic": "a".repeat(500) }).to_string();
+        assert!(notification_topic(true, Some(&long)).unwrap().len() <= ROOM_TOPIC_LENGTH);
+
+        // Rooms without a usable topic get no topic in their notifications
+        assert_eq!(notification_topic(true, None), None);
+        assert_eq!(notification_topic(true, Some(r#"{"topic":""}"#)), None);
+        assert_eq!(notification_topic(true, Some(r#"{"topic":5}"#)), None);
+    }
+
+    #[test]
+    fn payload_format_versions() {
+        let default_payload = json!({ "app": "data" });
+
+        let legacy = versioned_payload(1, default_payload.clone(), "id", "!room:example.org");
+        assert_eq!(legacy, default_payload);
+
+        let latest = versioned_payload(
+            LATEST_FORMAT_VERSION,
+            default_payload,
+            "id",
+            "!room:example.org",
+        );
+        assert_eq!(latest["app"], "data");
+        assert_eq!(latest[NOTIFICATION_ID_KEY], "id");
+        assert_eq!(latest[NOTIFICATION_GROUP_KEY], "!room:example.org");
+
+        // Pushers registered before format versions existed keep the first layout
+        assert_eq!(PusherSettings::default().format_version(), 1);
+        let settings = PusherSettings {
+            format_version: Some(LATEST_FORMAT_VERSION + 1),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn notification_history_flush() {
+        let record = |notification_id: &str| NotificationRecord {
+            notification_id: notification_id.to_owned(),
+            user_id: ruma::user_id!("@alice:example.org").to_owned(),
+            pushkey: "pushkey".to_owned(),
+            event_id: ruma::event_id!("$event:example.org").to_owned(),
+            sent_at: 1,
+            delivered: true,
+        };
+        let buffer = Mutex::new(vec![record("first"), record("second")]);
+
+        // A failed write keeps the records for the next flush
+        assert!(flush_history(&buffer, |_| Err(Error::bad_database("full"))).is_err());
+        assert_eq!(buffer.lock().unwrap().len(), 2);
+
+        // Flushing on shutdown persists everything that was buffered
+        let mut persisted = Vec::new();
+        let flushed = flush_history(&buffer, |records| {
+            persisted.extend_from_slice(records);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(flushed, 2);
+        assert_eq!(persisted, [record("first"), record("second")]);
+        assert!(buffer.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn notification_email_rendering() {
+        let event = |sender: &str, room: &str, snippet: Option<&str>| EmailEvent {
+            sender: sender.to_owned(),
+            room: room.to_owned(),
+            snippet: snippet.map(ToOwned::to_owned),
+        };
+
+        assert_eq!(notification_email(&[]), None);
+
+        let (subject, body) =
+            notification_email(&[event("Alice", "Conduit", Some("hello"))]).unwrap();
+        assert_eq!(subject, "New message from Alice in Conduit");
+        assert_eq!(body, "Alice in Conduit:\nhello\n");
+
+        // Pending events are coalesced into one email, hiding contents that aren't available
+        let (subject, body) = notification_email(&[
+            event("Alice", "Conduit", Some("hello")),
+            event("Bob", "Conduit", None),
+            event("Carol", "Lounge", Some("hi")),
+        ])
+        .unwrap();
+        assert_eq!(subject, "3 new notifications in 2 rooms");
+        assert!(body.contains("Bob in Conduit:\nYou have a new message\n"));
+        assert!(body.contains("Carol in Lounge:\nhi\n"));
+    }
+
+    #[test]
+    fn notification_schema_validation() {
+        let mut notification = Notification::new(vec![Device::new(
+            "org.example.app".to_owned(),
+            "token".to_owned(),
+        )]);
+        notification.event_id = Some(ruma::event_id!("$event:example.org").to_owned());
+        // High priority is the default, which is left out
+        notification.prio = NotificationPriority::Low;
+        notification.counts = NotificationCounts::new(uint!(3), uint!(0));
+        let json = notification_json(&notification, None);
+        assert_eq!(notification_schema_mismatch(&json), None);
+
+        let malformed = |pointer: &str, value: serde_json::Value| {
+            let mut json = json.clone();
+            *json.pointer_mut(pointer).unwrap() = value;
+            notification_schema_mismatch(&json)
+        };
+        assert!(malformed("/prio", json!("urgent")).is_some());
+        assert!(malformed("/counts/unread", json!(-1)).is_some());
+        assert!(malformed("/devices/0/pushkey", json!(5)).is_some());
+        assert!(malformed("/devices", json!([])).is_some());
+        assert!(malformed("/event_id", json!({})).is_some());
+    }
+
+    #[test]
+    fn unauthorized_room_mention() {
+        let mention = pdu(serde_json::json!({ "msgtype": "m.text", "body": "@room lunch!" }));
+        let intentional = pdu(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "lunch!",
+            "m.mentions": { "room": true },
+        }));
+        let message = pdu(serde_json::json!({ "msgtype": "m.text", "body": "lunch?" }));
+        let roommate = pdu(serde_json::json!({ "msgtype": "m.text", "body": "ask @roommate" }));
+        assert!(is_room_mention(&mention));
+        assert!(is_room_mention(&intentional));
+        assert!(!is_room_mention(&message));
+        assert!(!is_room_mention(&roommate));
+        assert!(contains_word("lunch, @room!", "@room"));
+        assert!(!contains_word("x@room", "@room"));
+
+        // By default, only moderators may notify the whole room
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        assert!(suppresses_room_mention(&mention, &power_levels, false));
+        assert!(suppresses_room_mention(&intentional, &power_levels, false));
+        assert!(!suppresses_room_mention(&message, &power_levels, false));
+        assert!(!suppresses_room_mention(&mention, &power_levels, true));
+
+        power_levels
+            .users
+            .insert(mention.sender.clone(), power_levels.notifications.room);
+        assert!(!suppresses_room_mention(&mention, &power_levels, false));
+    }
+
+    #[test]
+    fn missed_call_updates() {
+        let invite = |call_id: &str| {
+            call_update(
+                &TimelineEventType::CallInvite,
+                &json!({ "call_id": call_id, "lifetime": 60000, "version": 0 }).to_string(),
+            )
+        };
+
+        // Both invites of a glare are counted, until the losing one is hung up
+        assert_eq!(invite("a"), Some(CallUpdate::Invite("a".to_owned())));
+        assert_eq!(invite("b"), Some(CallUpdate::Invite("b".to_owned())));
+        assert_eq!(
+            call_update(&TimelineEventType::CallHangup, r#"{"call_id":"b"}"#),
+            Some(CallUpdate::End("b".to_owned()))
+        );
+        assert_eq!(
+            call_update(&TimelineEventType::CallAnswer, r#"{"call_id":"a"}"#),
+            Some(CallUpdate::End("a".to_owned()))
+        );
+
+        assert_eq!(call_update(&TimelineEventType::CallInvite, "{}"), None);
+        assert_eq!(
+            call_update(&TimelineEventType::RoomMessage, r#"{"call_id":"a"}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn rejected_pushkeys_and_pauses() {
+        assert_eq!(
+            rejected_pushkeys(br#"{"rejected":["uninstalled","gone"]}"#),
+            ["uninstalled", "gone"]
+        );
+        assert!(rejected_pushkeys(br#"{"rejected":[]}"#).is_empty());
+        assert!(rejected_pushkeys(b"").is_empty());
+
+        // Gateways that kept failing are left alone until the pause is over
+        assert!(!still_paused(None, 1000));
+        assert!(still_paused(Some(2000), 1000));
+        assert!(!still_paused(Some(2000), 2000));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn no_notice_after_leaving_the_room() {
+        crate::KeyValueDatabase::load_for_tests().await;
+
+        // Sending to this gateway would fail, as nothing listens on the discard port
+        let pusher: Pusher = serde_json::from_value(serde_json::json!({
+            "pushkey": "token",
+            "kind": "http",
+            "app_id": "org.example.app",
+            "app_display_name": "Example",
+            "device_display_name": "Phone",
+            "lang": "en",
+            "data": { "url": "http://127.0.0.1:9/_matrix/push/v1/notify" },
+        }))
+        .unwrap();
+
+        let user = ruma::user_id!("@bob:example.org");
+        let message = pdu(serde_json::json!({ "msgtype": "m.text", "body": "Hi bob!" }));
+
+        // Bob left the room after the push was queued
+        services()
+            .rooms
+            .state_cache
+            .db
+            .mark_as_left(user, &message.room_id)
+            .unwrap();
+
+        let outcomes = services()
+            .pusher
+            .send_push_notice(
+                user,
+                uint!(1),
+                vec![pusher],
+                Ruleset::server_default(user),
+                &message,
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(outcomes[..], [Ok(())]));
+        assert!(services().pusher.history_buffer.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::Method;
+use ruma::api::client::error::ErrorKind;
+
+use crate::{
+    config::{RateLimitConfig, RateLimitsConfig},
+    services, Error, Result,
+};
+
+/// Buckets that are full again are dropped once this many are tracked
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// Groups of client endpoints that share a rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitClass {
+    Login,
+    Registration,
+    Message,
+    Other,
+}
+
+impl RateLimitClass {
+    /// Returns the class of the route matched by `path`, given with `:name` placeholders.
+    /// Endpoints outside of the client-server API aren't rate limited.
+    pub fn from_route(method: &Method, path: &str) -> Option<Self> {
+        let path = path
+            .strip_prefix("/_matrix/client/")?
+            .split_once('/')
+            .map_or("", |(_version, path)| path);
+        let segments: Vec<_> = path.split('/').collect();
+
+        Some(match (method, segments.as_slice()) {
+            (&Method::POST, ["login"]) | (&Method::POST, ["account", "password"]) => Self::Login,
+            (&Method::POST, ["register"])
+            | (&Method::GET, ["register", "available"])
+            | (&Method::GET, ["register", "m.login.registration_token", "validity"]) => {
+                Self::Registration
+            }
+            (&Method::PUT, ["rooms", _, "send", _, _])
+            | (&Method::PUT, ["rooms", _, "redact", _, _]) => Self::Message,
+            _ => Self::Other,
+        })
+    }
+
+    fn config(self, config: &RateLimitsConfig) -> &RateLimitConfig {
+        match self {
+            Self::Login => &config.login,
+            Self::Registration => &config.registration,
+            Self::Message => &config.message,
+            Self::Other => &config.other,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(config.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.per_second).min(f64::from(config.burst));
+        self.updated = now;
+    }
+
+    /// Takes a token for a request. Returns how long until the next token is available if the
+    /// bucket is empty.
+    fn take(
+        &mut self,
+        config: &RateLimitConfig,
+        now: Instant,
+    ) -> std::result::Result<(), Duration> {
+        self.refill(config, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            // A bucket that never refills would make this infinite
+            let seconds = ((1.0 - self.tokens) / config.per_second).clamp(0.0, f64::from(u32::MAX));
+            Err(Duration::from_secs_f64(seconds))
+        }
+    }
+
+    fn is_full(&mut self, config: &RateLimitConfig, now: Instant) -> bool {
+        self.refill(config, now);
+        self.tokens >= f64::from(config.burst)
+    }
+}
+
+pub struct Service {
+    pub buckets: Mutex<HashMap<(String, RateLimitClass), TokenBucket>>,
+}
+
+impl Service {
+    /// Counts a request against the rate limit of the requester, which is the user the access
+    /// token belongs to, or the IP address for unauthenticated requests. `ip` must not come from
+    /// anything the client can choose, like the first `X-Forwarded-For` entry, or every request
+    /// would get a fresh bucket.
+    ///
+    /// Appservices and admins are only checked for exemption once they hit the limit, as
+    /// looking them up is more expensive than the bucket.
+    pub fn check(
+        &self,
+        class: RateLimitClass,
+        access_token: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        let config = services().globals.rate_limits();
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let user_id = match access_token {
+            Some(token) => services()
+                .users
+                .find_from_token(token)?
+                .map(|(user_id, _)| user_id),
+            None => None,
+        };
+        let requester = match (&user_id, ip) {
+            (Some(user_id), _) => user_id.to_string(),
+            (None, Some(ip)) => ip_requester(ip),
+            (None, None) => return Ok(()),
+        };
+
+        let retry_after = match self.take(requester, class, config, Instant::now()) {
+            Ok(()) => return Ok(()),
+            Err(retry_after) => retry_after,
+        };
+
+        if config.exempt_appservices {
+            if let Some(token) = access_token {
+                let is_appservice = services()
+                    .appservice
+                    .all()?
+                    .iter()
+                    .any(|(_, registration)| {
+                        registration
+                            .get("as_token")
+                            .and_then(|as_token| as_token.as_str())
+                            == Some(token)
+                    });
+                if is_appservice {
+                    return Ok(());
+                }
+            }
+        }
+
+        if config.exempt_admins {
+            if let Some(user_id) = &user_id {
+                if services().users.is_admin(user_id)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(limit_exceeded(retry_after))
+    }
+
+    fn take(
+        &self,
+        requester: String,
+        class: RateLimitClass,
+        config: &RateLimitsConfig,
+        now: Instant,
+    ) -> std::result::Result<(), Duration> {
+        let bucket_config = class.config(config);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            buckets.retain(|(_, bucket_class), bucket| {
+                !bucket.is_full(bucket_class.config(config), now)
+            });
+        }
+
+        buckets
+            .entry((requester, class))
+            .or_insert_with(|| TokenBucket::new(bucket_config, now))
+            .take(bucket_config, now)
+    }
+}
+
+/// Returns the requester an IP address is counted as. IPv6 hosts usually get a whole /64 network,
+/// so they could switch to a new address for every request.
+fn ip_requester(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.to_string(),
+            None => {
+                let [a, b, c, d, ..] = ip.segments();
+                format!("{}/64", Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+fn limit_exceeded(retry_after: Duration) -> Error {
+    Error::BadRequest(
+        ErrorKind::LimitExceeded {
+            retry_after_ms: Some(retry_after),
+        },
+        "Too many requests.",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::api::OutgoingResponse;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let config = RateLimitConfig {
+            burst: 2,
+            per_second: 0.5,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config, start);
+
+        assert!(bucket.take(&config, start).is_ok());
+        assert!(bucket.take(&config, start).is_ok());
+        assert_eq!(bucket.take(&config, start), Err(Duration::from_secs(2)));
+
+        // Half a token was refilled after a second
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(&config, later), Err(Duration::from_secs(1)));
+        assert!(bucket.take(&config, start + Duration::from_secs(2)).is_ok());
+
+        // The bucket never holds more than the burst
+        let much_later = start + Duration::from_secs(60);
+        assert!(bucket.is_full(&config, much_later));
+        assert!(bucket.take(&config, much_later).is_ok());
+        assert!(bucket.take(&config, much_later).is_ok());
+        assert!(bucket.take(&config, much_later).is_err());
+    }
+
+    #[test]
+    fn routes_are_classified() {
+        let class = RateLimitClass::from_route;
+
+        assert_eq!(
+            class(&Method::POST, "/_matrix/client/v3/login"),
+            Some(RateLimitClass::Login)
+        );
+        assert_eq!(
+            class(&Method::POST, "/_matrix/client/r0/register"),
+            Some(RateLimitClass::Registration)
+        );
+        assert_eq!(
+            class(
+                &Method::PUT,
+                "/_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id"
+            ),
+            Some(RateLimitClass::Message)
+        );
+        assert_eq!(
+            class(&Method::GET, "/_matrix/client/v3/login"),
+            Some(RateLimitClass::Other)
+        );
+        assert_eq!(class(&Method::GET, "/_matrix/federation/v1/version"), None);
+    }
+
+    #[test]
+    fn ip_addresses_share_buckets() {
+        let requester = |ip: &str| ip_requester(ip.parse().unwrap());
+
+        assert_eq!(requester("192.0.2.1"), "192.0.2.1");
+        assert_eq!(requester("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(requester("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::/64");
+        assert_eq!(
+            requester("2001:db8:1:2:3:4:5:6"),
+            requester("2001:db8:1:2:ffff::1")
+        );
+        assert_ne!(requester("2001:db8:1:2::1"), requester("2001:db8:1:3::1"));
+    }
+
+    #[test]
+    fn limit_exceeded_has_retry_after() {
+        let response = limit_exceeded(Duration::from_millis(1500))
+            .to_response()
+            .0
+            .try_into_http_response::<Vec<u8>>()
+            .unwrap();
+
+        assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["errcode"], "M_LIMIT_EXCEEDED");
+        assert_eq!(body["retry_after_ms"], 1500);
+    }
+}
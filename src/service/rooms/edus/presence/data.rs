@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use super::PresenceData;
 use crate::Result;
 use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
 
@@ -15,19 +16,16 @@ pub trait Data: Send + Sync {
         presence: PresenceEvent,
     ) -> Result<()>;
 
-    /// Resets the presence timeout, so the user will stay in their current presence state.
-    fn ping_presence(&self, user_id: &UserId) -> Result<()>;
+    /// Replaces the current presence of the user.
+    fn set_presence(&self, user_id: &UserId, presence: &PresenceData) -> Result<()>;
 
-    /// Returns the timestamp of the last presence update of this user in millis since the unix epoch.
-    fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>>;
+    /// Returns the current presence of the user, if it's known.
+    fn get_presence(&self, user_id: &UserId) -> Result<Option<PresenceData>>;
 
-    /// Returns the presence event with correct last_active_ago.
-    fn get_presence_event(
-        &self,
-        room_id: &RoomId,
-        user_id: &UserId,
-        count: u64,
-    ) -> Result<Option<PresenceEvent>>;
+    /// Returns the current presence of all users whose presence is known.
+    fn all_presences<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, PresenceData)>> + 'a>;
 
     /// Returns the most recent presence updates that happened after the event with id `since`.
     fn presence_since(
@@ -1,10 +1,31 @@
 mod data;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 pub use data::Data;
-use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
+use ruma::{
+    api::federation::transactions::edu::{Edu, PresenceContent, PresenceUpdate},
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    OwnedUserId, RoomId, UInt, UserId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use crate::Result;
+use crate::{services, utils, Result};
+
+/// The presence of a user as it was last set.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct PresenceData {
+    pub presence: PresenceState,
+    /// When the user was last active, in milliseconds since the unix epoch
+    pub last_active_ts: u64,
+    pub currently_active: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_msg: Option<String>,
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -24,91 +45,206 @@ impl Service {
         self.db.update_presence(user_id, room_id, presence)
     }
 
-    /// Resets the presence timeout, so the user will stay in their current presence state.
-    pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
-        self.db.ping_presence(user_id)
-    }
-
-    pub fn get_last_presence_event(
+    /// Sets the presence of a local user and sends it to the rooms and servers of the user.
+    pub fn set_presence(
         &self,
         user_id: &UserId,
-        room_id: &RoomId,
-    ) -> Result<Option<PresenceEvent>> {
-        let last_update = match self.db.last_presence_update(user_id)? {
-            Some(last) => last,
-            None => return Ok(None),
+        presence: PresenceState,
+        status_msg: Option<String>,
+    ) -> Result<()> {
+        if !services().globals.allow_presence() {
+            return Ok(());
+        }
+
+        let data = PresenceData {
+            currently_active: presence == PresenceState::Online,
+            presence,
+            last_active_ts: utils::millis_since_unix_epoch(),
+            status_msg,
         };
 
-        self.db.get_presence_event(room_id, user_id, last_update)
+        self.store(user_id, &data)?;
+        self.federation_send(user_id, &data)
     }
 
-    /* TODO
-    /// Sets all users to offline who have been quiet for too long.
-    fn _presence_maintain(
-        &self,
-        rooms: &super::Rooms,
-        globals: &super::super::globals::Globals,
-    ) -> Result<()> {
-        let current_timestamp = utils::millis_since_unix_epoch();
-
-        for (user_id_bytes, last_timestamp) in self
-            .userid_lastpresenceupdate
-            .iter()
-            .filter_map(|(k, bytes)| {
-                Some((
-                    k,
-                    utils::u64_from_bytes(&bytes)
-                        .map_err(|_| {
-                            Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                        })
-                        .ok()?,
-                ))
+    /// Stores the presence of a remote user, which was sent by their server.
+    pub fn set_remote_presence(&self, user_id: &UserId, data: &PresenceData) -> Result<()> {
+        if !services().globals.allow_presence() {
+            return Ok(());
+        }
+
+        self.store(user_id, data)
+    }
+
+    /// Returns the current presence of the user, `None` if it's unknown or presence is disabled.
+    pub fn get_presence(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
+        if !services().globals.allow_presence() {
+            return Ok(None);
+        }
+
+        self.db
+            .get_presence(user_id)?
+            .map(|data| {
+                presence_event(
+                    user_id,
+                    &data,
+                    UInt::new_saturating(
+                        utils::millis_since_unix_epoch().saturating_sub(data.last_active_ts),
+                    ),
+                )
             })
-            .take_while(|(_, timestamp)| current_timestamp.saturating_sub(*timestamp) > 5 * 60_000)
-        // 5 Minutes
-        {
-            // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
-            let user_id: Box<_> = utils::string_from_bytes(&user_id_bytes)
-                .map_err(|_| {
-                    Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
-                })?
-                .try_into()
-                .map_err(|_| Error::bad_database("Invalid UserId in userid_lastpresenceupdate."))?;
-            for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
-                let mut presence_id = room_id.as_bytes().to_vec();
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&count);
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&user_id_bytes);
-
-                self.presenceid_presence.insert(
-                    &presence_id,
-                    &serde_json::to_vec(&PresenceEvent {
-                        content: PresenceEventContent {
-                            avatar_url: None,
-                            currently_active: None,
-                            displayname: None,
-                            last_active_ago: Some(
-                                last_timestamp.try_into().expect("time is valid"),
-                            ),
-                            presence: PresenceState::Offline,
-                            status_msg: None,
-                        },
-                        sender: user_id.to_owned(),
-                    })
-                    .expect("PresenceEvent can be serialized"),
-                )?;
+            .transpose()
+    }
+
+    /// Marks the user as active, e.g. because they are syncing, with the presence the client asked
+    /// for. Clients that ask for offline don't change the presence of the user.
+    pub fn ping_presence(&self, user_id: &UserId, presence: &PresenceState) -> Result<()> {
+        if !services().globals.allow_presence() || *presence == PresenceState::Offline {
+            return Ok(());
+        }
+
+        match self.db.get_presence(user_id)? {
+            // Only the activity changed, which other users don't need to hear about on every sync
+            Some(mut data) if data.presence == *presence => {
+                data.last_active_ts = utils::millis_since_unix_epoch();
+                data.currently_active = *presence == PresenceState::Online;
+                self.db.set_presence(user_id, &data)
             }
+            data => self.set_presence(
+                user_id,
+                presence.clone(),
+                data.and_then(|data| data.status_msg),
+            ),
+        }
+    }
+
+    /// Regularly sets local users who were not active for the configured time to unavailable.
+    pub fn start_idle_handler(&self) {
+        if !services().globals.allow_presence() {
+            return;
+        }
+
+        let timeout = Duration::from_secs(services().globals.presence_idle_timeout());
+        let period = timeout.clamp(Duration::from_secs(1), Duration::from_secs(60));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = services().rooms.edus.presence.presence_maintain(timeout) {
+                    warn!("Failed to update the presence of idle users: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Sets all local users to unavailable who have been online, but quiet for too long.
+    fn presence_maintain(&self, timeout: Duration) -> Result<()> {
+        let now = utils::millis_since_unix_epoch();
+
+        let idle: Vec<_> = self
+            .db
+            .all_presences()
+            .filter_map(|r| r.ok())
+            .filter(|(user_id, data)| {
+                user_id.server_name() == services().globals.server_name()
+                    && is_idle(data, now, timeout)
+            })
+            .collect();
+
+        for (user_id, data) in idle {
+            debug!("{} is idle, setting them to unavailable", user_id);
+
+            let data = PresenceData {
+                presence: PresenceState::Unavailable,
+                currently_active: false,
+                ..data
+            };
 
-            self.userid_lastpresenceupdate.insert(
-                user_id.as_bytes(),
-                &utils::millis_since_unix_epoch().to_be_bytes(),
+            self.store(&user_id, &data)?;
+            self.federation_send(&user_id, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the presence of the user and adds a presence event to every room of the user, so
+    /// that it reaches the users in these rooms when they sync.
+    fn store(&self, user_id: &UserId, data: &PresenceData) -> Result<()> {
+        self.db.set_presence(user_id, data)?;
+
+        // Presence events in rooms store the timestamp of the last activity, see `presence_since`
+        let event = presence_event(user_id, data, UInt::new_saturating(data.last_active_ts))?;
+
+        let room_ids = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .collect::<Result<Vec<_>>>()?;
+        for room_id in &room_ids {
+            self.db.update_presence(user_id, room_id, event.clone())?;
+        }
+
+        let event = presence_event(
+            user_id,
+            data,
+            UInt::new_saturating(
+                utils::millis_since_unix_epoch().saturating_sub(data.last_active_ts),
+            ),
+        )?;
+        services().sending.send_edu_appservices(
+            &room_ids,
+            &serde_json::to_value(event).expect("presence event can be serialized"),
+        )
+    }
+
+    /// Sends an m.presence EDU to all servers that share a room with the local user.
+    fn federation_send(&self, user_id: &UserId, data: &PresenceData) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        let mut servers = HashSet::new();
+        for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+            servers.extend(
+                services()
+                    .rooms
+                    .state_cache
+                    .room_servers(&room_id?)
+                    .filter_map(|r| r.ok()),
+            );
+        }
+        servers.remove(services().globals.server_name());
+
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        let mut update = PresenceUpdate::new(
+            user_id.to_owned(),
+            data.presence.clone(),
+            UInt::new_saturating(
+                utils::millis_since_unix_epoch().saturating_sub(data.last_active_ts),
+            ),
+        );
+        update.currently_active = data.currently_active;
+        update.status_msg = data.status_msg.clone();
+
+        let edu = serde_json::to_vec(&Edu::Presence(PresenceContent::new(vec![update])))
+            .expect("Presence EDU can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                edu.clone(),
+                services().globals.next_count()?,
             )?;
         }
 
         Ok(())
-    }*/
+    }
 
     /// Returns the most recent presence updates that happened after the event with id `since`.
     #[tracing::instrument(skip(self, since, room_id))]
@@ -120,3 +256,79 @@ impl Service {
         self.db.presence_since(room_id, since)
     }
 }
+
+/// Builds the presence event of the user, with the profile of local users.
+fn presence_event(
+    user_id: &UserId,
+    data: &PresenceData,
+    last_active_ago: UInt,
+) -> Result<PresenceEvent> {
+    let (displayname, avatar_url) = if user_id.server_name() == services().globals.server_name() {
+        (
+            services().users.displayname(user_id)?,
+            services().users.avatar_url(user_id)?,
+        )
+    } else {
+        (None, None)
+    };
+
+    Ok(PresenceEvent {
+        content: PresenceEventContent {
+            avatar_url,
+            currently_active: Some(data.currently_active),
+            displayname,
+            last_active_ago: Some(last_active_ago),
+            presence: data.presence.clone(),
+            status_msg: data.status_msg.clone(),
+        },
+        sender: user_id.to_owned(),
+    })
+}
+
+/// Returns whether an online user was not active for the idle timeout.
+fn is_idle(data: &PresenceData, now: u64, timeout: Duration) -> bool {
+    data.presence == PresenceState::Online
+        && now.saturating_sub(data.last_active_ts) >= timeout.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(presence: PresenceState, last_active_ts: u64) -> PresenceData {
+        PresenceData {
+            presence,
+            last_active_ts,
+            currently_active: true,
+            status_msg: None,
+        }
+    }
+
+    #[test]
+    fn idle_after_timeout() {
+        let timeout = Duration::from_secs(300);
+
+        assert!(!is_idle(
+            &data(PresenceState::Online, 1_000_000),
+            1_000_000 + 299_999,
+            timeout
+        ));
+        assert!(is_idle(
+            &data(PresenceState::Online, 1_000_000),
+            1_000_000 + 300_000,
+            timeout
+        ));
+
+        // Only online users become idle, the others keep their presence
+        assert!(!is_idle(
+            &data(PresenceState::Unavailable, 0),
+            1_000_000,
+            timeout
+        ));
+        assert!(!is_idle(
+            &data(PresenceState::Offline, 0),
+            1_000_000,
+            timeout
+        ));
+    }
+}
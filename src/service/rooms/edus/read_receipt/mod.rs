@@ -2,22 +2,33 @@ mod data;
 
 pub use data::Data;
 
-use crate::Result;
+use crate::{services, Result};
 use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
+use serde_json::json;
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
 impl Service {
-    /// Replaces the previous read receipt.
+    /// Replaces the previous read receipt and sends it to the appservices that receive
+    /// ephemeral events.
     pub fn readreceipt_update(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         event: ReceiptEvent,
     ) -> Result<()> {
-        self.db.readreceipt_update(user_id, room_id, event)
+        let edu = json!({
+            "type": "m.receipt",
+            "room_id": room_id,
+            "content": event.content,
+        });
+        self.db.readreceipt_update(user_id, room_id, event)?;
+
+        services()
+            .sending
+            .send_edu_appservices(&[room_id.to_owned()], &edu)
     }
 
     /// Returns an iterator over the most recent read_receipts in a room that happened after the event with id `since`.
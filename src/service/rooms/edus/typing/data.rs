@@ -1,5 +1,5 @@
 use crate::Result;
-use ruma::{OwnedUserId, RoomId, UserId};
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
 use std::collections::HashSet;
 
 pub trait Data: Send + Sync {
@@ -18,4 +18,7 @@ pub trait Data: Send + Sync {
 
     /// Returns all user ids currently typing.
     fn typings_all(&self, room_id: &RoomId) -> Result<HashSet<OwnedUserId>>;
+
+    /// Returns all rooms in which someone is or recently was typing.
+    fn typing_rooms(&self) -> Result<HashSet<OwnedRoomId>>;
 }
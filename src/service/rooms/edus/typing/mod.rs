@@ -1,9 +1,17 @@
 mod data;
 
+use std::time::Duration;
+
 pub use data::Data;
-use ruma::{events::SyncEphemeralRoomEvent, RoomId, UserId};
+use ruma::{
+    api::federation::transactions::edu::{Edu, TypingContent},
+    events::SyncEphemeralRoomEvent,
+    RoomId, UserId,
+};
+use serde_json::json;
+use tracing::warn;
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -13,12 +21,86 @@ impl Service {
     /// Sets a user as typing until the timeout timestamp is reached or roomtyping_remove is
     /// called.
     pub fn typing_add(&self, user_id: &UserId, room_id: &RoomId, timeout: u64) -> Result<()> {
-        self.db.typing_add(user_id, room_id, timeout)
+        self.db.typing_add(user_id, room_id, timeout)?;
+
+        self.federation_send(room_id, user_id, true)?;
+        self.appservice_send(room_id)
     }
 
     /// Removes a user from typing before the timeout is reached.
     pub fn typing_remove(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
-        self.db.typing_remove(user_id, room_id)
+        self.db.typing_remove(user_id, room_id)?;
+
+        self.federation_send(room_id, user_id, false)?;
+        self.appservice_send(room_id)
+    }
+
+    /// Sends an m.typing EDU to the other servers in the room if the user is one of ours.
+    fn federation_send(&self, room_id: &RoomId, user_id: &UserId, typing: bool) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        let edu = serde_json::to_vec(&Edu::Typing(TypingContent::new(
+            room_id.to_owned(),
+            user_id.to_owned(),
+            typing,
+        )))
+        .expect("Typing EDU can be serialized");
+
+        for server in services().rooms.state_cache.room_servers(room_id) {
+            let server = server?;
+            if server == services().globals.server_name() {
+                continue;
+            }
+
+            services().sending.send_reliable_edu(
+                &server,
+                edu.clone(),
+                services().globals.next_count()?,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the users typing in the room to the appservices that receive ephemeral events.
+    fn appservice_send(&self, room_id: &RoomId) -> Result<()> {
+        let typing = self.typings_all(room_id)?;
+
+        services().sending.send_edu_appservices(
+            &[room_id.to_owned()],
+            &json!({
+                "type": "m.typing",
+                "room_id": room_id,
+                "content": typing.content,
+            }),
+        )
+    }
+
+    /// Regularly removes expired typing events, so that syncing clients are woken up when a
+    /// user stops typing without telling us.
+    pub fn start_timeout_handler(&self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = services().rooms.edus.typing.typings_maintain_all() {
+                    warn!("Failed to remove expired typing events: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Removes expired typing events in every room where someone is typing.
+    fn typings_maintain_all(&self) -> Result<()> {
+        for room_id in self.db.typing_rooms()? {
+            self.typings_maintain(&room_id)?;
+        }
+
+        Ok(())
     }
 
     /// Makes sure that typing events with old timestamps get removed.
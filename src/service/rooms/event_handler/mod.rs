@@ -817,6 +817,7 @@ impl Service {
                 .rooms
                 .pdu_metadata
                 .mark_event_soft_failed(&incoming_pdu.event_id)?;
+            services().rooms.pdu_metadata.record_soft_failure(origin)?;
             return Err(Error::BadRequest(
                 ErrorKind::InvalidParam,
                 "Event has been soft failed",
@@ -9,12 +9,14 @@ pub mod outlier;
 pub mod pdu_metadata;
 pub mod search;
 pub mod short;
+pub mod spaces;
 pub mod state;
 pub mod state_accessor;
 pub mod state_cache;
 pub mod state_compressor;
 pub mod threads;
 pub mod timeline;
+pub mod upgrade;
 pub mod user;
 
 pub trait Data:
@@ -50,11 +52,13 @@ pub struct Service {
     pub pdu_metadata: pdu_metadata::Service,
     pub search: search::Service,
     pub short: short::Service,
+    pub spaces: spaces::Service,
     pub state: state::Service,
     pub state_accessor: state_accessor::Service,
     pub state_cache: state_cache::Service,
     pub state_compressor: state_compressor::Service,
     pub timeline: timeline::Service,
     pub threads: threads::Service,
+    pub upgrade: upgrade::Service,
     pub user: user::Service,
 }
@@ -1,12 +1,38 @@
 use std::sync::Arc;
 
 use crate::Result;
-use ruma::{EventId, RoomId};
+use ruma::{EventId, OwnedServerName, RoomId, ServerName};
 
 pub trait Data: Send + Sync {
     fn add_relation(&self, from: u64, to: u64) -> Result<()>;
+    fn add_typed_relation(
+        &self,
+        from: u64,
+        to: u64,
+        rel_type: &str,
+        event_type: &str,
+    ) -> Result<()>;
+    /// Forgets the relations of all events to `to`.
+    fn remove_relations_to(&self, to: u64) -> Result<()>;
+    fn remove_relation(&self, from: u64, to: u64, rel_type: &str, event_type: &str) -> Result<()>;
+    /// Returns the short event ids of all events relating to `to`, optionally only those with
+    /// the given rel_type and event type.
+    fn relations_to<'a>(
+        &'a self,
+        to: u64,
+        rel_type: Option<&str>,
+        event_type: Option<&str>,
+    ) -> Box<dyn Iterator<Item = Result<u64>> + 'a>;
     fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()>;
     fn is_event_referenced(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool>;
     fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()>;
     fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool>;
+    /// Returns the start of the current window and the number of soft-failed events the origin
+    /// sent in it.
+    fn get_soft_failures(&self, origin: &ServerName) -> Result<Option<(u64, u64)>>;
+    fn set_soft_failures(&self, origin: &ServerName, window_start: u64, count: u64) -> Result<()>;
+    /// Returns the soft-failure windows of all origins that ever sent soft-failed events.
+    fn all_soft_failures<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedServerName, u64, u64)>> + 'a>;
 }
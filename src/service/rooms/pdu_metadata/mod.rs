@@ -1,10 +1,28 @@
 mod data;
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 pub use data::Data;
-use ruma::{EventId, RoomId};
+use ruma::{
+    api::Direction,
+    events::relation::BundledThread,
+    push::{Action, Tweak},
+    EventId, OwnedEventId, OwnedServerName, RoomId, ServerName, UInt, UserId,
+};
+use serde_json::json;
 
-use crate::{services, Result};
+use crate::{services, utils, PduEvent, Result};
+
+use super::timeline::PduCount;
+
+/// Soft failures of an origin are counted in windows of this length, in milliseconds.
+const SOFT_FAILURE_WINDOW: u64 = 60 * 60 * 1000;
+
+/// Upper bound for the delay of federation handling for origins that send many soft-failed events.
+const MAX_SOFT_FAILURE_BACKOFF: Duration = Duration::from_secs(60);
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -18,6 +36,318 @@ impl Service {
         self.db.add_relation(from, to)
     }
 
+    /// Indexes the relation by its rel_type and the type of the relating event.
+    #[tracing::instrument(skip(self, from, to))]
+    pub fn add_typed_relation(&self, from: &PduEvent, to: &EventId, rel_type: &str) -> Result<()> {
+        let from_short = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(&from.event_id)?;
+        let to = services().rooms.short.get_or_create_shorteventid(to)?;
+        self.db
+            .add_typed_relation(from_short, to, rel_type, &from.kind.to_string())
+    }
+
+    /// Returns all events relating to this event, optionally only those with the given rel_type
+    /// and event type. They are ordered by [`sort_relations`], so aggregations built from them
+    /// are the same on every server.
+    #[tracing::instrument(skip(self))]
+    pub fn relations(
+        &self,
+        event_id: &EventId,
+        rel_type: Option<&str>,
+        event_type: Option<&str>,
+    ) -> Result<Vec<Arc<PduEvent>>> {
+        let to = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(event_id)?;
+
+        self.db
+            .relations_to(to, rel_type, event_type)
+            .filter_map(|short| {
+                let event_id =
+                    match short.and_then(|s| services().rooms.short.get_eventid_from_short(s)) {
+                        Ok(event_id) => event_id,
+                        Err(e) => return Some(Err(e)),
+                    };
+                services().rooms.timeline.get_pdu(&event_id).transpose()
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|mut relations| {
+                sort_relations(&mut relations);
+                relations
+            })
+    }
+
+    /// Returns whether any event relates to this event, without loading the relating events.
+    #[tracing::instrument(skip(self))]
+    pub fn has_relations(&self, event_id: &EventId) -> Result<bool> {
+        let to = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(event_id)?;
+
+        has_any(self.db.relations_to(to, None, None))
+    }
+
+    /// Returns the events relating to `target` that are older than `from`, newest first, for
+    /// paginating `/rooms/{roomId}/relations/{eventId}`. Events the user can't see are skipped.
+    #[tracing::instrument(skip(self))]
+    pub fn relations_until(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        target: &EventId,
+        rel_type: Option<&str>,
+        from: PduCount,
+        limit: usize,
+    ) -> Result<Vec<(PduCount, Arc<PduEvent>)>> {
+        let mut relations = Vec::new();
+        for pdu in self.relations(target, rel_type, None)? {
+            if pdu.room_id != room_id {
+                continue;
+            }
+            if let Some(count) = services().rooms.timeline.get_pdu_count(&pdu.event_id)? {
+                relations.push((count, pdu));
+            }
+        }
+
+        let mut page = Vec::new();
+        for (count, pdu) in page_relations(relations, from, None, Direction::Backward) {
+            if page.len() == limit {
+                break;
+            }
+
+            if services().rooms.state_accessor.user_can_see_event(
+                user_id,
+                room_id,
+                &pdu.event_id,
+            )? {
+                page.push((count, pdu));
+            }
+        }
+
+        Ok(page)
+    }
+
+    /// Returns one page of the events relating to `target` with both the given rel_type and event
+    /// type, for `/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`, and where the next
+    /// page starts. The page goes from `from` in the direction `dir`, stopping before `to`. Only
+    /// the events on the page are loaded, the others are found in the relation index.
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(self))]
+    pub fn relations_for_type(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        target: &EventId,
+        rel_type: &str,
+        event_type: &str,
+        from: PduCount,
+        to: Option<PduCount>,
+        limit: usize,
+        dir: Direction,
+    ) -> Result<(Vec<(PduCount, Arc<PduEvent>)>, Option<PduCount>)> {
+        let target = services().rooms.short.get_or_create_shorteventid(target)?;
+
+        let mut relations = Vec::new();
+        for short in self
+            .db
+            .relations_to(target, Some(rel_type), Some(event_type))
+        {
+            let event_id = services().rooms.short.get_eventid_from_short(short?)?;
+            if let Some(count) = services().rooms.timeline.get_pdu_count(&event_id)? {
+                relations.push((count, event_id));
+            }
+        }
+
+        let mut page = Vec::new();
+        for (count, event_id) in page_relations(relations, from, to, dir) {
+            if page.len() == limit {
+                break;
+            }
+
+            let pdu = match services().rooms.timeline.get_pdu(&event_id)? {
+                Some(pdu) => pdu,
+                None => continue,
+            };
+            if pdu.room_id == room_id
+                && services()
+                    .rooms
+                    .state_accessor
+                    .user_can_see_event(user_id, room_id, &event_id)?
+            {
+                page.push((count, pdu));
+            }
+        }
+
+        let next = (page.len() == limit)
+            .then(|| page.last().map(|(count, _)| *count))
+            .flatten();
+
+        Ok((page, next))
+    }
+
+    /// Returns the edit replacing the content of this event, see [`latest_valid_edit`].
+    #[tracing::instrument(skip(self))]
+    pub fn latest_edit(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<Arc<PduEvent>>> {
+        let original = match services().rooms.timeline.get_pdu(event_id)? {
+            Some(original) if original.room_id == room_id => original,
+            _ => return Ok(None),
+        };
+
+        Ok(latest_valid_edit(
+            &original,
+            self.relations(event_id, Some("m.replace"), None)?,
+        ))
+    }
+
+    /// Adds the aggregations of the events relating to this event to its unsigned data, see
+    /// [`aggregate_relations`].
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn bundle_aggregations(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        pdu: &mut PduEvent,
+    ) -> Result<()> {
+        if pdu.room_id != room_id {
+            return Ok(());
+        }
+
+        let aggregations = aggregate_relations(
+            user_id,
+            pdu,
+            self.relations(&pdu.event_id, Some("m.thread"), None)?,
+            self.relations(&pdu.event_id, Some("m.annotation"), None)?,
+            self.relations(&pdu.event_id, Some("m.replace"), None)?,
+        );
+        if aggregations.is_empty() {
+            return Ok(());
+        }
+
+        pdu.add_unsigned("m.relations", aggregations.into())
+    }
+
+    /// Forgets the relations the redacted event was part of: those of the events relating to it
+    /// and its own relation to its parent. Has to be called before the event is redacted, which
+    /// removes the relation from its content.
+    #[tracing::instrument(skip(self))]
+    pub fn prune_relations_on_redact(
+        &self,
+        room_id: &RoomId,
+        redacted_event_id: &EventId,
+    ) -> Result<()> {
+        let pdu = match services().rooms.timeline.get_pdu(redacted_event_id)? {
+            Some(pdu) if pdu.room_id == room_id => pdu,
+            _ => return Ok(()),
+        };
+
+        let redacted = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(redacted_event_id)?;
+        self.db.remove_relations_to(redacted)?;
+
+        if let Some((rel_type, parent)) = pdu.relates_to() {
+            let parent = services().rooms.short.get_or_create_shorteventid(&parent)?;
+            self.db
+                .remove_relation(redacted, parent, &rel_type, &pdu.kind.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the events relating to this event with both the given rel_type and event type.
+    pub fn relations_filtered(
+        &self,
+        event_id: &EventId,
+        rel_type: &str,
+        event_type: &str,
+    ) -> Result<Vec<Arc<PduEvent>>> {
+        self.relations(event_id, Some(rel_type), Some(event_type))
+    }
+
+    /// Returns the events referencing this event with `m.reference`, in timeline order.
+    #[tracing::instrument(skip(self))]
+    pub fn references(&self, event_id: &EventId) -> Result<Vec<Arc<PduEvent>>> {
+        let mut references = Vec::new();
+        for pdu in self.relations(event_id, Some("m.reference"), None)? {
+            let count = services().rooms.timeline.get_pdu_count(&pdu.event_id)?;
+            references.push((count, pdu));
+        }
+        references.sort_by_key(|(count, _)| *count);
+
+        Ok(references.into_iter().map(|(_, pdu)| pdu).collect())
+    }
+
+    /// Follows the chain of `m.reference` relations starting at this event, taking the first
+    /// reference of every event. The returned chain starts with the given event.
+    #[tracing::instrument(skip(self))]
+    pub fn reference_chain(&self, event_id: &EventId) -> Result<Vec<OwnedEventId>> {
+        follow_chain(event_id, |event_id| {
+            Ok(self
+                .references(event_id)?
+                .first()
+                .map(|pdu| pdu.event_id.as_ref().to_owned()))
+        })
+    }
+
+    /// Returns the notification and highlight counts of a thread for this user, counting the
+    /// thread messages after the user's read marker, or all of them if there is none.
+    #[tracing::instrument(skip(self))]
+    pub fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_root: &EventId,
+    ) -> Result<(u64, u64)> {
+        let read_up_to = services()
+            .rooms
+            .edus
+            .read_receipt
+            .private_read_get(room_id, user_id)?;
+
+        let ruleset = services().pusher.ruleset(user_id)?;
+        let power_levels = services().pusher.power_levels(room_id)?;
+
+        let mut messages = Vec::new();
+        for pdu in self.relations(thread_root, Some("m.thread"), None)? {
+            if pdu.sender == user_id {
+                continue;
+            }
+
+            let count = match services().rooms.timeline.get_pdu_count(&pdu.event_id)? {
+                Some(PduCount::Normal(count)) => count,
+                // Backfilled events are older than any read marker
+                _ => 0,
+            };
+
+            let actions = services().pusher.get_actions(
+                user_id,
+                &ruleset,
+                &power_levels,
+                &pdu.to_sync_room_event(),
+                room_id,
+            )?;
+
+            messages.push((
+                count,
+                actions.iter().any(|a| matches!(a, Action::Notify)),
+                actions
+                    .iter()
+                    .any(|a| matches!(a, Action::SetTweak(Tweak::Highlight(true)))),
+            ));
+        }
+
+        Ok(count_unread(messages, read_up_to))
+    }
+
     #[tracing::instrument(skip(self, room_id, event_ids))]
     pub fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()> {
         self.db.mark_as_referenced(room_id, event_ids)
@@ -37,4 +367,677 @@ impl Service {
     pub fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool> {
         self.db.is_event_soft_failed(event_id)
     }
+
+    /// Counts a soft-failed event sent by the origin.
+    #[tracing::instrument(skip(self))]
+    pub fn record_soft_failure(&self, origin: &ServerName) -> Result<()> {
+        let (window_start, count) = roll_window(
+            self.db.get_soft_failures(origin)?,
+            utils::millis_since_unix_epoch(),
+        );
+        self.db.set_soft_failures(origin, window_start, count + 1)
+    }
+
+    /// Returns how many soft-failed events the origin sent in the current window.
+    pub fn soft_failure_count(&self, origin: &ServerName) -> Result<u64> {
+        Ok(roll_window(
+            self.db.get_soft_failures(origin)?,
+            utils::millis_since_unix_epoch(),
+        )
+        .1)
+    }
+
+    /// Returns how long to delay handling events of the origin because it sent too many
+    /// soft-failed events recently, `None` if it is not backed off.
+    pub fn soft_failure_backoff(&self, origin: &ServerName) -> Result<Option<Duration>> {
+        Ok(backoff_for(
+            self.soft_failure_count(origin)?,
+            services().globals.soft_fail_backoff_threshold(),
+        ))
+    }
+
+    /// Returns all origins with soft-failed events in the current window, with their count.
+    pub fn soft_failure_rates(&self) -> Result<Vec<(OwnedServerName, u64)>> {
+        let now = utils::millis_since_unix_epoch();
+
+        let mut rates = Vec::new();
+        for r in self.db.all_soft_failures() {
+            let (origin, window_start, count) = r?;
+            let (_, count) = roll_window(Some((window_start, count)), now);
+            if count > 0 {
+                rates.push((origin, count));
+            }
+        }
+        rates.sort_by(|a, b| b.1.cmp(&a.1));
+
+        Ok(rates)
+    }
+}
+
+/// Returns the window start and count at `now`, starting a new window if the stored one is over.
+fn roll_window(stored: Option<(u64, u64)>, now: u64) -> (u64, u64) {
+    match stored {
+        Some((window_start, count)) if now.saturating_sub(window_start) < SOFT_FAILURE_WINDOW => {
+            (window_start, count)
+        }
+        _ => (now, 0),
+    }
+}
+
+/// Returns the delay for an origin with `count` soft failures in the current window. It doubles
+/// every time the origin sends another `threshold` soft-failed events.
+fn backoff_for(count: u64, threshold: u64) -> Option<Duration> {
+    let threshold = threshold.max(1);
+    if count < threshold {
+        return None;
+    }
+
+    let exponent = (count / threshold - 1).min(16) as u32;
+    Some((Duration::from_secs(1) * 2_u32.pow(exponent)).min(MAX_SOFT_FAILURE_BACKOFF))
+}
+
+/// Returns whether the index yields any relation, looking at the first entry only.
+fn has_any(mut relations: impl Iterator<Item = Result<u64>>) -> Result<bool> {
+    Ok(relations.next().transpose()?.is_some())
+}
+
+/// Returns the relations after `from` in the direction `dir`, in that order, up to but excluding
+/// `to`.
+fn page_relations<T>(
+    mut relations: Vec<(PduCount, T)>,
+    from: PduCount,
+    to: Option<PduCount>,
+    dir: Direction,
+) -> Vec<(PduCount, T)> {
+    match dir {
+        Direction::Forward => {
+            relations.retain(|(count, _)| *count > from && to.map_or(true, |to| *count < to));
+            relations.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        Direction::Backward => {
+            relations.retain(|(count, _)| *count < from && to.map_or(true, |to| *count > to));
+            relations.sort_by(|(a, _), (b, _)| b.cmp(a));
+        }
+    }
+
+    relations
+}
+
+/// Returns the bundled aggregations of the parent event: a summary of its thread, the counts of
+/// its reactions and its latest edit. Redacted relations aren't counted, and redacted parents
+/// have no aggregations at all, as the content they were about doesn't exist anymore.
+fn aggregate_relations(
+    user_id: &UserId,
+    parent: &PduEvent,
+    thread: Vec<Arc<PduEvent>>,
+    reactions: Vec<Arc<PduEvent>>,
+    edits: Vec<Arc<PduEvent>>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut aggregations = serde_json::Map::new();
+    if parent.is_redacted() {
+        return aggregations;
+    }
+
+    let thread = thread
+        .into_iter()
+        .filter(|relation| relation.room_id == parent.room_id && !relation.is_redacted())
+        .collect::<Vec<_>>();
+    if let Some(latest) = thread.last() {
+        let summary = BundledThread {
+            latest_event: latest.to_message_like_event(),
+            count: UInt::try_from(thread.len()).unwrap_or(UInt::MAX),
+            current_user_participated: parent.sender == user_id
+                || thread.iter().any(|relation| relation.sender == user_id),
+        };
+        aggregations.insert(
+            "m.thread".to_owned(),
+            serde_json::to_value(summary).expect("to_value always works"),
+        );
+    }
+
+    let chunk = reaction_counts(
+        reactions
+            .iter()
+            .filter(|relation| relation.room_id == parent.room_id && !relation.is_redacted())
+            .filter_map(|relation| Some((relation.kind.to_string(), annotation_key(relation)?))),
+    );
+    if !chunk.is_empty() {
+        aggregations.insert("m.annotation".to_owned(), json!({ "chunk": chunk }));
+    }
+
+    if let Some(edit) = latest_valid_edit(parent, edits) {
+        aggregations.insert(
+            "m.replace".to_owned(),
+            serde_json::to_value(edit.to_message_like_event()).expect("to_value always works"),
+        );
+    }
+
+    aggregations
+}
+
+/// Returns the edit whose `m.new_content` replaces the content of the original event: the latest
+/// by origin_server_ts and then event id. Only edits by the original sender count, and redacted
+/// edits are ignored, so redacting an edit reverts to the content before it.
+fn latest_valid_edit(original: &PduEvent, edits: Vec<Arc<PduEvent>>) -> Option<Arc<PduEvent>> {
+    edits
+        .into_iter()
+        .filter(|edit| {
+            edit.room_id == original.room_id
+                && edit.sender == original.sender
+                && !edit.is_redacted()
+        })
+        .max_by(|a, b| (a.origin_server_ts, &a.event_id).cmp(&(b.origin_server_ts, &b.event_id)))
+}
+
+/// Returns the key of an annotation, e.g. the emoji of a reaction.
+fn annotation_key(pdu: &PduEvent) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(pdu.content.get())
+        .ok()?
+        .get("m.relates_to")?
+        .get("key")?
+        .as_str()
+        .map(ToOwned::to_owned)
+}
+
+/// Counts the annotations, given as their event type and key, in the bundled aggregation format.
+/// The most frequent come first.
+fn reaction_counts(annotations: impl Iterator<Item = (String, String)>) -> Vec<serde_json::Value> {
+    let mut counts: BTreeMap<(String, String), u64> = BTreeMap::new();
+    for annotation in annotations {
+        *counts.entry(annotation).or_default() += 1;
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    counts
+        .into_iter()
+        .map(|((kind, key), count)| json!({ "type": kind, "key": key, "count": count }))
+        .collect()
+}
+
+/// Sorts relating events by their origin_server_ts and then their event id. Unlike the order in
+/// which they arrived, this doesn't depend on the server.
+pub fn sort_relations(relations: &mut [Arc<PduEvent>]) {
+    relations
+        .sort_by(|a, b| (a.origin_server_ts, &a.event_id).cmp(&(b.origin_server_ts, &b.event_id)));
+}
+
+/// Returns the chain of events starting at `start`, asking `next` for the successor of each event.
+/// Stops at the first event that was seen before, so cycles don't loop forever.
+fn follow_chain(
+    start: &EventId,
+    mut next: impl FnMut(&EventId) -> Result<Option<OwnedEventId>>,
+) -> Result<Vec<OwnedEventId>> {
+    let mut chain = vec![start.to_owned()];
+    let mut seen = HashSet::from([start.to_owned()]);
+
+    while let Some(successor) = next(chain.last().expect("chain is never empty"))? {
+        if !seen.insert(successor.clone()) {
+            break;
+        }
+        chain.push(successor);
+    }
+
+    Ok(chain)
+}
+
+/// Counts the notifying and highlighting messages after the read marker. Messages are given as
+/// their pdu count and whether they notify and highlight.
+fn count_unread(
+    messages: impl IntoIterator<Item = (u64, bool, bool)>,
+    read_up_to: Option<u64>,
+) -> (u64, u64) {
+    messages
+        .into_iter()
+        .filter(|(count, _, _)| read_up_to.map_or(true, |read| *count > read))
+        .fold(
+            (0, 0),
+            |(notifications, highlights), (_, notify, highlight)| {
+                (
+                    notifications + u64::from(notify),
+                    highlights + u64::from(highlight),
+                )
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ruma::{api::Direction, event_id, OwnedEventId};
+
+    use super::{
+        aggregate_relations, backoff_for, count_unread, follow_chain, has_any, latest_valid_edit,
+        page_relations, reaction_counts, roll_window, sort_relations, MAX_SOFT_FAILURE_BACKOFF,
+        SOFT_FAILURE_WINDOW,
+    };
+    use crate::{service::rooms::timeline::PduCount, PduEvent};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn reference_chain_in_order() {
+        // Call events referencing the previous event of the call
+        let references: HashMap<OwnedEventId, OwnedEventId> = [
+            (
+                event_id!("$invite:example.org"),
+                event_id!("$answer:example.org"),
+            ),
+            (
+                event_id!("$answer:example.org"),
+                event_id!("$candidates:example.org"),
+            ),
+            (
+                event_id!("$candidates:example.org"),
+                event_id!("$hangup:example.org"),
+            ),
+        ]
+        .into_iter()
+        .map(|(to, from)| (to.to_owned(), from.to_owned()))
+        .collect();
+
+        let chain = follow_chain(event_id!("$invite:example.org"), |event_id| {
+            Ok(references.get(event_id).cloned())
+        })
+        .unwrap();
+        assert_eq!(
+            chain,
+            [
+                event_id!("$invite:example.org"),
+                event_id!("$answer:example.org"),
+                event_id!("$candidates:example.org"),
+                event_id!("$hangup:example.org"),
+            ]
+        );
+
+        let cyclic = follow_chain(event_id!("$a:example.org"), |event_id| {
+            Ok(Some(if event_id == "$a:example.org" {
+                event_id!("$b:example.org").to_owned()
+            } else {
+                event_id!("$a:example.org").to_owned()
+            }))
+        })
+        .unwrap();
+        assert_eq!(cyclic.len(), 2);
+    }
+
+    #[test]
+    fn relations_paginate_newest_first() {
+        let relations = (1..=5)
+            .map(|count| (PduCount::Normal(count), count))
+            .collect();
+
+        let page = page_relations(relations, PduCount::Normal(4), None, Direction::Backward);
+        assert_eq!(
+            page.iter()
+                .map(|(_, relation)| *relation)
+                .collect::<Vec<_>>(),
+            [3, 2, 1]
+        );
+
+        let backfilled = vec![
+            (PduCount::Backfilled(1), "old"),
+            (PduCount::Normal(1), "new"),
+        ];
+        assert_eq!(
+            page_relations(
+                backfilled.clone(),
+                PduCount::max(),
+                None,
+                Direction::Backward
+            ),
+            [
+                (PduCount::Normal(1), "new"),
+                (PduCount::Backfilled(1), "old")
+            ]
+        );
+        assert_eq!(
+            page_relations(backfilled, PduCount::Normal(1), None, Direction::Backward).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn typed_relations_paginate_both_ways() {
+        // Reactions and edits of the same message, as (count, rel_type, event_type)
+        let relations = [
+            (1, "m.annotation", "m.reaction"),
+            (2, "m.replace", "m.room.message"),
+            (3, "m.annotation", "m.reaction"),
+            (4, "m.annotation", "m.reaction"),
+            (5, "m.replace", "m.room.message"),
+            (6, "m.annotation", "m.reaction"),
+        ];
+        let of_type = |rel_type: &str, event_type: &str| {
+            relations
+                .iter()
+                .filter(|(_, r, e)| *r == rel_type && *e == event_type)
+                .map(|(count, _, _)| (PduCount::Normal(*count), *count))
+                .collect::<Vec<_>>()
+        };
+        let counts = |page: Vec<(PduCount, u64)>| {
+            page.into_iter().map(|(_, count)| count).collect::<Vec<_>>()
+        };
+
+        let reactions = of_type("m.annotation", "m.reaction");
+        assert_eq!(
+            counts(page_relations(
+                reactions.clone(),
+                PduCount::max(),
+                None,
+                Direction::Backward
+            )),
+            [6, 4, 3, 1]
+        );
+        assert_eq!(
+            counts(page_relations(
+                reactions.clone(),
+                PduCount::Normal(1),
+                Some(PduCount::Normal(6)),
+                Direction::Forward
+            )),
+            [3, 4]
+        );
+        assert_eq!(
+            counts(page_relations(
+                reactions,
+                PduCount::Normal(6),
+                Some(PduCount::Normal(1)),
+                Direction::Backward
+            )),
+            [4, 3]
+        );
+
+        let edits = of_type("m.replace", "m.room.message");
+        assert_eq!(
+            counts(page_relations(
+                edits,
+                PduCount::min(),
+                None,
+                Direction::Forward
+            )),
+            [2, 5]
+        );
+    }
+
+    #[test]
+    fn reactions_are_counted_by_key() {
+        let reaction = |key: &str| ("m.reaction".to_owned(), key.to_owned());
+        let chunk = reaction_counts([reaction("👍"), reaction("🎉"), reaction("👍")].into_iter());
+
+        assert_eq!(
+            chunk,
+            [
+                serde_json::json!({ "type": "m.reaction", "key": "👍", "count": 2 }),
+                serde_json::json!({ "type": "m.reaction", "key": "🎉", "count": 1 }),
+            ]
+        );
+        assert!(reaction_counts(std::iter::empty()).is_empty());
+    }
+
+    #[test]
+    fn thread_counts_after_read_marker() {
+        // (pdu count, notify, highlight)
+        let thread = [
+            (10, true, false),
+            (11, true, true),
+            (12, false, false),
+            (13, true, false),
+            (14, true, true),
+        ];
+
+        assert_eq!(count_unread(thread, None), (4, 2));
+        assert_eq!(count_unread(thread, Some(11)), (2, 1));
+        assert_eq!(count_unread(thread, Some(14)), (0, 0));
+    }
+
+    #[test]
+    fn soft_failure_backoff() {
+        let threshold = 100;
+        let mut window = None;
+
+        for now in 0..threshold - 1 {
+            let (start, count) = roll_window(window, now);
+            window = Some((start, count + 1));
+        }
+        assert_eq!(window, Some((0, threshold - 1)));
+        assert_eq!(backoff_for(window.unwrap().1, threshold), None);
+
+        // Exceeding the threshold backs the origin off, progressively
+        assert_eq!(
+            backoff_for(threshold, threshold),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            backoff_for(3 * threshold, threshold),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            backoff_for(1000 * threshold, threshold),
+            Some(MAX_SOFT_FAILURE_BACKOFF)
+        );
+
+        // Old soft failures are forgotten
+        assert_eq!(
+            roll_window(window, SOFT_FAILURE_WINDOW),
+            (SOFT_FAILURE_WINDOW, 0)
+        );
+    }
+
+    #[test]
+    fn deterministic_relation_order() {
+        let reaction = |event_id: &str, ts: u64, key: &str| -> Arc<PduEvent> {
+            Arc::new(
+                serde_json::from_value(serde_json::json!({
+                    "event_id": event_id,
+                    "room_id": "!room:example.org",
+                    "sender": "@alice:example.org",
+                    "origin_server_ts": ts,
+                    "type": "m.reaction",
+                    "content": { "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": "$target:example.org",
+                        "key": key,
+                    }},
+                    "prev_events": [],
+                    "depth": 1,
+                    "auth_events": [],
+                    "hashes": { "sha256": "" },
+                }))
+                .unwrap(),
+            )
+        };
+        let keys = |relations: &[Arc<PduEvent>]| -> Vec<String> {
+            relations
+                .iter()
+                .map(|pdu| {
+                    serde_json::from_str::<serde_json::Value>(pdu.content.get()).unwrap()
+                        ["m.relates_to"]["key"]
+                        .as_str()
+                        .unwrap()
+                        .to_owned()
+                })
+                .collect()
+        };
+
+        let thumbs = reaction("$b:example.org", 10, "👍");
+        let party = reaction("$a:example.org", 10, "🎉");
+        let heart = reaction("$c:example.org", 5, "❤️");
+
+        // The same relations, as they arrived at two different servers
+        let mut here = vec![thumbs.clone(), party.clone(), heart.clone()];
+        let mut there = vec![heart, thumbs, party];
+        sort_relations(&mut here);
+        sort_relations(&mut there);
+
+        assert_eq!(keys(&here), ["❤️", "🎉", "👍"]);
+        assert_eq!(keys(&here), keys(&there));
+    }
+
+    #[test]
+    fn relation_existence() {
+        assert!(!has_any(std::iter::empty()).unwrap());
+
+        // Only the first child is looked at, no matter how many there are
+        let children = [Ok(1), Ok(2)].into_iter().chain(std::iter::from_fn(|| {
+            panic!("read past the first relation")
+        }));
+        assert!(has_any(children).unwrap());
+    }
+
+    fn message(event_id: &str, sender: &str, ts: u64, content: serde_json::Value) -> Arc<PduEvent> {
+        Arc::new(
+            serde_json::from_value(serde_json::json!({
+                "event_id": event_id,
+                "room_id": "!room:example.org",
+                "sender": sender,
+                "origin_server_ts": ts,
+                "type": "m.room.message",
+                "content": content,
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn edit(event_id: &str, sender: &str, ts: u64, body: &str) -> Arc<PduEvent> {
+        message(
+            event_id,
+            sender,
+            ts,
+            serde_json::json!({
+                "msgtype": "m.text",
+                "body": format!("* {body}"),
+                "m.new_content": { "msgtype": "m.text", "body": body },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$original:example.org" },
+            }),
+        )
+    }
+
+    #[test]
+    fn latest_edit_wins() {
+        let original = message(
+            "$original:example.org",
+            "@alice:example.org",
+            1,
+            serde_json::json!({ "msgtype": "m.text", "body": "helo" }),
+        );
+
+        let first = edit("$first:example.org", "@alice:example.org", 10, "hello");
+        let second = edit("$second:example.org", "@alice:example.org", 20, "hello!");
+        // Sent at the same time as the second edit, wins because of its event id
+        let tied = edit("$third:example.org", "@alice:example.org", 20, "hello!!");
+
+        let latest =
+            latest_valid_edit(&original, vec![tied.clone(), first.clone(), second.clone()]);
+        assert_eq!(latest.unwrap().event_id, tied.event_id);
+
+        // Redacting the latest edit reverts to the one before it
+        let mut redacted = (*tied).clone();
+        redacted
+            .redact(&message(
+                "$redaction:example.org",
+                "@mod:example.org",
+                30,
+                serde_json::json!({}),
+            ))
+            .unwrap();
+        let latest = latest_valid_edit(&original, vec![first, Arc::new(redacted), second.clone()]);
+        assert_eq!(latest.unwrap().event_id, second.event_id);
+    }
+
+    #[test]
+    fn edits_by_other_users_are_rejected() {
+        let original = message(
+            "$original:example.org",
+            "@alice:example.org",
+            1,
+            serde_json::json!({ "msgtype": "m.text", "body": "hello" }),
+        );
+        let hijack = edit(
+            "$hijack:example.org",
+            "@mallory:example.org",
+            10,
+            "send me money",
+        );
+
+        assert!(latest_valid_edit(&original, vec![hijack]).is_none());
+    }
+
+    #[test]
+    fn redactions_update_aggregations() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let root = message(
+            "$original:example.org",
+            "@bob:example.org",
+            1,
+            serde_json::json!({ "msgtype": "m.text", "body": "Lunch?" }),
+        );
+        let reply = message(
+            "$reply:example.org",
+            "@alice:example.org",
+            2,
+            serde_json::json!({
+                "msgtype": "m.text",
+                "body": "Sure",
+                "m.relates_to": { "rel_type": "m.thread", "event_id": "$original:example.org" },
+            }),
+        );
+        let reaction = |event_id: &str, key: &str| {
+            Arc::new(
+                serde_json::from_value::<PduEvent>(serde_json::json!({
+                    "event_id": event_id,
+                    "room_id": "!room:example.org",
+                    "sender": "@carol:example.org",
+                    "origin_server_ts": 3,
+                    "type": "m.reaction",
+                    "content": { "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": "$original:example.org",
+                        "key": key,
+                    }},
+                    "prev_events": [],
+                    "depth": 1,
+                    "auth_events": [],
+                    "hashes": { "sha256": "" },
+                }))
+                .unwrap(),
+            )
+        };
+        let redaction = message(
+            "$redaction:example.org",
+            "@mod:example.org",
+            4,
+            serde_json::json!({}),
+        );
+
+        let thumbs = reaction("$thumbs:example.org", "👍");
+        let mut spam = (*reaction("$spam:example.org", "👍")).clone();
+        spam.redact(&redaction).unwrap();
+        let party = reaction("$party:example.org", "🎉");
+        let reactions = vec![thumbs, Arc::new(spam), party];
+
+        let aggregations =
+            aggregate_relations(alice, &root, vec![reply.clone()], reactions.clone(), vec![]);
+        assert_eq!(aggregations["m.thread"]["count"], 1);
+        assert_eq!(aggregations["m.thread"]["current_user_participated"], true);
+        // Only the redacted reaction is gone
+        assert_eq!(
+            aggregations["m.annotation"]["chunk"],
+            serde_json::json!([
+                { "type": "m.reaction", "key": "🎉", "count": 1 },
+                { "type": "m.reaction", "key": "👍", "count": 1 },
+            ])
+        );
+
+        let mut redacted_root = (*root).clone();
+        redacted_root.redact(&redaction).unwrap();
+        assert!(
+            aggregate_relations(alice, &redacted_root, vec![reply], reactions, vec![]).is_empty()
+        );
+    }
 }
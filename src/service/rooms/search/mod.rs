@@ -1,9 +1,18 @@
 mod data;
 
+use std::mem::size_of;
+
 pub use data::Data;
 
 use crate::Result;
-use ruma::RoomId;
+use ruma::{OwnedRoomId, RoomId};
+
+/// Words that are too common to be worth indexing or searching for.
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -15,6 +24,12 @@ impl Service {
         self.db.index_pdu(shortroomid, pdu_id, message_body)
     }
 
+    /// Removes the words of the message from the index, e.g. because it was redacted.
+    #[tracing::instrument(skip(self))]
+    pub fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
+        self.db.deindex_pdu(shortroomid, pdu_id, message_body)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn search_pdus<'a>(
         &'a self,
@@ -23,4 +38,118 @@ impl Service {
     ) -> Result<Option<(impl Iterator<Item = Vec<u8>> + 'a, Vec<String>)>> {
         self.db.search_pdus(room_id, search_string)
     }
+
+    /// Returns the pdu ids of the messages in these rooms that contain all words of the search
+    /// string, newest first.
+    pub fn search_rooms<'a>(
+        &'a self,
+        room_ids: &[OwnedRoomId],
+        search_string: &str,
+    ) -> Result<impl Iterator<Item = Vec<u8>> + 'a> {
+        let mut searches = Vec::new();
+        for room_id in room_ids {
+            if let Some((pdu_ids, _)) = self.db.search_pdus(room_id, search_string)? {
+                searches.push(pdu_ids);
+            }
+        }
+
+        Ok(merge_newest_first(searches))
+    }
+}
+
+/// Splits a message into the lowercase words that are indexed, skipping stop words.
+pub fn tokenize(message_body: &str) -> impl Iterator<Item = String> + '_ {
+    message_body
+        .split_terminator(|c: char| !c.is_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .filter(|word| word.len() <= 50)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+/// Returns how often the search terms appear in the message, to rank search results.
+pub fn rank(message_body: &str, terms: &[String]) -> usize {
+    tokenize(message_body)
+        .filter(|word| terms.contains(word))
+        .count()
+}
+
+/// Merges search results of several rooms, which are sorted newest first, into one list that is
+/// sorted newest first.
+fn merge_newest_first<I: Iterator<Item = Vec<u8>>>(
+    searches: Vec<I>,
+) -> impl Iterator<Item = Vec<u8>> {
+    let mut searches: Vec<_> = searches.into_iter().map(Iterator::peekable).collect();
+
+    std::iter::from_fn(move || {
+        // Pdu ids start with the short room id, only the rest says how recent the pdu is
+        let newest = searches
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, search)| {
+                search.peek().map(|pdu_id| {
+                    (
+                        i,
+                        pdu_id.get(size_of::<u64>()..).unwrap_or_default().to_vec(),
+                    )
+                })
+            })
+            .max_by(|(_, a), (_, b)| a.cmp(b))?
+            .0;
+
+        searches[newest].next()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pdu_id(shortroomid: u64, count: u64) -> Vec<u8> {
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+        pdu_id
+    }
+
+    #[test]
+    fn phrases_are_tokenized() {
+        assert_eq!(
+            tokenize("Meet me at 10:30, near Café-Übersee!").collect::<Vec<_>>(),
+            ["meet", "me", "10", "30", "near", "café", "übersee"]
+        );
+        assert_eq!(tokenize("  ...  ").count(), 0);
+        assert_eq!(tokenize(&"x".repeat(51)).count(), 0);
+    }
+
+    #[test]
+    fn stop_words_are_skipped() {
+        assert_eq!(
+            tokenize("The cat is on THE mat").collect::<Vec<_>>(),
+            ["cat", "mat"]
+        );
+        assert_eq!(tokenize("to be or not to be").count(), 0);
+
+        let terms: Vec<_> = tokenize("the cat").collect();
+        assert_eq!(rank("A cat, another cat and the dog", &terms), 2);
+    }
+
+    #[test]
+    fn rooms_are_searched_newest_first() {
+        // Results of the room with the higher short room id must not all come first
+        let first_room = vec![pdu_id(1, 9), pdu_id(1, 4)];
+        let second_room = vec![pdu_id(2, 7), pdu_id(2, 2)];
+        let third_room = Vec::new();
+
+        let merged: Vec<_> = merge_newest_first(vec![
+            first_room.into_iter(),
+            second_room.into_iter(),
+            third_room.into_iter(),
+        ])
+        .collect();
+
+        assert_eq!(
+            merged,
+            [pdu_id(1, 9), pdu_id(2, 7), pdu_id(1, 4), pdu_id(2, 2)]
+        );
+    }
 }
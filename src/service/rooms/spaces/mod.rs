@@ -0,0 +1,513 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use ruma::{
+    api::{
+        client::{
+            error::ErrorKind,
+            space::{get_hierarchy, SpaceHierarchyRoomsChunk, SpaceRoomJoinRule},
+        },
+        federation,
+    },
+    events::{
+        room::join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
+        space::child::HierarchySpaceChildEvent,
+        StateEventType,
+    },
+    room::RoomType,
+    serde::Raw,
+    OwnedRoomId, OwnedServerName, RoomId, UInt, UserId,
+};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use tracing::debug;
+
+use crate::{services, Error, PduEvent, Result};
+
+/// Most rooms that are returned per page.
+const MAX_LIMIT: usize = 100;
+
+/// Most rooms that are visited while walking a space, so huge spaces can't take up the server.
+const MAX_VISITED_ROOMS: usize = 1000;
+
+pub struct Service;
+
+/// A room of a space, with the children it lists.
+struct SpaceRoom {
+    chunk: SpaceHierarchyRoomsChunk,
+    children: Vec<SpaceChild>,
+}
+
+/// A child of a space, from its `m.space.child` state event.
+#[derive(Debug, Deserialize)]
+struct SpaceChild {
+    #[serde(rename = "state_key")]
+    room_id: OwnedRoomId,
+    origin_server_ts: UInt,
+    content: SpaceChildContent,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpaceChildContent {
+    #[serde(default)]
+    via: Vec<OwnedServerName>,
+    order: Option<String>,
+    #[serde(default)]
+    suggested: bool,
+}
+
+impl Service {
+    /// Walks the space breadth-first and returns the rooms the user could join or see, `limit` at
+    /// a time. `from` is the `next_batch` of the previous page, which stays valid as long as the
+    /// space doesn't change. Rooms this server doesn't know are asked for over federation.
+    pub async fn get_hierarchy(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+        suggested_only: bool,
+        max_depth: Option<UInt>,
+        from: Option<&str>,
+        limit: Option<UInt>,
+    ) -> Result<get_hierarchy::v1::Response> {
+        let skip = match from {
+            Some(from) => from
+                .parse()
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid from token."))?,
+            None => 0,
+        };
+        let limit = limit
+            .map_or(50, |limit| u64::from(limit) as usize)
+            .clamp(1, MAX_LIMIT);
+        let max_depth = max_depth.map_or(u64::MAX, u64::from);
+
+        let mut walk = SpaceWalk::new(room_id.to_owned());
+        let mut rooms = Vec::new();
+        let mut visible = 0;
+        let mut next_batch = None;
+
+        while let Some((current, via, depth)) = walk.next() {
+            let room = match self
+                .get_room(sender_user, &current, &via, suggested_only)
+                .await?
+            {
+                Some(room) => room,
+                None if current == room_id => {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "The requested room is inaccessible.",
+                    ))
+                }
+                None => continue,
+            };
+
+            if visible == skip + limit {
+                next_batch = Some(visible.to_string());
+                break;
+            }
+            if visible >= skip {
+                rooms.push(room.chunk);
+            }
+            visible += 1;
+
+            if depth < max_depth {
+                walk.add_children(
+                    room.children
+                        .into_iter()
+                        .filter(|child| !suggested_only || child.content.suggested)
+                        .map(|child| (child.room_id, child.content.via)),
+                    depth + 1,
+                );
+            }
+        }
+
+        Ok(get_hierarchy::v1::Response { next_batch, rooms })
+    }
+
+    /// Returns the summary and children of the room, or None if the user can't see it or it's
+    /// unknown.
+    async fn get_room(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+        via: &[OwnedServerName],
+        suggested_only: bool,
+    ) -> Result<Option<SpaceRoom>> {
+        if !services().rooms.metadata.exists(room_id)? {
+            return self
+                .get_remote_room(sender_user, room_id, via, suggested_only)
+                .await;
+        }
+
+        let state = services()
+            .rooms
+            .state_accessor
+            .room_state_full(room_id)
+            .await?;
+
+        let join_rule =
+            state_content::<RoomJoinRulesEventContent>(&state, StateEventType::RoomJoinRules)
+                .map_or(JoinRule::Invite, |content| content.join_rule);
+        let world_readable = state_field(
+            &state,
+            StateEventType::RoomHistoryVisibility,
+            "history_visibility",
+        )
+        .as_deref()
+            == Some("world_readable");
+
+        let allowed_room_ids = allowed_room_ids(&join_rule);
+        if !services()
+            .rooms
+            .state_cache
+            .is_joined(sender_user, room_id)?
+            && !services()
+                .rooms
+                .state_cache
+                .is_invited(sender_user, room_id)?
+            && !is_accessible(sender_user, &join_rule, world_readable, &allowed_room_ids)?
+        {
+            return Ok(None);
+        }
+
+        let children_state: Vec<_> = state
+            .iter()
+            .filter(|((event_type, _), _)| *event_type == StateEventType::SpaceChild)
+            .map(|(_, pdu)| pdu.to_stripped_spacechild_state_event())
+            .collect();
+
+        Ok(Some(SpaceRoom {
+            children: children(&children_state),
+            chunk: SpaceHierarchyRoomsChunk {
+                canonical_alias: state_field(&state, StateEventType::RoomCanonicalAlias, "alias")
+                    .and_then(|alias| alias.try_into().ok()),
+                name: state_field(&state, StateEventType::RoomName, "name"),
+                num_joined_members: UInt::new_saturating(
+                    services()
+                        .rooms
+                        .state_cache
+                        .room_joined_count(room_id)?
+                        .unwrap_or(0),
+                ),
+                room_id: room_id.to_owned(),
+                topic: state_field(&state, StateEventType::RoomTopic, "topic"),
+                world_readable,
+                guest_can_join: state_field(
+                    &state,
+                    StateEventType::RoomGuestAccess,
+                    "guest_access",
+                )
+                .as_deref()
+                    == Some("can_join"),
+                avatar_url: state_field(&state, StateEventType::RoomAvatar, "url").map(Into::into),
+                join_rule: space_join_rule(&join_rule),
+                room_type: state_field(&state, StateEventType::RoomCreate, "type")
+                    .map(|room_type| RoomType::from(room_type.as_str())),
+                children_state,
+            },
+        }))
+    }
+
+    /// Asks the servers the room can be joined through for its summary and children.
+    async fn get_remote_room(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+        via: &[OwnedServerName],
+        suggested_only: bool,
+    ) -> Result<Option<SpaceRoom>> {
+        for server in via {
+            if server == services().globals.server_name() {
+                continue;
+            }
+
+            let response = match services()
+                .sending
+                .send_federation_request(
+                    server,
+                    federation::space::get_hierarchy::v1::Request {
+                        room_id: room_id.to_owned(),
+                        suggested_only,
+                    },
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!(
+                        "Failed to fetch hierarchy of {} from {}: {}",
+                        room_id, server, e
+                    );
+                    continue;
+                }
+            };
+
+            let summary = response.room;
+            let join_rule = match summary.join_rule {
+                SpaceRoomJoinRule::Public => JoinRule::Public,
+                SpaceRoomJoinRule::Knock => JoinRule::Knock,
+                _ => JoinRule::Invite,
+            };
+            if !is_accessible(
+                sender_user,
+                &join_rule,
+                summary.world_readable,
+                &summary.allowed_room_ids,
+            )? {
+                return Ok(None);
+            }
+
+            return Ok(Some(SpaceRoom {
+                children: children(&summary.children_state),
+                chunk: SpaceHierarchyRoomsChunk {
+                    canonical_alias: summary.canonical_alias,
+                    name: summary.name,
+                    num_joined_members: summary.num_joined_members,
+                    room_id: summary.room_id,
+                    topic: summary.topic,
+                    world_readable: summary.world_readable,
+                    guest_can_join: summary.guest_can_join,
+                    avatar_url: summary.avatar_url,
+                    join_rule: summary.join_rule,
+                    room_type: summary.room_type,
+                    children_state: summary.children_state,
+                },
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+/// The rooms of a space in breadth-first order. Every room is only visited once, so cycles in the
+/// space graph end the walk instead of looping forever.
+struct SpaceWalk {
+    queue: VecDeque<(OwnedRoomId, Vec<OwnedServerName>, u64)>,
+    seen: HashSet<OwnedRoomId>,
+}
+
+impl SpaceWalk {
+    fn new(root: OwnedRoomId) -> Self {
+        Self {
+            seen: HashSet::from([root.clone()]),
+            queue: VecDeque::from([(root, Vec::new(), 0)]),
+        }
+    }
+
+    /// Returns the next room, the servers to reach it through and its depth.
+    fn next(&mut self) -> Option<(OwnedRoomId, Vec<OwnedServerName>, u64)> {
+        self.queue.pop_front()
+    }
+
+    fn add_children(
+        &mut self,
+        children: impl IntoIterator<Item = (OwnedRoomId, Vec<OwnedServerName>)>,
+        depth: u64,
+    ) {
+        for (room_id, via) in children {
+            if self.seen.len() >= MAX_VISITED_ROOMS {
+                break;
+            }
+
+            if self.seen.insert(room_id.clone()) {
+                self.queue.push_back((room_id, via, depth));
+            }
+        }
+    }
+}
+
+/// Returns whether the user could join or peek into a room they are not in.
+fn is_accessible(
+    sender_user: &UserId,
+    join_rule: &JoinRule,
+    world_readable: bool,
+    allowed_room_ids: &[OwnedRoomId],
+) -> Result<bool> {
+    if world_readable || matches!(join_rule, JoinRule::Public | JoinRule::Knock) {
+        return Ok(true);
+    }
+
+    for allowed in allowed_room_ids {
+        if services()
+            .rooms
+            .state_cache
+            .is_joined(sender_user, allowed)?
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns the rooms whose members may join a room with a restricted join rule.
+fn allowed_room_ids(join_rule: &JoinRule) -> Vec<OwnedRoomId> {
+    match join_rule {
+        JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted) => restricted
+            .allow
+            .iter()
+            .filter_map(|rule| match rule {
+                AllowRule::RoomMembership(membership) => Some(membership.room_id.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn space_join_rule(join_rule: &JoinRule) -> SpaceRoomJoinRule {
+    match join_rule {
+        JoinRule::Public => SpaceRoomJoinRule::Public,
+        JoinRule::Knock => SpaceRoomJoinRule::Knock,
+        JoinRule::Private => SpaceRoomJoinRule::Private,
+        JoinRule::Restricted(_) => SpaceRoomJoinRule::Restricted,
+        JoinRule::KnockRestricted(_) => SpaceRoomJoinRule::KnockRestricted,
+        _ => SpaceRoomJoinRule::Invite,
+    }
+}
+
+/// Parses the `m.space.child` events of a space into its children, in the order the spec
+/// defines. Children without servers to join through were removed from the space.
+fn children(children_state: &[Raw<HierarchySpaceChildEvent>]) -> Vec<SpaceChild> {
+    let mut children: Vec<SpaceChild> = children_state
+        .iter()
+        .filter_map(|event| event.deserialize_as().ok())
+        .filter(|child: &SpaceChild| !child.content.via.is_empty())
+        .collect();
+
+    children.sort_by(compare_children);
+    children
+}
+
+/// Children with a valid `order` come first, sorted by it, then by when they were added and by
+/// room id.
+fn compare_children(a: &SpaceChild, b: &SpaceChild) -> Ordering {
+    let order = |child: &SpaceChild| {
+        child.content.order.clone().filter(|order| {
+            order.len() <= 50 && order.chars().all(|c| ('\x20'..='\x7e').contains(&c))
+        })
+    };
+
+    match (order(a), order(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+    .then_with(|| a.origin_server_ts.cmp(&b.origin_server_ts))
+    .then_with(|| a.room_id.cmp(&b.room_id))
+}
+
+/// Returns the content of a state event without state key, if it's valid.
+fn state_content<T: for<'de> Deserialize<'de>>(
+    state: &HashMap<(StateEventType, String), Arc<PduEvent>>,
+    event_type: StateEventType,
+) -> Option<T> {
+    state
+        .get(&(event_type, String::new()))
+        .and_then(|pdu| serde_json::from_str(pdu.content.get()).ok())
+}
+
+/// Returns a string field of the content of a state event without state key.
+fn state_field(
+    state: &HashMap<(StateEventType, String), Arc<PduEvent>>,
+    event_type: StateEventType,
+    field: &str,
+) -> Option<String> {
+    state_content::<JsonValue>(state, event_type)?
+        .get(field)?
+        .as_str()
+        .map(ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn room(id: &str) -> OwnedRoomId {
+        OwnedRoomId::try_from(id).unwrap()
+    }
+
+    fn child(
+        room_id: &str,
+        order: Option<&str>,
+        ts: u64,
+        via: &[&str],
+    ) -> Raw<HierarchySpaceChildEvent> {
+        Raw::from_json(
+            serde_json::value::to_raw_value(&json!({
+                "type": "m.space.child",
+                "state_key": room_id,
+                "sender": "@admin:example.org",
+                "origin_server_ts": ts,
+                "content": { "via": via, "order": order },
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn children_are_ordered() {
+        let children = children(&[
+            child("!late:example.org", None, 3, &["example.org"]),
+            child("!early:example.org", None, 1, &["example.org"]),
+            child("!b:example.org", Some("b"), 5, &["example.org"]),
+            child("!a:example.org", Some("a"), 9, &["example.org"]),
+            child("!invalid:example.org", Some("\n"), 2, &["example.org"]),
+            child("!removed:example.org", Some("a"), 1, &[]),
+        ]);
+
+        let room_ids: Vec<_> = children
+            .iter()
+            .map(|child| child.room_id.as_str())
+            .collect();
+        assert_eq!(
+            room_ids,
+            [
+                "!a:example.org",
+                "!b:example.org",
+                "!early:example.org",
+                "!invalid:example.org",
+                "!late:example.org",
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_stops_at_cycles() {
+        // !space contains !sub, which contains !space again and !room
+        let graph: HashMap<OwnedRoomId, Vec<OwnedRoomId>> = HashMap::from([
+            (room("!space:x"), vec![room("!sub:x"), room("!room:x")]),
+            (
+                room("!sub:x"),
+                vec![room("!space:x"), room("!room:x"), room("!deep:x")],
+            ),
+            (room("!deep:x"), vec![room("!sub:x")]),
+        ]);
+
+        let mut walk = SpaceWalk::new(room("!space:x"));
+        let mut visited = Vec::new();
+        while let Some((room_id, _, depth)) = walk.next() {
+            if let Some(children) = graph.get(&room_id) {
+                walk.add_children(
+                    children.iter().map(|child| (child.clone(), Vec::new())),
+                    depth + 1,
+                );
+            }
+            visited.push((room_id, depth));
+        }
+
+        assert_eq!(
+            visited,
+            [
+                (room("!space:x"), 0),
+                (room("!sub:x"), 1),
+                (room("!room:x"), 1),
+                (room("!deep:x"), 2),
+            ]
+        );
+    }
+}
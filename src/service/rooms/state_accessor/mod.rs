@@ -9,17 +9,32 @@ use lru_cache::LruCache;
 use ruma::{
     events::{
         room::{
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
             history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
+            power_levels::RoomPowerLevelsEventContent,
         },
         StateEventType,
     },
-    EventId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
+    EventId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use tracing::error;
 
 use crate::{services, Error, PduEvent, Result};
 
+/// How a user may join a room with a restricted join rule.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestrictedJoin {
+    /// The user is invited or already joined, so the join rule doesn't matter
+    Member,
+    /// The user is in one of the rooms the join rule allows, but a member of the room who can
+    /// invite users has to authorize the join
+    Allowed,
+    /// The user is in none of the rooms the join rule allows
+    Forbidden,
+}
+
 pub struct Service {
     pub db: &'static dyn Data,
     pub server_visibility_cache: Mutex<LruCache<(OwnedServerName, u64), bool>>,
@@ -231,7 +246,7 @@ impl Service {
                     })
             })?;
 
-        Ok(currently_member || history_visibility == HistoryVisibility::WorldReadable)
+        Ok(state_events_visible(&history_visibility, currently_member))
     }
 
     /// Returns the state hash for this pdu.
@@ -269,4 +284,294 @@ impl Service {
     ) -> Result<Option<Arc<PduEvent>>> {
         self.db.room_state_get(room_id, event_type, state_key)
     }
+
+    /// Returns the join rule of the room, `invite` if the room has none.
+    pub fn get_join_rule(&self, room_id: &RoomId) -> Result<JoinRule> {
+        Ok(self
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|event| {
+                serde_json::from_str::<RoomJoinRulesEventContent>(event.content.get())
+                    .map(|content| content.join_rule)
+                    .map_err(|_| Error::bad_database("Invalid join rules event in db."))
+            })
+            .transpose()?
+            .unwrap_or(JoinRule::Invite))
+    }
+
+    /// Returns whether users may knock on the room, which needs a join rule that allows it in a
+    /// room version that supports knocking.
+    pub fn allows_knocking(&self, room_id: &RoomId) -> Result<bool> {
+        let join_rule = self.get_join_rule(room_id)?;
+        let room_version = services().rooms.state.get_room_version(room_id)?;
+
+        Ok(knocking_allowed(&join_rule, &room_version))
+    }
+
+    /// Returns whether guests may join the room. Rooms without a guest access event forbid it.
+    pub fn guests_can_join(&self, room_id: &RoomId) -> Result<bool> {
+        let guest_access = self
+            .room_state_get(room_id, &StateEventType::RoomGuestAccess, "")?
+            .map(|event| {
+                serde_json::from_str::<RoomGuestAccessEventContent>(event.content.get())
+                    .map(|content| content.guest_access)
+                    .map_err(|_| Error::bad_database("Invalid guest access event in db."))
+            })
+            .transpose()?;
+
+        Ok(guest_access_allows_join(guest_access.as_ref()))
+    }
+
+    /// Checks the `allow` conditions of a restricted join rule for the user. Returns `None` if
+    /// the join rule of the room is not restricted.
+    ///
+    /// Note: Only memberships this server knows about are considered, so the user may be in an
+    /// allowed room this server is not part of.
+    pub fn restricted_join(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<RestrictedJoin>> {
+        let allowed_room_ids = match self.get_join_rule(room_id)? {
+            JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted) => restricted
+                .allow
+                .into_iter()
+                .filter_map(|rule| match rule {
+                    AllowRule::RoomMembership(membership) => Some(membership.room_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            _ => return Ok(None),
+        };
+
+        let state_cache = &services().rooms.state_cache;
+        let member =
+            state_cache.is_joined(user_id, room_id)? || state_cache.is_invited(user_id, room_id)?;
+
+        Ok(Some(check_restricted_join(
+            member,
+            &allowed_room_ids,
+            |allowed| state_cache.is_joined(user_id, allowed).unwrap_or(false),
+        )))
+    }
+
+    /// Returns the local member of the room with the highest power level that may invite users,
+    /// who can authorize restricted joins through `join_authorised_via_users_server`.
+    pub fn restricted_join_authorizer(&self, room_id: &RoomId) -> Result<Option<OwnedUserId>> {
+        let local_members = services()
+            .rooms
+            .state_cache
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|user_id| user_id.server_name() == services().globals.server_name());
+
+        Ok(most_powerful_inviter(
+            &self.power_levels(room_id)?,
+            local_members,
+        ))
+    }
+
+    /// Returns whether the user is a local member of the room that may invite users, and can
+    /// therefore be named in `join_authorised_via_users_server`.
+    pub fn can_authorize_restricted_join(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<bool> {
+        if user_id.server_name() != services().globals.server_name()
+            || !services().rooms.state_cache.is_joined(user_id, room_id)?
+        {
+            return Ok(false);
+        }
+
+        Ok(most_powerful_inviter(
+            &self.power_levels(room_id)?,
+            std::iter::once(user_id.to_owned()),
+        )
+        .is_some())
+    }
+
+    fn power_levels(&self, room_id: &RoomId) -> Result<RoomPowerLevelsEventContent> {
+        Ok(self
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|event| {
+                serde_json::from_str(event.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+            })
+            .transpose()?
+            .unwrap_or_default())
+    }
+}
+
+fn check_restricted_join(
+    member: bool,
+    allowed_room_ids: &[OwnedRoomId],
+    is_joined: impl Fn(&RoomId) -> bool,
+) -> RestrictedJoin {
+    if member {
+        RestrictedJoin::Member
+    } else if allowed_room_ids.iter().any(|allowed| is_joined(&**allowed)) {
+        RestrictedJoin::Allowed
+    } else {
+        RestrictedJoin::Forbidden
+    }
+}
+
+fn most_powerful_inviter(
+    power_levels: &RoomPowerLevelsEventContent,
+    members: impl Iterator<Item = OwnedUserId>,
+) -> Option<OwnedUserId> {
+    members
+        .map(|user_id| {
+            let level = power_levels
+                .users
+                .get(&user_id)
+                .copied()
+                .unwrap_or(power_levels.users_default);
+            (level, user_id)
+        })
+        .filter(|(level, _)| *level >= power_levels.invite)
+        .max_by_key(|(level, _)| *level)
+        .map(|(_, user_id)| user_id)
+}
+
+fn state_events_visible(history_visibility: &HistoryVisibility, currently_member: bool) -> bool {
+    currently_member || history_visibility == &HistoryVisibility::WorldReadable
+}
+
+fn guest_access_allows_join(guest_access: Option<&GuestAccess>) -> bool {
+    guest_access == Some(&GuestAccess::CanJoin)
+}
+
+fn knocking_allowed(join_rule: &JoinRule, room_version: &RoomVersionId) -> bool {
+    // Knocking was added in room version 7, knock_restricted in room version 10
+    let supports_knock = !matches!(
+        room_version,
+        RoomVersionId::V1
+            | RoomVersionId::V2
+            | RoomVersionId::V3
+            | RoomVersionId::V4
+            | RoomVersionId::V5
+            | RoomVersionId::V6
+    );
+    let supports_knock_restricted = supports_knock
+        && !matches!(
+            room_version,
+            RoomVersionId::V7 | RoomVersionId::V8 | RoomVersionId::V9
+        );
+
+    match join_rule {
+        JoinRule::Knock => supports_knock,
+        JoinRule::KnockRestricted(_) => supports_knock_restricted,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::{events::room::join_rules::Restricted, int};
+
+    fn room(id: &str) -> OwnedRoomId {
+        OwnedRoomId::try_from(id).unwrap()
+    }
+
+    fn user(id: &str) -> OwnedUserId {
+        OwnedUserId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn knocking_needs_join_rule_and_room_version() {
+        let knock_restricted = JoinRule::KnockRestricted(Restricted::new(Vec::new()));
+
+        assert!(knocking_allowed(&JoinRule::Knock, &RoomVersionId::V7));
+        assert!(knocking_allowed(&JoinRule::Knock, &RoomVersionId::V10));
+        assert!(!knocking_allowed(&JoinRule::Knock, &RoomVersionId::V6));
+
+        assert!(knocking_allowed(&knock_restricted, &RoomVersionId::V10));
+        assert!(!knocking_allowed(&knock_restricted, &RoomVersionId::V9));
+
+        assert!(!knocking_allowed(&JoinRule::Public, &RoomVersionId::V10));
+        assert!(!knocking_allowed(&JoinRule::Invite, &RoomVersionId::V10));
+    }
+
+    #[test]
+    fn members_of_allowed_rooms_may_join() {
+        let allowed = [room("!space:example.org"), room("!lobby:example.org")];
+        let joined = room("!lobby:example.org");
+
+        assert_eq!(
+            check_restricted_join(false, &allowed, |room_id| room_id == &*joined),
+            RestrictedJoin::Allowed
+        );
+        assert_eq!(
+            check_restricted_join(false, &allowed, |_| false),
+            RestrictedJoin::Forbidden
+        );
+        assert_eq!(
+            check_restricted_join(false, &[], |_| true),
+            RestrictedJoin::Forbidden
+        );
+    }
+
+    #[test]
+    fn invited_users_may_join_restricted_rooms() {
+        // An invite is enough, even without being in any of the allowed rooms
+        assert_eq!(
+            check_restricted_join(true, &[room("!space:example.org")], |_| false),
+            RestrictedJoin::Member
+        );
+    }
+
+    #[test]
+    fn authorizer_must_be_able_to_invite() {
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.invite = int!(50);
+        power_levels
+            .users
+            .insert(user("@admin:example.org"), int!(100));
+        power_levels
+            .users
+            .insert(user("@mod:example.org"), int!(50));
+        let members = || {
+            [
+                user("@mod:example.org"),
+                user("@admin:example.org"),
+                user("@someone:example.org"),
+            ]
+            .into_iter()
+        };
+
+        assert_eq!(
+            most_powerful_inviter(&power_levels, members()),
+            Some(user("@admin:example.org"))
+        );
+        assert_eq!(
+            most_powerful_inviter(
+                &power_levels,
+                members().filter(|u| u.localpart() != "admin")
+            ),
+            Some(user("@mod:example.org"))
+        );
+        assert_eq!(
+            most_powerful_inviter(&power_levels, std::iter::once(user("@someone:example.org"))),
+            None
+        );
+    }
+
+    #[test]
+    fn world_readable_state_is_visible_to_non_members() {
+        assert!(state_events_visible(
+            &HistoryVisibility::WorldReadable,
+            false
+        ));
+        assert!(!state_events_visible(&HistoryVisibility::Shared, false));
+        assert!(state_events_visible(&HistoryVisibility::Joined, true));
+    }
+
+    #[test]
+    fn guests_need_can_join_guest_access() {
+        assert!(guest_access_allows_join(Some(&GuestAccess::CanJoin)));
+        assert!(!guest_access_allows_join(Some(&GuestAccess::Forbidden)));
+        assert!(!guest_access_allows_join(None));
+    }
 }
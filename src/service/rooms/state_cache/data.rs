@@ -16,6 +16,12 @@ pub trait Data: Send + Sync {
         room_id: &RoomId,
         last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
     ) -> Result<()>;
+    fn mark_as_knocked(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
+    ) -> Result<()>;
     fn mark_as_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
 
     fn update_joined_count(&self, room_id: &RoomId) -> Result<()>;
@@ -69,6 +75,14 @@ pub trait Data: Send + Sync {
 
     fn get_invite_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>>;
 
+    /// Returns an iterator over all users who knocked on a room.
+    fn room_members_knocked<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a>;
+
+    fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>>;
+
     fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>>;
 
     /// Returns an iterator over all rooms this user joined.
@@ -89,6 +103,18 @@ pub trait Data: Send + Sync {
         room_id: &RoomId,
     ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>>;
 
+    /// Returns an iterator over all rooms a user knocked on.
+    fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a>;
+
+    fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>>;
+
     fn left_state(
         &self,
         user_id: &UserId,
@@ -107,5 +133,7 @@ pub trait Data: Send + Sync {
 
     fn is_invited(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool>;
 
+    fn is_knocked(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool>;
+
     fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool>;
 }
@@ -11,7 +11,6 @@ use std::{
 };
 
 pub use data::Data;
-use regex::Regex;
 use ruma::{
     api::{client::error::ErrorKind, federation},
     canonical_json::to_canonical_value,
@@ -37,7 +36,11 @@ use tracing::{error, info, warn};
 
 use crate::{
     api::server_server,
-    service::pdu::{EventHash, PduBuilder},
+    service::{
+        appservice::{alias_matches_namespace, room_matches_namespace, user_matches_namespace},
+        pdu::{EventHash, PduBuilder},
+        pusher,
+    },
     services, utils, Error, PduEvent, Result,
 };
 
@@ -304,6 +307,8 @@ impl Service {
         drop(insert_lock);
 
         // See if the event matches any known pushers
+        let push_span = tracing::info_span!("push_fanout", event_id = %pdu.event_id).entered();
+
         let power_levels: RoomPowerLevelsEventContent = services()
             .rooms
             .state_accessor
@@ -319,6 +324,15 @@ impl Service {
 
         let mut notifies = Vec::new();
         let mut highlights = Vec::new();
+        let mut push_targets = Vec::new();
+
+        // Operators can limit how many users a single mention of the whole room reaches
+        let mass_mention_cap = services()
+            .globals
+            .push_mass_mention_cap()
+            .filter(|_| pusher::is_room_mention(pdu));
+        let mut pushed_users = 0_usize;
+        let mut capped_users = 0_usize;
 
         for user in services()
             .rooms
@@ -326,8 +340,14 @@ impl Service {
             .get_our_real_users(&pdu.room_id)?
             .iter()
         {
-            // Don't notify the user of their own events
+            // Don't notify the user of their own events, except for silent pushes that let their
+            // other devices pick up edits
             if user == &pdu.sender {
+                if services().globals.push_self_edits() && pdu.is_edit() {
+                    for push_key in services().pusher.get_pushkeys(user) {
+                        push_targets.push((user.clone(), push_key?));
+                    }
+                }
                 continue;
             }
 
@@ -373,20 +393,57 @@ impl Service {
                 highlights.push(user.clone());
             }
 
-            for push_key in services().pusher.get_pushkeys(user) {
-                services().sending.send_push_pdu(&pdu_id, user, push_key?)?;
+            let push_keys = services()
+                .pusher
+                .get_pushkeys(user)
+                .collect::<Result<Vec<_>>>()?;
+            if push_keys.is_empty() {
+                continue;
+            }
+
+            if mass_mention_cap.map_or(false, |cap| pushed_users >= cap) {
+                capped_users += 1;
+                continue;
+            }
+            pushed_users += 1;
+
+            for push_key in push_keys {
+                push_targets.push((user.clone(), push_key));
             }
         }
 
+        if capped_users > 0 {
+            warn!(
+                "Not pushing room mention {} to {} users beyond the mass mention cap",
+                pdu.event_id, capped_users
+            );
+        }
+
         self.db
             .increment_notification_counts(&pdu.room_id, notifies, highlights)?;
 
+        services().sending.send_push_pdus(&pdu_id, push_targets)?;
+
+        drop(push_span);
+
         match pdu.kind {
             TimelineEventType::RoomRedaction => {
                 if let Some(redact_id) = &pdu.redacts {
+                    services()
+                        .rooms
+                        .pdu_metadata
+                        .prune_relations_on_redact(&pdu.room_id, redact_id)?;
                     self.redact_pdu(redact_id, pdu)?;
+                    services()
+                        .pusher
+                        .notify_redaction(&pdu.room_id, redact_id)?;
                 }
             }
+            TimelineEventType::CallInvite
+            | TimelineEventType::CallAnswer
+            | TimelineEventType::CallHangup => {
+                services().pusher.update_missed_calls(pdu)?;
+            }
             TimelineEventType::RoomMember => {
                 if let Some(state_key) = &pdu.state_key {
                     #[derive(Deserialize)]
@@ -402,7 +459,7 @@ impl Service {
                         .map_err(|_| Error::bad_database("Invalid content in pdu."))?;
 
                     let invite_state = match content.membership {
-                        MembershipState::Invite => {
+                        MembershipState::Invite | MembershipState::Knock => {
                             let state = services().rooms.state.calculate_invite_state(pdu)?;
                             Some(state)
                         }
@@ -470,6 +527,7 @@ impl Service {
         #[derive(Clone, Debug, Deserialize)]
         struct ExtractEventId {
             event_id: OwnedEventId,
+            rel_type: Option<String>,
         }
         #[derive(Clone, Debug, Deserialize)]
         struct ExtractRelatesToEventId {
@@ -482,6 +540,14 @@ impl Service {
                 .rooms
                 .pdu_metadata
                 .add_relation(&pdu.event_id, &content.relates_to.event_id)?;
+
+            if let Some(rel_type) = &content.relates_to.rel_type {
+                services().rooms.pdu_metadata.add_typed_relation(
+                    pdu,
+                    &content.relates_to.event_id,
+                    rel_type,
+                )?;
+            }
         }
 
         if let Ok(content) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
@@ -543,54 +609,29 @@ impl Service {
                 }
             }
 
-            if let Some(namespaces) = appservice.1.get("namespaces") {
-                let users = namespaces
-                    .get("users")
-                    .and_then(|users| users.as_sequence())
-                    .map_or_else(Vec::new, |users| {
-                        users
-                            .iter()
-                            .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let aliases = namespaces
-                    .get("aliases")
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let rooms = namespaces
-                    .get("rooms")
-                    .and_then(|rooms| rooms.as_sequence());
-
-                let matching_users = |users: &Regex| {
-                    users.is_match(pdu.sender.as_str())
-                        || pdu.kind == TimelineEventType::RoomMember
-                            && pdu
-                                .state_key
-                                .as_ref()
-                                .map_or(false, |state_key| users.is_match(state_key))
-                };
-                let matching_aliases = |aliases: &Regex| {
-                    services()
-                        .rooms
-                        .alias
-                        .local_aliases_for_room(&pdu.room_id)
-                        .filter_map(|r| r.ok())
-                        .any(|room_alias| aliases.is_match(room_alias.as_str()))
-                };
-
-                if aliases.iter().any(matching_aliases)
-                    || rooms.map_or(false, |rooms| rooms.contains(&pdu.room_id.as_str().into()))
-                    || users.iter().any(matching_users)
-                {
-                    services()
-                        .sending
-                        .send_pdu_appservice(appservice.0, pdu_id.clone())?;
-                }
+            let matching_users = |user_id: &str| {
+                UserId::parse(user_id).map_or(false, |user_id| {
+                    user_matches_namespace(&appservice.1, &user_id)
+                })
+            };
+            let matching_aliases = || {
+                services()
+                    .rooms
+                    .alias
+                    .local_aliases_for_room(&pdu.room_id)
+                    .filter_map(|r| r.ok())
+                    .any(|room_alias| alias_matches_namespace(&appservice.1, &room_alias))
+            };
+
+            if matching_users(pdu.sender.as_str())
+                || pdu.kind == TimelineEventType::RoomMember
+                    && pdu.state_key.as_deref().map_or(false, matching_users)
+                || room_matches_namespace(&appservice.1, &pdu.room_id)
+                || matching_aliases()
+            {
+                services()
+                    .sending
+                    .send_pdu_appservice(appservice.0, pdu_id.clone())?;
             }
         }
 
@@ -1009,6 +1050,18 @@ impl Service {
             let mut pdu = self
                 .get_pdu_from_id(&pdu_id)?
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+
+            // The redacted message must not be found by searching for its words anymore. A
+            // malformed message must still be redactable, so this is best-effort.
+            if pdu.kind == TimelineEventType::RoomMessage {
+                if let Err(e) = self.deindex_pdu(&pdu_id, &pdu) {
+                    warn!(
+                        "Failed to remove redacted event {} from the search index: {}",
+                        event_id, e
+                    );
+                }
+            }
+
             pdu.redact(reason)?;
             self.replace_pdu(
                 &pdu_id,
@@ -1020,6 +1073,32 @@ impl Service {
         Ok(())
     }
 
+    /// Removes the words of a message from the search index of its room.
+    fn deindex_pdu(&self, pdu_id: &[u8], pdu: &PduEvent) -> Result<()> {
+        #[derive(Deserialize)]
+        struct ExtractBody {
+            body: Option<String>,
+        }
+
+        let content = serde_json::from_str::<ExtractBody>(pdu.content.get())
+            .map_err(|_| Error::bad_database("Invalid content in pdu."))?;
+
+        if let Some(body) = content.body {
+            let shortroomid = services()
+                .rooms
+                .short
+                .get_shortroomid(&pdu.room_id)?
+                .ok_or_else(|| Error::bad_database("Room of PDU has no short id."))?;
+
+            services()
+                .rooms
+                .search
+                .deindex_pdu(shortroomid, pdu_id, &body)?;
+        }
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self, room_id))]
     pub async fn backfill_if_required(&self, room_id: &RoomId, from: PduCount) -> Result<()> {
         let first_pdu = self
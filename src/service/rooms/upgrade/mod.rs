@@ -0,0 +1,428 @@
+use std::{cmp::max, sync::Arc};
+
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{
+        room::{
+            create::PreviousRoom,
+            member::{MembershipState, RoomMemberEventContent},
+            power_levels::RoomPowerLevelsEventContent,
+            tombstone::RoomTombstoneEventContent,
+        },
+        StateEventType, TimelineEventType,
+    },
+    int, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedRoomId, RoomId, RoomVersionId,
+    UserId,
+};
+use serde_json::value::to_raw_value;
+use tracing::warn;
+
+use crate::{service::pdu::PduBuilder, services, Error, Result};
+
+/// State events that are copied to the replacement room. The spec recommends all of these, except
+/// for the canonical alias, which is kept because the local aliases move to the new room.
+const TRANSFERABLE_STATE_EVENTS: &[StateEventType] = &[
+    StateEventType::RoomServerAcl,
+    StateEventType::RoomEncryption,
+    StateEventType::RoomName,
+    StateEventType::RoomAvatar,
+    StateEventType::RoomTopic,
+    StateEventType::RoomGuestAccess,
+    StateEventType::RoomHistoryVisibility,
+    StateEventType::RoomJoinRules,
+    StateEventType::RoomPowerLevels,
+    StateEventType::RoomCanonicalAlias,
+];
+
+pub struct Service;
+
+impl Service {
+    /// Replaces the room with a new room of the given version and returns the id of the new room.
+    ///
+    /// - Sends a tombstone event into the old room, which fails if the user may not upgrade it
+    /// - Creates the new room with the old room as predecessor and joins the user
+    /// - Copies the transferable state and the bans of the old room
+    /// - Moves the local aliases and invites the local members of the old room
+    /// - Raises the power levels of the old room so that users can't send events or invite
+    pub async fn upgrade_room(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+        new_version: &RoomVersionId,
+    ) -> Result<OwnedRoomId> {
+        if !services()
+            .globals
+            .supported_room_versions()
+            .contains(new_version)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::UnsupportedRoomVersion,
+                "This server does not support that room version.",
+            ));
+        }
+
+        let replacement_room = RoomId::new(services().globals.server_name());
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        // Send a m.room.tombstone event to the old room to indicate that it is not intended to be
+        // used any further. Fail if the sender does not have the required permissions
+        let tombstone_event_id = services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomTombstone,
+                content: to_raw_value(&RoomTombstoneEventContent {
+                    body: "This room has been replaced".to_owned(),
+                    replacement_room: replacement_room.clone(),
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            room_id,
+            &state_lock,
+        )?;
+
+        let old_state = services()
+            .rooms
+            .state_accessor
+            .room_state_full(room_id)
+            .await?;
+
+        drop(state_lock);
+
+        services()
+            .rooms
+            .short
+            .get_or_create_shortroomid(&replacement_room)?;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(replacement_room.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let old_create_content = serde_json::from_str::<CanonicalJsonObject>(
+            old_state
+                .get(&(StateEventType::RoomCreate, "".to_owned()))
+                .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
+                .content
+                .get(),
+        )
+        .map_err(|_| Error::bad_database("Invalid room event in database."))?;
+
+        // Send a m.room.create event containing a predecessor field and the new room version
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomCreate,
+                content: to_raw_value(&upgraded_create_content(
+                    old_create_content,
+                    sender_user,
+                    new_version,
+                    room_id,
+                    &tombstone_event_id,
+                ))
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room,
+            &state_lock,
+        )?;
+
+        // Join the new room
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent {
+                    membership: MembershipState::Join,
+                    displayname: services().users.displayname(sender_user)?,
+                    avatar_url: services().users.avatar_url(sender_user)?,
+                    is_direct: None,
+                    third_party_invite: None,
+                    blurhash: services().users.blurhash(sender_user)?,
+                    reason: None,
+                    join_authorized_via_users_server: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(sender_user.to_string()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room,
+            &state_lock,
+        )?;
+
+        // Moves any local aliases to the new room, before the canonical alias is copied
+        for alias in services()
+            .rooms
+            .alias
+            .local_aliases_for_room(room_id)
+            .filter_map(|r| r.ok())
+        {
+            services()
+                .rooms
+                .alias
+                .set_alias(&alias, &replacement_room)?;
+        }
+
+        // Replicate transferable state events to the new room
+        for event_type in TRANSFERABLE_STATE_EVENTS {
+            let event_content = match old_state.get(&(event_type.clone(), "".to_owned())) {
+                Some(event) => event.content.clone(),
+                None => continue, // Skipping missing events.
+            };
+
+            services().rooms.timeline.build_and_append_pdu(
+                PduBuilder {
+                    event_type: event_type.to_string().into(),
+                    content: event_content,
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                sender_user,
+                &replacement_room,
+                &state_lock,
+            )?;
+        }
+
+        // Banned users stay banned, but other memberships are not copied
+        for ((event_type, state_key), event) in &old_state {
+            if *event_type != StateEventType::RoomMember {
+                continue;
+            }
+
+            let content = match serde_json::from_str::<RoomMemberEventContent>(event.content.get())
+            {
+                Ok(content) if content.membership == MembershipState::Ban => content,
+                _ => continue,
+            };
+
+            if let Err(e) = services().rooms.timeline.build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMember,
+                    content: to_raw_value(&RoomMemberEventContent {
+                        membership: MembershipState::Ban,
+                        displayname: None,
+                        avatar_url: None,
+                        is_direct: None,
+                        third_party_invite: None,
+                        blurhash: None,
+                        reason: content.reason,
+                        join_authorized_via_users_server: None,
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(state_key.clone()),
+                    redacts: None,
+                },
+                sender_user,
+                &replacement_room,
+                &state_lock,
+            ) {
+                warn!("Failed to ban {} in upgraded room: {}", state_key, e);
+            }
+        }
+
+        // Invite the local members, so they can follow the upgrade
+        let local_members: Vec<_> = services()
+            .rooms
+            .state_cache
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|user_id| {
+                user_id.server_name() == services().globals.server_name()
+                    && &**user_id != sender_user
+            })
+            .collect();
+
+        for user_id in local_members {
+            if let Err(e) = services().rooms.timeline.build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMember,
+                    content: to_raw_value(&RoomMemberEventContent {
+                        membership: MembershipState::Invite,
+                        displayname: services().users.displayname(&user_id)?,
+                        avatar_url: services().users.avatar_url(&user_id)?,
+                        is_direct: None,
+                        third_party_invite: None,
+                        blurhash: services().users.blurhash(&user_id)?,
+                        reason: None,
+                        join_authorized_via_users_server: None,
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(user_id.to_string()),
+                    redacts: None,
+                },
+                sender_user,
+                &replacement_room,
+                &state_lock,
+            ) {
+                warn!("Failed to invite {} to upgraded room: {}", user_id, e);
+            }
+        }
+
+        drop(state_lock);
+
+        // Modify the power levels in the old room to prevent sending of events and inviting new
+        // users
+        if let Some(power_levels) = old_state.get(&(StateEventType::RoomPowerLevels, "".to_owned()))
+        {
+            let power_levels_event_content: RoomPowerLevelsEventContent =
+                serde_json::from_str(power_levels.content.get())
+                    .map_err(|_| Error::bad_database("Invalid room event in database."))?;
+
+            let mutex_state = Arc::clone(
+                services()
+                    .globals
+                    .roomid_mutex_state
+                    .write()
+                    .unwrap()
+                    .entry(room_id.to_owned())
+                    .or_default(),
+            );
+            let state_lock = mutex_state.lock().await;
+
+            services().rooms.timeline.build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomPowerLevels,
+                    content: to_raw_value(&restricted_power_levels(power_levels_event_content))
+                        .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                sender_user,
+                room_id,
+                &state_lock,
+            )?;
+        }
+
+        Ok(replacement_room)
+    }
+}
+
+/// Builds the create event of the replacement room from the one of the old room, with the new
+/// room version and the old room as predecessor.
+fn upgraded_create_content(
+    mut content: CanonicalJsonObject,
+    sender_user: &UserId,
+    new_version: &RoomVersionId,
+    old_room_id: &RoomId,
+    tombstone_event_id: &EventId,
+) -> CanonicalJsonObject {
+    let predecessor = PreviousRoom::new(old_room_id.to_owned(), tombstone_event_id.to_owned());
+
+    content.insert(
+        "creator".to_owned(),
+        CanonicalJsonValue::String(sender_user.to_string()),
+    );
+    content.insert(
+        "room_version".to_owned(),
+        CanonicalJsonValue::String(new_version.to_string()),
+    );
+    content.insert(
+        "predecessor".to_owned(),
+        serde_json::to_value(predecessor)
+            .expect("predecessor can be serialized")
+            .try_into()
+            .expect("predecessor is valid canonical json"),
+    );
+
+    content
+}
+
+/// Raises `events_default` and `invite` to the greater of 50 and `users_default + 1`, so that
+/// regular users can't use the old room anymore.
+fn restricted_power_levels(
+    mut power_levels: RoomPowerLevelsEventContent,
+) -> RoomPowerLevelsEventContent {
+    let new_level = max(int!(50), power_levels.users_default + int!(1));
+    power_levels.events_default = new_level;
+    power_levels.invite = new_level;
+
+    power_levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::{event_id, room_id, user_id};
+    use serde_json::json;
+
+    #[test]
+    fn create_event_links_to_tombstone() {
+        let old_content: CanonicalJsonObject = serde_json::from_value(json!({
+            "creator": "@old:example.org",
+            "room_version": "6",
+            "m.federate": false,
+            "type": "m.space",
+        }))
+        .unwrap();
+
+        let content = upgraded_create_content(
+            old_content,
+            user_id!("@admin:example.org"),
+            &RoomVersionId::V10,
+            room_id!("!old:example.org"),
+            event_id!("$tombstone"),
+        );
+
+        assert_eq!(
+            serde_json::to_value(content).unwrap(),
+            json!({
+                "creator": "@admin:example.org",
+                "room_version": "10",
+                "m.federate": false,
+                "type": "m.space",
+                "predecessor": {
+                    "room_id": "!old:example.org",
+                    "event_id": "$tombstone",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn old_room_is_locked_for_regular_users() {
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users_default = int!(0);
+        let power_levels = restricted_power_levels(power_levels);
+        assert_eq!(power_levels.events_default, int!(50));
+        assert_eq!(power_levels.invite, int!(50));
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users_default = int!(60);
+        let power_levels = restricted_power_levels(power_levels);
+        assert_eq!(power_levels.events_default, int!(61));
+        assert_eq!(power_levels.invite, int!(61));
+    }
+
+    #[test]
+    fn memberships_are_not_transferred() {
+        assert!(!TRANSFERABLE_STATE_EVENTS.contains(&StateEventType::RoomMember));
+        assert!(!TRANSFERABLE_STATE_EVENTS.contains(&StateEventType::RoomCreate));
+        assert!(!TRANSFERABLE_STATE_EVENTS.contains(&StateEventType::RoomTombstone));
+        assert!(TRANSFERABLE_STATE_EVENTS.contains(&StateEventType::RoomPowerLevels));
+    }
+}
@@ -3,7 +3,7 @@ mod data;
 pub use data::Data;
 use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -11,7 +11,9 @@ pub struct Service {
 
 impl Service {
     pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
-        self.db.reset_notification_counts(user_id, room_id)
+        self.db.reset_notification_counts(user_id, room_id)?;
+        services().pusher.clear_missed_calls(user_id, room_id)?;
+        services().pusher.clear_last_notified(user_id, room_id)
     }
 
     pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
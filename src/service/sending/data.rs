@@ -1,4 +1,4 @@
-use ruma::ServerName;
+use ruma::{OwnedUserId, ServerName, UserId};
 
 use crate::Result;
 
@@ -23,7 +23,30 @@ pub trait Data: Send + Sync {
         &'a self,
         outgoing_kind: &OutgoingKind,
     ) -> Box<dyn Iterator<Item = Result<(SendingEventType, Vec<u8>)>> + 'a>;
+    /// Returns all push requests as user, pushkey and pdu id, either the active or the queued ones.
+    fn push_requests<'a>(
+        &'a self,
+        active: bool,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, Vec<u8>)>> + 'a>;
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()>;
     fn set_latest_educount(&self, server_name: &ServerName, educount: u64) -> Result<()>;
     fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
+    /// Returns the ID of the transaction that is currently sent to the appservice.
+    fn appservice_txn_id(&self, appservice_id: &str) -> Result<Option<u64>>;
+    fn set_appservice_txn_id(&self, appservice_id: &str, txn_id: u64) -> Result<()>;
+    fn remove_appservice_txn_id(&self, appservice_id: &str) -> Result<()>;
+    /// Stores a push request that must not be sent before `due`.
+    fn queue_delayed_push(
+        &self,
+        due: u64,
+        user: &UserId,
+        pushkey: &str,
+        pdu_id: &[u8],
+    ) -> Result<()>;
+    /// Returns the delayed push requests that are due at `now` as key, user, pushkey and pdu id.
+    fn due_delayed_pushes<'a>(
+        &'a self,
+        now: u64,
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, OwnedUserId, String, Vec<u8>)>> + 'a>;
+    fn delete_delayed_push(&self, key: Vec<u8>) -> Result<()>;
 }
@@ -5,14 +5,16 @@ pub use data::Data;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
-    sync::Arc,
+    sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
 
 use crate::{
     api::{appservice_server, server_server},
+    config::RetryJitter,
+    service::appservice::{receives_ephemeral, room_matches_namespace, user_matches_namespace},
     services,
-    utils::calculate_hash,
+    utils::{self, calculate_hash},
     Config, Error, PduEvent, Result,
 };
 use federation::transactions::send_transaction_message;
@@ -34,13 +36,17 @@ use ruma::{
         push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent,
         GlobalAccountDataEventType,
     },
-    push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, ServerName, UInt, UserId,
+    push,
+    serde::Raw,
+    uint, DeviceId, MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedServerName, OwnedUserId,
+    ServerName, UInt, UserId,
 };
+use serde_json::{json, value::RawValue as RawJsonValue, Value as JsonValue};
 use tokio::{
     select,
     sync::{mpsc, Mutex, Semaphore},
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, warn, Instrument};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum OutgoingKind {
@@ -75,6 +81,28 @@ impl OutgoingKind {
 
         prefix
     }
+
+    /// Returns the key of an event in the sending queue. `count` is only used for EDUs and
+    /// appservice events, whose PDUs would otherwise be sorted by room instead of by the order
+    /// they were sent in.
+    pub fn get_event_key(&self, event: &SendingEventType, count: u64) -> Vec<u8> {
+        let mut key = self.get_prefix();
+        match (self, event) {
+            (OutgoingKind::Appservice(_), SendingEventType::Pdu(pdu_id)) => {
+                key.extend_from_slice(&count.to_be_bytes());
+                key.extend_from_slice(pdu_id);
+            }
+            (_, SendingEventType::Pdu(pdu_id)) => key.extend_from_slice(pdu_id),
+            (_, SendingEventType::Edu(_)) => key.extend_from_slice(&count.to_be_bytes()),
+        }
+
+        key
+    }
+
+    /// Returns whether the key of the event needs a count, see `get_event_key`.
+    pub fn needs_count(&self, event: &SendingEventType) -> bool {
+        matches!(self, OutgoingKind::Appservice(_)) || matches!(event, SendingEventType::Edu(_))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -90,12 +118,22 @@ pub struct Service {
     pub(super) maximum_requests: Arc<Semaphore>,
     pub sender: mpsc::UnboundedSender<(OutgoingKind, SendingEventType, Vec<u8>)>,
     receiver: Mutex<mpsc::UnboundedReceiver<(OutgoingKind, SendingEventType, Vec<u8>)>>,
+    /// Number of consecutive failed attempts of destinations that are currently failing
+    failed_attempts: RwLock<HashMap<OutgoingKind, u32>>,
+    /// Destinations whose next attempt should not wait for the backoff to expire
+    expedited: RwLock<HashSet<OutgoingKind>>,
 }
 
+/// Time between two waves of push notifications for the same event.
+const FANOUT_WAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often failed appservice transactions are checked for whether they should be retried.
+const APPSERVICE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
 enum TransactionStatus {
     Running,
-    Failed(u32, Instant), // number of times failed, time of last failure
-    Retrying(u32),        // number of times failed
+    Failed(u32, Instant, Duration), // number of times failed, time of last failure, delay before retrying
+    Retrying(u32),                  // number of times failed
 }
 
 impl Service {
@@ -105,6 +143,8 @@ impl Service {
             db,
             sender,
             receiver: Mutex::new(receiver),
+            failed_attempts: RwLock::new(HashMap::new()),
+            expedited: RwLock::new(HashSet::new()),
             maximum_requests: Arc::new(Semaphore::new(config.max_concurrent_requests as usize)),
         })
     }
@@ -131,7 +171,8 @@ impl Service {
                 .entry(outgoing_kind.clone())
                 .or_insert_with(Vec::new);
 
-            if entry.len() > 30 {
+            // Appservices must receive every event, a retried transaction also has to stay the same
+            if entry.len() > 30 && !matches!(outgoing_kind, OutgoingKind::Appservice(_)) {
                 warn!(
                     "Dropping some current events: {:?} {:?} {:?}",
                     key, outgoing_kind, event
@@ -148,12 +189,21 @@ impl Service {
             futures.push(Self::handle_events(outgoing_kind.clone(), events));
         }
 
+        let mut appservice_retry = tokio::time::interval(APPSERVICE_RETRY_INTERVAL);
+        let mut push_waves = tokio::time::interval(FANOUT_WAVE_INTERVAL);
+
         loop {
             select! {
                 Some(response) = futures.next() => {
                     match response {
                         Ok(outgoing_kind) => {
+                            // Without the transaction ID, the events are sent again in a new
+                            // transaction if we crash before they are deleted
+                            if let OutgoingKind::Appservice(id) = &outgoing_kind {
+                                self.db.remove_appservice_txn_id(id)?;
+                            }
                             self.db.delete_all_active_requests_for(&outgoing_kind)?;
+                            self.failed_attempts.write().unwrap().remove(&outgoing_kind);
 
                             // Find events that have been added since starting the last request
                             let new_events = self.db.queued_requests(&outgoing_kind).filter_map(|r| r.ok()).take(30).collect::<Vec<_>>();
@@ -173,14 +223,35 @@ impl Service {
                             }
                         }
                         Err((outgoing_kind, _)) => {
-                            current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
-                                TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
-                                TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
-                                TransactionStatus::Failed(_, _) => {
+                            current_transaction_status.entry(outgoing_kind.clone()).and_modify(|e| *e = match e {
+                                TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now(), retry_delay(&outgoing_kind, 1)),
+                                TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now(), retry_delay(&outgoing_kind, *n+1)),
+                                TransactionStatus::Failed(_, _, _) => {
                                     error!("Request that was not even running failed?!");
                                     return
                                 },
                             });
+
+                            if let Some(&TransactionStatus::Failed(tries, _, _)) = current_transaction_status.get(&outgoing_kind) {
+                                self.failed_attempts.write().unwrap().insert(outgoing_kind.clone(), tries);
+                            }
+
+                            // Pushers can limit how often delivery is attempted
+                            if let (OutgoingKind::Push(user, pushkey), Some(&TransactionStatus::Failed(tries, _, _))) =
+                                (&outgoing_kind, current_transaction_status.get(&outgoing_kind))
+                            {
+                                if services().pusher.retry_policy(user, pushkey)?.exhausted(tries) {
+                                    warn!("Giving up on push notifications for {} after {} attempts", user, tries);
+                                    self.db.delete_all_requests_for(&outgoing_kind)?;
+                                    current_transaction_status.remove(&outgoing_kind);
+                                    self.failed_attempts.write().unwrap().remove(&outgoing_kind);
+
+                                    // Don't hammer a gateway that is down with the next events
+                                    if let Err(e) = services().pusher.pause_pusher(user, pushkey) {
+                                        warn!("Failed to pause pusher of {}: {}", user, e);
+                                    }
+                                }
+                            }
                         }
                     };
                 },
@@ -193,10 +264,58 @@ impl Service {
                         futures.push(Self::handle_events(outgoing_kind, events));
                     }
                 }
+                _ = push_waves.tick() => {
+                    if let Err(e) = self.send_due_push_waves() {
+                        warn!("Failed to queue the next push fan-out wave: {}", e);
+                    }
+                }
+                _ = appservice_retry.tick() => {
+                    // Appservices are retried without waiting for a new event, because the
+                    // queued events must reach them even if nothing else happens
+                    let due = current_transaction_status
+                        .iter()
+                        .filter_map(|(outgoing_kind, status)| match (outgoing_kind, status) {
+                            (OutgoingKind::Appservice(_), TransactionStatus::Failed(_, time, delay))
+                                if time.elapsed() >= *delay =>
+                            {
+                                Some(outgoing_kind.clone())
+                            }
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+
+                    for outgoing_kind in due {
+                        if let Ok(Some(events)) = self.select_events(
+                            &outgoing_kind,
+                            Vec::new(),
+                            &mut current_transaction_status,
+                        ) {
+                            futures.push(Self::handle_events(outgoing_kind, events));
+                        }
+                    }
+                }
             }
         }
     }
 
+    /// Returns how often sending to this destination failed in a row, 0 if it is not failing.
+    pub fn failed_attempts(&self, outgoing_kind: &OutgoingKind) -> u32 {
+        self.failed_attempts
+            .read()
+            .unwrap()
+            .get(outgoing_kind)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns all push requests as user, pushkey and pdu id, either the active or the queued ones.
+    pub fn push_requests<'a>(
+        &'a self,
+        active: bool,
+    ) -> impl Iterator<Item = Result<(OwnedUserId, String, Vec<u8>)>> + 'a {
+        self.db.push_requests(active)
+    }
+
     #[tracing::instrument(skip(self, outgoing_kind, new_events, current_transaction_status))]
     fn select_events(
         &self,
@@ -214,14 +333,11 @@ impl Service {
                 TransactionStatus::Running | TransactionStatus::Retrying(_) => {
                     allow = false; // already running
                 }
-                TransactionStatus::Failed(tries, time) => {
+                TransactionStatus::Failed(tries, time, delay) => {
                     // Fail if a request has failed recently (exponential backoff)
-                    let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-                        min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
-                    }
-
-                    if time.elapsed() < min_elapsed_duration {
+                    if time.elapsed() < *delay
+                        && !self.expedited.write().unwrap().remove(outgoing_kind)
+                    {
                         allow = false;
                     } else {
                         retry = true;
@@ -379,6 +495,71 @@ impl Service {
         Ok(())
     }
 
+    /// Queues the pdu for this pusher again, without waiting for a running backoff or pause to
+    /// expire.
+    #[tracing::instrument(skip(self, pdu_id))]
+    pub fn requeue_push_pdu(&self, pdu_id: &[u8], user: &UserId, pushkey: String) -> Result<()> {
+        // Paused pushers drop their events, which would include this one
+        services().pusher.resume_pusher(user, &pushkey)?;
+        self.expedited
+            .write()
+            .unwrap()
+            .insert(OutgoingKind::Push(user.to_owned(), pushkey.clone()));
+        self.send_push_pdu(pdu_id, user, pushkey)
+    }
+
+    /// Queues the pdu for all given pushers. Events reaching more pushers than the configured
+    /// maximum fan-out are queued in waves, so a single event can't flood the push gateways.
+    #[tracing::instrument(skip(self, pdu_id, targets))]
+    pub fn send_push_pdus(&self, pdu_id: &[u8], targets: Vec<(OwnedUserId, String)>) -> Result<()> {
+        let max_fanout = services().globals.push_max_fanout();
+        let total = targets.len();
+        let mut waves = fanout_waves(targets, max_fanout).into_iter();
+
+        for (user, pushkey) in waves.next().unwrap_or_default() {
+            self.send_push_pdu(pdu_id, &user, pushkey)?;
+        }
+
+        let remaining = waves.collect::<Vec<_>>();
+        if remaining.is_empty() {
+            return Ok(());
+        }
+
+        warn!(
+            "Push fan-out of {} exceeds the maximum of {}, sending the rest in {} waves",
+            total,
+            max_fanout,
+            remaining.len()
+        );
+
+        // The later waves are stored with the time they are due, so they aren't lost on restart
+        let now = utils::millis_since_unix_epoch();
+        let interval = FANOUT_WAVE_INTERVAL.as_millis() as u64;
+        for (i, wave) in remaining.into_iter().enumerate() {
+            let due = now + interval * (i as u64 + 1);
+            for (user, pushkey) in wave {
+                self.db.queue_delayed_push(due, &user, &pushkey, pdu_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queues the push requests of fan-out waves that are due now.
+    fn send_due_push_waves(&self) -> Result<()> {
+        let due = self
+            .db
+            .due_delayed_pushes(utils::millis_since_unix_epoch())
+            .collect::<Result<Vec<_>>>()?;
+
+        for (key, user, pushkey, pdu_id) in due {
+            self.send_push_pdu(&pdu_id, &user, pushkey)?;
+            self.db.delete_delayed_push(key)?;
+        }
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self, servers, pdu_id))]
     pub fn send_pdu<I: Iterator<Item = OwnedServerName>>(
         &self,
@@ -438,11 +619,103 @@ impl Service {
         Ok(())
     }
 
+    /// Queues an ephemeral event, like a typing notification, for all appservices that want to
+    /// receive them and are interested in one of the rooms.
+    #[tracing::instrument(skip(self, room_ids, edu))]
+    pub fn send_edu_appservices(&self, room_ids: &[OwnedRoomId], edu: &JsonValue) -> Result<()> {
+        for appservice in services().appservice.all()? {
+            if !receives_ephemeral(&appservice.1) {
+                continue;
+            }
+
+            let mut interested = false;
+            for room_id in room_ids {
+                if room_matches_namespace(&appservice.1, room_id)
+                    || services()
+                        .rooms
+                        .state_cache
+                        .appservice_in_room(room_id, &appservice)?
+                {
+                    interested = true;
+                    break;
+                }
+            }
+
+            if interested {
+                let outgoing_kind = OutgoingKind::Appservice(appservice.0);
+                let event =
+                    SendingEventType::Edu(serde_json::to_vec(edu).expect("json can be serialized"));
+                let keys = self.db.queue_requests(&[(&outgoing_kind, event.clone())])?;
+                self.sender
+                    .send((outgoing_kind, event, keys.into_iter().next().unwrap()))
+                    .unwrap();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queues a to-device message for the appservices that receive ephemeral events and whose
+    /// users namespace contains the target user. Returns whether any appservice receives it.
+    #[tracing::instrument(skip(self, content))]
+    pub fn send_to_device_appservices(
+        &self,
+        sender: &UserId,
+        target_user_id: &UserId,
+        target_device_id: &DeviceId,
+        event_type: &str,
+        content: &JsonValue,
+    ) -> Result<bool> {
+        let mut sent = false;
+
+        for (id, registration) in services().appservice.all()? {
+            if !receives_ephemeral(&registration)
+                || !user_matches_namespace(&registration, target_user_id)
+            {
+                continue;
+            }
+
+            let outgoing_kind = OutgoingKind::Appservice(id);
+            let event = SendingEventType::Edu(
+                serde_json::to_vec(&appservice_to_device_event(
+                    sender,
+                    target_user_id,
+                    target_device_id,
+                    event_type,
+                    content,
+                ))
+                .expect("json can be serialized"),
+            );
+            let keys = self.db.queue_requests(&[(&outgoing_kind, event.clone())])?;
+            self.sender
+                .send((outgoing_kind, event, keys.into_iter().next().unwrap()))
+                .unwrap();
+
+            sent = true;
+        }
+
+        Ok(sent)
+    }
+
+    /// Returns the ID of the transaction of the events that are currently sent to the
+    /// appservice. Retries use the same ID, so the appservice can tell it already handled them.
+    fn appservice_txn_id(&self, appservice_id: &str) -> Result<u64> {
+        if let Some(txn_id) = self.db.appservice_txn_id(appservice_id)? {
+            return Ok(txn_id);
+        }
+
+        let txn_id = services().globals.next_count()?;
+        self.db.set_appservice_txn_id(appservice_id, txn_id)?;
+
+        Ok(txn_id)
+    }
+
     /// Cleanup event data
     /// Used for instance after we remove an appservice registration
     ///
     #[tracing::instrument(skip(self))]
     pub fn cleanup_events(&self, appservice_id: String) -> Result<()> {
+        self.db.remove_appservice_txn_id(&appservice_id)?;
         self.db
             .delete_all_requests_for(&OutgoingKind::Appservice(appservice_id))?;
 
@@ -457,6 +730,7 @@ impl Service {
         match &kind {
             OutgoingKind::Appservice(id) => {
                 let mut pdu_jsons = Vec::new();
+                let mut edus = Vec::new();
 
                 for event in &events {
                     match event {
@@ -474,12 +748,21 @@ impl Service {
                                 })?
                                 .to_room_event())
                         }
-                        SendingEventType::Edu(_) => {
-                            // Appservices don't need EDUs (?)
+                        SendingEventType::Edu(edu) => {
+                            if let Ok(edu) = serde_json::from_slice(edu) {
+                                edus.push(edu);
+                            }
                         }
                     }
                 }
 
+                let (ephemeral, to_device) = split_appservice_edus(edus);
+
+                let txn_id = services()
+                    .sending
+                    .appservice_txn_id(id)
+                    .map_err(|e| (kind.clone(), e))?;
+
                 let permit = services().sending.maximum_requests.acquire().await;
 
                 let response = appservice_server::send_request(
@@ -497,18 +780,9 @@ impl Service {
                         })?,
                     appservice::event::push_events::v1::Request {
                         events: pdu_jsons,
-                        txn_id: (&*base64::encode_config(
-                            calculate_hash(
-                                &events
-                                    .iter()
-                                    .map(|e| match e {
-                                        SendingEventType::Edu(b) | SendingEventType::Pdu(b) => &**b,
-                                    })
-                                    .collect::<Vec<_>>(),
-                            ),
-                            base64::URL_SAFE_NO_PAD,
-                        ))
-                            .into(),
+                        ephemeral,
+                        to_device,
+                        txn_id: (&*txn_id.to_string()).into(),
                     },
                 )
                 .await
@@ -525,7 +799,8 @@ impl Service {
                 for event in &events {
                     match event {
                         SendingEventType::Pdu(pdu_id) => {
-                            pdus.push(
+                            pdus.push((
+                                pdu_id,
                                 services().rooms
                                     .timeline
                                     .get_pdu_from_id(pdu_id)
@@ -538,7 +813,7 @@ impl Service {
                                             ),
                                         )
                                     })?,
-                            );
+                            ));
                         }
                         SendingEventType::Edu(_) => {
                             // Push gateways don't need EDUs (?)
@@ -546,59 +821,32 @@ impl Service {
                     }
                 }
 
-                for pdu in pdus {
-                    // Redacted events are not notification targets (we don't send push for them)
-                    if let Some(unsigned) = &pdu.unsigned {
-                        if let Ok(unsigned) =
-                            serde_json::from_str::<serde_json::Value>(unsigned.get())
-                        {
-                            if unsigned.get("redacted_because").is_some() {
-                                continue;
-                            }
-                        }
-                    }
-
-                    let pusher = match services()
-                        .pusher
-                        .get_pusher(userid, pushkey)
-                        .map_err(|e| (OutgoingKind::Push(userid.clone(), pushkey.clone()), e))?
-                    {
-                        Some(pusher) => pusher,
-                        None => continue,
-                    };
-
-                    let rules_for_user = services()
-                        .account_data
-                        .get(
-                            None,
-                            userid,
-                            GlobalAccountDataEventType::PushRules.to_string().into(),
-                        )
-                        .unwrap_or_default()
-                        .and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
-                        .map(|ev: PushRulesEvent| ev.content.global)
-                        .unwrap_or_else(|| push::Ruleset::server_default(userid));
-
-                    let unread: UInt = services()
-                        .rooms
-                        .user
-                        .notification_count(userid, &pdu.room_id)
-                        .map_err(|e| (kind.clone(), e))?
-                        .try_into()
-                        .expect("notification count can't go that high");
+                let mut failure = None;
 
-                    let permit = services().sending.maximum_requests.acquire().await;
-
-                    let _response = services()
-                        .pusher
-                        .send_push_notice(userid, unread, &pusher, rules_for_user, &pdu)
+                for (pdu_id, pdu) in pdus {
+                    match Self::handle_push_pdu(userid, pushkey, pdu)
                         .await
-                        .map(|_response| kind.clone())
-                        .map_err(|e| (kind.clone(), e));
+                        .map_err(|e| (kind.clone(), e))?
+                    {
+                        // Keep sending the other events, only the failed ones are retried later
+                        Some(e) => {
+                            failure.get_or_insert(e);
+                        }
+                        // Done with this event, so a retry doesn't push it a second time
+                        None => services()
+                            .sending
+                            .db
+                            .delete_active_request(
+                                kind.get_event_key(&SendingEventType::Pdu(pdu_id.clone()), 0),
+                            )
+                            .map_err(|e| (kind.clone(), e))?,
+                    }
+                }
 
-                    drop(permit);
+                match failure {
+                    Some(e) => Err((kind.clone(), e)),
+                    None => Ok(OutgoingKind::Push(userid.clone(), pushkey.clone())),
                 }
-                Ok(OutgoingKind::Push(userid.clone(), pushkey.clone()))
             }
             OutgoingKind::Normal(server) => {
                 let mut edu_jsons = Vec::new();
@@ -677,6 +925,97 @@ impl Service {
         }
     }
 
+    /// Sends a notification about the pdu to the pusher. Returns the error if sending failed but
+    /// should be retried, `None` if the pdu is done with, e.g. because it was sent or can't be.
+    async fn handle_push_pdu(
+        userid: &UserId,
+        pushkey: &str,
+        pdu: PduEvent,
+    ) -> Result<Option<Error>> {
+        // Redacted events are not notification targets (we don't send push for them)
+        if let Some(unsigned) = &pdu.unsigned {
+            if let Ok(unsigned) = serde_json::from_str::<serde_json::Value>(unsigned.get()) {
+                if unsigned.get("redacted_because").is_some() {
+                    return Ok(None);
+                }
+            }
+        }
+
+        let pusher = match services().pusher.get_pusher(userid, pushkey)? {
+            Some(pusher) => pusher,
+            None => return Ok(None),
+        };
+
+        // Quarantined pushers failed too often, they would only hold up the others
+        if services().pusher.is_quarantined(userid, pushkey)? {
+            return Ok(None);
+        }
+
+        // Paused pushers' gateways kept failing, there's no point in trying yet
+        if services().pusher.is_paused(userid, pushkey)? {
+            return Ok(None);
+        }
+
+        let rules_for_user = services()
+            .account_data
+            .get(
+                None,
+                userid,
+                GlobalAccountDataEventType::PushRules.to_string().into(),
+            )
+            .unwrap_or_default()
+            .and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
+            .map(|ev: PushRulesEvent| ev.content.global)
+            .unwrap_or_else(|| push::Ruleset::server_default(userid));
+
+        let unread: UInt = services()
+            .rooms
+            .user
+            .notification_count(userid, &pdu.room_id)?
+            .try_into()
+            .expect("notification count can't go that high");
+
+        let permit = services().sending.maximum_requests.acquire().await;
+
+        // One span per event covers the rule evaluation and all gateway requests
+        let span = tracing::info_span!(
+            "push_pdu",
+            event_id = %pdu.event_id,
+            user_id = %userid,
+        );
+
+        // A panic, e.g. because of malformed pusher data, must not take down the
+        // sending of all other notifications
+        let user = userid.to_owned();
+        let response = tokio::spawn(
+            async move {
+                services()
+                    .pusher
+                    .send_push_notice(&user, unread, vec![pusher], rules_for_user, &pdu)
+                    .await
+                    .and_then(|outcomes| outcomes.into_iter().next().unwrap_or(Ok(())))
+            }
+            .instrument(span),
+        )
+        .await
+        .unwrap_or_else(|_| Err(Error::bad_pusher("Sending a push notification panicked.")));
+
+        drop(permit);
+
+        services()
+            .pusher
+            .record_pusher_outcome(userid, pushkey, response.as_ref().err())?;
+
+        match response {
+            Err(e) if services().pusher.is_retryable(&e) => Ok(Some(e)),
+            Err(e) => {
+                warn!("Dropping push notification for {}: {}", userid, e);
+                Ok(None)
+            }
+            Ok(_) => Ok(None),
+        }
+    }
+
     #[tracing::instrument(skip(self, destination, request))]
     pub async fn send_federation_request<T: OutgoingRequest>(
         &self,
@@ -719,3 +1058,212 @@ impl Service {
         response
     }
 }
+
+/// Returns the exponential backoff after `tries` failed attempts.
+fn backoff(tries: u32) -> Duration {
+    let backoff = Duration::from_secs(30) * tries * tries;
+    backoff.min(Duration::from_secs(60 * 60 * 24))
+}
+
+/// Returns how long to wait before retrying after `tries` failed attempts. Pushes are jittered,
+/// so pushers that failed together, e.g. because their gateway was down, don't all retry at once.
+fn retry_delay(outgoing_kind: &OutgoingKind, tries: u32) -> Duration {
+    match outgoing_kind {
+        OutgoingKind::Push(_, _) => jittered(
+            backoff(tries),
+            services().globals.push_retry_jitter(),
+            rand::random(),
+        ),
+        _ => backoff(tries),
+    }
+}
+
+/// Applies the jitter to the backoff, `random` is between 0 and 1.
+fn jittered(backoff: Duration, jitter: RetryJitter, random: f64) -> Duration {
+    match jitter {
+        RetryJitter::None => backoff,
+        RetryJitter::Full => backoff.mul_f64(random),
+        RetryJitter::Equal => backoff / 2 + (backoff / 2).mul_f64(random),
+    }
+}
+
+/// Returns a to-device message in the format appservices receive it in. Unlike for clients, it
+/// contains the recipient, because the appservice receives the messages of all its users.
+fn appservice_to_device_event(
+    sender: &UserId,
+    target_user_id: &UserId,
+    target_device_id: &DeviceId,
+    event_type: &str,
+    content: &JsonValue,
+) -> JsonValue {
+    json!({
+        "type": event_type,
+        "sender": sender,
+        "to_user_id": target_user_id,
+        "to_device_id": target_device_id,
+        "content": content,
+    })
+}
+
+/// Splits the queued EDUs of an appservice transaction into ephemeral events and to-device
+/// messages, which are queued the same way, but only the latter have a recipient.
+fn split_appservice_edus<E, T>(edus: Vec<Box<RawJsonValue>>) -> (Vec<Raw<E>>, Vec<Raw<T>>) {
+    let mut ephemeral = Vec::new();
+    let mut to_device = Vec::new();
+
+    for edu in edus {
+        let is_to_device = serde_json::from_str::<JsonValue>(edu.get())
+            .map_or(false, |edu| edu.get("to_device_id").is_some());
+
+        if is_to_device {
+            to_device.push(Raw::from_json(edu));
+        } else {
+            ephemeral.push(Raw::from_json(edu));
+        }
+    }
+
+    (ephemeral, to_device)
+}
+
+/// Splits the push targets of an event into waves of at most `max_fanout` targets each.
+fn fanout_waves<T>(targets: Vec<T>, max_fanout: usize) -> Vec<Vec<T>> {
+    let max_fanout = max_fanout.max(1);
+    let mut waves = Vec::new();
+    let mut targets = targets.into_iter().peekable();
+
+    while targets.peek().is_some() {
+        waves.push(targets.by_ref().take(max_fanout).collect());
+    }
+
+    waves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        appservice_to_device_event, backoff, fanout_waves, jittered, split_appservice_edus,
+        OutgoingKind, SendingEventType,
+    };
+    use crate::config::RetryJitter;
+    use ruma::{device_id, serde::Raw, user_id};
+    use serde_json::{json, Value as JsonValue};
+    use std::{
+        collections::{BTreeSet, HashSet},
+        time::Duration,
+    };
+
+    fn pdu_id(shortroomid: u64, count: u64) -> Vec<u8> {
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+        pdu_id
+    }
+
+    #[test]
+    fn appservice_transactions_contain_to_device_messages() {
+        let to_device = appservice_to_device_event(
+            user_id!("@alice:example.org"),
+            user_id!("@telegram_123:example.org"),
+            device_id!("BRIDGE"),
+            "m.room_key_request",
+            &json!({ "action": "request_cancellation" }),
+        );
+        let typing = json!({
+            "type": "m.typing",
+            "room_id": "!room:example.org",
+            "content": { "user_ids": [] },
+        });
+
+        let edus = [typing, to_device]
+            .iter()
+            .map(|edu| serde_json::value::to_raw_value(edu).unwrap())
+            .collect();
+        let (ephemeral, to_device): (Vec<Raw<JsonValue>>, Vec<Raw<JsonValue>>) =
+            split_appservice_edus(edus);
+
+        assert_eq!(ephemeral.len(), 1);
+        assert_eq!(ephemeral[0].deserialize().unwrap()["type"], "m.typing");
+
+        assert_eq!(to_device.len(), 1);
+        let message = to_device[0].deserialize().unwrap();
+        assert_eq!(message["type"], "m.room_key_request");
+        assert_eq!(message["sender"], "@alice:example.org");
+        assert_eq!(message["to_user_id"], "@telegram_123:example.org");
+        assert_eq!(message["to_device_id"], "BRIDGE");
+        assert_eq!(message["content"]["action"], "request_cancellation");
+    }
+
+    #[test]
+    fn appservice_events_keep_their_order() {
+        let appservice = OutgoingKind::Appservice("bridge".to_owned());
+
+        // A burst of events alternating between two rooms, with an EDU in between
+        let events = (0..100)
+            .map(|count| {
+                if count == 50 {
+                    SendingEventType::Edu(b"{}".to_vec())
+                } else {
+                    SendingEventType::Pdu(pdu_id(2 - count % 2, count))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // The database returns the queued events sorted by key
+        let keys = events
+            .iter()
+            .zip(1000..)
+            .map(|(event, count)| appservice.get_event_key(event, count))
+            .collect::<BTreeSet<_>>();
+        let prefix = appservice.get_prefix();
+
+        for (key, event) in keys.iter().zip(&events) {
+            let event_part = &key[prefix.len()..];
+            match event {
+                SendingEventType::Pdu(pdu_id) => assert_eq!(&event_part[8..], &pdu_id[..]),
+                SendingEventType::Edu(_) => assert_eq!(event_part.len(), 8),
+            }
+        }
+
+        // Federation keys are still the pdu id
+        let server = OutgoingKind::Normal("example.org".try_into().unwrap());
+        let event = SendingEventType::Pdu(pdu_id(1, 5));
+        assert!(!server.needs_count(&event));
+        assert!(server.get_event_key(&event, 0).ends_with(&pdu_id(1, 5)));
+    }
+
+    #[test]
+    fn large_room_fanout_in_waves() {
+        let pushers = (0..2500).collect::<Vec<_>>();
+
+        let waves = fanout_waves(pushers, 1000);
+        assert_eq!(
+            waves.iter().map(Vec::len).collect::<Vec<_>>(),
+            [1000, 1000, 500]
+        );
+        assert_eq!(waves[1][0], 1000);
+        assert_eq!(waves.concat(), (0..2500).collect::<Vec<_>>());
+
+        let small_room = fanout_waves((0..10).collect(), 1000);
+        assert_eq!(small_room.len(), 1);
+        assert!(fanout_waves(Vec::<u32>::new(), 1000).is_empty());
+    }
+
+    #[test]
+    fn retry_delays_are_jittered() {
+        let delay = backoff(3);
+        assert_eq!(delay, Duration::from_secs(30 * 9));
+        assert_eq!(backoff(1000), Duration::from_secs(60 * 60 * 24));
+
+        let delays = (0..100)
+            .map(|_| jittered(delay, RetryJitter::Full, rand::random()))
+            .collect::<Vec<_>>();
+        assert!(delays.iter().all(|d| *d <= delay));
+        assert!(delays.iter().collect::<HashSet<_>>().len() > 1);
+
+        for _ in 0..100 {
+            let d = jittered(delay, RetryJitter::Equal, rand::random());
+            assert!(d >= delay / 2 && d <= delay);
+        }
+
+        assert_eq!(jittered(delay, RetryJitter::None, 0.3), delay);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use ruma::{
+    api::client::sync::sync_events::v4::{
+        self, ExtensionsConfig, RoomSubscription, SyncRequestList,
+    },
+    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, UInt, UserId,
+};
+
+use crate::service::rooms::timeline::PduCount;
+
+/// The rooms of a list the client knows about, with the position they were last sent at. Rooms
+/// at position 0 have to be sent in full again.
+pub type KnownRooms = BTreeMap<OwnedRoomId, u64>;
+
+/// What the server remembers about a sliding sync connection between requests.
+#[derive(Debug, Default)]
+pub struct SlidingSyncCache {
+    lists: BTreeMap<String, SyncRequestList>,
+    subscriptions: BTreeMap<OwnedRoomId, RoomSubscription>,
+    known_rooms: BTreeMap<String, KnownRooms>,
+    extensions: ExtensionsConfig,
+}
+
+pub struct Service {
+    pub connections:
+        Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>,
+}
+
+impl Service {
+    /// Forgets everything about the connection, e.g. because the client starts over.
+    pub fn forget_connection(&self, user_id: &UserId, device_id: &DeviceId, conn_id: String) {
+        self.connections.lock().unwrap().remove(&(
+            user_id.to_owned(),
+            device_id.to_owned(),
+            conn_id,
+        ));
+    }
+
+    /// Fills in the sticky parameters the request left out with the ones of earlier requests on
+    /// the same connection, and remembers the new ones. Returns the rooms the client already knows
+    /// for every list.
+    pub fn update_request_with_cache(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request: &mut v4::Request,
+    ) -> BTreeMap<String, KnownRooms> {
+        let conn_id = match request.conn_id.clone() {
+            Some(conn_id) => conn_id,
+            None => return BTreeMap::new(),
+        };
+
+        let cache = self.connection(user_id, device_id, conn_id);
+        let mut cache = cache.lock().unwrap();
+
+        for (list_id, list) in &mut request.lists {
+            if let Some(cached) = cache.lists.get(list_id) {
+                merge_list(list, cached);
+            }
+            cache.lists.insert(list_id.clone(), list.clone());
+        }
+
+        cache
+            .subscriptions
+            .extend(request.room_subscriptions.clone());
+        for room_id in &request.unsubscribe_rooms {
+            cache.subscriptions.remove(room_id);
+        }
+        request.room_subscriptions = cache.subscriptions.clone();
+
+        let extensions = &mut request.extensions;
+        extensions.to_device.enabled = extensions
+            .to_device
+            .enabled
+            .or(cache.extensions.to_device.enabled);
+        extensions.receipts.enabled = extensions
+            .receipts
+            .enabled
+            .or(cache.extensions.receipts.enabled);
+        cache.extensions = extensions.clone();
+
+        cache.known_rooms.clone()
+    }
+
+    /// Remembers which rooms of the list were sent at position `pos`.
+    pub fn update_known_rooms(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        conn_id: String,
+        list_id: String,
+        new_rooms: BTreeSet<OwnedRoomId>,
+        pos: u64,
+    ) {
+        let cache = self.connection(user_id, device_id, conn_id);
+        let mut cache = cache.lock().unwrap();
+
+        update_known(
+            cache.known_rooms.entry(list_id).or_default(),
+            new_rooms,
+            pos,
+        );
+    }
+
+    fn connection(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        conn_id: String,
+    ) -> Arc<Mutex<SlidingSyncCache>> {
+        Arc::clone(
+            self.connections
+                .lock()
+                .unwrap()
+                .entry((user_id.to_owned(), device_id.to_owned(), conn_id))
+                .or_default(),
+        )
+    }
+}
+
+/// Takes the sticky parameters the list doesn't set from the cached list.
+fn merge_list(list: &mut SyncRequestList, cached: &SyncRequestList) {
+    if list.sort.is_empty() {
+        list.sort = cached.sort.clone();
+    }
+    if list.room_details.required_state.is_empty() {
+        list.room_details.required_state = cached.room_details.required_state.clone();
+    }
+    list.room_details.timeline_limit = list
+        .room_details
+        .timeline_limit
+        .or(cached.room_details.timeline_limit);
+    if list.filters.is_none() {
+        list.filters = cached.filters.clone();
+    }
+}
+
+/// Marks the rooms as sent at `pos`. Rooms that left the window are set to 0, so they are sent in
+/// full again once they come back.
+fn update_known(known: &mut KnownRooms, new_rooms: BTreeSet<OwnedRoomId>, pos: u64) {
+    for (room_id, since) in known.iter_mut() {
+        if !new_rooms.contains(room_id) {
+            *since = 0;
+        }
+    }
+
+    for room_id in new_rooms {
+        known.insert(room_id, pos);
+    }
+}
+
+/// Orders the rooms by their latest event, most recent first.
+pub fn sort_by_recency(rooms: &mut [(OwnedRoomId, PduCount)]) {
+    rooms.sort_by(|(_, a), (_, b)| b.cmp(a));
+}
+
+/// Returns the rooms inside the range, which includes both ends, and the range clamped to the
+/// rooms that exist.
+pub fn window(rooms: &[OwnedRoomId], range: (UInt, UInt)) -> ((UInt, UInt), &[OwnedRoomId]) {
+    let start = usize::try_from(u64::from(range.0)).unwrap_or(usize::MAX);
+    let end = usize::try_from(u64::from(range.1))
+        .unwrap_or(usize::MAX)
+        .min(rooms.len().saturating_sub(1));
+
+    if start >= rooms.len() || end < start {
+        return (range, &[]);
+    }
+
+    (
+        (range.0, UInt::new_saturating(end as u64)),
+        &rooms[start..=end],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::{room_id, uint};
+
+    fn sorted(rooms: &[(&str, u64)]) -> Vec<OwnedRoomId> {
+        let mut rooms: Vec<_> = rooms
+            .iter()
+            .map(|&(room_id, count)| {
+                (
+                    OwnedRoomId::try_from(room_id).unwrap(),
+                    PduCount::Normal(count),
+                )
+            })
+            .collect();
+        sort_by_recency(&mut rooms);
+        rooms.into_iter().map(|(room_id, _)| room_id).collect()
+    }
+
+    fn ids(room_ids: &[&str]) -> Vec<OwnedRoomId> {
+        room_ids
+            .iter()
+            .map(|&room_id| OwnedRoomId::try_from(room_id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn window_follows_reordered_rooms() {
+        let mut known = KnownRooms::new();
+
+        let rooms = sorted(&[("!a:x", 4), ("!b:x", 3), ("!c:x", 2), ("!d:x", 1)]);
+        let (range, first) = window(&rooms, (uint!(0), uint!(1)));
+        assert_eq!(range, (uint!(0), uint!(1)));
+        assert_eq!(first, ids(&["!a:x", "!b:x"]));
+        update_known(&mut known, first.iter().cloned().collect(), 10);
+
+        // A new message moves !c to the top, pushing !b out of the window
+        let rooms = sorted(&[("!a:x", 4), ("!b:x", 3), ("!c:x", 11), ("!d:x", 1)]);
+        let (_, second) = window(&rooms, (uint!(0), uint!(1)));
+        assert_eq!(second, ids(&["!c:x", "!a:x"]));
+
+        // !c is new to the client, !a only needs what happened since the last request
+        assert_eq!(known.get(room_id!("!c:x")), None);
+        assert_eq!(known.get(room_id!("!a:x")), Some(&10));
+        update_known(&mut known, second.iter().cloned().collect(), 20);
+
+        assert_eq!(known.get(room_id!("!a:x")), Some(&20));
+        assert_eq!(known.get(room_id!("!c:x")), Some(&20));
+        // !b has to be sent in full once it comes back
+        assert_eq!(known.get(room_id!("!b:x")), Some(&0));
+    }
+
+    #[test]
+    fn ranges_are_clamped() {
+        let rooms = sorted(&[("!a:x", 4), ("!b:x", 3), ("!c:x", 2), ("!d:x", 1)]);
+
+        let (range, rooms_in_range) = window(&rooms, (uint!(2), uint!(10)));
+        assert_eq!(range, (uint!(2), uint!(3)));
+        assert_eq!(rooms_in_range, ids(&["!c:x", "!d:x"]));
+
+        assert!(window(&rooms, (uint!(5), uint!(6))).1.is_empty());
+        assert!(window(&rooms, (uint!(3), uint!(1))).1.is_empty());
+        assert!(window(&[], (uint!(0), uint!(10))).1.is_empty());
+    }
+}
@@ -0,0 +1,10 @@
+use crate::Result;
+use ruma::{OwnedUserId, UserId};
+
+pub trait Data: Send + Sync {
+    /// Returns the user that logs in as this subject of the identity provider.
+    fn sso_user(&self, idp_id: &str, subject: &str) -> Result<Option<OwnedUserId>>;
+
+    /// Makes the subject of the identity provider log in as this user from now on.
+    fn set_sso_user(&self, idp_id: &str, subject: &str, user_id: &UserId) -> Result<()>;
+}
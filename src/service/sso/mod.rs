@@ -0,0 +1,633 @@
+mod data;
+
+use std::{collections::HashMap, sync::Mutex};
+
+pub use data::Data;
+
+use reqwest::Url;
+use ring::hmac;
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
+    push, OwnedUserId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use tracing::{info, warn};
+
+use crate::{config::SsoProviderConfig, services, utils, Error, Result};
+
+/// How long users have to log in at the identity provider, in milliseconds
+const SSO_STATE_LIFETIME: u64 = 10 * 60 * 1000;
+
+/// How long clients have to exchange a login token for an access token, in milliseconds
+const LOGIN_TOKEN_LIFETIME: u64 = 2 * 60 * 1000;
+
+const LOGIN_TOKEN_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 32;
+const SESSION_LENGTH: usize = 32;
+const SSO_PASSWORD_LENGTH: usize = 64;
+
+/// The cookie that ties the callback to the browser that started the login
+const SESSION_COOKIE: &str = "conduit_sso_session";
+
+pub struct Service {
+    pub db: &'static dyn Data,
+    /// Signs the state passed through the identity provider, so the callback can trust it
+    state_key: hmac::Key,
+    /// Login tokens handed out after SSO, with the user they log in and when they expire
+    login_tokens: Mutex<HashMap<String, (OwnedUserId, u64)>>,
+}
+
+/// What the callback needs to know about a login, passed through the identity provider.
+#[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
+struct SsoState {
+    idp_id: String,
+    redirect_url: String,
+    nonce: String,
+    /// Has to match the session cookie of the browser that comes back from the identity provider
+    session: String,
+    /// Milliseconds since the unix epoch
+    expires_at: u64,
+}
+
+/// Where to send the user after a successful login.
+pub struct LoginRedirect {
+    /// The redirect url of the client, with the login token
+    pub url: String,
+    /// Whether the client is in `sso_client_allowlist`. Users have to confirm that they want to
+    /// log in to other clients.
+    pub trusted: bool,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+impl Service {
+    pub fn build(db: &'static dyn Data) -> Self {
+        Self {
+            db,
+            state_key: hmac::Key::new(hmac::HMAC_SHA256, utils::random_string(32).as_bytes()),
+            login_tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns where to send the user to log in at the identity provider, and the session cookie
+    /// to set in their browser. The first configured provider is used if the client didn't pick
+    /// one.
+    pub fn authorization_url(
+        &self,
+        idp_id: Option<&str>,
+        redirect_url: &str,
+    ) -> Result<(String, String)> {
+        let provider = match idp_id {
+            Some(idp_id) => find_provider(idp_id),
+            None => services().globals.sso_providers().first(),
+        }
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown identity provider.",
+        ))?;
+
+        // Mobile clients use custom schemes, but scripts must not end up in a link
+        Url::parse(redirect_url)
+            .ok()
+            .filter(|url| !matches!(url.scheme(), "javascript" | "data" | "vbscript"))
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid redirect url.",
+            ))?;
+
+        let nonce = utils::random_string(NONCE_LENGTH);
+        let session = utils::random_string(SESSION_LENGTH);
+        let state = sign_state(
+            &self.state_key,
+            &SsoState {
+                idp_id: provider.id.clone(),
+                redirect_url: redirect_url.to_owned(),
+                nonce: nonce.clone(),
+                session: session.clone(),
+                expires_at: utils::millis_since_unix_epoch() + SSO_STATE_LIFETIME,
+            },
+        );
+        let callback_url = services().globals.sso_callback_url();
+        let scope = provider.scopes.join(" ");
+
+        let url = Url::parse_with_params(
+            &provider.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", callback_url.as_str()),
+                ("scope", scope.as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+            ],
+        )
+        .map_err(|_| Error::bad_config("Invalid authorization endpoint of identity provider."))?;
+
+        let cookie = format!(
+            "{SESSION_COOKIE}={session}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+            SSO_STATE_LIFETIME / 1000
+        );
+
+        Ok((url.into(), cookie))
+    }
+
+    /// Finishes a login at the identity provider. `cookies` is the `Cookie` header of the
+    /// request. Returns where to send the user next, with a login token for the client.
+    pub async fn complete_login(
+        &self,
+        code: &str,
+        state: &str,
+        cookies: Option<&str>,
+    ) -> Result<LoginRedirect> {
+        let now = utils::millis_since_unix_epoch();
+        let state = verify_state(&self.state_key, state, now)?;
+
+        // Otherwise someone could start a login and trick another user into finishing it
+        if cookies.and_then(|cookies| cookie_value(cookies, SESSION_COOKIE))
+            != Some(state.session.as_str())
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "SSO login was started in another browser.",
+            ));
+        }
+        let provider = find_provider(&state.idp_id).ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown identity provider.",
+        ))?;
+
+        let claims = self.exchange_code(provider, code).await?;
+        validate_id_token_claims(
+            &claims,
+            &provider.issuer,
+            &provider.client_id,
+            &state.nonce,
+            now / 1000,
+        )?;
+
+        let subject = claims
+            .get("sub")
+            .and_then(JsonValue::as_str)
+            .ok_or(Error::BadServerResponse("ID token has no subject."))?;
+
+        let user_id = match self.db.sso_user(&provider.id, subject)? {
+            Some(user_id) => user_id,
+            None => self.provision_user(provider, subject, &claims)?,
+        };
+
+        if services().users.is_deactivated(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::UserDeactivated,
+                "The user has been deactivated",
+            ));
+        }
+
+        let token = utils::random_string(LOGIN_TOKEN_LENGTH);
+        {
+            let mut login_tokens = self.login_tokens.lock().unwrap();
+            login_tokens.retain(|_, (_, expires_at)| *expires_at > now);
+            login_tokens.insert(token.clone(), (user_id, now + LOGIN_TOKEN_LIFETIME));
+        }
+
+        let mut redirect_url =
+            Url::parse(&state.redirect_url).expect("redirect url was checked before signing");
+        redirect_url
+            .query_pairs_mut()
+            .append_pair("loginToken", &token);
+
+        Ok(LoginRedirect {
+            trusted: client_allowed(
+                &state.redirect_url,
+                services().globals.sso_client_allowlist(),
+            ),
+            url: redirect_url.into(),
+        })
+    }
+
+    /// Returns the user a login token was issued for. Every token can only be used once.
+    pub fn consume_login_token(&self, token: &str) -> Option<OwnedUserId> {
+        let (user_id, expires_at) = self.login_tokens.lock().unwrap().remove(token)?;
+
+        (expires_at > utils::millis_since_unix_epoch()).then_some(user_id)
+    }
+
+    /// Exchanges the authorization code for an ID token and returns its claims.
+    ///
+    /// The signature of the ID token isn't checked, as it comes straight from the token endpoint
+    /// over TLS (OpenID Connect Core 1.0, section 3.1.3.7).
+    async fn exchange_code(
+        &self,
+        provider: &SsoProviderConfig,
+        code: &str,
+    ) -> Result<JsonMap<String, JsonValue>> {
+        let callback_url = services().globals.sso_callback_url();
+
+        let response = services()
+            .globals
+            .default_client()
+            .post(&provider.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", callback_url.as_str()),
+                ("client_id", provider.client_id.as_str()),
+                ("client_secret", provider.client_secret.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("Could not reach identity provider {}: {}", provider.id, e);
+                Error::BadServerResponse("Could not reach identity provider.")
+            })?;
+
+        if !response.status().is_success() {
+            warn!(
+                "Identity provider {} returned bad response {}",
+                provider.id,
+                response.status()
+            );
+            return Err(Error::BadServerResponse(
+                "Identity provider rejected the login.",
+            ));
+        }
+
+        let response: TokenResponse =
+            serde_json::from_slice(&response.bytes().await.unwrap_or_default()).map_err(|_| {
+                Error::BadServerResponse("Identity provider returned invalid response.")
+            })?;
+
+        id_token_claims(&response.id_token).ok_or(Error::BadServerResponse(
+            "Identity provider sent invalid ID token.",
+        ))
+    }
+
+    /// Creates the account a subject of the identity provider logs in as.
+    fn provision_user(
+        &self,
+        provider: &SsoProviderConfig,
+        subject: &str,
+        claims: &JsonMap<String, JsonValue>,
+    ) -> Result<OwnedUserId> {
+        let localpart =
+            render_localpart(&provider.localpart_template, claims).ok_or(Error::BadRequest(
+                ErrorKind::InvalidUsername,
+                "The identity provider didn't send the claims needed for a user id.",
+            ))?;
+
+        let user_id = UserId::parse_with_server_name(localpart, services().globals.server_name())
+            .ok()
+            .filter(|user_id| !user_id.is_historical())
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidUsername,
+                "Username is invalid.",
+            ))?;
+
+        // Accounts that didn't log in through this provider before can't be taken over
+        if services().users.exists(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::UserInUse,
+                "Desired user ID is already taken.",
+            ));
+        }
+
+        // Nobody knows the password, but an empty one would mark the account as deactivated
+        services()
+            .users
+            .create(&user_id, Some(&utils::random_string(SSO_PASSWORD_LENGTH)))?;
+
+        let mut displayname = claims
+            .get("name")
+            .and_then(JsonValue::as_str)
+            .unwrap_or_else(|| user_id.localpart())
+            .to_owned();
+
+        // If enabled append lightning bolt to display name (default true)
+        if services().globals.enable_lightning_bolt() {
+            displayname.push_str(" ⚡️");
+        }
+
+        services()
+            .users
+            .set_displayname(&user_id, Some(displayname))?;
+
+        // Initial account data
+        services().account_data.update(
+            None,
+            &user_id,
+            GlobalAccountDataEventType::PushRules.to_string().into(),
+            &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+                content: ruma::events::push_rules::PushRulesEventContent {
+                    global: push::Ruleset::server_default(&user_id),
+                },
+            })
+            .expect("to json always works"),
+        )?;
+
+        self.db.set_sso_user(&provider.id, subject, &user_id)?;
+
+        info!("New user {} registered through {}.", user_id, provider.id);
+        services()
+            .admin
+            .send_message(RoomMessageEventContent::notice_plain(format!(
+                "New user {user_id} registered on this server through {}.",
+                provider.name
+            )));
+
+        Ok(user_id)
+    }
+}
+
+fn find_provider(idp_id: &str) -> Option<&'static SsoProviderConfig> {
+    services()
+        .globals
+        .sso_providers()
+        .iter()
+        .find(|provider| provider.id == idp_id)
+}
+
+fn sign_state(key: &hmac::Key, state: &SsoState) -> String {
+    let payload = base64::encode_config(
+        serde_json::to_vec(state).expect("SsoState can be serialized"),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let tag = hmac::sign(key, payload.as_bytes());
+
+    format!(
+        "{payload}.{}",
+        base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Returns the state if it was signed with the key and didn't expire yet.
+fn verify_state(key: &hmac::Key, state: &str, now: u64) -> Result<SsoState> {
+    let invalid = || Error::BadRequest(ErrorKind::Forbidden, "Invalid SSO state.");
+
+    let (payload, tag) = state.split_once('.').ok_or_else(invalid)?;
+    let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+    hmac::verify(key, payload.as_bytes(), &tag).map_err(|_| invalid())?;
+
+    let state: SsoState = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(invalid)?;
+
+    if state.expires_at <= now {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "SSO login took too long, please try again.",
+        ));
+    }
+
+    Ok(state)
+}
+
+/// Returns the value of the cookie with that name in a `Cookie` header.
+fn cookie_value<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns whether the redirect url starts with one of the allowed client urls.
+fn client_allowed(redirect_url: &str, allowlist: &[String]) -> bool {
+    allowlist
+        .iter()
+        .any(|client_url| redirect_url.starts_with(client_url.as_str()))
+}
+
+/// Returns the claims of an ID token, without checking its signature.
+fn id_token_claims(id_token: &str) -> Option<JsonMap<String, JsonValue>> {
+    let payload = id_token.split('.').nth(1)?;
+    let json = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+
+    serde_json::from_slice(&json).ok()
+}
+
+/// Checks that the ID token was issued by the provider for us, for this login, and didn't expire.
+/// `now` is in seconds since the unix epoch.
+fn validate_id_token_claims(
+    claims: &JsonMap<String, JsonValue>,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now: u64,
+) -> Result<()> {
+    if claims.get("iss").and_then(JsonValue::as_str) != Some(issuer) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "ID token was issued by another identity provider.",
+        ));
+    }
+
+    let audience_matches = match claims.get("aud") {
+        Some(JsonValue::String(aud)) => aud == client_id,
+        Some(JsonValue::Array(aud)) => aud.iter().any(|aud| aud.as_str() == Some(client_id)),
+        _ => false,
+    };
+    if !audience_matches {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "ID token was issued for another client.",
+        ));
+    }
+
+    if claims
+        .get("exp")
+        .and_then(JsonValue::as_u64)
+        .map_or(true, |exp| exp <= now)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "ID token has expired.",
+        ));
+    }
+
+    // Ties the ID token to the login that was started here, so it can't be replayed
+    if claims.get("nonce").and_then(JsonValue::as_str) != Some(nonce) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "ID token was issued for another login.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Fills the claims into the template, e.g. "{preferred_username}", and replaces characters
+/// that aren't allowed in user ids.
+fn render_localpart(template: &str, claims: &JsonMap<String, JsonValue>) -> Option<String> {
+    let mut localpart = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        localpart.push_str(&rest[..start]);
+
+        match claims.get(&rest[start + 1..end])? {
+            JsonValue::String(claim) => localpart.push_str(claim),
+            JsonValue::Number(claim) => localpart.push_str(&claim.to_string()),
+            _ => return None,
+        }
+
+        rest = &rest[end + 1..];
+    }
+    localpart.push_str(rest);
+
+    let localpart: String = localpart
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '=' | '-' | '/') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    (!localpart.is_empty()).then_some(localpart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn as_map(value: JsonValue) -> JsonMap<String, JsonValue> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn state(expires_at: u64) -> SsoState {
+        SsoState {
+            idp_id: "keycloak".to_owned(),
+            redirect_url: "https://client.example.com/".to_owned(),
+            nonce: "nonce".to_owned(),
+            session: "session".to_owned(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn state_signature() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let signed = sign_state(&key, &state(1000));
+
+        assert_eq!(verify_state(&key, &signed, 999).unwrap(), state(1000));
+
+        // Expired
+        assert!(verify_state(&key, &signed, 1000).is_err());
+
+        // Signed by someone else
+        let other_key = hmac::Key::new(hmac::HMAC_SHA256, b"other secret");
+        assert!(verify_state(&other_key, &signed, 0).is_err());
+
+        // Payload swapped out, but signature kept
+        let (_, tag) = signed.split_once('.').unwrap();
+        let forged = sign_state(&other_key, &state(u64::MAX));
+        let (payload, _) = forged.split_once('.').unwrap();
+        assert!(verify_state(&key, &format!("{payload}.{tag}"), 0).is_err());
+
+        assert!(verify_state(&key, "garbage", 0).is_err());
+    }
+
+    #[test]
+    fn session_cookie() {
+        let cookies = "theme=dark; conduit_sso_session=abc; other=1";
+        assert_eq!(cookie_value(cookies, SESSION_COOKIE), Some("abc"));
+        assert_eq!(
+            cookie_value("conduit_sso_session=abc", SESSION_COOKIE),
+            Some("abc")
+        );
+
+        assert_eq!(cookie_value("theme=dark", SESSION_COOKIE), None);
+        assert_eq!(
+            cookie_value("x_conduit_sso_session=abc", SESSION_COOKIE),
+            None
+        );
+    }
+
+    #[test]
+    fn client_allowlist() {
+        let allowlist = vec!["https://app.element.io/".to_owned()];
+
+        assert!(client_allowed("https://app.element.io/#/home", &allowlist));
+        assert!(!client_allowed(
+            "https://app.element.io.evil.com/",
+            &allowlist
+        ));
+        assert!(!client_allowed("https://attacker.example.com/", &allowlist));
+        assert!(!client_allowed("https://app.element.io/", &[]));
+    }
+
+    #[test]
+    fn id_token_validation() {
+        let valid = json!({
+            "iss": "https://idp.example.com",
+            "aud": ["other", "conduit"],
+            "exp": 100,
+            "nonce": "nonce",
+            "sub": "alice",
+        });
+        let check = |claims: &JsonMap<String, JsonValue>, now| {
+            validate_id_token_claims(claims, "https://idp.example.com", "conduit", "nonce", now)
+        };
+
+        assert!(check(&as_map(valid.clone()), 99).is_ok());
+        assert!(check(&as_map(valid.clone()), 100).is_err());
+
+        for (claim, value) in [
+            ("iss", json!("https://evil.example.com")),
+            ("aud", json!("other")),
+            ("nonce", json!("replayed")),
+        ] {
+            let mut claims = as_map(valid.clone());
+            claims.insert(claim.to_owned(), value);
+            assert!(check(&claims, 0).is_err(), "{claim}");
+        }
+
+        let mut claims = as_map(valid);
+        claims.remove("nonce");
+        assert!(check(&claims, 0).is_err());
+    }
+
+    #[test]
+    fn id_token_payload() {
+        let payload = base64::encode_config(br#"{"sub":"alice"}"#, base64::URL_SAFE_NO_PAD);
+        let claims = id_token_claims(&format!("header.{payload}.signature")).unwrap();
+        assert_eq!(claims["sub"], "alice");
+
+        assert!(id_token_claims("no dots").is_none());
+    }
+
+    #[test]
+    fn localpart_template() {
+        let claims = as_map(json!({
+            "sub": "f2c1-77",
+            "preferred_username": "Alice Smith",
+            "employee": 42,
+            "groups": ["admins"],
+        }));
+
+        assert_eq!(render_localpart("{sub}", &claims).unwrap(), "f2c1-77");
+        assert_eq!(
+            render_localpart("{preferred_username}", &claims).unwrap(),
+            "alice_smith"
+        );
+        assert_eq!(
+            render_localpart("staff.{employee}", &claims).unwrap(),
+            "staff.42"
+        );
+
+        assert!(render_localpart("{email}", &claims).is_none());
+        assert!(render_localpart("{groups}", &claims).is_none());
+        assert!(render_localpart("{sub", &claims).is_none());
+        assert!(render_localpart("", &claims).is_none());
+    }
+}
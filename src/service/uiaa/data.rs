@@ -1,3 +1,4 @@
+use super::RegistrationToken;
 use crate::Result;
 use ruma::{api::client::uiaa::UiaaInfo, CanonicalJsonValue, DeviceId, UserId};
 
@@ -31,4 +32,17 @@ pub trait Data: Send + Sync {
         device_id: &DeviceId,
         session: &str,
     ) -> Result<UiaaInfo>;
+
+    /// Creates or replaces a registration token.
+    fn set_registration_token(&self, token: &str, info: &RegistrationToken) -> Result<()>;
+
+    fn registration_token(&self, token: &str) -> Result<Option<RegistrationToken>>;
+
+    /// Removes a registration token and returns whether it existed.
+    fn remove_registration_token(&self, token: &str) -> Result<bool>;
+
+    /// Returns all registration tokens, used up and expired ones included.
+    fn registration_tokens<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, RegistrationToken)>> + 'a>;
 }
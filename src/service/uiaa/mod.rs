@@ -1,20 +1,79 @@
 mod data;
 
+use std::sync::Mutex;
+
 pub use data::Data;
 
 use ruma::{
     api::client::{
-        error::ErrorKind,
+        error::{ErrorKind, StandardErrorBody},
         uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
     },
     CanonicalJsonValue, DeviceId, UserId,
 };
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 
 use crate::{api::client_server::SESSION_ID_LENGTH, services, utils, Error, Result};
 
+/// Length of generated registration tokens.
+const REGISTRATION_TOKEN_LENGTH: usize = 16;
+
+/// A token that allows registering an account while registration requires one.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RegistrationToken {
+    /// How many accounts may register with the token, unlimited if `None`
+    pub uses_allowed: Option<u64>,
+    /// How many accounts registered with the token so far
+    pub completed: u64,
+    /// When the token expires, in milliseconds since the unix epoch
+    pub expiry_time: Option<u64>,
+}
+
+impl RegistrationToken {
+    /// Returns whether the token can still be used at `now`.
+    pub fn is_valid(&self, now: u64) -> bool {
+        self.check(now).is_ok()
+    }
+
+    fn check(&self, now: u64) -> Result<()> {
+        if self
+            .expiry_time
+            .map_or(false, |expiry_time| now >= expiry_time)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Unauthorized,
+                "Registration token has expired.",
+            ));
+        }
+
+        if self
+            .uses_allowed
+            .map_or(false, |uses_allowed| self.completed >= uses_allowed)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Unauthorized,
+                "Registration token has no uses left.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Takes up one use of the token, if it can still be used at `now`.
+    fn consume(&mut self, now: u64) -> Result<()> {
+        self.check(now)?;
+        self.completed += 1;
+
+        Ok(())
+    }
+}
+
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Held while a use of a registration token is taken up, so that registrations racing for
+    /// the last use of a token can't both get it
+    pub registration_token_lock: Mutex<()>,
 }
 
 impl Service {
@@ -40,7 +99,7 @@ impl Service {
         )
     }
 
-    pub fn try_auth(
+    pub async fn try_auth(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
@@ -99,6 +158,18 @@ impl Service {
             AuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
+            AuthData::RegistrationToken(auth) => {
+                if let Err(e) = self.use_registration_token(&auth.token) {
+                    return failed_stage(uiaainfo, e);
+                }
+                uiaainfo.completed.push(AuthType::RegistrationToken);
+            }
+            AuthData::ReCaptcha(auth) => {
+                if let Err(e) = self.verify_captcha(&auth.response).await {
+                    return failed_stage(uiaainfo, e);
+                }
+                uiaainfo.completed.push(AuthType::ReCaptcha);
+            }
             k => error!("type not supported: {:?}", k),
         }
 
@@ -142,4 +213,257 @@ impl Service {
     ) -> Option<CanonicalJsonValue> {
         self.db.get_uiaa_request(user_id, device_id, session)
     }
+
+    /// Creates a registration token and returns it. A random token is generated if none is given.
+    pub fn create_registration_token(
+        &self,
+        token: Option<String>,
+        uses_allowed: Option<u64>,
+        expiry_time: Option<u64>,
+    ) -> Result<String> {
+        let token = token.unwrap_or_else(|| utils::random_string(REGISTRATION_TOKEN_LENGTH));
+
+        if !is_valid_token(&token) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Registration tokens consist of at most 64 letters, digits, '.', '_', '~' or '-'.",
+            ));
+        }
+
+        let _lock = self.registration_token_lock.lock().unwrap();
+
+        if self.db.registration_token(&token)?.is_some() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Registration token already exists.",
+            ));
+        }
+
+        self.db.set_registration_token(
+            &token,
+            &RegistrationToken {
+                uses_allowed,
+                completed: 0,
+                expiry_time,
+            },
+        )?;
+
+        Ok(token)
+    }
+
+    /// Revokes a registration token and returns whether it existed.
+    pub fn delete_registration_token(&self, token: &str) -> Result<bool> {
+        let _lock = self.registration_token_lock.lock().unwrap();
+
+        self.db.remove_registration_token(token)
+    }
+
+    /// Returns all registration tokens, used up and expired ones included.
+    pub fn registration_tokens<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = Result<(String, RegistrationToken)>> + 'a {
+        self.db.registration_tokens()
+    }
+
+    /// Returns whether an account could register with the token right now.
+    pub fn registration_token_valid(&self, token: &str) -> Result<bool> {
+        Ok(self.db.registration_token(token)?.map_or(false, |info| {
+            info.is_valid(utils::millis_since_unix_epoch())
+        }))
+    }
+
+    /// Checks the response to a captcha with the configured provider.
+    async fn verify_captcha(&self, response: &str) -> Result<()> {
+        let config = services().globals.captcha().ok_or(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Captchas are not enabled on this server.",
+        ))?;
+
+        let verify_url = config
+            .verify_url
+            .as_deref()
+            .unwrap_or_else(|| config.provider.verify_url());
+
+        let siteverify = services()
+            .globals
+            .default_client()
+            .post(verify_url)
+            .form(&[
+                ("secret", config.secret_key.as_str()),
+                ("response", response),
+            ])
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("Could not reach captcha provider {}: {}", verify_url, e);
+                Error::BadServerResponse("Could not reach captcha provider.")
+            })?;
+
+        if !siteverify.status().is_success() {
+            warn!(
+                "Captcha provider {} returned bad response {}",
+                verify_url,
+                siteverify.status()
+            );
+            return Err(Error::BadServerResponse(
+                "Captcha provider returned bad response.",
+            ));
+        }
+
+        let siteverify: SiteVerifyResponse = serde_json::from_slice(
+            &siteverify.bytes().await.unwrap_or_default(),
+        )
+        .map_err(|_| Error::BadServerResponse("Captcha provider returned invalid response."))?;
+
+        if !captcha_passed(&siteverify, config.min_score) {
+            return Err(Error::BadRequest(
+                ErrorKind::CaptchaInvalid,
+                "Captcha is invalid.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Takes up one use of the registration token, fails if it's unknown, expired or used up.
+    fn use_registration_token(&self, token: &str) -> Result<()> {
+        let _lock = self.registration_token_lock.lock().unwrap();
+
+        let mut info = self.db.registration_token(token)?.ok_or(Error::BadRequest(
+            ErrorKind::Unauthorized,
+            "Invalid registration token.",
+        ))?;
+
+        info.consume(utils::millis_since_unix_epoch())?;
+
+        self.db.set_registration_token(token, &info)
+    }
+}
+
+/// Reports why a stage failed to the client, without completing the stage.
+fn failed_stage(mut uiaainfo: UiaaInfo, error: Error) -> Result<(bool, UiaaInfo)> {
+    match error {
+        Error::BadRequest(kind, message) => {
+            uiaainfo.auth_error = Some(StandardErrorBody {
+                kind,
+                message: message.to_owned(),
+            });
+            Ok((false, uiaainfo))
+        }
+        e => Err(e),
+    }
+}
+
+/// The response of a captcha provider's siteverify API.
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    /// Only sent by reCAPTCHA v3
+    score: Option<f64>,
+}
+
+/// Returns whether the provider accepted the captcha, with a high enough score if one is required.
+fn captcha_passed(response: &SiteVerifyResponse, min_score: Option<f64>) -> bool {
+    response.success
+        && min_score.map_or(true, |min_score| {
+            response.score.map_or(false, |score| score >= min_score)
+        })
+}
+
+/// Returns whether the token only uses characters the spec allows for registration tokens.
+fn is_valid_token(token: &str) -> bool {
+    (1..=64).contains(&token.len())
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '~' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn token(
+        uses_allowed: Option<u64>,
+        completed: u64,
+        expiry_time: Option<u64>,
+    ) -> RegistrationToken {
+        RegistrationToken {
+            uses_allowed,
+            completed,
+            expiry_time,
+        }
+    }
+
+    #[test]
+    fn expired_and_used_up_tokens_are_rejected() {
+        assert!(token(None, 1000, None).is_valid(5));
+        assert!(token(Some(2), 1, Some(10)).is_valid(9));
+
+        assert!(!token(Some(2), 1, Some(10)).is_valid(10));
+        assert!(!token(Some(2), 2, None).is_valid(0));
+
+        let mut info = token(Some(1), 0, None);
+        assert!(info.consume(0).is_ok());
+        assert!(info.consume(0).is_err());
+        assert_eq!(info.completed, 1);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn last_use_is_given_out_once() {
+        crate::KeyValueDatabase::load_for_tests().await;
+
+        let token = services()
+            .uiaa
+            .create_registration_token(None, Some(3), None)
+            .unwrap();
+        services().uiaa.use_registration_token(&token).unwrap();
+        services().uiaa.use_registration_token(&token).unwrap();
+
+        let registrations: Vec<_> = (0..8)
+            .map(|_| {
+                let token = token.clone();
+                thread::spawn(move || services().uiaa.use_registration_token(&token).is_ok())
+            })
+            .collect();
+
+        let successful = registrations
+            .into_iter()
+            .map(|registration| registration.join().unwrap())
+            .filter(|&succeeded| succeeded)
+            .count();
+
+        assert_eq!(successful, 1);
+        assert_eq!(
+            services()
+                .uiaa
+                .db
+                .registration_token(&token)
+                .unwrap()
+                .unwrap()
+                .completed,
+            3
+        );
+    }
+
+    #[test]
+    fn captcha_score() {
+        let response = |success, score| SiteVerifyResponse { success, score };
+
+        assert!(captcha_passed(&response(true, None), None));
+        assert!(captcha_passed(&response(true, Some(0.7)), Some(0.5)));
+
+        assert!(!captcha_passed(&response(false, Some(0.9)), None));
+        assert!(!captcha_passed(&response(true, Some(0.3)), Some(0.5)));
+        assert!(!captcha_passed(&response(true, None), Some(0.5)));
+    }
+
+    #[test]
+    fn token_characters() {
+        assert!(is_valid_token("abc-DEF_123.~"));
+        assert!(!is_valid_token(""));
+        assert!(!is_valid_token("with space"));
+        assert!(!is_valid_token(&"a".repeat(65)));
+    }
 }
@@ -4,8 +4,8 @@ use ruma::{
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedUserId, UInt, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 use std::collections::BTreeMap;
 
@@ -16,6 +16,12 @@ pub trait Data: Send + Sync {
     /// Check if account is deactivated
     fn is_deactivated(&self, user_id: &UserId) -> Result<bool>;
 
+    /// Check if the account was registered as a guest.
+    fn is_guest(&self, user_id: &UserId) -> Result<bool>;
+
+    /// Marks the account as a guest account.
+    fn set_guest(&self, user_id: &UserId) -> Result<()>;
+
     /// Returns the number of users registered on this server.
     fn count(&self) -> Result<usize>;
 
@@ -108,7 +114,7 @@ pub trait Data: Send + Sync {
     fn add_cross_signing_keys(
         &self,
         user_id: &UserId,
-        master_key: &Raw<CrossSigningKey>,
+        master_key: &Option<Raw<CrossSigningKey>>,
         self_signing_key: &Option<Raw<CrossSigningKey>>,
         user_signing_key: &Option<Raw<CrossSigningKey>>,
     ) -> Result<()>;
@@ -183,6 +189,23 @@ pub trait Data: Send + Sync {
     fn get_device_metadata(&self, user_id: &UserId, device_id: &DeviceId)
         -> Result<Option<Device>>;
 
+    /// Returns when the device was last used, and from which IP address.
+    fn last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<(MilliSecondsSinceUnixEpoch, Option<String>)>>;
+
+    /// Records that the device was used. Unlike `update_device_metadata`, this doesn't change the
+    /// device list version.
+    fn set_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ts: MilliSecondsSinceUnixEpoch,
+        ip: Option<&str>,
+    ) -> Result<()>;
+
     fn get_devicelist_version(&self, user_id: &UserId) -> Result<Option<u64>>;
 
     fn all_devices_metadata<'a>(
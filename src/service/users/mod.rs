@@ -1,17 +1,31 @@
 mod data;
-use std::{collections::BTreeMap, mem};
+use std::{
+    collections::{BTreeMap, HashSet},
+    mem,
+    net::IpAddr,
+};
 
 pub use data::Data;
 use ruma::{
-    api::client::{device::Device, error::ErrorKind, filter::FilterDefinition},
+    api::{
+        client::{device::Device, error::ErrorKind, filter::FilterDefinition, push::set_pusher},
+        federation::transactions::edu::{Edu, SigningKeyUpdateContent},
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedUserId, RoomAliasId, UInt, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedServerName, OwnedUserId, RoomAliasId, UInt, UserId,
 };
+use serde_json::{json, value::to_raw_value};
+
+use crate::{api::client_server::leave_all_rooms, services, utils, Error, Result};
+
+/// How often the last seen time of a device is stored, in milliseconds, unless its IP address
+/// changes
+const LAST_SEEN_UPDATE_INTERVAL: u32 = 60 * 1000;
 
-use crate::{services, Error, Result};
+pub const GUEST_PASSWORD_LENGTH: usize = 64;
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -28,6 +42,30 @@ impl Service {
         self.db.is_deactivated(user_id)
     }
 
+    /// Check if the account was registered as a guest
+    pub fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        self.db.is_guest(user_id)
+    }
+
+    /// Creates a guest account. Guests get numeric localparts, which other users can't register.
+    pub fn create_guest(&self) -> Result<OwnedUserId> {
+        loop {
+            let user_id = UserId::parse_with_server_name(
+                services().globals.next_count()?.to_string(),
+                services().globals.server_name(),
+            )
+            .expect("numeric localparts are valid");
+
+            if !self.exists(&user_id)? {
+                // Nobody knows the password, but an empty one would mark the account as
+                // deactivated
+                self.create(&user_id, Some(&utils::random_string(GUEST_PASSWORD_LENGTH)))?;
+                self.db.set_guest(&user_id)?;
+                return Ok(user_id);
+            }
+        }
+    }
+
     /// Check if a user is an admin
     pub fn is_admin(&self, user_id: &UserId) -> Result<bool> {
         let admin_room_alias_id =
@@ -83,6 +121,27 @@ impl Service {
         self.db.set_password(user_id, password)
     }
 
+    /// Sets a new password, hashed with Argon2. If `logout_other_devices` is true, every device
+    /// except `current_device` is removed, so their access tokens stop working.
+    pub fn change_password(
+        &self,
+        user_id: &UserId,
+        current_device: &DeviceId,
+        new_password: &str,
+        logout_other_devices: bool,
+    ) -> Result<()> {
+        self.db.set_password(user_id, Some(new_password))?;
+
+        if logout_other_devices {
+            let device_ids = self.all_device_ids(user_id).collect::<Result<Vec<_>>>()?;
+            for device_id in other_devices(device_ids, current_device) {
+                self.remove_device(user_id, &device_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns the displayname of a user on this homeserver.
     pub fn displayname(&self, user_id: &UserId) -> Result<Option<String>> {
         self.db.displayname(user_id)
@@ -127,6 +186,11 @@ impl Service {
 
     /// Removes a device from a user.
     pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        // Pushers of a device that was logged out would keep notifying it
+        services()
+            .pusher
+            .remove_device_pushers(user_id, device_id)?;
+
         self.db.remove_device(user_id, device_id)
     }
 
@@ -181,18 +245,45 @@ impl Service {
         device_id: &DeviceId,
         device_keys: &Raw<DeviceKeys>,
     ) -> Result<()> {
-        self.db.add_device_keys(user_id, device_id, device_keys)
+        self.db.add_device_keys(user_id, device_id, device_keys)?;
+        services().pusher.notify_device_list_change(user_id)
     }
 
+    /// Replaces the given cross-signing keys of the user. Signatures of a key that is uploaded
+    /// again are kept, and servers sharing a room with a local user are told about the change.
     pub fn add_cross_signing_keys(
         &self,
         user_id: &UserId,
-        master_key: &Raw<CrossSigningKey>,
+        master_key: &Option<Raw<CrossSigningKey>>,
         self_signing_key: &Option<Raw<CrossSigningKey>>,
         user_signing_key: &Option<Raw<CrossSigningKey>>,
     ) -> Result<()> {
-        self.db
-            .add_cross_signing_keys(user_id, master_key, self_signing_key, user_signing_key)
+        let master_key = master_key
+            .as_ref()
+            .map(|key| keep_signatures(key, self.get_master_key(user_id, &|_| true)?))
+            .transpose()?;
+        let self_signing_key = self_signing_key
+            .as_ref()
+            .map(|key| keep_signatures(key, self.get_self_signing_key(user_id, &|_| true)?))
+            .transpose()?;
+        let user_signing_key = user_signing_key
+            .as_ref()
+            .map(|key| keep_signatures(key, self.get_user_signing_key(user_id)?))
+            .transpose()?;
+
+        self.db.add_cross_signing_keys(
+            user_id,
+            &master_key,
+            &self_signing_key,
+            &user_signing_key,
+        )?;
+
+        // The user-signing key is private to the user, so other servers don't need to know
+        if master_key.is_some() || self_signing_key.is_some() {
+            self.federation_send_signing_keys(user_id)?;
+        }
+
+        Ok(())
     }
 
     pub fn sign_key(
@@ -202,9 +293,36 @@ impl Service {
         signature: (String, String),
         sender_id: &UserId,
     ) -> Result<()> {
+        // Marking the keys as changed also sends a device list update to other servers, which
+        // makes them fetch the signed keys again
         self.db.sign_key(target_id, key_id, signature, sender_id)
     }
 
+    /// Sends an m.signing_key_update EDU with the current master and self-signing keys of a
+    /// local user to all servers that share a room with them.
+    fn federation_send_signing_keys(&self, user_id: &UserId) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        let edu = serde_json::to_vec(&Edu::SigningKeyUpdate(SigningKeyUpdateContent {
+            user_id: user_id.to_owned(),
+            master_key: self.get_master_key(user_id, &|u| u == user_id)?,
+            self_signing_key: self.get_self_signing_key(user_id, &|u| u == user_id)?,
+        }))
+        .expect("SigningKeyUpdate EDU can be serialized");
+
+        for server in servers_sharing_rooms(user_id)? {
+            services().sending.send_reliable_edu(
+                &server,
+                edu.clone(),
+                services().globals.next_count()?,
+            )?;
+        }
+
+        Ok(())
+    }
+
     pub fn keys_changed<'a>(
         &'a self,
         user_or_room_id: &str,
@@ -254,6 +372,17 @@ impl Service {
         event_type: &str,
         content: serde_json::Value,
     ) -> Result<()> {
+        // Appservice users don't sync, their appservice receives the messages in its transactions
+        if services().sending.send_to_device_appservices(
+            sender,
+            target_user_id,
+            target_device_id,
+            event_type,
+            &content,
+        )? {
+            return Ok(());
+        }
+
         self.db.add_to_device_event(
             sender,
             target_user_id,
@@ -298,6 +427,29 @@ impl Service {
         self.db.get_device_metadata(user_id, device_id)
     }
 
+    /// Records that the device was used just now, from the given IP address. To keep writes
+    /// down, this is only stored if the IP address changed or the last update is a while ago.
+    pub fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let ip = ip.map(|ip| ip.to_string());
+
+        if last_seen_outdated(
+            self.db.last_seen(user_id, device_id)?.as_ref(),
+            now,
+            ip.as_deref(),
+        ) {
+            self.db
+                .set_last_seen(user_id, device_id, now, ip.as_deref())?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_devicelist_version(&self, user_id: &UserId) -> Result<Option<u64>> {
         self.db.get_devicelist_version(user_id)
     }
@@ -310,8 +462,9 @@ impl Service {
     }
 
     /// Deactivate account
-    pub fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
-        // Remove all associated devices
+    /// Makes the user unable to use the account, but keeps them in their rooms. Can be retried.
+    pub fn lock_account(&self, user_id: &UserId) -> Result<()> {
+        // Remove all associated devices, which invalidates their access tokens
         for device_id in self.all_device_ids(user_id) {
             self.remove_device(user_id, &device_id?)?;
         }
@@ -321,10 +474,32 @@ impl Service {
         // password without logging in should check if the account is deactivated.
         self.db.set_password(user_id, None)?;
 
-        // TODO: Unhook 3PID
+        // Nothing should be pushed to a deactivated account
+        for pusher in services().pusher.get_pushers(user_id)? {
+            services()
+                .pusher
+                .set_pusher(user_id, set_pusher::v3::PusherAction::Delete(pusher.ids))?;
+        }
+
+        // Conduit doesn't store third party identifiers, so there are none to unbind
         Ok(())
     }
 
+    /// Deactivates the account and makes the user leave all rooms. If `erase` is true, the
+    /// display name and avatar are removed as well. Can be retried.
+    pub async fn deactivate_account(&self, user_id: &UserId, erase: bool) -> Result<()> {
+        self.lock_account(user_id)?;
+
+        // Remove the profile before leaving, so the leave events don't carry it
+        if erase {
+            self.set_displayname(user_id, None)?;
+            self.set_avatar_url(user_id, None)?;
+            self.set_blurhash(user_id, None)?;
+        }
+
+        leave_all_rooms(user_id).await
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
         self.db.create_filter(user_id, filter)
@@ -365,3 +540,241 @@ pub fn clean_signatures<F: Fn(&UserId) -> bool>(
 
     Ok(())
 }
+
+/// Returns the other servers that share a room with the user.
+fn servers_sharing_rooms(user_id: &UserId) -> Result<HashSet<OwnedServerName>> {
+    let mut servers = HashSet::new();
+    for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+        servers.extend(
+            services()
+                .rooms
+                .state_cache
+                .room_servers(&room_id?)
+                .filter_map(|r| r.ok()),
+        );
+    }
+    servers.remove(services().globals.server_name());
+
+    Ok(servers)
+}
+
+/// Returns the uploaded cross-signing key with the signatures of the stored key, if both are the
+/// same key. Uploading a key again must not drop the signatures other users made.
+fn keep_signatures(
+    uploaded: &Raw<CrossSigningKey>,
+    stored: Option<Raw<CrossSigningKey>>,
+) -> Result<Raw<CrossSigningKey>> {
+    let stored = match stored {
+        Some(stored) => stored,
+        None => return Ok(uploaded.clone()),
+    };
+
+    let mut uploaded_json: serde_json::Value = serde_json::from_str(uploaded.json().get())
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid cross-signing key."))?;
+    let stored_json: serde_json::Value = serde_json::from_str(stored.json().get())
+        .map_err(|_| Error::bad_database("Invalid cross-signing key in database."))?;
+
+    merge_signatures(&mut uploaded_json, &stored_json);
+
+    Ok(Raw::from_json(
+        to_raw_value(&uploaded_json).expect("json can be serialized"),
+    ))
+}
+
+/// Adds the signatures of the stored key to the uploaded one if they contain the same public key.
+/// Signatures in the upload win, because they are newer.
+fn merge_signatures(uploaded: &mut serde_json::Value, stored: &serde_json::Value) {
+    if uploaded.get("keys") != stored.get("keys") {
+        return;
+    }
+
+    let stored_signatures = match stored.get("signatures").and_then(|s| s.as_object()) {
+        Some(signatures) => signatures,
+        None => return,
+    };
+    let uploaded_signatures = match uploaded.as_object_mut().map(|key| {
+        key.entry("signatures")
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+    }) {
+        Some(Some(signatures)) => signatures,
+        _ => return,
+    };
+
+    for (user, stored) in stored_signatures {
+        let uploaded = uploaded_signatures.entry(user).or_insert_with(|| json!({}));
+
+        if let (Some(uploaded), Some(stored)) = (uploaded.as_object_mut(), stored.as_object()) {
+            for (key_id, signature) in stored {
+                uploaded.entry(key_id).or_insert_with(|| signature.clone());
+            }
+        }
+    }
+}
+
+/// Returns whether the localpart is reserved for guests, which are the only users with numeric
+/// localparts.
+pub fn is_guest_localpart(localpart: &str) -> bool {
+    !localpart.is_empty() && localpart.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Returns whether the stored last seen time and IP address of a device should be replaced.
+fn last_seen_outdated(
+    last_seen: Option<&(MilliSecondsSinceUnixEpoch, Option<String>)>,
+    now: MilliSecondsSinceUnixEpoch,
+    ip: Option<&str>,
+) -> bool {
+    match last_seen {
+        Some((ts, last_ip)) => {
+            last_ip.as_deref() != ip
+                || now.get().saturating_sub(ts.get()) >= UInt::from(LAST_SEEN_UPDATE_INTERVAL)
+        }
+        None => true,
+    }
+}
+
+/// Returns all devices except the current one, which has to stay logged in.
+fn other_devices(
+    device_ids: Vec<OwnedDeviceId>,
+    current_device: &DeviceId,
+) -> impl Iterator<Item = OwnedDeviceId> + '_ {
+    device_ids
+        .into_iter()
+        .filter(move |device_id| &**device_id != current_device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::events::room::member::MembershipState;
+
+    #[test]
+    fn reuploaded_keys_keep_their_signatures() {
+        let stored = json!({
+            "user_id": "@alice:example.org",
+            "usage": ["master"],
+            "keys": { "ed25519:master": "master" },
+            "signatures": {
+                "@alice:example.org": { "ed25519:DEVICE": "old", "ed25519:master": "self" },
+                "@bob:example.org": { "ed25519:bob": "bob" },
+            },
+        });
+
+        let mut reuploaded = json!({
+            "user_id": "@alice:example.org",
+            "usage": ["master"],
+            "keys": { "ed25519:master": "master" },
+            "signatures": {
+                "@alice:example.org": { "ed25519:DEVICE": "new" },
+            },
+        });
+        merge_signatures(&mut reuploaded, &stored);
+        assert_eq!(
+            reuploaded["signatures"],
+            json!({
+                "@alice:example.org": { "ed25519:DEVICE": "new", "ed25519:master": "self" },
+                "@bob:example.org": { "ed25519:bob": "bob" },
+            })
+        );
+
+        // Signatures of a replaced key don't apply to the new one
+        let mut replaced = json!({
+            "user_id": "@alice:example.org",
+            "usage": ["master"],
+            "keys": { "ed25519:other": "other" },
+        });
+        merge_signatures(&mut replaced, &stored);
+        assert!(replaced.get("signatures").is_none());
+    }
+
+    #[test]
+    fn numeric_localparts_are_reserved_for_guests() {
+        assert!(is_guest_localpart("1234"));
+        assert!(!is_guest_localpart("alice"));
+        assert!(!is_guest_localpart("1234a"));
+        assert!(!is_guest_localpart(""));
+    }
+
+    #[test]
+    fn current_device_stays_logged_in() {
+        let device_ids: Vec<OwnedDeviceId> = vec!["PHONE".into(), "LAPTOP".into(), "TABLET".into()];
+
+        let current_device = <&DeviceId>::from("LAPTOP");
+
+        let logged_out: Vec<_> = other_devices(device_ids, current_device).collect();
+        assert_eq!(
+            logged_out,
+            vec![OwnedDeviceId::from("PHONE"), "TABLET".into()]
+        );
+
+        let logged_out: Vec<_> = other_devices(vec!["LAPTOP".into()], current_device).collect();
+        assert!(logged_out.is_empty());
+    }
+
+    #[test]
+    fn last_seen_is_updated_on_new_ip_or_after_interval() {
+        let ts = |millis: u32| MilliSecondsSinceUnixEpoch(millis.into());
+        let last_seen = (ts(1_000), Some("192.0.2.1".to_owned()));
+
+        assert!(last_seen_outdated(None, ts(1_000), None));
+        assert!(!last_seen_outdated(
+            Some(&last_seen),
+            ts(2_000),
+            Some("192.0.2.1")
+        ));
+        assert!(last_seen_outdated(
+            Some(&last_seen),
+            ts(2_000),
+            Some("192.0.2.2")
+        ));
+        assert!(last_seen_outdated(
+            Some(&last_seen),
+            ts(1_000 + LAST_SEEN_UPDATE_INTERVAL),
+            Some("192.0.2.1")
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn deactivated_user_leaves_member_lists() {
+        crate::KeyValueDatabase::load_for_tests().await;
+
+        let user = ruma::user_id!("@deactivated:example.org");
+        let sender = ruma::user_id!("@someone:remote.example.org");
+        let joined = ruma::room_id!("!joined:remote.example.org");
+        let invited = ruma::room_id!("!invited:remote.example.org");
+        let state_cache = &services().rooms.state_cache;
+
+        services().users.create(user, Some("hunter2")).unwrap();
+        state_cache
+            .update_membership(joined, user, MembershipState::Join, user, None, true)
+            .unwrap();
+        state_cache
+            .update_membership(
+                invited,
+                user,
+                MembershipState::Invite,
+                sender,
+                Some(vec![]),
+                true,
+            )
+            .unwrap();
+        assert!(state_cache.is_joined(user, joined).unwrap());
+        assert!(state_cache.is_invited(user, invited).unwrap());
+
+        services()
+            .users
+            .deactivate_account(user, false)
+            .await
+            .unwrap();
+
+        assert!(services().users.is_deactivated(user).unwrap());
+        assert!(!state_cache
+            .room_members(joined)
+            .any(|member| &*member.unwrap() == user));
+        assert!(!state_cache
+            .room_members_invited(invited)
+            .any(|member| &*member.unwrap() == user));
+        assert_eq!(state_cache.rooms_joined(user).count(), 0);
+    }
+}
@@ -63,11 +63,16 @@ pub enum Error {
     },
     #[error("{0}")]
     BadServerResponse(&'static str),
+    #[error("Push gateway returned status {0}")]
+    PushGatewayError(StatusCode),
     #[error("{0}")]
     BadConfig(&'static str),
     #[error("{0}")]
     /// Don't create this directly. Use Error::bad_database instead.
     BadDatabase(&'static str),
+    #[error("{0}")]
+    /// Don't create this directly. Use Error::bad_pusher instead.
+    BadPusher(&'static str),
     #[error("uiaa")]
     Uiaa(UiaaInfo),
     #[error("{0}: {1}")]
@@ -88,6 +93,11 @@ impl Error {
         Self::BadDatabase(message)
     }
 
+    pub fn bad_pusher(message: &'static str) -> Self {
+        error!("BadPusher: {}", message);
+        Self::BadPusher(message)
+    }
+
     pub fn bad_config(message: &'static str) -> Self {
         error!("BadConfig: {}", message);
         Self::BadConfig(message)
@@ -7,9 +7,11 @@ use ring::digest;
 use ruma::{canonical_json::try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
 use std::{
     cmp, fmt,
+    net::IpAddr,
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
+use unicode_segmentation::UnicodeSegmentation;
 
 pub fn millis_since_unix_epoch() -> u64 {
     SystemTime::now()
@@ -180,3 +182,104 @@ impl<'a> fmt::Display for HtmlEscape<'a> {
         Ok(())
     }
 }
+
+/// Returns the IP address of the client. The `X-Forwarded-For` header is only trusted if the
+/// request comes from a reverse proxy on the same host, as anyone else could forge it.
+///
+/// Only the last entry, which the proxy appended, is used. The entries before it were sent by
+/// the client and can be anything.
+pub fn client_ip(peer_addr: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+    match (peer_addr, forwarded_for) {
+        (Some(peer_addr), Some(forwarded_for)) if peer_addr.is_loopback() => forwarded_for
+            .rsplit(',')
+            .next()
+            .and_then(|ip| ip.trim().parse().ok())
+            .or(Some(peer_addr)),
+        (peer_addr, _) => peer_addr,
+    }
+}
+
+/// Truncates `s` so that it is at most `max_len` bytes long, including a trailing ellipsis.
+///
+/// The string is only ever cut at a grapheme cluster boundary, so emoji sequences (ZWJ
+/// sequences, skin tones, flags) and combining characters are never split.
+pub fn truncate_with_ellipsis(s: &str, max_len: usize) -> std::borrow::Cow<'_, str> {
+    const ELLIPSIS: &str = "…";
+
+    if s.len() <= max_len {
+        return s.into();
+    }
+
+    let budget = match max_len.checked_sub(ELLIPSIS.len()) {
+        Some(budget) => budget,
+        None => return "".into(),
+    };
+
+    // Each grapheme cluster starts at a valid place to cut
+    let end = s
+        .grapheme_indices(true)
+        .map(|(i, _)| i)
+        .take_while(|&i| i <= budget)
+        .last()
+        .unwrap_or(0);
+
+    format!("{}{ELLIPSIS}", &s[..end]).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_short_string_is_unchanged() {
+        assert_eq!(truncate_with_ellipsis("hello", 10), "hello");
+    }
+
+    #[test]
+    fn truncate_ascii() {
+        let truncated = truncate_with_ellipsis("hello world", 8);
+        assert_eq!(truncated, "hello…");
+        assert!(truncated.len() <= 8);
+    }
+
+    #[test]
+    fn truncate_does_not_split_emoji() {
+        // Family emoji (ZWJ sequence, 25 bytes), followed by a thumbs up with skin tone
+        let input = "hi 👨‍👩‍👧‍👦 👍🏽 there";
+
+        for max_len in 0..input.len() {
+            let truncated = truncate_with_ellipsis(input, max_len);
+            assert!(truncated.len() <= max_len);
+            if !truncated.is_empty() {
+                assert!(truncated.ends_with('…'));
+                let kept = truncated.trim_end_matches('…');
+                assert!(input.starts_with(kept));
+                // Never end on a joiner or in the middle of the family sequence
+                assert!(!kept.ends_with('\u{200D}'));
+                assert!(!kept.ends_with('👨'));
+                assert!(!kept.ends_with('👍'));
+            }
+        }
+    }
+
+    #[test]
+    fn truncate_does_not_split_flags() {
+        let input = "🇩🇪🇫🇷🇮🇹";
+        let truncated = truncate_with_ellipsis(input, 14);
+        assert_eq!(truncated, "🇩🇪…");
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_local_proxies() {
+        let proxy = "127.0.0.1".parse().ok();
+        let client = "192.0.2.1".parse().ok();
+
+        assert_eq!(client_ip(proxy, Some("192.0.2.1")), client);
+        // Clients can send their own header, which the proxy appends to
+        assert_eq!(client_ip(proxy, Some("198.51.100.1, 192.0.2.1")), client);
+        assert_eq!(client_ip(proxy, Some("not an ip")), proxy);
+        assert_eq!(client_ip(proxy, None), proxy);
+        assert_eq!(client_ip(client, Some("198.51.100.1")), client);
+        assert_eq!(client_ip(None, Some("198.51.100.1")), None);
+    }
+}